@@ -0,0 +1,624 @@
+//! Turns instructions.txt into the decoder's opcode tables and the executor, see the top of that
+//! file for the format.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::{env, fs, path::Path};
+
+const SPEC: &str = "instructions.txt";
+
+/// Mnemonics that end in a condition code, they become `Op::Jcc(Cond::E)` and so on.
+const CC_FAMILIES: [(&str, &str); 3] = [("J", "Jcc"), ("SET", "Setcc"), ("CMOV", "Cmovcc")];
+const CONDITIONS: [&str; 16] = [
+    "O", "NO", "B", "AE", "E", "NE", "BE", "A", "S", "NS", "P", "NP", "L", "GE", "LE", "G",
+];
+
+struct Form {
+    map: &'static str,
+    prefix: &'static str,
+    w: Option<bool>,
+    size: Option<&'static str>,
+    l: Option<u8>,
+    opcode: u8,
+    plus_reg: bool,
+    ext: Option<u8>,
+    modrm: bool,
+    fixed_modrm: Option<u8>,
+    op: String,
+    operands: Vec<(&'static str, &'static str, &'static str)>,
+    mask: &'static str,
+    broadcast: Option<&'static str>,
+    er: bool,
+    sae: bool,
+}
+
+/// A semantics line, what an instruction does to its operands.
+struct Semantics {
+    op: String,
+    /// whether the result is written to the first operand
+    write: bool,
+    /// the result expression, or a block of statements when it starts with `{`
+    body: &'static str,
+    flags: &'static str,
+}
+
+/// The flags helpers a semantics line can name, with the arguments they take.
+const FLAGS: [(&str, &str); 6] = [
+    ("logic", "result, instr.size"),
+    ("add", "a, b, result, instr.size"),
+    ("sub", "a, b, result, instr.size"),
+    ("inc", "a, result, instr.size"),
+    ("dec", "a, result, instr.size"),
+    ("neg", "a, result, instr.size"),
+];
+
+fn parse_hex(s: &str) -> Option<u8> {
+    if s.len() == 2 {
+        u8::from_str_radix(s, 16).ok()
+    } else {
+        None
+    }
+}
+
+fn title_case(s: &str) -> String {
+    let lower = s.to_ascii_lowercase();
+    let mut chars = lower.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+/// `Op` variant for a mnemonic, with condition codes split off.
+fn op_name(mnemonic: &str) -> String {
+    for (family, variant) in CC_FAMILIES {
+        if let Some(cond) = mnemonic.strip_prefix(family) {
+            if CONDITIONS.contains(&cond) {
+                return format!("{variant}(Cond::{})", title_case(cond));
+            }
+        }
+    }
+    title_case(mnemonic)
+}
+
+/// An operand as (kind, register kind, width), the kind is turned into a `Src` once the op/en
+/// letter is known. The width is the one of the memory operand when the register is different,
+/// like in `xmm2/m64`.
+fn parse_operand(token: &str) -> Result<(&'static str, &'static str, &'static str), String> {
+    let width = |s: &str| match s {
+        "8" => Ok("Fixed(Size::Byte)"),
+        "16" => Ok("Fixed(Size::Word)"),
+        "32" => Ok("Fixed(Size::Dword)"),
+        "64" => Ok("Fixed(Size::Qword)"),
+        "128" => Ok("Fixed(Size::Oword)"),
+        "256" => Ok("Fixed(Size::Yword)"),
+        "512" => Ok("Fixed(Size::Zword)"),
+        "80" => Ok("Fixed(Size::Tword)"),
+        "16/32/64" => Ok("V"),
+        "16/32" => Ok("Z"),
+        "16/64" => Ok("D"),
+        _ => Err(format!("unknown operand size in {token}")),
+    };
+
+    match token {
+        "<XMM0>" => return Ok(("Fixed(0)", "Xmm", "Fixed(Size::Oword)")),
+        "AL" => return Ok(("Fixed(0)", "Gpr", "Fixed(Size::Byte)")),
+        "AX" => return Ok(("Fixed(0)", "Gpr", "Fixed(Size::Word)")),
+        "CL" => return Ok(("Fixed(1)", "Gpr", "Fixed(Size::Byte)")),
+        "rAX" => return Ok(("Fixed(0)", "Gpr", "V")),
+        "1" => return Ok(("One", "Gpr", "Fixed(Size::Byte)")),
+        "ST(0)" => return Ok(("Fixed(0)", "St", "Fixed(Size::Tword)")),
+        "ST(i)" => return Ok(("Reg", "St", "Fixed(Size::Tword)")),
+        "m2byte" => return Ok(("Mem", "Gpr", "Fixed(Size::Word)")),
+        _ => {}
+    }
+    // x87 memory operands say what's in them, m32fp, m64int and so on
+    if let Some(bits) = token
+        .strip_prefix('m')
+        .and_then(|x| x.strip_suffix("fp").or_else(|| x.strip_suffix("int")))
+    {
+        return Ok(("Mem", "Gpr", width(bits)?));
+    }
+    if let Some((reg, mem)) = token.split_once("/m") {
+        let reg = match reg {
+            "r" => "Gpr",
+            "r32" => "Gpr32",
+            _ if reg.starts_with("xmm") => "Xmm",
+            _ if reg.starts_with("ymm") => "Ymm",
+            _ if reg.starts_with("zmm") => "Zmm",
+            _ if reg.starts_with('k') => "Mask",
+            _ => return Err(format!("unknown register in {token}")),
+        };
+        return Ok(("Rm", reg, width(mem)?));
+    }
+    if token.starts_with("xmm") {
+        return Ok(("Reg", "Xmm", "Fixed(Size::Oword)"));
+    }
+    if token.starts_with("ymm") {
+        return Ok(("Reg", "Ymm", "Fixed(Size::Yword)"));
+    }
+    if token.starts_with("zmm") {
+        return Ok(("Reg", "Zmm", "Fixed(Size::Zword)"));
+    }
+    if token.starts_with('k') {
+        return Ok(("Reg", "Mask", "Fixed(Size::Qword)"));
+    }
+
+    let kinds = [("imm", "Imm"), ("rel", "Rel"), ("m", "Mem"), ("r", "Reg")];
+    for (prefix, kind) in kinds {
+        if let Some(rest) = token.strip_prefix(prefix) {
+            return Ok((kind, "Gpr", width(rest)?));
+        }
+    }
+    Err(format!("unknown operand {token}"))
+}
+
+/// `VEX.L.pp.map.W` or `EVEX.L.pp.map.W` without the `VEX.`, pp and W can be left out.
+fn parse_vex(form: &mut Form, vex: &str, evex: bool) -> Result<(), String> {
+    let mut fields = vex.split('.').peekable();
+    form.l = match fields.next() {
+        Some("128" | "L0" | "LZ") => Some(0),
+        Some("256" | "L1") => Some(1),
+        Some("512") if evex => Some(2),
+        Some("LIG") => None,
+        _ => return Err(format!("bad VEX.L in {vex}")),
+    };
+    form.prefix = match fields.peek() {
+        Some(&"66") => "P66",
+        Some(&"F2") => "F2",
+        Some(&"F3") => "F3",
+        _ => "Np",
+    };
+    if form.prefix != "Np" {
+        fields.next();
+    }
+    form.map = match (fields.next(), evex) {
+        (Some("0F"), false) => "Vex0F",
+        (Some("0F38"), false) => "Vex0F38",
+        (Some("0F3A"), false) => "Vex0F3A",
+        (Some("0F"), true) => "Evex0F",
+        (Some("0F38"), true) => "Evex0F38",
+        (Some("0F3A"), true) => "Evex0F3A",
+        _ => return Err(format!("bad VEX map in {vex}")),
+    };
+    form.w = match fields.next() {
+        Some("W0") => Some(false),
+        Some("W1") => Some(true),
+        Some("WIG") | None => None,
+        _ => return Err(format!("bad VEX.W in {vex}")),
+    };
+    Ok(())
+}
+
+/// Strips the evex decorations off an operand, like `{k1}{z}` or the `/m32bcst` and `{er}`
+/// in `zmm3/m512/m32bcst{er}`, and records them in `form`.
+fn parse_decorations<'x>(form: &mut Form, token: &'x str) -> Result<&'x str, String> {
+    let (mut token, decorations) = match token.find('{') {
+        Some(i) => (token[..i].trim_end(), &token[i..]),
+        None => (token, ""),
+    };
+    for decoration in decorations.split_inclusive('}') {
+        match decoration {
+            "{k1}" | "{k2}" => form.mask = "Merge",
+            "{z}" => form.mask = "Zero",
+            "{er}" => form.er = true,
+            "{sae}" => form.sae = true,
+            _ => return Err(format!("unknown decoration {decoration} in {token}")),
+        }
+    }
+    if let Some(rest) = token.strip_suffix("bcst") {
+        let (rest, size) = rest
+            .rsplit_once("/m")
+            .ok_or(format!("bad broadcast {token}"))?;
+        form.broadcast = Some(match size {
+            "16" => "Word",
+            "32" => "Dword",
+            "64" => "Qword",
+            _ => return Err(format!("bad broadcast size in {token}")),
+        });
+        token = rest;
+    }
+    Ok(token)
+}
+
+fn parse_line(line: &str) -> Result<Form, String> {
+    let columns: Vec<&str> = line.split('|').map(str::trim).collect();
+    let [opcode, instruction, op_en] = columns[..] else {
+        return Err("expected 3 columns".into());
+    };
+
+    let mut form = Form {
+        map: "OneByte",
+        prefix: "Any",
+        w: None,
+        size: None,
+        l: None,
+        opcode: 0,
+        plus_reg: false,
+        ext: None,
+        modrm: false,
+        fixed_modrm: None,
+        op: String::new(),
+        operands: Vec::new(),
+        mask: "None",
+        broadcast: None,
+        er: false,
+        sae: false,
+    };
+
+    let mut tokens = opcode.split_whitespace().peekable();
+    form.size = match tokens.peek() {
+        Some(&"o16") => Some("Word"),
+        Some(&"o32") => Some("Dword"),
+        Some(&"o64") => Some("Qword"),
+        _ => None,
+    };
+    if form.size.is_some() {
+        tokens.next();
+    }
+    if let Some(vex) = tokens.peek().and_then(|x| x.strip_prefix("VEX.")) {
+        parse_vex(&mut form, vex, false)?;
+        tokens.next();
+    } else if let Some(evex) = tokens.peek().and_then(|x| x.strip_prefix("EVEX.")) {
+        parse_vex(&mut form, evex, true)?;
+        tokens.next();
+    } else if let Some(&first) = tokens.peek() {
+        let prefix = match first {
+            "NP" => Some("Np"),
+            "66" => Some("P66"),
+            "F2" => Some("F2"),
+            "F3" => Some("F3"),
+            _ => None,
+        };
+        // 66/F2/F3 are only mandatory prefixes when an escape byte follows
+        if let Some(prefix) = prefix {
+            if first == "NP" || tokens.clone().nth(1).is_some() {
+                form.prefix = prefix;
+                tokens.next();
+            }
+        }
+    }
+    if tokens.peek() == Some(&"REX.W") {
+        form.w = Some(true);
+        tokens.next();
+        // the manual writes both REX.W + B8 and F2 REX.W 0F
+        if tokens.peek() == Some(&"+") {
+            tokens.next();
+        }
+    }
+    if tokens.peek() == Some(&"0F") {
+        form.map = "Map0F";
+        tokens.next();
+        match tokens.peek() {
+            Some(&"38") => form.map = "Map0F38",
+            Some(&"3A") => form.map = "Map0F3A",
+            _ => {}
+        }
+        if form.map != "Map0F" {
+            tokens.next();
+        }
+    }
+
+    let main = tokens.next().ok_or("missing opcode")?;
+    let (byte, plus_reg) = match main.split_once('+') {
+        Some((byte, "rb" | "rw" | "rd" | "ro")) => (byte, true),
+        Some(_) => return Err(format!("bad opcode {main}")),
+        None => (main, false),
+    };
+    form.opcode = parse_hex(byte).ok_or(format!("bad opcode {main}"))?;
+    form.plus_reg = plus_reg;
+
+    for token in tokens {
+        match token {
+            "/r" => form.modrm = true,
+            "ib" | "iw" | "id" | "io" | "cb" | "cw" | "cd" | "/is4" => {}
+            _ => {
+                if let Some(digit) = token.strip_prefix('/') {
+                    let ext = digit.parse().ok().filter(|x| *x < 8);
+                    form.ext = Some(ext.ok_or(format!("bad opcode extension {token}"))?);
+                    form.modrm = true;
+                } else if let Some(x) = token.strip_suffix("+i").and_then(parse_hex) {
+                    // x87 register forms like D8 C0+i, modrm.rm is st(i)
+                    if x & 0xc7 != 0xc0 || form.modrm {
+                        return Err(format!("bad x87 register opcode {token}"));
+                    }
+                    form.ext = Some(x >> 3 & 7);
+                    form.modrm = true;
+                } else if let Some(x) = parse_hex(token).filter(|_| !form.modrm) {
+                    form.fixed_modrm = Some(x);
+                    form.modrm = true;
+                } else {
+                    return Err(format!("unknown opcode token {token}"));
+                }
+            }
+        }
+    }
+
+    let (mnemonic, operands) = instruction.split_once(' ').unwrap_or((instruction, ""));
+    form.op = op_name(mnemonic);
+
+    let mut letters = if op_en == "ZO" { "" } else { op_en }.chars();
+    let mut seen_r = false;
+    for token in operands.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let token = parse_decorations(&mut form, token)?;
+        let (kind, reg, width) = parse_operand(token)?;
+        let src = if kind.starts_with("Fixed") || kind == "One" {
+            kind
+        } else {
+            let letter = letters
+                .next()
+                .ok_or(format!("no op/en letter for {token}"))?;
+            match (letter, kind) {
+                ('M', "Rm" | "Mem") => kind,
+                ('M', "Reg") => "RmReg",
+                // RVMR, the second register is in the high bits of an immediate
+                ('R', "Reg") if seen_r => "Is4",
+                ('R', "Reg") => {
+                    seen_r = true;
+                    "Reg"
+                }
+                ('V', "Reg") => "Vvvv",
+                ('O', "Reg") => "Opcode",
+                ('I', "Imm") => "Imm",
+                ('D', "Rel") => "Rel",
+                _ => return Err(format!("op/en {letter} doesn't fit {token}")),
+            }
+        };
+        form.operands.push((src, reg, width));
+    }
+    if letters.next().is_some() {
+        return Err(format!(
+            "op/en {op_en} has more operands than {instruction}"
+        ));
+    }
+
+    Ok(form)
+}
+
+fn parse_semantics(line: &'static str) -> Result<Semantics, String> {
+    let (mnemonic, rest) = line.split_once("=>").ok_or("expected =>")?;
+    // the body is rust code, so it's only split on the last |
+    let (body, flags) = rest.rsplit_once('|').ok_or("expected a flags column")?;
+    let (body, flags) = (body.trim(), flags.trim());
+    if flags != "-" && !FLAGS.iter().any(|x| x.0 == flags) {
+        return Err(format!("unknown flags {flags}"));
+    }
+    let (write, body) = match body.strip_prefix("a =") {
+        Some(expression) => (true, expression.trim()),
+        None => (false, body),
+    };
+    if body.starts_with('{') && (write || flags != "-") {
+        return Err("a block can't have a result".into());
+    }
+    Ok(Semantics {
+        op: op_name(mnemonic.trim()),
+        write,
+        body,
+        flags,
+    })
+}
+
+/// Whether the identifier `name` appears in `code`.
+fn uses(code: &str, name: &str) -> bool {
+    code.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .any(|x| x == name)
+}
+
+fn write_executor(out: &mut String, semantics: &[Semantics]) {
+    out.push_str("impl<D: DisasmWriter> Emulator<D> {\n");
+    out.push_str("    /// Executes `instr`, which was decoded at `self.instr_ip`. `self.ip` already points to the next instruction.\n");
+    out.push_str("    pub fn execute(&mut self, instr: &Instr) -> Result<Option<StopReason>, EmulatorError> {\n");
+    out.push_str("        match instr.op {\n");
+    for x in semantics {
+        let pattern = if CC_FAMILIES.iter().any(|family| family.1 == x.op) {
+            format!("{}(cond)", x.op)
+        } else {
+            x.op.clone()
+        };
+        writeln!(out, "            Op::{pattern} => {{").unwrap();
+        let flags = FLAGS.iter().find(|f| f.0 == x.flags);
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            if uses(x.body, name) || flags.is_some_and(|f| uses(f.1, name)) {
+                writeln!(
+                    out,
+                    "                let {name} = self.read(instr.operands[{i}])?;"
+                )
+                .unwrap();
+            }
+        }
+        if let Some(block) = x.body.strip_prefix('{').and_then(|x| x.strip_suffix('}')) {
+            let block = block.trim();
+            if !block.is_empty() {
+                let semicolon = if block.ends_with(['}', ';']) { "" } else { ";" };
+                writeln!(out, "                {block}{semicolon}").unwrap();
+            }
+        } else {
+            writeln!(out, "                let result = {};", x.body).unwrap();
+            if let Some((name, args)) = flags {
+                writeln!(out, "                self.regs.flags.{name}({args});").unwrap();
+            }
+            if x.write {
+                out.push_str("                self.write(instr.operands[0], result)?;\n");
+            }
+        }
+        out.push_str("            }\n");
+    }
+    out.push_str(
+        "            _ => self.execute_vector(instr)?,\n        }\n        Ok(None)\n    }\n}\n",
+    );
+}
+
+fn write_def(out: &mut String, form: &Form) {
+    let opt = |x: Option<u8>| match x {
+        Some(x) => format!("Some({x:#04x})"),
+        None => "None".into(),
+    };
+    let size = |x: Option<&str>| match x {
+        Some(size) => format!("Some(Size::{size})"),
+        None => "None".into(),
+    };
+    write!(
+        out,
+        "Def {{ op: Op::{}, prefix: Mandatory::{}, w: {:?}, size: {}, l: {:?}, ext: {}, modrm: {}, fixed_modrm: {}, mask: Masking::{}, broadcast: {}, er: {}, sae: {}, operands: &[",
+        form.op,
+        form.prefix,
+        form.w,
+        size(form.size),
+        form.l,
+        opt(form.ext),
+        form.modrm,
+        opt(form.fixed_modrm),
+        form.mask,
+        size(form.broadcast),
+        form.er,
+        form.sae,
+    )
+    .unwrap();
+    for (src, reg, width) in &form.operands {
+        write!(
+            out,
+            "Spec {{ src: Src::{src}, reg: RegKind::{reg}, width: Width::{width} }}, "
+        )
+        .unwrap();
+    }
+    out.push_str("] }, ");
+}
+
+fn write_table(out: &mut String, name: &str, forms: &[Form], map: &str) {
+    writeln!(out, "pub static {name}: [&[Def]; 256] = [").unwrap();
+    for byte in 0..=255u8 {
+        out.push_str("    &[");
+        let matching = forms.iter().filter(|form| {
+            form.map == map && (form.opcode == byte || (form.plus_reg && byte & !7 == form.opcode))
+        });
+        for form in matching {
+            write_def(out, form);
+        }
+        out.push_str("],\n");
+    }
+    out.push_str("];\n");
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={SPEC}");
+
+    let spec: &'static str = fs::read_to_string(SPEC).unwrap().leak();
+    let mut forms = Vec::new();
+    let mut semantics: Vec<Semantics> = Vec::new();
+    for (number, line) in spec.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = if line.contains("=>") {
+            parse_semantics(line).and_then(|x| {
+                if semantics.iter().any(|y| y.op == x.op) {
+                    return Err(format!("{} already has semantics", x.op));
+                }
+                semantics.push(x);
+                Ok(())
+            })
+        } else {
+            parse_line(line).map(|form| forms.push(form))
+        };
+        if let Err(e) = parsed {
+            panic!("{SPEC}:{}: {e}\n{line}", number + 1);
+        }
+    }
+
+    let mut ops = BTreeSet::new();
+    for form in &forms {
+        let op = match form.op.split_once('(') {
+            Some((family, _)) => format!("{family}(Cond)"),
+            None => form.op.clone(),
+        };
+        ops.insert(op);
+    }
+
+    let mut out = String::new();
+    out.push_str("#[derive(Debug, Copy, Clone, PartialEq, Eq)]\npub enum Op {\n");
+    for op in &ops {
+        writeln!(out, "    {op},").unwrap();
+    }
+    out.push_str(
+        "}\n\nimpl Op {\n    /// The mnemonic, without the condition code for `Jcc` and friends.\n",
+    );
+    out.push_str("    pub fn name(self) -> &'static str {\n        match self {\n");
+    for op in &ops {
+        let (pattern, name) = match op.split_once('(') {
+            Some((family, _)) => {
+                let prefix = CC_FAMILIES.iter().find(|x| x.1 == family).unwrap().0;
+                (format!("{family}(_)"), prefix.to_ascii_lowercase())
+            }
+            None => (op.clone(), op.to_ascii_lowercase()),
+        };
+        writeln!(out, "            Op::{pattern} => \"{name}\",").unwrap();
+    }
+    out.push_str("        }\n    }\n\n");
+
+    // like add r/m32, imm8 next to add r/m32, imm32, or push imm8 next to push imm32
+    let byte = |x: &&(&str, &str, &str)| x.2 == "Fixed(Size::Byte)";
+    let wide: BTreeSet<&str> = forms
+        .iter()
+        .filter(|form| {
+            form.operands.iter().any(|x| x.0 == "Imm" && !byte(&x))
+                && form.operands.iter().any(|x| matches!(x.2, "V" | "Z"))
+        })
+        .map(|form| form.op.as_str())
+        .collect();
+    let both: BTreeSet<&str> = forms
+        .iter()
+        .filter(|form| {
+            let (imm, other): (Vec<_>, Vec<_>) = form.operands.iter().partition(|x| x.0 == "Imm");
+            imm.iter().any(byte) && !other.iter().any(byte)
+        })
+        .map(|form| form.op.as_str())
+        .filter(|x| wide.contains(x))
+        .collect();
+    out.push_str(
+        "    /// Whether the op has sign extended imm8 forms next to full size ones, an imm8 has to say so.\n",
+    );
+    out.push_str("    pub fn has_imm8_forms(self) -> bool {\n        matches!(self, ");
+    let patterns: Vec<String> = both.iter().map(|x| format!("Op::{x}")).collect();
+    out.push_str(&patterns.join(" | "));
+    out.push_str(")\n    }\n}\n\n");
+
+    write_table(&mut out, "ONE_BYTE", &forms, "OneByte");
+    write_table(&mut out, "MAP_0F", &forms, "Map0F");
+    write_table(&mut out, "MAP_0F38", &forms, "Map0F38");
+    write_table(&mut out, "MAP_0F3A", &forms, "Map0F3A");
+    write_table(&mut out, "VEX_0F", &forms, "Vex0F");
+    write_table(&mut out, "VEX_0F38", &forms, "Vex0F38");
+    write_table(&mut out, "VEX_0F3A", &forms, "Vex0F3A");
+    write_table(&mut out, "EVEX_0F", &forms, "Evex0F");
+    write_table(&mut out, "EVEX_0F38", &forms, "Evex0F38");
+    write_table(&mut out, "EVEX_0F3A", &forms, "Evex0F3A");
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("opcodes.rs"), out).unwrap();
+
+    for x in &semantics {
+        if !forms
+            .iter()
+            .any(|form| form.op.split('(').next() == Some(&x.op))
+        {
+            panic!("{SPEC}: semantics for {}, which has no forms", x.op);
+        }
+    }
+    // everything but the vector forms goes through a semantics line, those are in execute/vector.rs
+    for form in &forms {
+        let vector = form.map.starts_with("Vex")
+            || form.map.starts_with("Evex")
+            || form
+                .operands
+                .iter()
+                .any(|x| matches!(x.1, "Xmm" | "Ymm" | "Zmm" | "Mask"));
+        let op = form.op.split('(').next().unwrap();
+        if !vector && !semantics.iter().any(|x| x.op == op) {
+            panic!("{SPEC}: {} has no semantics", form.op);
+        }
+    }
+    let mut executor = String::new();
+    write_executor(&mut executor, &semantics);
+    fs::write(Path::new(&out_dir).join("execute.rs"), executor).unwrap();
+}
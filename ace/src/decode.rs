@@ -0,0 +1,1223 @@
+use crate::instr::{Base, Instr, Mem, Operand, Size};
+use crate::opcodes::{
+    Def, Mandatory, Masking, Op, RegKind, Spec, Src, Width, EVEX_0F, EVEX_0F38, EVEX_0F3A, MAP_0F,
+    MAP_0F38, MAP_0F3A, ONE_BYTE, VEX_0F, VEX_0F38, VEX_0F3A,
+};
+use crate::prefix::{Evex, Prefix, Prefixes, Rep, Rex, Rounding, Vex};
+use crate::registers::{MaskReg, Reg, StReg, VecReg};
+use std::fmt::Debug;
+
+#[derive(Clone, Copy)]
+pub struct Sib(pub u8);
+impl Sib {
+    pub fn scale(self) -> u8 {
+        (self.0 >> 6) & 0b11
+    }
+    pub fn index(self) -> u8 {
+        (self.0 >> 3) & 0b111
+    }
+    pub fn base(self) -> u8 {
+        self.0 & 0b111
+    }
+}
+impl Debug for Sib {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sib")
+            .field("scale", &self.scale())
+            .field("index", &self.index())
+            .field("base", &self.base())
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ModRm(pub u8);
+impl ModRm {
+    pub fn mod_(self) -> u8 {
+        (self.0 >> 6) & 0b11
+    }
+    pub fn reg(self) -> u8 {
+        (self.0 >> 3) & 0b111
+    }
+    pub fn rm(self) -> u8 {
+        self.0 & 0b111
+    }
+}
+impl Debug for ModRm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModRm")
+            .field("mod", &self.mod_())
+            .field("reg", &self.reg())
+            .field("rm", &self.rm())
+            .finish()
+    }
+}
+
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+type Table = [&'static [Def]; 256];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A valid instruction we don't know about yet.
+    Unimplemented,
+    /// Not an instruction, or longer than 15 bytes.
+    Invalid,
+    /// `code` ended in the middle of the instruction.
+    Truncated,
+}
+
+struct Decoder<'x> {
+    code: &'x [u8],
+    pos: usize,
+    prefixes: Prefixes,
+}
+impl<'x> Decoder<'x> {
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        if self.pos == MAX_INSTRUCTION_LEN {
+            return Err(DecodeError::Invalid);
+        }
+        let x = *self.code.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(x)
+    }
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut x = [0; N];
+        for i in &mut x {
+            *i = self.u8()?;
+        }
+        Ok(x)
+    }
+    /// Reads an immediate of `size` and sign extends it.
+    fn imm(&mut self, size: Size) -> Result<i64, DecodeError> {
+        let x = match size {
+            Size::Byte => self.u8()? as i8 as i64,
+            Size::Word => i16::from_le_bytes(self.bytes()?) as i64,
+            Size::Dword => i32::from_le_bytes(self.bytes()?) as i64,
+            Size::Qword => i64::from_le_bytes(self.bytes()?),
+            Size::Tword | Size::Oword | Size::Yword | Size::Zword => {
+                unreachable!("no {size:?} immediates")
+            }
+        };
+        Ok(x)
+    }
+    fn rel(&mut self, size: Size) -> Result<Operand, DecodeError> {
+        let disp = self.imm(size)?;
+        Ok(Operand::Rel {
+            offset: disp + self.pos as i64,
+            size,
+        })
+    }
+
+    fn width(&self, width: Width) -> Size {
+        match width {
+            Width::Fixed(size) => size,
+            Width::V => self.prefixes.op_size(),
+            Width::Z => match self.prefixes.op_size() {
+                Size::Qword => Size::Dword,
+                size => size,
+            },
+            Width::D => match self.prefixes.op_size() {
+                Size::Word => Size::Word,
+                _ => Size::Qword,
+            },
+        }
+    }
+
+    /// Register `index` of the register file `spec` asks for.
+    fn register(&self, spec: Spec, index: u8) -> Result<Operand, DecodeError> {
+        let rex = self.prefixes.rex.is_some();
+        let operand = match spec.reg {
+            RegKind::Gpr => Operand::Reg(Reg::new(self.width(spec.width), index, rex)),
+            RegKind::Gpr32 => Operand::Reg(Reg::new(Size::Dword, index, rex)),
+            RegKind::Xmm => Operand::Vec(VecReg {
+                size: Size::Oword,
+                index,
+            }),
+            RegKind::Ymm => Operand::Vec(VecReg {
+                size: Size::Yword,
+                index,
+            }),
+            RegKind::Zmm => Operand::Vec(VecReg {
+                size: Size::Zword,
+                index,
+            }),
+            // there are only k0 to k7, anything that picks a higher one is undefined
+            RegKind::Mask if index > 7 => return Err(DecodeError::Invalid),
+            RegKind::Mask => Operand::Mask(MaskReg(index)),
+            RegKind::St => Operand::St(StReg(index & 7)),
+        };
+        Ok(operand)
+    }
+    fn addr_size(&self) -> Size {
+        if self.prefixes.address_size {
+            Size::Dword
+        } else {
+            Size::Qword
+        }
+    }
+    /// Decodes the memory operand in `modrm`, reading the SIB byte and displacement when present.
+    fn mem_operand(&mut self, modrm: ModRm, size: Size) -> Result<Mem, DecodeError> {
+        let rex = self.prefixes.rex();
+        let rm = modrm.rm() + 8 * rex.b() as u8;
+        let addr_size = self.addr_size();
+        let addr_reg = |index| Reg::new(addr_size, index, true);
+        let vl = self.prefixes.vex.map_or(0, |x| 16 << x.l);
+        let broadcast = self.prefixes.evex.and_then(|x| x.broadcast);
+        let size = broadcast.unwrap_or(size);
+
+        let mut mem = Mem {
+            size,
+            base: None,
+            index: None,
+            scale: 1,
+            disp: 0,
+            disp_size: None,
+            disp_scale: if self.prefixes.evex.is_some() {
+                size.bytes() as u8
+            } else {
+                1
+            },
+            addr_size,
+            segment: self.prefixes.segment,
+            broadcast: broadcast.map(|x| (vl / x.bytes()) as u8),
+        };
+        let mut disp_size = match modrm.mod_() {
+            0b00 => None,
+            0b01 => Some(Size::Byte),
+            _ => Some(Size::Dword),
+        };
+
+        if modrm.rm() == 0b100 {
+            let sib = Sib(self.u8()?);
+            let index = sib.index() + 8 * rex.x() as u8;
+            // rsp can't be an index, but r12 can
+            if index != 0b100 {
+                mem.index = Some(addr_reg(index));
+                mem.scale = 1 << sib.scale();
+            }
+            if sib.base() == 0b101 && modrm.mod_() == 0b00 {
+                disp_size = Some(Size::Dword);
+            } else {
+                mem.base = Some(Base::Reg(addr_reg(sib.base() + 8 * rex.b() as u8)));
+            }
+        } else if modrm.rm() == 0b101 && modrm.mod_() == 0b00 {
+            mem.base = Some(Base::Rip);
+            disp_size = Some(Size::Dword);
+        } else {
+            mem.base = Some(Base::Reg(addr_reg(rm)));
+        }
+
+        if let Some(disp_size) = disp_size {
+            mem.disp = self.imm(disp_size)?;
+        }
+        if disp_size == Some(Size::Byte) {
+            mem.disp *= mem.disp_scale as i64;
+        }
+        mem.disp_size = disp_size;
+        Ok(mem)
+    }
+
+    fn operand(
+        &mut self,
+        spec: Spec,
+        opcode: u8,
+        modrm: Option<ModRm>,
+    ) -> Result<Operand, DecodeError> {
+        let rex = self.prefixes.rex();
+        let size = self.width(spec.width);
+        // evex has a fifth bit for vector registers, evex.R' for modrm.reg and evex.X for modrm.rm
+        let high = |bit: bool| {
+            let evex = self.prefixes.evex.is_some() && spec.reg.is_vector();
+            16 * (evex && bit) as u8
+        };
+        let operand = match spec.src {
+            Src::Reg => {
+                let high_r = self.prefixes.evex.is_some_and(|x| x.high_r);
+                // for a mask register evex.R' only makes the index too big
+                let mask_r = 16 * (spec.reg == RegKind::Mask && high_r) as u8;
+                self.register(
+                    spec,
+                    modrm.unwrap().reg() + 8 * rex.r() as u8 + high(high_r) + mask_r,
+                )?
+            }
+            Src::Rm | Src::Mem | Src::RmReg => {
+                let modrm = modrm.unwrap();
+                if modrm.mod_() != 0b11 {
+                    Operand::Mem(self.mem_operand(modrm, size)?)
+                } else if spec.reg == RegKind::Mask {
+                    // vex.B and evex.X are ignored for a mask register in modrm.rm
+                    self.register(spec, modrm.rm())?
+                } else {
+                    self.register(spec, modrm.rm() + 8 * rex.b() as u8 + high(rex.x()))?
+                }
+            }
+            Src::Opcode => self.register(spec, (opcode & 7) + 8 * rex.b() as u8)?,
+            Src::Fixed(index) => self.register(spec, index)?,
+            Src::Vvvv => self.register(spec, self.prefixes.vex.unwrap().vvvv)?,
+            Src::Is4 => {
+                let imm = self.u8()?;
+                self.register(spec, imm >> 4)?
+            }
+            Src::One => Operand::Imm {
+                value: 1,
+                size: Size::Byte,
+            },
+            Src::Imm => Operand::Imm {
+                value: self.imm(size)?,
+                size,
+            },
+            Src::Rel => self.rel(if size == Size::Byte {
+                Size::Byte
+            } else {
+                Size::Dword
+            })?,
+        };
+        Ok(operand)
+    }
+
+    /// Reads the rest of a C4/C5 prefix into `self.prefixes` and returns the opcode map it selects.
+    fn vex(&mut self, first: u8) -> Result<&'static Table, DecodeError> {
+        self.check_no_legacy_prefixes()?;
+
+        let byte1 = self.u8()?;
+        // C5 is C4 with X, B, W and the map fixed
+        let (inverted_rxb, map, byte2) = if first == 0xc5 {
+            (byte1 | 0b0110_0000, 1, byte1 & 0x7f)
+        } else {
+            (byte1, byte1 & 0x1f, self.u8()?)
+        };
+        let rxb = (!inverted_rxb >> 5) & 0b111;
+        let w = byte2 >> 7;
+
+        self.prefixes.rex = Some(Rex(0x40 | w << 3 | rxb));
+        match byte2 & 0b11 {
+            0b01 => self.prefixes.operand_size = true,
+            0b10 => self.prefixes.rep = Some(Rep::Rep),
+            0b11 => self.prefixes.rep = Some(Rep::Repne),
+            _ => {}
+        }
+        self.prefixes.vex = Some(Vex {
+            vvvv: !(byte2 >> 3) & 0xf,
+            l: (byte2 >> 2) & 1,
+            vex3: first == 0xc4 && map == 1 && w == 0 && rxb & 0b011 == 0,
+        });
+
+        match map {
+            1 => Ok(&VEX_0F),
+            2 => Ok(&VEX_0F38),
+            3 => Ok(&VEX_0F3A),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    /// Vex and evex already encode these, so they can't come before them.
+    fn check_no_legacy_prefixes(&self) -> Result<(), DecodeError> {
+        let p = &self.prefixes;
+        if p.lock || p.operand_size || p.rep.is_some() || p.rex.is_some() {
+            return Err(DecodeError::Invalid);
+        }
+        Ok(())
+    }
+
+    /// Reads the rest of a 62 prefix into `self.prefixes` and returns the opcode map it
+    /// selects, along with the vex one for the same map.
+    fn evex(&mut self) -> Result<(&'static Table, &'static Table), DecodeError> {
+        self.check_no_legacy_prefixes()?;
+
+        let [p0, p1, p2] = self.bytes()?;
+        // fixed bits
+        if p0 & 0b1000 != 0 || p1 & 0b100 == 0 {
+            return Err(DecodeError::Invalid);
+        }
+        let rxb = (!p0 >> 5) & 0b111;
+        let w = p1 >> 7;
+        let high_v = !p2 & 0b1000 != 0;
+
+        self.prefixes.rex = Some(Rex(0x40 | w << 3 | rxb));
+        match p1 & 0b11 {
+            0b01 => self.prefixes.operand_size = true,
+            0b10 => self.prefixes.rep = Some(Rep::Rep),
+            0b11 => self.prefixes.rep = Some(Rep::Repne),
+            _ => {}
+        }
+        self.prefixes.vex = Some(Vex {
+            vvvv: (!(p1 >> 3) & 0xf) | (16 * high_v as u8),
+            l: (p2 >> 5) & 0b11,
+            vex3: false,
+        });
+        self.prefixes.evex = Some(Evex {
+            mask: p2 & 0b111,
+            zeroing: p2 & 0x80 != 0,
+            b: p2 & 0x10 != 0,
+            high_r: !p0 & 0x10 != 0,
+            rounding: None,
+            broadcast: None,
+            vex_encodable: false,
+        });
+
+        match p0 & 0b111 {
+            1 => Ok((&EVEX_0F, &VEX_0F)),
+            2 => Ok((&EVEX_0F38, &VEX_0F38)),
+            3 => Ok((&EVEX_0F3A, &VEX_0F3A)),
+            // avx512-fp16
+            5 | 6 => Err(DecodeError::Unimplemented),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    /// Checks the evex fields against what `def` allows and works out what evex.b means, `l`
+    /// is the original L'L.
+    fn evex_decorations(
+        &mut self,
+        def: &Def,
+        modrm: Option<ModRm>,
+        l: u8,
+    ) -> Result<(), DecodeError> {
+        let Some(evex) = &mut self.prefixes.evex else {
+            return Ok(());
+        };
+        let is_reg = modrm.is_none_or(|x| x.mod_() == 0b11);
+        let mem_destination = !is_reg
+            && def
+                .operands
+                .first()
+                .is_some_and(|x| matches!(x.src, Src::Rm | Src::Mem));
+        let valid_mask = match def.mask {
+            Masking::None => evex.mask == 0 && !evex.zeroing,
+            Masking::Merge => !evex.zeroing,
+            // {z} needs a mask, and a memory destination can only merge
+            Masking::Zero => !evex.zeroing || (evex.mask != 0 && !mem_destination),
+        };
+        if !valid_mask {
+            return Err(DecodeError::Invalid);
+        }
+
+        if evex.b {
+            if !is_reg {
+                evex.broadcast = Some(def.broadcast.ok_or(DecodeError::Invalid)?);
+            } else if def.er {
+                evex.rounding = Some(match l {
+                    0 => Rounding::Nearest,
+                    1 => Rounding::Down,
+                    2 => Rounding::Up,
+                    _ => Rounding::Zero,
+                });
+            } else if def.sae {
+                evex.rounding = Some(Rounding::Sae);
+            } else {
+                return Err(DecodeError::Invalid);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self) -> Result<Instr, DecodeError> {
+        let mut opcode = self.u8()?;
+        while let Some(prefix) = Prefix::from_byte(opcode) {
+            self.prefixes.add(prefix);
+            opcode = self.u8()?;
+        }
+
+        let mut vex_table = None;
+        let table = if opcode == 0x62 {
+            let (table, vex) = self.evex()?;
+            vex_table = Some(vex);
+            opcode = self.u8()?;
+            table
+        } else if opcode == 0xc4 || opcode == 0xc5 {
+            let table = self.vex(opcode)?;
+            opcode = self.u8()?;
+            table
+        } else if opcode == 0x0f {
+            opcode = self.u8()?;
+            match opcode {
+                0x38 => {
+                    opcode = self.u8()?;
+                    &MAP_0F38
+                }
+                0x3a => {
+                    opcode = self.u8()?;
+                    &MAP_0F3A
+                }
+                _ => &MAP_0F,
+            }
+        } else {
+            &ONE_BYTE
+        };
+        let defs = table[opcode as usize];
+        let modrm = if defs.iter().any(|x| x.modrm) {
+            Some(ModRm(self.u8()?))
+        } else {
+            None
+        };
+        let mut evex_l = 0;
+        if let (Some(evex), Some(vex)) = (self.prefixes.evex, &mut self.prefixes.vex) {
+            evex_l = vex.l;
+            // with rounding or sae the vector length is 512 and L'L is the rounding mode
+            if evex.b && modrm.is_none_or(|x| x.mod_() == 0b11) {
+                vex.l = 2;
+            } else if vex.l == 3 {
+                return Err(DecodeError::Invalid);
+            }
+        }
+        let mut matching = defs
+            .iter()
+            .filter(|x| x.matches(&self.prefixes, modrm))
+            // 90 is nop, with rex.b it's xchg r8, rax
+            .filter(|x| x.op != Op::Nop || !self.prefixes.rex().b())
+            .peekable();
+        if matching.peek().is_none() {
+            // a 66 form that an f2 or f3 took away, like f2 66 0f d4, is undefined
+            let without_rep = Prefixes {
+                rep: None,
+                ..self.prefixes
+            };
+            if defs
+                .iter()
+                .any(|x| x.prefix == Mandatory::P66 && x.matches(&without_rep, modrm))
+            {
+                return Err(DecodeError::Invalid);
+            }
+            return Err(DecodeError::Unimplemented);
+        }
+        // like movntdqa with a register
+        let def = matching
+            .find(|x| x.fits(modrm))
+            .ok_or(DecodeError::Invalid)?;
+        def.strip_prefix(&mut self.prefixes);
+        self.evex_decorations(def, modrm, evex_l)?;
+
+        // vex.vvvv has to be 1111 when it isn't used
+        if self.prefixes.vex.is_some_and(|x| x.vvvv != 0)
+            && !def.operands.iter().any(|x| x.src == Src::Vvvv)
+        {
+            return Err(DecodeError::Invalid);
+        }
+
+        let mut instr = Instr::new(def.op, Size::Qword, self.prefixes);
+        for (i, &spec) in def.operands.iter().enumerate() {
+            instr.operands[i] = self.operand(spec, opcode, modrm)?;
+        }
+        // the operation size is the one of the first operand, branches don't have one
+        if let Some(spec) = def.operands.first().filter(|x| x.src != Src::Rel) {
+            instr.size = self.width(spec.width);
+        }
+        // cwde and the like don't have operands to get it from
+        if let Some(size) = def.size {
+            instr.size = size;
+        }
+        // push imm8 and push imm32 still push a whole stack slot, enter and leave push and
+        // pop frame pointers of one
+        let push_imm = def.op == Op::Push && def.operands[0].src == Src::Imm;
+        if push_imm || matches!(def.op, Op::Enter | Op::Leave) {
+            instr.size = self.width(Width::D);
+        }
+        if let (Some(evex), Some(vex_table)) = (&mut instr.prefixes.evex, vex_table) {
+            let plain = evex.mask == 0 && !evex.zeroing && !evex.b;
+            let vex_registers = instr.operands.iter().all(|x| match x {
+                Operand::Vec(reg) => reg.index < 16 && reg.size != Size::Zword,
+                _ => true,
+            });
+            evex.vex_encodable =
+                plain && vex_registers && vex_table[opcode as usize].iter().any(|x| x.op == def.op);
+        }
+        Ok(instr)
+    }
+}
+
+/// Decodes the instruction at the start of `code`, returning it and its length in bytes.
+pub fn decode(code: &[u8]) -> Result<(Instr, usize), DecodeError> {
+    let mut decoder = Decoder {
+        code,
+        pos: 0,
+        prefixes: Prefixes::default(),
+    };
+    let mut instr = decoder.decode()?;
+    let len = decoder.pos;
+
+    // rip relative displacements are only known once the whole instruction is decoded
+    for operand in &mut instr.operands {
+        if let Operand::Mem(
+            mem @ Mem {
+                base: Some(Base::Rip),
+                ..
+            },
+        ) = operand
+        {
+            mem.disp += len as i64;
+        }
+    }
+
+    Ok((instr, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, DecodeError};
+
+    fn d(code: &[u8]) -> (String, usize) {
+        let (instr, len) = decode(code).unwrap();
+        (instr.to_string(), len)
+    }
+
+    #[test]
+    fn mov() {
+        assert_eq!(d(&[0x48, 0x89, 0xe5]), ("mov rbp, rsp".into(), 3));
+        assert_eq!(d(&[0x88, 0x5d, 0xfc]), ("mov [rbp-0x4], bl".into(), 3));
+        assert_eq!(d(&[0x41, 0xb0, 0x05]), ("mov r8b, 0x5".into(), 3));
+        assert_eq!(d(&[0xb4, 0x05]), ("mov ah, 0x5".into(), 2));
+        assert_eq!(d(&[0x40, 0xb4, 0x05]), ("mov spl, 0x5".into(), 3));
+        assert_eq!(
+            d(&[0xc7, 0x45, 0xfc, 0x00, 0x00, 0x00, 0x00]),
+            ("mov dword [rbp-0x4], 0x0".into(), 7)
+        );
+        assert_eq!(
+            d(&[0x66, 0x41, 0xbf, 0x10, 0x00]),
+            ("mov r15w, 0x10".into(), 5)
+        );
+    }
+
+    #[test]
+    fn alu() {
+        assert_eq!(d(&[0x4d, 0x31, 0xc8]), ("xor r8, r9".into(), 3));
+        assert_eq!(
+            d(&[0x81, 0xeb, 0xf6, 0xff, 0xff, 0xff]),
+            ("sub ebx, -0xa".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x80, 0x7d, 0xfc, 0x00]),
+            ("cmp byte [rbp-0x4], 0x0".into(), 4)
+        );
+    }
+
+    #[test]
+    fn addressing() {
+        assert_eq!(
+            d(&[0x67, 0xc7, 0x04, 0x99, 0x05, 0x00, 0x00, 0x00]),
+            ("mov dword [ecx+ebx*4], 0x5".into(), 8)
+        );
+        assert_eq!(
+            d(&[0x8b, 0x05, 0x10, 0x00, 0x00, 0x00]),
+            ("mov eax, [rel $+0x16]".into(), 6)
+        );
+        assert_eq!(d(&[0x48, 0x8b, 0x04, 0x24]), ("mov rax, [rsp]".into(), 4));
+        assert_eq!(
+            d(&[0x49, 0x8b, 0x44, 0x24, 0x08]),
+            ("mov rax, [r12+0x8]".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x48, 0x8b, 0x45, 0x00]),
+            ("mov rax, [rbp+0x0]".into(), 4)
+        );
+        assert_eq!(
+            d(&[0x48, 0x8b, 0x44, 0xcb, 0x80]),
+            ("mov rax, [rbx+rcx*8-0x80]".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x48, 0x8b, 0x04, 0x4d, 0x10, 0x00, 0x00, 0x00]),
+            ("mov rax, [nosplit rcx*2+0x10]".into(), 8)
+        );
+        assert_eq!(
+            d(&[0x48, 0x8b, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00]),
+            ("mov rax, [0x1000]".into(), 8)
+        );
+        assert_eq!(
+            d(&[0x4b, 0x8b, 0x84, 0x20, 0x45, 0x23, 0x01, 0x00]),
+            ("mov rax, [r8+r12*1+0x12345]".into(), 8)
+        );
+        assert_eq!(
+            d(&[0x48, 0x8b, 0x80, 0x01, 0x00, 0x00, 0x00]),
+            ("mov rax, [dword rax+0x1]".into(), 7)
+        );
+        assert_eq!(
+            d(&[0x48, 0x8b, 0x40, 0x00]),
+            ("mov rax, [byte rax+0x0]".into(), 4)
+        );
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(
+            d(&[0x0f, 0x85, 0x0a, 0x00, 0x00, 0x00]),
+            ("jne near $+16".into(), 6)
+        );
+        assert_eq!(d(&[0x74, 0xfe]), ("je short $+0".into(), 2));
+        assert_eq!(d(&[0xf3, 0x0f, 0x1e, 0xfa]), ("endbr64".into(), 4));
+        assert_eq!(d(&[0x7f, 0x10]), ("jg short $+18".into(), 2));
+        assert_eq!(d(&[0xeb, 0x10]), ("jmp short $+18".into(), 2));
+        assert_eq!(d(&[0xff, 0xd0]), ("call rax".into(), 2));
+        assert_eq!(
+            d(&[0x41, 0xff, 0x64, 0x24, 0x08]),
+            ("jmp qword [r12+0x8]".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x0f, 0x8a, 0xf0, 0xff, 0xff, 0xff]),
+            ("jp near $-10".into(), 6)
+        );
+        assert_eq!(d(&[0x0f, 0x4c, 0xc1]), ("cmovl eax, ecx".into(), 3));
+        assert_eq!(d(&[0x48, 0x0f, 0x46, 0xc1]), ("cmovbe rax, rcx".into(), 4));
+        assert_eq!(d(&[0x0f, 0x94, 0xc0]), ("sete al".into(), 3));
+        assert_eq!(d(&[0x41, 0x0f, 0x99, 0xc0]), ("setns r8b".into(), 4));
+
+        let (instr, len) = decode(&[0x7f, 0x10]).unwrap();
+        assert_eq!(instr.branch_target(0x100), Some(0x100 + len as u64 + 0x10));
+        let (instr, _) = decode(&[0x0f, 0x94, 0xc0]).unwrap();
+        assert_eq!(instr.branch_target(0x100), None);
+    }
+
+    #[test]
+    fn stack() {
+        assert_eq!(d(&[0x6a, 0xfd]), ("push byte -0x3".into(), 2));
+        assert_eq!(
+            d(&[0x68, 0x78, 0x56, 0x34, 0x12]),
+            ("push 0x12345678".into(), 5)
+        );
+        assert_eq!(d(&[0x66, 0x6a, 0x05]), ("o16 push byte 0x5".into(), 3));
+        assert_eq!(d(&[0x66, 0x41, 0x5c]), ("pop r12w".into(), 3));
+        // rex.w doesn't change anything, 64 bits is already the default
+        assert_eq!(d(&[0x48, 0x50]), ("push rax".into(), 2));
+        assert_eq!(d(&[0xff, 0x30]), ("push qword [rax]".into(), 2));
+        assert_eq!(d(&[0x66, 0x8f, 0x00]), ("pop word [rax]".into(), 3));
+        assert_eq!(d(&[0xc2, 0x10, 0x00]), ("ret 0x10".into(), 3));
+        assert_eq!(d(&[0xc8, 0x20, 0x00, 0x01]), ("enter 0x20, 0x1".into(), 4));
+        assert_eq!(d(&[0xc9]), ("leave".into(), 1));
+        assert_eq!(
+            d(&[0x66, 0xc8, 0x20, 0x00, 0x01]),
+            ("o16 enter 0x20, 0x1".into(), 5)
+        );
+        assert_eq!(d(&[0x66, 0xc9]), ("o16 leave".into(), 2));
+        assert_eq!(d(&[0x66, 0x9c]), ("pushfw".into(), 2));
+        assert_eq!(d(&[0x9c]), ("pushfq".into(), 1));
+        assert_eq!(
+            d(&[0xe8, 0x00, 0x00, 0x00, 0x00]),
+            ("call near $+5".into(), 5)
+        );
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(d(&[0xd1, 0xe0]), ("shl eax, 0x1".into(), 2));
+        assert_eq!(d(&[0xc1, 0xe0, 0x05]), ("shl eax, 0x5".into(), 3));
+        assert_eq!(d(&[0x48, 0xd3, 0xf8]), ("sar rax, cl".into(), 3));
+        assert_eq!(d(&[0xd0, 0x10]), ("rcl byte [rax], 0x1".into(), 2));
+        assert_eq!(d(&[0x66, 0xd3, 0xcb]), ("ror bx, cl".into(), 3));
+        assert_eq!(
+            d(&[0x0f, 0xa4, 0xc8, 0x04]),
+            ("shld eax, ecx, 0x4".into(), 4)
+        );
+        assert_eq!(
+            d(&[0x48, 0x0f, 0xad, 0x10]),
+            ("shrd [rax], rdx, cl".into(), 4)
+        );
+        // /6 is an undocumented copy of shl
+        assert_eq!(
+            decode(&[0xd1, 0xf0]).unwrap_err(),
+            DecodeError::Unimplemented
+        );
+    }
+
+    #[test]
+    fn sse() {
+        assert_eq!(d(&[0x0f, 0x28, 0xc1]), ("movaps xmm0, xmm1".into(), 3));
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x28, 0xc1]),
+            ("movapd xmm0, xmm1".into(), 4)
+        );
+        assert_eq!(d(&[0xf2, 0x0f, 0x58, 0xca]), ("addsd xmm1, xmm2".into(), 4));
+        assert_eq!(
+            d(&[0xf3, 0x0f, 0x59, 0x08]),
+            ("mulss xmm1, dword [rax]".into(), 4)
+        );
+        // f2 a5 is rep movsd, f2 0f 10 is the sse2 one
+        assert_eq!(d(&[0xf2, 0x0f, 0x10, 0xc1]), ("movsd xmm0, xmm1".into(), 4));
+        assert_eq!(
+            d(&[0xf2, 0x48, 0x0f, 0x2a, 0xc0]),
+            ("cvtsi2sd xmm0, rax".into(), 5)
+        );
+        assert_eq!(
+            d(&[0xf3, 0x0f, 0x2c, 0xc1]),
+            ("cvttss2si eax, xmm1".into(), 4)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x73, 0xd8, 0x04]),
+            ("psrldq xmm0, 0x4".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0xd7, 0xc1]),
+            ("pmovmskb eax, xmm1".into(), 4)
+        );
+        assert_eq!(
+            d(&[0x0f, 0xc2, 0xc1, 0x01]),
+            ("cmpps xmm0, xmm1, 0x1".into(), 4)
+        );
+        assert_eq!(d(&[0x0f, 0xae, 0x10]), ("ldmxcsr dword [rax]".into(), 3));
+        assert_eq!(
+            d(&[0xf2, 0x0f, 0x12, 0x08]),
+            ("movddup xmm1, qword [rax]".into(), 4)
+        );
+        assert_eq!(
+            d(&[0xf2, 0x0f, 0x7c, 0xc1]),
+            ("haddps xmm0, xmm1".into(), 4)
+        );
+        assert_eq!(
+            d(&[0x48, 0x0f, 0xc3, 0x08]),
+            ("movnti [rax], rcx".into(), 4)
+        );
+        // movnti doesn't take a mandatory prefix
+        for prefix in [0x66, 0xf2, 0xf3] {
+            assert_eq!(
+                decode(&[prefix, 0x0f, 0xc3, 0x08]).unwrap_err(),
+                DecodeError::Unimplemented
+            );
+        }
+        // f2 and f3 pick the form over 66, in either order
+        assert_eq!(
+            d(&[0x66, 0xf3, 0x0f, 0x11, 0xc1]),
+            ("movss xmm1, xmm0".into(), 5)
+        );
+        assert_eq!(
+            d(&[0xf3, 0x66, 0x0f, 0x11, 0xc1]),
+            ("movss xmm1, xmm0".into(), 5)
+        );
+        assert_eq!(
+            d(&[0xf3, 0x66, 0x0f, 0x70, 0xc1, 0x1b]),
+            ("pshufhw xmm0, xmm1, 0x1b".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x66, 0xf3, 0x0f, 0x70, 0xc1, 0x1b]),
+            ("pshufhw xmm0, xmm1, 0x1b".into(), 6)
+        );
+        assert_eq!(
+            decode(&[0xf2, 0x66, 0x0f, 0xd4, 0xc1]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        assert_eq!(
+            decode(&[0x66, 0xf2, 0x0f, 0xd4, 0xc1]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // lddqu only takes memory
+        assert_eq!(
+            decode(&[0xf2, 0x0f, 0xf0, 0xc1]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // the mmx forms without a 66 prefix aren't there
+        assert_eq!(
+            decode(&[0x0f, 0xfe, 0xc1]).unwrap_err(),
+            DecodeError::Unimplemented
+        );
+    }
+
+    #[test]
+    fn atomics() {
+        assert_eq!(d(&[0x91]), ("xchg ecx, eax".into(), 1));
+        assert_eq!(d(&[0x49, 0x90]), ("xchg r8, rax".into(), 2));
+        // 90 is nop rather than xchg eax, eax, which would clear the top of rax
+        assert_eq!(d(&[0x90]), ("nop".into(), 1));
+        assert_eq!(d(&[0x48, 0x90]), ("nop".into(), 2));
+        assert_eq!(
+            d(&[0xf0, 0x48, 0x87, 0x08]),
+            ("lock xchg [rax], rcx".into(), 4)
+        );
+        assert_eq!(
+            d(&[0xf0, 0x0f, 0xc1, 0x03]),
+            ("lock xadd [rbx], eax".into(), 4)
+        );
+        assert_eq!(
+            d(&[0xf0, 0x0f, 0xb0, 0x26]),
+            ("lock cmpxchg [rsi], ah".into(), 4)
+        );
+        assert_eq!(d(&[0x0f, 0xc7, 0x0f]), ("cmpxchg8b qword [rdi]".into(), 3));
+        assert_eq!(
+            d(&[0x48, 0x0f, 0xc7, 0x0f]),
+            ("cmpxchg16b oword [rdi]".into(), 4)
+        );
+        assert_eq!(
+            decode(&[0x0f, 0xc7, 0xc8]).unwrap_err(),
+            DecodeError::Invalid
+        );
+    }
+
+    #[test]
+    fn bits() {
+        assert_eq!(d(&[0x0f, 0xa3, 0xc8]), ("bt eax, ecx".into(), 3));
+        assert_eq!(
+            d(&[0x48, 0x0f, 0xba, 0x28, 0x05]),
+            ("bts qword [rax], 0x5".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0xb3, 0x53, 0x08]),
+            ("btr [rbx+0x8], dx".into(), 5)
+        );
+        assert_eq!(d(&[0x0f, 0xbc, 0xc1]), ("bsf eax, ecx".into(), 3));
+        // f3 turns bsf and bsr into tzcnt and lzcnt
+        assert_eq!(d(&[0xf3, 0x0f, 0xbc, 0xc1]), ("tzcnt eax, ecx".into(), 4));
+        assert_eq!(
+            d(&[0x66, 0xf3, 0x0f, 0xbd, 0xc1]),
+            ("lzcnt ax, cx".into(), 5)
+        );
+        assert_eq!(
+            d(&[0xf3, 0x48, 0x0f, 0xb8, 0x03]),
+            ("popcnt rax, [rbx]".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x66, 0x48, 0x0f, 0x38, 0xf6, 0xc1]),
+            ("adcx rax, rcx".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x38, 0xf0, 0x3c, 0x24]),
+            ("movbe di, [rsp]".into(), 6)
+        );
+
+        // adcx carries through CF and adox through OF, each leaving the other alone
+        let code = [
+            0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, // mov rax, -1
+            0xb9, 0x02, 0x00, 0x00, 0x00, // mov ecx, 2
+            0xbe, 0xff, 0xff, 0xff, 0xff, // mov esi, -1
+            0xf9, // stc
+            0x66, 0x48, 0x0f, 0x38, 0xf6, 0xc1, // adcx rax, rcx
+            0xf3, 0x0f, 0x38, 0xf6, 0xf6, // adox esi, esi
+            0x0f, 0x92, 0xc3, // setc bl
+            0x0f, 0x90, 0xc7, // seto bh
+            0xba, 0x44, 0x33, 0x22, 0x11, // mov edx, 0x11223344
+            0x52, // push rdx
+            0x0f, 0x38, 0xf0, 0x14, 0x24, // movbe edx, [rsp]
+            0x66, 0x0f, 0x38, 0xf0, 0x3c, 0x24, // movbe di, [rsp]
+            0xf4, // hlt
+        ];
+        let mut output = String::new();
+        let r = crate::Emulator::new(&code, &mut output).run_to_end();
+        assert_eq!(r[crate::registers::R64::RAX].r64(), 2);
+        assert_eq!(r[crate::registers::R64::RSI].r64(), 0xffff_fffe);
+        assert_eq!(r[crate::registers::R64::RBX].r64() & 0xffff, 0x0101);
+        assert_eq!(r[crate::registers::R64::RDX].r64(), 0x4433_2211);
+        assert_eq!(r[crate::registers::R64::RDI].r64() & 0xffff, 0x4433);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(d(&[0xa4]), ("movsb".into(), 1));
+        assert_eq!(d(&[0x66, 0xa5]), ("movsw".into(), 2));
+        assert_eq!(d(&[0xf3, 0x48, 0xab]), ("rep stosq".into(), 3));
+        assert_eq!(d(&[0xf3, 0xa6]), ("repe cmpsb".into(), 2));
+        assert_eq!(d(&[0xf2, 0xaf]), ("repne scasd".into(), 2));
+        assert_eq!(d(&[0x67, 0xf3, 0xa4]), ("a32 rep movsb".into(), 3));
+        assert_eq!(d(&[0x64, 0xac]), ("fs lodsb".into(), 2));
+    }
+
+    #[test]
+    fn widen() {
+        assert_eq!(d(&[0x66, 0x98]), ("cbw".into(), 2));
+        assert_eq!(d(&[0x98]), ("cwde".into(), 1));
+        assert_eq!(d(&[0x48, 0x98]), ("cdqe".into(), 2));
+        // rex.w wins over 66
+        assert_eq!(d(&[0x66, 0x48, 0x99]), ("cqo".into(), 3));
+        assert_eq!(d(&[0x0f, 0xb6, 0x00]), ("movzx eax, byte [rax]".into(), 3));
+        assert_eq!(
+            d(&[0x66, 0x0f, 0xb6, 0x00]),
+            ("movzx ax, byte [rax]".into(), 4)
+        );
+        assert_eq!(d(&[0x48, 0x0f, 0xbf, 0xc1]), ("movsx rax, cx".into(), 4));
+        assert_eq!(d(&[0x48, 0x63, 0xc1]), ("movsxd rax, ecx".into(), 3));
+        assert_eq!(d(&[0x63, 0xc1]), ("movsxd eax, ecx".into(), 2));
+        assert_eq!(d(&[0x66, 0x63, 0xd1]), ("movsxd dx, cx".into(), 3));
+
+        // without rex.w there's nothing to sign extend: eax gets ecx with the upper half
+        // zeroed, dx gets cx and the rest of rdx stays
+        let code = [
+            0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, // mov rax, -1
+            0x48, 0xc7, 0xc2, 0xff, 0xff, 0xff, 0xff, // mov rdx, -1
+            0xb9, 0x01, 0x80, 0x00, 0x80, // mov ecx, 0x80008001
+            0x63, 0xc1, // movsxd eax, ecx
+            0x66, 0x63, 0xd1, // movsxd dx, cx
+            0xf4, // hlt
+        ];
+        let mut output = String::new();
+        let r = crate::Emulator::new(&code, &mut output).run_to_end();
+        assert_eq!(r[crate::registers::R64::RAX].r64(), 0x8000_8001);
+        assert_eq!(r[crate::registers::R64::RDX].r64(), 0xffff_ffff_ffff_8001);
+    }
+
+    #[test]
+    fn lea() {
+        assert_eq!(
+            d(&[0x48, 0x8d, 0x44, 0x8b, 0x08]),
+            ("lea rax, [rbx+rcx*4+0x8]".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x67, 0x66, 0x8d, 0x04, 0x11]),
+            ("lea ax, [ecx+edx*1]".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x8d, 0x05, 0x10, 0x00, 0x00, 0x00]),
+            ("lea eax, [rel $+0x16]".into(), 6)
+        );
+        // there's no register form
+        assert_eq!(decode(&[0x8d, 0xc0]).unwrap_err(), DecodeError::Invalid);
+    }
+
+    #[test]
+    fn unary() {
+        assert_eq!(d(&[0xff, 0xc0]), ("inc eax".into(), 2));
+        assert_eq!(d(&[0x48, 0xff, 0x09]), ("dec qword [rcx]".into(), 3));
+        assert_eq!(d(&[0xfe, 0xc4]), ("inc ah".into(), 2));
+        assert_eq!(d(&[0xf7, 0xd8]), ("neg eax".into(), 2));
+        assert_eq!(d(&[0x66, 0xf7, 0xd1]), ("not cx".into(), 3));
+        assert_eq!(d(&[0x84, 0xc0]), ("test al, al".into(), 2));
+        assert_eq!(d(&[0x48, 0x85, 0xff]), ("test rdi, rdi".into(), 3));
+        assert_eq!(d(&[0xa8, 0x01]), ("test al, 0x1".into(), 2));
+        assert_eq!(
+            d(&[0xf7, 0x00, 0x00, 0x01, 0x00, 0x00]),
+            ("test dword [rax], 0x100".into(), 6)
+        );
+    }
+
+    #[test]
+    fn multiply() {
+        assert_eq!(d(&[0xf7, 0xe1]), ("mul ecx".into(), 2));
+        assert_eq!(d(&[0xf6, 0x28]), ("imul byte [rax]".into(), 2));
+        assert_eq!(d(&[0x66, 0xf7, 0xfe]), ("idiv si".into(), 3));
+        assert_eq!(d(&[0x0f, 0xaf, 0xc1]), ("imul eax, ecx".into(), 3));
+        assert_eq!(
+            d(&[0x48, 0x6b, 0x03, 0x05]),
+            ("imul rax, [rbx], byte 0x5".into(), 4)
+        );
+        assert_eq!(
+            d(&[0x66, 0x69, 0xc1, 0x34, 0x12]),
+            ("imul ax, cx, 0x1234".into(), 5)
+        );
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(
+            d(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]),
+            ("mov rax, [fs:0x28]".into(), 9)
+        );
+        // the rex isn't right before the opcode, so it's ignored
+        assert_eq!(d(&[0x48, 0x66, 0x31, 0xc0]), ("xor ax, ax".into(), 4));
+        assert_eq!(d(&[0x41, 0x49, 0x31, 0xc0]), ("xor r8, rax".into(), 4));
+        assert_eq!(
+            d(&[0x3e, 0x0f, 0x84, 0x00, 0x00, 0x00, 0x00]),
+            ("ds je near $+7".into(), 7)
+        );
+    }
+
+    #[test]
+    fn tables() {
+        assert_eq!(d(&[0x41, 0x54]), ("push r12".into(), 2));
+        assert_eq!(d(&[0x5b]), ("pop rbx".into(), 1));
+        assert_eq!(d(&[0x80, 0xc0, 0x01]), ("add al, 0x1".into(), 3));
+        assert_eq!(d(&[0x83, 0xc0, 0xff]), ("add eax, byte -0x1".into(), 3));
+        assert_eq!(d(&[0x3c, 0xff]), ("cmp al, -0x1".into(), 2));
+        // port io isn't in the spec, and 0f 1e needs the f3 to be endbr64
+        assert_eq!(
+            decode(&[0xe4, 0x01]).unwrap_err(),
+            DecodeError::Unimplemented
+        );
+        assert_eq!(
+            decode(&[0x0f, 0x1e, 0xfa]).unwrap_err(),
+            DecodeError::Unimplemented
+        );
+        assert_eq!(decode(&[0x0f]).unwrap_err(), DecodeError::Truncated);
+    }
+
+    #[test]
+    fn three_byte_maps() {
+        assert_eq!(
+            d(&[0x66, 0x44, 0x0f, 0x38, 0x00, 0x48, 0x08]),
+            ("pshufb xmm9, [rax+0x8]".into(), 7)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x38, 0x15, 0x1c, 0x24]),
+            ("blendvpd xmm3, [rsp], xmm0".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x38, 0x30, 0x00]),
+            ("pmovzxbw xmm0, qword [rax]".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x66, 0x4d, 0x0f, 0x3a, 0x16, 0xd1, 0x01]),
+            ("pextrq r9, xmm10, 0x1".into(), 7)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x3a, 0x14, 0xc8, 0x03]),
+            ("pextrb eax, xmm1, 0x3".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x3a, 0x20, 0x08, 0x01]),
+            ("pinsrb xmm1, byte [rax], 0x1".into(), 6)
+        );
+        // movntdqa only takes memory
+        assert_eq!(
+            decode(&[0x66, 0x0f, 0x38, 0x2a, 0xc1]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // the mmx form isn't supported
+        assert_eq!(
+            decode(&[0x0f, 0x38, 0x00, 0xca]).unwrap_err(),
+            DecodeError::Unimplemented
+        );
+    }
+
+    #[test]
+    fn mandatory_prefixes() {
+        // f2 selects crc32 over movbe, and 66 still picks the operand size
+        assert_eq!(
+            d(&[0x66, 0xf2, 0x0f, 0x38, 0xf1, 0x00]),
+            ("crc32 eax, word [rax]".into(), 6)
+        );
+        assert_eq!(
+            d(&[0xf2, 0x48, 0x0f, 0x38, 0xf1, 0x00]),
+            ("crc32 rax, qword [rax]".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x38, 0xf1, 0x03]),
+            ("movbe [rbx], ax".into(), 5)
+        );
+        assert_eq!(
+            d(&[0x66, 0x0f, 0x38, 0xf6, 0xc3]),
+            ("adcx eax, ebx".into(), 5)
+        );
+        assert_eq!(
+            d(&[0xf3, 0x48, 0x0f, 0x38, 0xf6, 0x03]),
+            ("adox rax, [rbx]".into(), 6)
+        );
+    }
+
+    #[test]
+    fn vex() {
+        assert_eq!(
+            d(&[0xc4, 0xa1, 0x75, 0xfe, 0x04, 0xa0]),
+            ("vpaddd ymm0, ymm1, [rax+r12*4]".into(), 6)
+        );
+        assert_eq!(
+            d(&[0xc5, 0xf2, 0x10, 0xc2]),
+            ("vmovss xmm0, xmm1, xmm2".into(), 4)
+        );
+        assert_eq!(
+            d(&[0xc4, 0xe3, 0x75, 0x4c, 0xc2, 0x30]),
+            ("vpblendvb ymm0, ymm1, ymm2, ymm3".into(), 6)
+        );
+        assert_eq!(
+            d(&[0xc4, 0xe3, 0xfd, 0x00, 0xc1, 0x4e]),
+            ("vpermq ymm0, ymm1, 0x4e".into(), 6)
+        );
+        // c4 where c5 would do
+        assert_eq!(
+            d(&[0xc4, 0xe1, 0x7c, 0x28, 0xc1]),
+            ("{vex3} vmovaps ymm0, ymm1".into(), 5)
+        );
+        // vvvv must be 1111 when it isn't an operand
+        assert_eq!(
+            decode(&[0xc5, 0xf5, 0x6f, 0xc1]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // no 66 or rex before vex
+        assert_eq!(
+            decode(&[0x66, 0xc5, 0xf9, 0x6f, 0xc1]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        assert_eq!(
+            decode(&[0x40, 0xc5, 0xf9, 0x6f, 0xc1]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // vpslldq only has a register form
+        assert_eq!(
+            decode(&[0xc5, 0xf9, 0x73, 0x38, 0x04]).unwrap_err(),
+            DecodeError::Invalid
+        );
+    }
+
+    #[test]
+    fn evex() {
+        // disp8 is scaled by the operand size
+        assert_eq!(
+            d(&[0x62, 0xf1, 0x6d, 0xca, 0xfe, 0x48, 0x01]),
+            ("vpaddd zmm1{k2}{z}, zmm2, [rax+0x40]".into(), 7)
+        );
+        // or by the element size for a broadcast
+        assert_eq!(
+            d(&[0x62, 0xe1, 0xed, 0x5f, 0xd4, 0x48, 0x01]),
+            ("vpaddq zmm17{k7}, zmm2, [rax+0x8]{1to8}".into(), 7)
+        );
+        assert_eq!(
+            d(&[0x62, 0xf1, 0x6c, 0x78, 0x58, 0xcb]),
+            ("vaddps zmm1, zmm2, zmm3, {rz-sae}".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x62, 0xf1, 0xef, 0x18, 0x5f, 0xcb]),
+            ("vmaxsd xmm1, xmm2, xmm3, {sae}".into(), 6)
+        );
+        assert_eq!(
+            d(&[0x62, 0x93, 0x75, 0x4a, 0x1f, 0xcf, 0x04]),
+            ("vpcmpd k1{k2}, zmm1, zmm31, 0x4".into(), 7)
+        );
+        // evex where vex would do
+        assert_eq!(
+            d(&[0x62, 0xf1, 0x6d, 0x08, 0xfe, 0xcb]),
+            ("{evex} vpaddd xmm1, xmm2, xmm3".into(), 6)
+        );
+        assert_eq!(d(&[0xc5, 0xec, 0x41, 0xcb]), ("kandw k1, k2, k3".into(), 4));
+        // zeroing can't go to memory
+        assert_eq!(
+            decode(&[0x62, 0xf1, 0x7e, 0xc9, 0x7f, 0x10]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // no rounding control for integer instructions
+        assert_eq!(
+            decode(&[0x62, 0xf1, 0x6d, 0x18, 0xfe, 0xcb]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // L'L = 3 is reserved
+        assert_eq!(
+            decode(&[0x62, 0xf1, 0x6d, 0x68, 0xfe, 0xcb]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // {z} without a mask
+        assert_eq!(
+            decode(&[0x62, 0xf1, 0x34, 0xa0, 0x59, 0xe9]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // mask registers above k7 through vex.R, vvvv and evex.R'
+        assert_eq!(
+            decode(&[0xc5, 0x5d, 0x41, 0xed]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        assert_eq!(
+            decode(&[0xc4, 0xe1, 0x15, 0x41, 0xdd]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        assert_eq!(
+            decode(&[0x62, 0xa3, 0xfd, 0x4e, 0x1e, 0xd0, 0x07]).unwrap_err(),
+            DecodeError::Invalid
+        );
+        // vpmovm2d can't be masked
+        assert_eq!(
+            decode(&[0x62, 0xf2, 0x7e, 0x49, 0x38, 0xca]).unwrap_err(),
+            DecodeError::Invalid
+        );
+    }
+
+    #[test]
+    fn x87() {
+        assert_eq!(d(&[0xd8, 0xc1]), ("fadd st0, st1".into(), 2));
+        assert_eq!(d(&[0xdc, 0xc1]), ("fadd st1, st0".into(), 2));
+        assert_eq!(d(&[0xde, 0xc1]), ("faddp st1, st0".into(), 2));
+        assert_eq!(d(&[0xdd, 0xd9]), ("fstp st1".into(), 2));
+        assert_eq!(d(&[0xdb, 0xf1]), ("fcomi st0, st1".into(), 2));
+        assert_eq!(d(&[0xd9, 0xe8]), ("fld1".into(), 2));
+        // the memory forms say the format with the size
+        assert_eq!(d(&[0xdb, 0x28]), ("fld tword [rax]".into(), 2));
+        assert_eq!(d(&[0xdd, 0x00]), ("fld qword [rax]".into(), 2));
+        assert_eq!(d(&[0xdf, 0x38]), ("fistp qword [rax]".into(), 2));
+        assert_eq!(d(&[0xde, 0x00]), ("fiadd word [rax]".into(), 2));
+        assert_eq!(d(&[0xd9, 0x7d, 0xfe]), ("fnstcw word [rbp-0x2]".into(), 3));
+        assert_eq!(d(&[0xdf, 0xe0]), ("fnstsw ax".into(), 2));
+        // db /4 is only a register form
+        assert_eq!(
+            decode(&[0xdb, 0x20]).unwrap_err(),
+            DecodeError::Unimplemented
+        );
+    }
+}
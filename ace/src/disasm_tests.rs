@@ -1,3 +1,4 @@
+use crate::error::{Access, EmulatorError, StopReason};
 use crate::registers::R64;
 use crate::{DisasmWriter, Emulator, Registers};
 use std::fmt::Write;
@@ -5,7 +6,7 @@ use std::{fmt::Arguments, fs, process::Command};
 
 impl DisasmWriter for &mut String {
     fn write(&mut self, args: Arguments<'_>) {
-        write!(self, "{}\n", args).unwrap();
+        writeln!(self, "{}", args).unwrap();
     }
 }
 
@@ -54,6 +55,93 @@ fn t(text: &str) -> Registers {
     r
 }
 
+fn run_bytes(code: &[u8]) -> Result<StopReason, EmulatorError> {
+    let mut output = String::new();
+    Emulator::new(code, &mut output).run()
+}
+
+#[test]
+fn stop_reasons() {
+    // mov eax, 5; hlt
+    assert_eq!(
+        run_bytes(&[0xb8, 0x05, 0x00, 0x00, 0x00, 0xf4]),
+        Ok(StopReason::Halted)
+    );
+    // ud2
+    assert_eq!(
+        run_bytes(&[0x0f, 0x0b]),
+        Err(EmulatorError::UnimplementedOpcode {
+            address: 0,
+            bytes: vec![0x0f, 0x0b]
+        })
+    );
+    // 16 operand size prefixes before a nop
+    let mut code = vec![0x66; 16];
+    code.push(0x90);
+    assert!(matches!(
+        run_bytes(&code),
+        Err(EmulatorError::InvalidEncoding { address: 0, .. })
+    ));
+    // aesenc xmm1, xmm2 decodes, but doesn't run yet
+    assert_eq!(
+        run_bytes(&[0x66, 0x0f, 0x38, 0xdc, 0xca]),
+        Err(EmulatorError::UnimplementedInstruction {
+            address: 0,
+            instr: "aesenc xmm1, xmm2".into()
+        })
+    );
+    // xor ecx, ecx; div ecx
+    assert_eq!(
+        run_bytes(&[0x31, 0xc9, 0xf7, 0xf1]),
+        Ok(StopReason::DivideError)
+    );
+    // mov edx, 1; mov ecx, 1; div ecx, the quotient doesn't fit in eax
+    assert_eq!(
+        run_bytes(&[0xba, 1, 0, 0, 0, 0xb9, 1, 0, 0, 0, 0xf7, 0xf1]),
+        Ok(StopReason::DivideError)
+    );
+    // lock add eax, ecx, lock needs a memory destination
+    assert_eq!(
+        run_bytes(&[0xf0, 0x01, 0xc8]),
+        Ok(StopReason::InvalidOpcode)
+    );
+    // cmpxchg16b [rsp], rsp is 8 bytes below the end of memory
+    assert_eq!(
+        run_bytes(&[0x48, 0x0f, 0xc7, 0x0c, 0x24]),
+        Ok(StopReason::GeneralProtection)
+    );
+    // movaps xmm0, [rsp-12], which isn't 16 byte aligned
+    assert_eq!(
+        run_bytes(&[0x0f, 0x28, 0x44, 0x24, 0xf4]),
+        Ok(StopReason::GeneralProtection)
+    );
+    // movups xmm0, [rsp-12]; hlt
+    assert_eq!(
+        run_bytes(&[0x0f, 0x10, 0x44, 0x24, 0xf4, 0xf4]),
+        Ok(StopReason::Halted)
+    );
+    // lddqu xmm0, [rsp-12]; hlt
+    assert_eq!(
+        run_bytes(&[0xf2, 0x0f, 0xf0, 0x44, 0x24, 0xf4, 0xf4]),
+        Ok(StopReason::Halted)
+    );
+    // mov dword [rsp], 0x10000; ldmxcsr [rsp], which sets a reserved bit
+    assert_eq!(
+        run_bytes(&[0xc7, 0x04, 0x24, 0x00, 0x00, 0x01, 0x00, 0x0f, 0xae, 0x14, 0x24]),
+        Ok(StopReason::GeneralProtection)
+    );
+    // a ret with nothing pushed goes back to whoever started the code
+    assert_eq!(run_bytes(&[0xc3]), Ok(StopReason::Returned));
+    // mov eax, [0x7fffffff]
+    assert_eq!(
+        run_bytes(&[0x8b, 0x04, 0x25, 0xff, 0xff, 0xff, 0x7f]),
+        Err(EmulatorError::MemoryFault {
+            address: 0x7fffffff,
+            access: Access::Read
+        })
+    );
+}
+
 #[test]
 fn sub_mem() {
     let text = "
@@ -77,6 +165,523 @@ cmp ebx, -10
     t(text);
 }
 
+#[test]
+fn alu() {
+    let text = "
+mov ecx, 0x10
+add ecx, 0x20
+add cl, 0xf0
+adc ecx, byte 1
+mov ebx, 0xff
+sub bl, 1
+or ebx, 0x100
+and ebx, ecx
+xor edx, edx
+sbb edx, byte 0
+cmp ebx, ecx
+mov edi, 5
+sub edi, 6
+sbb rsi, rsi
+mov DWORD [rbp-8], 1
+add DWORD [rbp-8], byte 2
+adc r8d, DWORD [rbp-8]
+add al, 1
+cmp eax, 2
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RCX].r64(), 0x22);
+    assert_eq!(r[R64::RBX].r64(), 0x22);
+    assert_eq!(r[R64::RDX].r64(), 0);
+    assert_eq!(r[R64::RDI].r64(), 0xffff_ffff);
+    assert_eq!(r[R64::RSI].r64(), u64::MAX);
+    assert_eq!(r[R64::R8].r64(), 3);
+    assert_eq!(r[R64::RAX].r64(), 1);
+}
+
+#[test]
+fn flags() {
+    let text = "
+mov eax, 0x7fffffff
+add eax, 1
+pushfq
+mov r15, [rsp]
+popfq
+stc
+cmc
+std
+pushfq
+mov r14, [rsp]
+cld
+popfq
+mov ebx, 0
+sub ebx, 1
+pushfq
+mov r13, [rsp]
+popfq
+    ";
+
+    let r = t(text);
+    // of, sf, af and pf, then the same with df
+    assert_eq!(r[R64::R15].r64(), 0xa96);
+    assert_eq!(r[R64::R14].r64(), 0xe96);
+    // sf, af, pf and cf
+    assert_eq!(r[R64::R13].r64(), 0x297);
+}
+
+#[test]
+fn conditions() {
+    let text = "
+mov eax, 5
+cmp eax, 7
+setl bl
+setb cl
+setg dl
+mov esi, 1
+mov edi, 2
+cmovge esi, edi
+cmovle edi, eax
+xor r8d, r8d
+cmp eax, 5
+jne .skip
+mov r8d, 1
+.skip:
+cmp eax, 4
+jle near .skip2
+add r8d, 2
+.skip2:
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RBX].r64() & 0xff, 1);
+    assert_eq!(r[R64::RCX].r64() & 0xff, 1);
+    assert_eq!(r[R64::RDX].r64() & 0xff, 0);
+    assert_eq!(r[R64::RSI].r64(), 1);
+    assert_eq!(r[R64::RDI].r64(), 5);
+    assert_eq!(r[R64::R8].r64(), 3);
+}
+
+#[test]
+fn shifts() {
+    let text = "
+mov eax, 0x80000001
+shl eax, 1
+setc bl
+seto bh
+mov ecx, 0x21
+mov rdx, -16
+sar rdx, cl
+mov esi, 0x81
+rol sil, 1
+rcr sil, 1
+mov edi, 0x1234
+shr di, 4
+shl edi, 0
+mov r8d, 0x12345678
+mov r9d, 0xabcdef01
+shld r8d, r9d, 8
+mov r10, r9
+shrd r10, r8, cl
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RAX].r64(), 2);
+    // cf and of, the sign changed
+    assert_eq!(r[R64::RBX].r64() & 0xffff, 0x0101);
+    // 64 bit counts aren't masked to 5 bits
+    assert_eq!(r[R64::RDX].r64(), u64::MAX);
+    // rol moved the top bit into cf, rcr put it back on top
+    assert_eq!(r[R64::RSI].r64(), 0x81);
+    assert_eq!(r[R64::RDI].r64(), 0x123);
+    assert_eq!(r[R64::R8].r64(), 0x345678ab);
+    assert_eq!(r[R64::R10].r64(), 0x1a2b_3c55_8000_0000);
+}
+
+#[test]
+fn atomics() {
+    let text = "
+mov QWORD [rbp-16], 5
+mov QWORD [rbp-8], 0
+mov eax, 3
+lock xadd [rbp-16], eax
+mov r8, rax
+mov ecx, 9
+xchg [rbp-16], ecx
+mov r9, rcx
+mov eax, 9
+mov edx, 1
+lock cmpxchg [rbp-16], edx
+setz r10b
+mov eax, 7
+lock cmpxchg [rbp-16], edx
+mov r11, rax
+mov rax, 1
+xor edx, edx
+mov rbx, 0x11
+mov rcx, 0x22
+lock cmpxchg16b [rbp-16]
+setz r12b
+mov r13, [rbp-16]
+mov r14, [rbp-8]
+mov rsi, -1
+mov edi, 2
+xchg rsi, rdi
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::R8].r64(), 5);
+    assert_eq!(r[R64::R9].r64(), 8);
+    assert_eq!(r[R64::R10].r64() & 0xff, 1);
+    // the compare failed, eax got the value in memory
+    assert_eq!(r[R64::R11].r64(), 1);
+    assert_eq!(r[R64::R12].r64() & 0xff, 1);
+    assert_eq!(r[R64::R13].r64(), 0x11);
+    assert_eq!(r[R64::R14].r64(), 0x22);
+    assert_eq!(r[R64::RSI].r64(), 2);
+    assert_eq!(r[R64::RDI].r64(), u64::MAX);
+}
+
+#[test]
+fn atomics_address_register() {
+    // the register operand is part of the address, memory goes where it pointed before
+    let text = "
+lea r15, [rbp-0x200]
+and r15, -256
+mov rbx, r15
+mov QWORD [r15], 0x10
+mov QWORD [r15+0x10], 0x77
+xchg [rbx], bl
+mov r8, [r15]
+mov r9, [r15+0x10]
+xadd [rbx], bl
+movzx r10d, bl
+movzx r11d, BYTE [r15+0x10]
+lea rax, [r15+0x30]
+mov [r15+0x20], rax
+mov QWORD [r15+0x30], 0
+lea rax, [r15+0x20]
+mov ecx, 5
+cmpxchg [rax], rcx
+mov r12, [r15+0x30]
+    ";
+
+    let r = t(text);
+    let base = r[R64::R15].r64();
+    assert_eq!(r[R64::R8].r64(), 0);
+    assert_eq!(r[R64::R9].r64(), 0x77);
+    assert_eq!(r[R64::R10].r64(), 0x77);
+    assert_eq!(r[R64::R11].r64(), 0x87);
+    assert_eq!(r[R64::RBX].r64(), base + 0x77);
+    // the failed compare loads rax after storing the old value back through it
+    assert_eq!(r[R64::RAX].r64(), base + 0x30);
+    assert_eq!(r[R64::R12].r64(), 0);
+}
+
+#[test]
+fn bits() {
+    let text = "
+mov QWORD [rbp-16], 0
+mov QWORD [rbp-8], 0
+mov eax, 100
+bts QWORD [rbp-16], rax
+mov r8, [rbp-8]
+mov rcx, -1
+bt QWORD [rbp-8], rcx
+setc r9b
+mov ebx, 0x90
+btr ebx, 36
+setc r10b
+mov rdx, -1
+xor esi, esi
+bsf rdx, rsi
+setz r11b
+mov esi, 0x00f0
+bsr edi, esi
+popcnt r12d, esi
+tzcnt r13, rsi
+lzcnt r14w, si
+    ";
+
+    let r = t(text);
+    // bit 100 is bit 36 of the second qword
+    assert_eq!(r[R64::R8].r64(), 1 << 36);
+    // bit -1 is the top bit of the qword before
+    assert_eq!(r[R64::R9].r64() & 0xff, 0);
+    // a register offset wraps around
+    assert_eq!(r[R64::RBX].r64(), 0x80);
+    assert_eq!(r[R64::R10].r64() & 0xff, 1);
+    // the destination isn't touched when the source is 0
+    assert_eq!(r[R64::RDX].r64(), u64::MAX);
+    assert_eq!(r[R64::R11].r64() & 0xff, 1);
+    assert_eq!(r[R64::RDI].r64(), 7);
+    assert_eq!(r[R64::R12].r64(), 4);
+    assert_eq!(r[R64::R13].r64(), 4);
+    assert_eq!(r[R64::R14].r64() & 0xffff, 8);
+}
+
+#[test]
+fn strings() {
+    let text = "
+lea rsi, [rel .src]
+lea rdi, [rbp-64]
+mov ecx, 12
+rep movsb
+mov r8, [rbp-64]
+mov r9, rdi
+sub r9, rbp
+lea rdi, [rbp-64]
+mov al, 0
+mov rcx, -1
+repne scasb
+not rcx
+dec rcx
+mov r10, rcx
+std
+lea rdi, [rbp-8]
+mov eax, 0x41414141
+mov ecx, 2
+rep stosd
+cld
+mov r11, [rbp-12]
+lea rsi, [rbp-64]
+lea rdi, [rel .src]
+mov ecx, 11
+repe cmpsb
+mov r12, rcx
+lea esi, [rbp-64]
+a32 lodsw
+mov r13, rax
+mov r14, rsi
+jmp near .end
+.src:
+    db 'hello world', 0
+.end:
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::R8].r64(), 0x6f77206f6c6c6568);
+    assert_eq!(r[R64::R9].r64(), -52i64 as u64);
+    // strlen
+    assert_eq!(r[R64::R10].r64(), 11);
+    // stosd went down from rbp-8 to rbp-12
+    assert_eq!(r[R64::R11].r64(), 0x4141_4141_4141_4141);
+    assert_eq!(r[R64::R12].r64(), 0);
+    assert_eq!(r[R64::R13].r64() & 0xffff, 0x6568);
+    assert_eq!(r[R64::R14].r64(), r[R64::RBP].r64() - 62);
+}
+
+#[test]
+fn widen() {
+    let text = "
+mov eax, 0x1234ff80
+movzx ebx, al
+movsx rcx, al
+movsx dx, ah
+mov DWORD [rbp-8], 0x8000ffff
+movzx esi, WORD [rbp-8]
+movsxd rdi, DWORD [rbp-8]
+mov r8, -1
+mov r8d, eax
+cbw
+mov r9, rax
+cwde
+mov r10, rax
+cdqe
+mov r11, rax
+mov rax, -2
+cqo
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RBX].r64(), 0x80);
+    assert_eq!(r[R64::RCX].r64(), 0xffff_ffff_ffff_ff80);
+    assert_eq!(r[R64::RDX].r64(), u64::MAX);
+    assert_eq!(r[R64::RSI].r64(), 0xffff);
+    assert_eq!(r[R64::RDI].r64(), 0xffff_ffff_8000_ffff);
+    assert_eq!(r[R64::R8].r64(), 0x1234_ff80);
+    assert_eq!(r[R64::R9].r64(), 0x1234_ff80);
+    assert_eq!(r[R64::R10].r64(), 0xffff_ff80);
+    assert_eq!(r[R64::R11].r64(), 0xffff_ffff_ffff_ff80);
+}
+
+#[test]
+fn lea() {
+    let text = "
+mov rbx, 0x1000
+mov rcx, 0x10
+lea rax, [rbx+rcx*4+8]
+lea edx, [rcx+rcx*2]
+mov rsi, 0xffffffff
+lea edi, [esi+2]
+lea r8, [rsi+2]
+lea r9w, [rbx-1]
+lea r10, [rel $]
+lea r11, [rel $+0x20]
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RAX].r64(), 0x1048);
+    assert_eq!(r[R64::RDX].r64(), 0x30);
+    // the 32 bit address wraps around
+    assert_eq!(r[R64::RDI].r64(), 1);
+    assert_eq!(r[R64::R8].r64(), 0x1_0000_0001);
+    assert_eq!(r[R64::R9].r64() & 0xffff, 0xfff);
+    assert_eq!(r[R64::R11].r64() - r[R64::R10].r64(), 0x27);
+}
+
+#[test]
+fn unary() {
+    let text = "
+mov eax, 0xff
+stc
+inc al
+setc bl
+setz bh
+mov ecx, 1
+dec ecx
+setc dl
+mov esi, 5
+neg esi
+setc dh
+mov rdi, 0x00ff00ff00ff00ff
+not rdi
+mov DWORD [rbp-8], 0x7fffffff
+inc DWORD [rbp-8]
+seto r8b
+mov r9d, 0x80
+test r9b, r9b
+sets r10b
+test r9d, 0x7f
+setz r11b
+    ";
+
+    let r = t(text);
+    // inc kept the cf from stc
+    assert_eq!(r[R64::RBX].r64() & 0xffff, 0x0101);
+    assert_eq!(r[R64::RCX].r64(), 0);
+    // dec doesn't set cf, neg of something that isn't 0 does
+    assert_eq!(r[R64::RDX].r64() & 0xffff, 0x0101);
+    assert_eq!(r[R64::RSI].r64(), 0xffff_fffb);
+    assert_eq!(r[R64::RDI].r64(), 0xff00_ff00_ff00_ff00);
+    assert_eq!(r[R64::R8].r64() & 0xff, 1);
+    assert_eq!(r[R64::R10].r64() & 0xff, 1);
+    assert_eq!(r[R64::R11].r64() & 0xff, 1);
+}
+
+#[test]
+fn multiply() {
+    let text = "
+mov eax, 7
+mov ecx, 6
+mul ecx
+mov rbx, rax
+mov rax, -5
+mov rsi, 3
+imul rsi
+mov rdi, rax
+mov r8, rdx
+imul r9, rdi, byte -3
+mov eax, 100
+xor edx, edx
+mov ecx, 7
+div ecx
+mov r10, rax
+mov r11, rdx
+mov ax, -100
+mov cl, 7
+idiv cl
+mov r12, rax
+mov rax, 0x4000000000000000
+mov rcx, 4
+mul rcx
+mov r13, rdx
+mov r14, rax
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RBX].r64(), 42);
+    // rdx:rax is -15
+    assert_eq!(r[R64::RDI].r64(), -15i64 as u64);
+    assert_eq!(r[R64::R8].r64(), u64::MAX);
+    assert_eq!(r[R64::R9].r64(), 45);
+    assert_eq!(r[R64::R10].r64(), 14);
+    assert_eq!(r[R64::R11].r64(), 2);
+    // -14 in al and -2 in ah
+    assert_eq!(r[R64::R12].r64(), 0xfef2);
+    assert_eq!(r[R64::R13].r64(), 1);
+    assert_eq!(r[R64::R14].r64(), 0);
+}
+
+#[test]
+fn stack() {
+    let text = "
+mov r15, rsp
+mov rax, 0x1122334455667788
+push rax
+push byte -3
+push 0x12345678
+pop rcx
+pop rdx
+pop rsi
+mov QWORD [rsp-8], 0x777
+push QWORD [rsp-8]
+pop rdi
+push rax
+pop QWORD [rsp-16]
+mov r8, [rsp-16]
+call .f
+mov r10, rsp
+sub r10, r15
+jmp near .end
+.f:
+    enter 0x20, 0
+    mov r9, rbp
+    sub r9, rsp
+    push 1
+    leave
+    ret 8
+.end:
+mov r11, rsp
+pushfw
+o16 enter 0x10, 0
+mov r12, r11
+sub r12, rsp
+lea rbp, [rsp+0x10]
+o16 leave
+popfw
+sub r11, rsp
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RCX].r64(), 0x12345678);
+    assert_eq!(r[R64::RDX].r64(), -3i64 as u64);
+    assert_eq!(r[R64::RSI].r64(), 0x1122334455667788);
+    assert_eq!(r[R64::RDI].r64(), 0x777);
+    assert_eq!(r[R64::R8].r64(), 0x1122334455667788);
+    assert_eq!(r[R64::R9].r64(), 0x20);
+    // ret 8 drops 8 more bytes than the call pushed
+    assert_eq!(r[R64::R10].r64(), 8);
+    // pushfw and o16 enter push 16 bits each, popfw and o16 leave take them back off
+    assert_eq!(r[R64::R12].r64(), 0x14);
+    assert_eq!(r[R64::R11].r64(), 0);
+}
+
+#[test]
+fn leave_fault() {
+    // mov ebp, 0x7fffffff; leave, the pop faults and rsp stays where it was
+    let mut output = String::new();
+    let mut emulator = Emulator::new(&[0xbd, 0xff, 0xff, 0xff, 0x7f, 0xc9], &mut output);
+    let rsp = emulator.regs[R64::RSP].r64();
+    assert_eq!(
+        emulator.run(),
+        Err(EmulatorError::MemoryFault {
+            address: 0x7fffffff,
+            access: Access::Read
+        })
+    );
+    assert_eq!(emulator.regs[R64::RSP].r64(), rsp);
+}
+
 #[test]
 fn stack_alloc_ret_1000() {
     let text = "
@@ -160,6 +765,30 @@ fn simple_jump() {
     t(text);
 }
 
+#[test]
+fn indirect_branches() {
+    let text = "
+xor ecx, ecx
+lea rax, [rel .f]
+call rax
+lea rax, [rel .g]
+push rax
+jmp QWORD [rsp]
+.f:
+    inc ecx
+    ret
+.g:
+    pop rdx
+    jmp short .h
+    mov ecx, 100
+.h:
+    add ecx, 10
+    ";
+
+    let r = t(text);
+    assert_eq!(r[R64::RCX].r64(), 11);
+}
+
 #[test]
 fn simple_jump2() {
     let text = "
@@ -186,6 +815,44 @@ f:
     t(text);
 }
 
+#[test]
+fn mem_addressing() {
+    let text = "
+mov ebx, 0x100
+mov ecx, 0x1000
+mov dword [ebx*4+ecx], 0x12345
+mov eax, [rcx+rbx*4]
+mov rdx, 0x1400
+mov r12, 16
+mov r8b, 0x77
+mov [rdx+r12*2-32], r8b
+mov esi, [rdx]
+mov r13, rdx
+mov edi, [r13]
+mov r9d, [rel $]
+    ";
+
+    let regs = t(text);
+    assert_eq!(regs[R64::RAX].r64(), 0x12345);
+    assert_eq!(regs[R64::RSI].r64(), 0x12377);
+    assert_eq!(regs[R64::RDI].r64(), 0x12377);
+    // reads its own encoding, 44 8b 0d f9 ff ff ff
+    assert_eq!(regs[R64::R9].r64(), 0xf90d8b44);
+}
+
+#[test]
+fn segment_override() {
+    let text = "
+mov dword [0x2000], 0x1234
+mov eax, [fs:0x2000]
+mov ebx, [ds:0x2000]
+    ";
+
+    let regs = t(text);
+    assert_eq!(regs[R64::RAX].r64(), 0x1234);
+    assert_eq!(regs[R64::RBX].r64(), 0x1234);
+}
+
 #[test]
 fn mov_m() {
     let text = "
@@ -365,3 +1032,287 @@ mov r15w, 16
     assert_eq!(regs[R64::RAX].r64(), 1);
     assert_eq!(regs[R64::R15].r64(), 16);
 }
+
+#[test]
+fn avx() {
+    let text = "
+mov rax, 0x0102030405060708
+vmovq xmm0, rax
+vpbroadcastq ymm1, xmm0
+vpaddb ymm2, ymm1, ymm1
+vpaddd xmm3, xmm1, xmm1
+vpermq ymm4, ymm2, 0x1b
+vextracti128 xmm5, ymm2, 1
+vpmovmskb ecx, ymm2
+    ";
+
+    let regs = t(text);
+    let doubled = 0x020406080a0c0e10u64.to_le_bytes();
+    assert_eq!(regs.zmm[2][..8], doubled);
+    assert_eq!(regs.zmm[2][24..32], doubled);
+    // 128 bit vex forms zero the upper half
+    assert_eq!(regs.zmm[3][..8], doubled);
+    assert_eq!(regs.zmm[3][16..], [0; 48]);
+    assert_eq!(regs.zmm[4], regs.zmm[2]);
+    assert_eq!(regs.zmm[5][..16], regs.zmm[2][16..32]);
+    assert_eq!(regs.zmm[5][16..], [0; 48]);
+    assert_eq!(regs[R64::RCX].r64(), 0);
+}
+
+#[test]
+fn avx512() {
+    let text = "
+mov eax, 0x5555
+kmovw k1, eax
+mov eax, 3
+vpbroadcastd zmm0, eax
+vpaddd zmm1, zmm0, zmm0
+vpaddd zmm2{k1}{z}, zmm0, zmm1
+vmovdqa32 zmm3, zmm1
+vpaddd zmm3{k1}, zmm0, zmm1
+vpcmpd k2, zmm3, zmm2, 0
+kmovw ecx, k2
+vcvtdq2ps zmm4, zmm0
+mov eax, 1
+vpbroadcastd zmm5, eax
+vcvtdq2ps zmm5, zmm5
+vdivps zmm6, zmm5, zmm4, {rd-sae}
+vdivps zmm7, zmm5, zmm4, {ru-sae}
+    ";
+
+    let regs = t(text);
+    let lanes = |i: usize| -> Vec<u32> {
+        regs.zmm[i]
+            .chunks(4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+            .collect()
+    };
+    // zeroing and merging masks
+    assert_eq!(lanes(2)[..4], [9, 0, 9, 0]);
+    assert_eq!(lanes(3)[..4], [9, 6, 9, 6]);
+    assert_eq!(regs[R64::RCX].r64(), 0x5555);
+    // 1/3 rounded down and up
+    assert_eq!(lanes(6)[15], 0x3eaaaaaa);
+    assert_eq!(lanes(7)[15], 0x3eaaaaab);
+}
+
+#[test]
+fn sse() {
+    let text = "
+vpternlogd zmm1, zmm1, zmm1, 0xff
+mov eax, 3
+cvtsi2sd xmm1, eax
+mov ecx, 7
+cvtsi2sd xmm2, ecx
+divsd xmm2, xmm1
+cvttsd2si rdx, xmm2
+mov DWORD [rbp-8], 0x5f80
+ldmxcsr [rbp-8]
+cvtsd2si rsi, xmm2
+stmxcsr [rbp-4]
+mov edi, [rbp-4]
+pcmpeqb xmm3, xmm3
+pmovmskb r8d, xmm3
+mov eax, 0x7fff
+movd xmm5, eax
+paddsw xmm5, xmm5
+movd r9d, xmm5
+ucomisd xmm1, xmm2
+seta r10b
+    ";
+
+    let r = t(text);
+    assert_eq!(r.zmm[1][..8], 3f64.to_le_bytes());
+    // legacy forms leave everything above the low 128 bits alone
+    assert_eq!(r.zmm[1][8..], [0xff; 56]);
+    // 7 / 3 truncated, then rounded up once ldmxcsr sets the rounding mode
+    assert_eq!(r[R64::RDX].r64(), 2);
+    assert_eq!(r[R64::RSI].r64(), 3);
+    // and that was inexact
+    assert_eq!(r[R64::RDI].r64(), 0x5fa0);
+    assert_eq!(r[R64::R8].r64(), 0xffff);
+    // paddsw saturates
+    assert_eq!(r[R64::R9].r64(), 0x7fff);
+    assert_eq!(r[R64::R10].r64() & 0xff, 1);
+}
+
+#[test]
+fn mxcsr_exceptions() {
+    let text = "
+mov eax, 1
+cvtsi2ss xmm0, eax
+mov eax, 3
+cvtsi2ss xmm1, eax
+divss xmm0, xmm1
+stmxcsr [rbp-4]
+mov ecx, [rbp-4]
+xorps xmm2, xmm2
+divss xmm1, xmm2
+stmxcsr [rbp-4]
+mov edx, [rbp-4]
+mov DWORD [rbp-8], 0x1f80
+ldmxcsr [rbp-8]
+vdivss xmm6, xmm0, xmm1, {rz-sae}
+stmxcsr [rbp-4]
+mov esi, [rbp-4]
+mov eax, 0x7fc00000
+movd xmm7, eax
+ucomiss xmm7, xmm0
+stmxcsr [rbp-4]
+mov edi, [rbp-4]
+comiss xmm7, xmm0
+stmxcsr [rbp-4]
+mov r11d, [rbp-4]
+ldmxcsr [rbp-8]
+mov eax, 1
+movd xmm3, eax
+addss xmm3, xmm3
+movd r13d, xmm3
+stmxcsr [rbp-4]
+mov r12d, [rbp-4]
+mov DWORD [rbp-8], 0x9fc0
+ldmxcsr [rbp-8]
+movd xmm3, eax
+addss xmm3, xmm3
+movd r8d, xmm3
+mov eax, 0x800000
+movd xmm4, eax
+mov eax, 0x3f000000
+movd xmm5, eax
+mulss xmm4, xmm5
+movd r9d, xmm4
+stmxcsr [rbp-4]
+mov r10d, [rbp-4]
+    ";
+
+    let r = t(text);
+    // 1 / 3 is inexact, then 3 / 0 divides by zero, and the flags stay set
+    assert_eq!(r[R64::RCX].r64(), 0x1fa0);
+    assert_eq!(r[R64::RDX].r64(), 0x1fa4);
+    // {sae} suppresses them
+    assert_eq!(r[R64::RSI].r64(), 0x1f80);
+    // a quiet NaN only signals for comiss
+    assert_eq!(r[R64::RDI].r64(), 0x1f80);
+    assert_eq!(r[R64::R11].r64(), 0x1f81);
+    // denormal operands, which DAZ turns into zeros
+    assert_eq!(r[R64::R12].r64(), 0x1f82);
+    assert_eq!(r[R64::R13].r64(), 2);
+    assert_eq!(r[R64::R8].r64(), 0);
+    // FTZ flushes the denormal half of the smallest normal to zero
+    assert_eq!(r[R64::R9].r64(), 0);
+    assert_eq!(r[R64::R10].r64(), 0x9ff0);
+}
+
+#[test]
+fn sse4() {
+    let text = "
+mov rax, 'hello wo'
+movq xmm1, rax
+mov eax, 'rld'
+pinsrq xmm1, rax, 1
+mov eax, 'wo'
+movd xmm2, eax
+pcmpistri xmm2, xmm1, 0x0c
+mov r8, rcx
+setc bl
+pxor xmm3, xmm3
+ptest xmm3, xmm1
+setz r9b
+palignr xmm3, xmm1, 6
+movq r10, xmm3
+mov ecx, -1
+mov rax, '12345678'
+crc32 rcx, rax
+mov dl, '9'
+crc32 ecx, dl
+not ecx
+mov eax, 5
+cvtsi2sd xmm4, eax
+mov eax, 2
+cvtsi2sd xmm5, eax
+divsd xmm4, xmm5
+roundsd xmm6, xmm4, 0
+cvttsd2si r11, xmm6
+roundsd xmm7, xmm4, 2
+cvttsd2si r12, xmm7
+mov eax, -1
+movd xmm8, eax
+mov eax, 7
+movd xmm9, eax
+pminud xmm8, xmm9
+movd r13d, xmm8
+pcmpeqb xmm0, xmm0
+psrldq xmm0, 8
+pblendvb xmm9, xmm1, xmm0
+movq r14, xmm9
+pshufd xmm10, xmm1, 0x4e
+pshufb xmm10, xmm0
+pextrq r15, xmm10, 1
+    ";
+
+    let r = t(text);
+    // 'wo' starts at byte 6
+    assert_eq!(r[R64::R8].r64(), 6);
+    assert_eq!(r[R64::RBX].r64() & 0xff, 1);
+    assert_eq!(r[R64::R9].r64() & 0xff, 1);
+    assert_eq!(r[R64::R10].r64(), u64::from_le_bytes(*b"world\0\0\0"));
+    // the standard crc32c check value
+    assert_eq!(r[R64::RCX].r64(), 0xe306_9283);
+    // 2.5 rounds to even, then up
+    assert_eq!(r[R64::R11].r64(), 2);
+    assert_eq!(r[R64::R12].r64(), 3);
+    assert_eq!(r[R64::R13].r64(), 7);
+    assert_eq!(r[R64::R14].r64(), u64::from_le_bytes(*b"hello wo"));
+    // the high half of xmm0 picks byte 0 everywhere
+    assert_eq!(r[R64::R15].r64(), u64::from_le_bytes(*b"rrrrrrrr"));
+}
+
+#[test]
+fn x87() {
+    let text = "
+mov dword [rbp-4], 7
+fild dword [rbp-4]
+mov dword [rbp-4], 2
+fidiv dword [rbp-4]
+fistp dword [rbp-4]
+mov ecx, [rbp-4]
+fld1
+fldz
+fcomi st0, st1
+setb dl
+fdivp st1, st0
+fnstsw ax
+fldpi
+fstp tword [rbp-16]
+mov rsi, [rbp-16]
+movzx edi, word [rbp-8]
+mov word [rbp-2], 0xc7f
+fldcw [rbp-2]
+mov dword [rbp-4], 3
+fld1
+fidiv dword [rbp-4]
+fstp qword [rbp-16]
+mov r8, [rbp-16]
+mov word [rbp-2], 0xffff
+fldcw [rbp-2]
+fnstcw [rbp-2]
+movzx r9d, word [rbp-2]
+    ";
+
+    let r = t(text);
+    // 3.5 rounds to even, which is inexact
+    assert_eq!(r[R64::RCX].r64(), 4);
+    assert_eq!(r[R64::RDX].r64() & 0xff, 1);
+    // zero divide and precision, with one register in use
+    assert_eq!(r[R64::RAX].r64() & 0xffff, 0x3824);
+    assert_eq!(r[R64::RSI].r64(), 0xc90f_daa2_2168_c235);
+    assert_eq!(r[R64::RDI].r64(), 0x4000);
+    // 1/3 chopped to 24 bits by the control word
+    assert_eq!(r[R64::R8].r64(), 0x3fd5_5555_4000_0000);
+    // the reserved bits of the control word are fixed
+    assert_eq!(r[R64::R9].r64(), 0x1f7f);
+    assert_eq!(r.fpu.top, 7);
+    assert_eq!(r.fpu.tags, 0xbfff);
+    assert_eq!(r.fpu.st[7].sign_exponent, 0x7fff);
+    assert_eq!(r.fpu.st[7].mantissa, 1 << 63);
+}
@@ -0,0 +1,838 @@
+//! Turns an `Instr` back into bytes by searching the decoder's opcode tables for a form
+//! that takes its operands.
+
+use crate::instr::{Base, Instr, Mem, Operand, Size};
+use crate::opcodes::{
+    Def, Mandatory, Masking, Op, RegKind, Spec, Src, Width, EVEX_0F, EVEX_0F38, EVEX_0F3A, MAP_0F,
+    MAP_0F38, MAP_0F3A, ONE_BYTE, VEX_0F, VEX_0F38, VEX_0F3A,
+};
+use crate::prefix::{Rep, Rounding, Segment};
+use crate::registers::Reg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// No form of the instruction takes these operands.
+    NoForm,
+    /// A branch target or rip relative address is too far away for its displacement.
+    OutOfRange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Legacy,
+    Vex,
+    Evex,
+}
+
+/// An opcode table and how to select it, `map` is 0 for one byte opcodes and then vex's
+/// mmmmm: 1 for 0F, 2 for 0F 38 and 3 for 0F 3A.
+struct Map {
+    defs: &'static [&'static [Def]; 256],
+    kind: Kind,
+    map: u8,
+}
+
+const MAPS: [Map; 10] = [
+    Map {
+        defs: &ONE_BYTE,
+        kind: Kind::Legacy,
+        map: 0,
+    },
+    Map {
+        defs: &MAP_0F,
+        kind: Kind::Legacy,
+        map: 1,
+    },
+    Map {
+        defs: &MAP_0F38,
+        kind: Kind::Legacy,
+        map: 2,
+    },
+    Map {
+        defs: &MAP_0F3A,
+        kind: Kind::Legacy,
+        map: 3,
+    },
+    Map {
+        defs: &VEX_0F,
+        kind: Kind::Vex,
+        map: 1,
+    },
+    Map {
+        defs: &VEX_0F38,
+        kind: Kind::Vex,
+        map: 2,
+    },
+    Map {
+        defs: &VEX_0F3A,
+        kind: Kind::Vex,
+        map: 3,
+    },
+    Map {
+        defs: &EVEX_0F,
+        kind: Kind::Evex,
+        map: 1,
+    },
+    Map {
+        defs: &EVEX_0F38,
+        kind: Kind::Evex,
+        map: 2,
+    },
+    Map {
+        defs: &EVEX_0F3A,
+        kind: Kind::Evex,
+        map: 3,
+    },
+];
+
+/// modrm.rm, a register number or a memory operand.
+#[derive(Clone, Copy)]
+enum Rm {
+    Reg(u8),
+    Mem(Mem),
+}
+
+/// Where the operands of one form go, register numbers are up to 5 bits wide.
+#[derive(Default)]
+struct Fields {
+    w: bool,
+    operand_size: bool,
+    reg: u8,
+    rm: Option<Rm>,
+    vvvv: u8,
+    opcode_reg: u8,
+    /// enter has two
+    imm: Vec<(i64, Size)>,
+    is4: Option<u8>,
+    rel: Option<(i64, Size)>,
+    /// spl..dil need a rex prefix and ah..bh can't have one
+    needs_rex: bool,
+    no_rex: bool,
+}
+
+fn fits(value: i64, size: Size) -> bool {
+    match size {
+        Size::Byte => i8::try_from(value).is_ok(),
+        Size::Word => i16::try_from(value).is_ok(),
+        Size::Dword => i32::try_from(value).is_ok(),
+        _ => true,
+    }
+}
+
+/// The operand size, like `Prefixes::op_size`.
+fn op_size(w: bool, operand_size: bool) -> Size {
+    if w {
+        Size::Qword
+    } else if operand_size {
+        Size::Word
+    } else {
+        Size::Dword
+    }
+}
+
+fn width(width: Width, op_size: Size) -> Size {
+    match width {
+        Width::Fixed(size) => size,
+        Width::V => op_size,
+        Width::Z => match op_size {
+            Size::Qword => Size::Dword,
+            size => size,
+        },
+        Width::D => match op_size {
+            Size::Word => Size::Word,
+            _ => Size::Qword,
+        },
+    }
+}
+
+/// The number of `operand` if it's a register of the kind `spec` asks for.
+fn register(
+    spec: Spec,
+    size: Size,
+    operand: Operand,
+    kind: Kind,
+    fields: &mut Fields,
+) -> Option<u8> {
+    let vector_size = match spec.reg {
+        RegKind::Xmm => Size::Oword,
+        RegKind::Ymm => Size::Yword,
+        RegKind::Zmm => Size::Zword,
+        RegKind::Gpr | RegKind::Gpr32 => {
+            let size = if spec.reg == RegKind::Gpr32 {
+                Size::Dword
+            } else {
+                size
+            };
+            let reg = match operand {
+                Operand::Reg(reg) if reg.size() == size => reg,
+                _ => return None,
+            };
+            let index = reg.as_usize() as u8;
+            if let Reg::R8(x) = reg {
+                // ah..bh are encoded as 4..7, they're the high bytes of 0..3
+                if x.is_high() {
+                    fields.no_rex = true;
+                    return Some(index + 4);
+                }
+                fields.needs_rex |= index >= 4;
+            }
+            return Some(index);
+        }
+        RegKind::Mask => {
+            let Operand::Mask(k) = operand else {
+                return None;
+            };
+            return (k.0 < 8).then_some(k.0);
+        }
+        RegKind::St => {
+            let Operand::St(st) = operand else {
+                return None;
+            };
+            return (st.0 < 8).then_some(st.0);
+        }
+    };
+    let Operand::Vec(reg) = operand else {
+        return None;
+    };
+    let limit = if kind == Kind::Evex { 32 } else { 16 };
+    (reg.size == vector_size && reg.index < limit).then_some(reg.index)
+}
+
+/// Works out where the operands of `instr` go in `def`, if they fit it.
+fn fields(def: &Def, instr: &Instr, kind: Kind, w: bool, operand_size: bool) -> Option<Fields> {
+    let count = instr
+        .operands
+        .iter()
+        .take_while(|x| !matches!(x, Operand::None))
+        .count();
+    if count != def.operands.len() {
+        return None;
+    }
+    let op_size = op_size(w, operand_size);
+    let mut fields = Fields {
+        w,
+        operand_size,
+        reg: def.ext.unwrap_or(0),
+        ..Default::default()
+    };
+    // like in the decoder, only the size says how much push imm8, enter and leave push or pop
+    let push_imm = instr.op == Op::Push && def.operands[0].src == Src::Imm;
+    let frame = matches!(instr.op, Op::Enter | Op::Leave);
+    if (push_imm || frame) && width(Width::D, op_size) != instr.size {
+        return None;
+    }
+    if def.size.is_some_and(|x| x != op_size) {
+        return None;
+    }
+    for (&spec, &operand) in def.operands.iter().zip(&instr.operands) {
+        let size = width(spec.width, op_size);
+        match spec.src {
+            Src::Reg => fields.reg = register(spec, size, operand, kind, &mut fields)?,
+            Src::Rm | Src::Mem | Src::RmReg => {
+                fields.rm = Some(match operand {
+                    Operand::Mem(mem) if spec.src != Src::RmReg => {
+                        let size = match mem.broadcast {
+                            Some(count) => {
+                                let vl = 16 << def.l.unwrap_or(0);
+                                def.broadcast.filter(|x| x.bytes() * count as usize == vl)?
+                            }
+                            None => size,
+                        };
+                        if mem.size != size {
+                            return None;
+                        }
+                        Rm::Mem(mem)
+                    }
+                    _ if spec.src == Src::Mem => return None,
+                    _ => Rm::Reg(register(spec, size, operand, kind, &mut fields)?),
+                })
+            }
+            Src::Vvvv => fields.vvvv = register(spec, size, operand, kind, &mut fields)?,
+            Src::Is4 => fields.is4 = Some(register(spec, size, operand, kind, &mut fields)?),
+            Src::Opcode => fields.opcode_reg = register(spec, size, operand, kind, &mut fields)?,
+            Src::Fixed(index) => {
+                if register(spec, size, operand, kind, &mut fields)? != index {
+                    return None;
+                }
+            }
+            Src::One => {
+                if !matches!(operand, Operand::Imm { value: 1, .. }) {
+                    return None;
+                }
+            }
+            Src::Imm => match operand {
+                Operand::Imm { value, size: imm } if imm == size && fits(value, size) => {
+                    fields.imm.push((value, size))
+                }
+                _ => return None,
+            },
+            Src::Rel => match operand {
+                Operand::Rel { offset, size: rel }
+                    if (rel == Size::Byte) == (size == Size::Byte) =>
+                {
+                    fields.rel = Some((offset, rel))
+                }
+                _ => return None,
+            },
+        }
+    }
+    Some(fields)
+}
+
+/// Appends modrm, sib and the displacement, returning where a rip relative displacement goes.
+fn modrm(
+    out: &mut Vec<u8>,
+    reg: u8,
+    rm: Rm,
+    disp_scale: i64,
+) -> Result<Option<usize>, EncodeError> {
+    let reg = (reg & 7) << 3;
+    let mem = match rm {
+        Rm::Reg(rm) => {
+            out.push(0b11 << 6 | reg | (rm & 7));
+            return Ok(None);
+        }
+        Rm::Mem(mem) => mem,
+    };
+
+    let base = match mem.base {
+        Some(Base::Rip) => {
+            out.push(reg | 0b101);
+            out.extend([0; 4]);
+            return Ok(Some(out.len() - 4));
+        }
+        Some(Base::Reg(base)) => Some(base.as_usize() as u8 & 7),
+        None => None,
+    };
+    let scale = match mem.scale {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        _ => 3,
+    };
+    let index = mem.index.map_or(0b100, |x| x.as_usize() as u8 & 7);
+
+    let compressed = mem.disp % disp_scale == 0 && fits(mem.disp / disp_scale, Size::Byte);
+    let disp_size = match (base, mem.disp_size) {
+        (None, _) => Some(Size::Dword),
+        (Some(_), Some(Size::Byte)) if compressed => Some(Size::Byte),
+        (Some(_), Some(_)) => Some(Size::Dword),
+        // rbp and r13 as a base mean there's no base with mod 00
+        (Some(0b101), None) => Some(Size::Byte),
+        (Some(_), None) => None,
+    };
+    let disp_size = match disp_size {
+        None if mem.disp != 0 => Some(if compressed { Size::Byte } else { Size::Dword }),
+        x => x,
+    };
+    let mod_ = match (base, disp_size) {
+        (None, _) | (_, None) => 0b00,
+        (_, Some(Size::Byte)) => 0b01,
+        _ => 0b10,
+    };
+
+    match base {
+        Some(base) if base != 0b100 && mem.index.is_none() => out.push(mod_ << 6 | reg | base),
+        // no base is a sib byte with base 101 and mod 00, rsp and r12 always need one
+        _ => {
+            out.push(mod_ << 6 | reg | 0b100);
+            out.push(scale << 6 | index << 3 | base.unwrap_or(0b101));
+        }
+    }
+    match disp_size {
+        Some(Size::Byte) => out.push((mem.disp / disp_scale) as u8),
+        Some(_) if fits(mem.disp, Size::Dword) => out.extend((mem.disp as i32).to_le_bytes()),
+        Some(_) => return Err(EncodeError::OutOfRange),
+        None => {}
+    }
+    Ok(None)
+}
+
+fn segment_prefix(segment: Segment) -> u8 {
+    match segment {
+        Segment::Es => 0x26,
+        Segment::Cs => 0x2e,
+        Segment::Ss => 0x36,
+        Segment::Ds => 0x3e,
+        Segment::Fs => 0x64,
+        Segment::Gs => 0x65,
+    }
+}
+
+/// Whether the address registers of `mem` can be encoded at all.
+fn valid_address(mem: &Mem) -> bool {
+    let size_ok = |reg: Reg| reg.size() == mem.addr_size;
+    matches!(mem.addr_size, Size::Dword | Size::Qword)
+        && matches!(mem.scale, 1 | 2 | 4 | 8)
+        && mem.index.is_none_or(|x| size_ok(x) && x.as_usize() != 4)
+        && match mem.base {
+            Some(Base::Reg(base)) => size_ok(base),
+            Some(Base::Rip) => mem.index.is_none(),
+            None => true,
+        }
+}
+
+/// Encodes `instr` with `def`, which is at `opcode` in `map`.
+fn encode_with(
+    instr: &Instr,
+    def: &Def,
+    map: &Map,
+    opcode: u8,
+    f: &Fields,
+) -> Result<Vec<u8>, EncodeError> {
+    let p = &instr.prefixes;
+    let mem = match f.rm {
+        Some(Rm::Mem(mem)) => Some(mem),
+        _ => None,
+    };
+    let rm_reg = match f.rm {
+        Some(Rm::Reg(x)) => x,
+        _ => 0,
+    };
+    let mem_index = mem.and_then(|x| x.index).map_or(0, |x| x.as_usize() as u8);
+    let mem_base = match mem.and_then(|x| x.base) {
+        Some(Base::Reg(base)) => base.as_usize() as u8,
+        _ => 0,
+    };
+    let r = f.reg & 8 != 0;
+    let x = mem_index & 8 != 0 || (map.kind == Kind::Evex && rm_reg & 16 != 0);
+    let b = (rm_reg | mem_base | f.opcode_reg) & 8 != 0;
+    if f.no_rex && (map.kind != Kind::Legacy || f.w || r || x || b || f.needs_rex) {
+        return Err(EncodeError::NoForm);
+    }
+
+    let mut out = Vec::new();
+    if p.lock {
+        out.push(0xf0);
+    }
+    match p.rep {
+        Some(Rep::Rep) => out.push(0xf3),
+        Some(Rep::Repne) => out.push(0xf2),
+        None => {}
+    }
+    if let Some(segment) = mem.and_then(|x| x.segment).or(p.segment) {
+        out.push(segment_prefix(segment));
+    }
+    if f.operand_size {
+        out.push(0x66);
+    }
+    let string_a32 = p.address_size && instr.is_string();
+    if mem.is_some_and(|x| x.addr_size == Size::Dword) || string_a32 {
+        out.push(0x67);
+    }
+
+    let pp = match def.prefix {
+        Mandatory::Any | Mandatory::Np => 0b00,
+        Mandatory::P66 => 0b01,
+        Mandatory::F3 => 0b10,
+        Mandatory::F2 => 0b11,
+    };
+    let w = f.w as u8;
+    let l = def.l.unwrap_or(0);
+    let mut disp_scale = 1;
+    match map.kind {
+        Kind::Legacy => {
+            out.extend(match pp {
+                0b01 => Some(0x66),
+                0b10 => Some(0xf3),
+                0b11 => Some(0xf2),
+                _ => None,
+            });
+            let rex = w << 3 | (r as u8) << 2 | (x as u8) << 1 | b as u8;
+            if rex != 0 || f.needs_rex {
+                out.push(0x40 | rex);
+            }
+            match map.map {
+                0 => {}
+                1 => out.push(0x0f),
+                2 => out.extend([0x0f, 0x38]),
+                _ => out.extend([0x0f, 0x3a]),
+            }
+        }
+        Kind::Vex => {
+            let byte2 = w << 7 | (!f.vvvv & 0xf) << 3 | l << 2 | pp;
+            let needs_vex3 = x || b || w != 0 || map.map != 1;
+            // {vex3} is only a thing when c5 would have done, which depends on the form
+            let vex3 = p.vex.is_some_and(|x| x.vex3);
+            if vex3 && needs_vex3 {
+                return Err(EncodeError::NoForm);
+            }
+            if needs_vex3 || vex3 {
+                let byte1 = (!r as u8) << 7 | (!x as u8) << 6 | (!b as u8) << 5 | map.map;
+                out.extend([0xc4, byte1, byte2]);
+            } else {
+                out.extend([0xc5, (!r as u8) << 7 | (byte2 & 0x7f)]);
+            }
+        }
+        Kind::Evex => {
+            let evex = p.evex.unwrap();
+            let high_r = f.reg & 16 != 0;
+            let high_v = f.vvvv & 16 != 0;
+            let (l, broadcast) = match (evex.rounding, mem.and_then(|x| x.broadcast)) {
+                (Some(Rounding::Nearest), _) => (0, true),
+                (Some(Rounding::Down), _) => (1, true),
+                (Some(Rounding::Up), _) => (2, true),
+                (Some(Rounding::Zero), _) => (3, true),
+                (Some(Rounding::Sae), _) => (0, true),
+                (None, broadcast) => (l, broadcast.is_some()),
+            };
+            let p0 = (!r as u8) << 7
+                | (!x as u8) << 6
+                | (!b as u8) << 5
+                | (!high_r as u8) << 4
+                | map.map;
+            let p1 = w << 7 | (!f.vvvv & 0xf) << 3 | 0b100 | pp;
+            let p2 = (evex.zeroing as u8) << 7
+                | l << 5
+                | (broadcast as u8) << 4
+                | (!high_v as u8) << 3
+                | evex.mask;
+            out.extend([0x62, p0, p1, p2]);
+            disp_scale = mem.map_or(1, |x| x.size.bytes() as i64);
+        }
+    }
+    out.push(opcode);
+
+    let mut rip = None;
+    if let Some(fixed) = def.fixed_modrm {
+        out.push(fixed);
+    } else if def.modrm {
+        rip = modrm(&mut out, f.reg, f.rm.unwrap_or(Rm::Reg(0)), disp_scale)?;
+    }
+    let mut rel = None;
+    for &(imm, size) in &f.imm {
+        out.extend(&imm.to_le_bytes()[..size.bytes()]);
+    }
+    if let Some(is4) = f.is4 {
+        out.push(is4 << 4);
+    }
+    if let Some((_, size)) = f.rel {
+        rel = Some(out.len());
+        out.extend(&[0; 4][..size.bytes()]);
+    }
+
+    // both are relative to the start of the instruction, the encoded ones to its end
+    let len = out.len() as i64;
+    if let (Some(pos), Some(mem)) = (rip, mem) {
+        let disp = mem.disp - len;
+        if !fits(disp, Size::Dword) {
+            return Err(EncodeError::OutOfRange);
+        }
+        out[pos..pos + 4].copy_from_slice(&(disp as i32).to_le_bytes());
+    }
+    if let (Some(pos), Some((offset, size))) = (rel, f.rel) {
+        let disp = offset - len;
+        if !fits(disp, size) {
+            return Err(EncodeError::OutOfRange);
+        }
+        out[pos..].copy_from_slice(&disp.to_le_bytes()[..size.bytes()]);
+    }
+    Ok(out)
+}
+
+/// Whether `def` allows the evex decorations of `instr`.
+fn evex_fits(instr: &Instr, def: &Def, f: &Fields) -> bool {
+    let Some(evex) = instr.prefixes.evex else {
+        return false;
+    };
+    let is_mem = matches!(f.rm, Some(Rm::Mem(_)));
+    let mem_destination = is_mem
+        && def
+            .operands
+            .first()
+            .is_some_and(|x| matches!(x.src, Src::Rm | Src::Mem));
+    let mask = match def.mask {
+        Masking::None => evex.mask == 0 && !evex.zeroing,
+        Masking::Merge => !evex.zeroing,
+        Masking::Zero => !evex.zeroing || !mem_destination,
+    };
+    let rounding = match evex.rounding {
+        None => true,
+        // rounding and sae make the vector length 512
+        Some(_) if is_mem || def.l.is_some_and(|x| x != 2) => false,
+        Some(Rounding::Sae) => def.sae && !def.er,
+        Some(_) => def.er,
+    };
+    mask && rounding
+}
+
+/// Encodes `instr`, picking the shortest form that takes its operands. Evex is only used when
+/// `instr` has an evex prefix, which is also how the opmask, `{z}`, rounding and `{evex}`
+/// are given.
+pub fn encode(instr: &Instr) -> Result<Vec<u8>, EncodeError> {
+    let p = &instr.prefixes;
+    let mems = instr.operands.iter().filter_map(|x| match x {
+        Operand::Mem(mem) => Some(mem),
+        _ => None,
+    });
+    if !mems.clone().all(valid_address) {
+        return Err(EncodeError::NoForm);
+    }
+    let broadcast = mems.clone().any(|x| x.broadcast.is_some());
+
+    let mut best: Option<Result<Vec<u8>, EncodeError>> = None;
+    for map in &MAPS {
+        let legacy_only = p.lock || p.rep.is_some();
+        let kind_ok = match map.kind {
+            Kind::Legacy => p.evex.is_none() && !broadcast,
+            Kind::Vex => p.evex.is_none() && !broadcast && !legacy_only,
+            Kind::Evex => p.evex.is_some() && !legacy_only,
+        };
+        if !kind_ok {
+            continue;
+        }
+        for (opcode, defs) in map.defs.iter().enumerate() {
+            for def in defs.iter().filter(|x| x.op == instr.op) {
+                // a rep prefix would be taken as the mandatory one or turn np into something else
+                let rep_conflict =
+                    matches!(def.prefix, Mandatory::Np | Mandatory::F2 | Mandatory::F3);
+                if p.rep.is_some() && rep_conflict {
+                    continue;
+                }
+                let sizes: &[(bool, bool)] = match (map.kind, def.w) {
+                    (_, Some(true)) => &[(true, false)],
+                    (Kind::Legacy, None) => &[(false, false), (false, true), (true, false)],
+                    (Kind::Legacy, Some(false)) => &[(false, false), (false, true)],
+                    (_, Some(false)) => &[(false, false)],
+                    (_, None) => &[(false, false), (true, false)],
+                };
+                let plus_reg = def.operands.iter().any(|x| x.src == Src::Opcode);
+                let fits = sizes.iter().find_map(|&(w, operand_size)| {
+                    if operand_size && (def.prefix == Mandatory::P66 || def.prefix == Mandatory::Np)
+                    {
+                        return None;
+                    }
+                    let f = fields(def, instr, map.kind, w, operand_size)?;
+                    if map.kind == Kind::Evex && !evex_fits(instr, def, &f) {
+                        return None;
+                    }
+                    // +r opcodes are in the table once for every register
+                    if plus_reg && f.opcode_reg & 7 != opcode as u8 & 7 {
+                        return None;
+                    }
+                    // xchg rax, rax would come out as nop
+                    if instr.op == Op::Xchg && opcode == 0x90 && f.opcode_reg == 0 {
+                        return None;
+                    }
+                    Some(f)
+                });
+                let Some(f) = fits else { continue };
+                let bytes = encode_with(instr, def, map, opcode as u8, &f);
+                let better = match (&best, &bytes) {
+                    (None, _) | (Some(Err(_)), Ok(_)) => true,
+                    (Some(Ok(best)), Ok(bytes)) => bytes.len() < best.len(),
+                    _ => false,
+                };
+                if better {
+                    best = Some(bytes);
+                }
+            }
+        }
+    }
+    best.unwrap_or(Err(EncodeError::NoForm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, EncodeError};
+    use crate::decode::decode;
+    use crate::instr::{Instr, Operand, Size};
+    use crate::opcodes::Op;
+    use crate::prefix::Prefixes;
+    use crate::registers::{Reg, R64::*};
+
+    /// Encodings an assembler would pick have to come back out unchanged.
+    #[test]
+    fn canonical() {
+        let cases: [&[u8]; 70] = [
+            &[0x31, 0xc0],
+            &[0x48, 0x8b, 0x44, 0x24, 0x08],
+            &[0x41, 0xb0, 0x05],
+            &[0x40, 0x88, 0xf7],
+            &[0x88, 0xe0],
+            &[0x66, 0x89, 0x18],
+            &[0x81, 0xef, 0x00, 0x01, 0x00, 0x00],
+            &[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8],
+            &[0x8b, 0x05, 0x10, 0x00, 0x00, 0x00],
+            &[0x67, 0x8b, 0x03],
+            &[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00],
+            &[0x42, 0x8b, 0x44, 0xa5, 0x00],
+            &[0x41, 0x8b, 0x04, 0x24],
+            &[0x0f, 0x84, 0xfa, 0xff, 0xff, 0xff],
+            &[0x74, 0xfe],
+            &[0xeb, 0x10],
+            &[0xff, 0xd0],
+            &[0x41, 0xff, 0x64, 0x24, 0x08],
+            &[0xf3, 0x0f, 0x1e, 0xfa],
+            &[0x66, 0x0f, 0x38, 0x00, 0xc1],
+            &[0xc5, 0xf1, 0xef, 0xc2],
+            &[0xc4, 0xc1, 0x75, 0xef, 0xc2],
+            &[0x62, 0xf1, 0x7c, 0x48, 0x58, 0x40, 0x01],
+            &[0x62, 0xe1, 0x6c, 0xdd, 0x58, 0x48, 0x02],
+            &[0x62, 0xb1, 0x74, 0x38, 0x58, 0xc2],
+            &[0x6a, 0xfd],
+            &[0x66, 0x6a, 0x05],
+            &[0x66, 0x41, 0x5c],
+            &[0xff, 0x74, 0x24, 0x08],
+            &[0xc8, 0x20, 0x00, 0x01],
+            &[0x66, 0xc8, 0x20, 0x00, 0x01],
+            &[0x66, 0xc9],
+            &[0x66, 0x9c],
+            &[0xd1, 0xe0],
+            &[0x48, 0xd3, 0xf8],
+            &[0x0f, 0xa4, 0xc8, 0x04],
+            &[0x48, 0xf7, 0xf3],
+            &[0x48, 0x6b, 0x03, 0x05],
+            &[0x69, 0xc1, 0x45, 0x23, 0x01, 0x00],
+            &[0x48, 0xff, 0x09],
+            &[0xa9, 0x00, 0x01, 0x00, 0x00],
+            &[0xf6, 0xc3, 0x80],
+            &[0x4a, 0x8d, 0x44, 0x0b, 0xf8],
+            &[0x67, 0x8d, 0x3c, 0x45, 0x00, 0x00, 0x00, 0x00],
+            &[0x66, 0x98],
+            &[0x48, 0x0f, 0xbe, 0x04, 0x24],
+            &[0x4c, 0x63, 0xc0],
+            &[0xf3, 0x48, 0xab],
+            &[0xf2, 0xae],
+            &[0xf3, 0x67, 0xa4],
+            &[0x48, 0x0f, 0xab, 0x08],
+            &[0xf3, 0x0f, 0xbc, 0xc1],
+            &[0x0f, 0xbd, 0xc1],
+            &[0x91],
+            &[0x87, 0xc0],
+            &[0xf0, 0x0f, 0xb1, 0x0b],
+            &[0xf0, 0x48, 0x0f, 0xc7, 0x0f],
+            &[0x0f, 0x28, 0xc1],
+            &[0xf2, 0x0f, 0x10, 0xc1],
+            &[0xf2, 0x48, 0x0f, 0x2a, 0xc0],
+            &[0x66, 0x0f, 0x73, 0xd8, 0x04],
+            &[0x66, 0x0f, 0xfe, 0x08],
+            &[0xf3, 0x0f, 0x16, 0xc1],
+            &[0x66, 0x0f, 0x3a, 0x63, 0xca, 0x0c],
+            &[0x66, 0x0f, 0x3a, 0x0b, 0xc1, 0x09],
+            &[0xf2, 0x48, 0x0f, 0x38, 0xf1, 0xc1],
+            &[0xde, 0xc1],
+            &[0xdb, 0x28],
+            &[0xdf, 0xe0],
+            &[0xd9, 0x7d, 0xfe],
+        ];
+        for code in cases {
+            let (instr, _) = decode(code).unwrap();
+            assert_eq!(encode(&instr).as_deref(), Ok(code), "{instr}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut instr = Instr::new(Op::Mov, Size::Qword, Prefixes::default());
+        instr.operands[0] = Operand::Reg(Reg::R64(RAX));
+        instr.operands[1] = Operand::Reg(Reg::R64(RBX));
+        assert_eq!(encode(&instr), Ok(vec![0x48, 0x89, 0xd8]));
+        instr.operands[1] = Operand::Imm {
+            value: -1,
+            size: Size::Dword,
+        };
+        assert_eq!(
+            encode(&instr),
+            Ok(vec![0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff])
+        );
+
+        // no mov takes a branch target, a sub can't take a 64 bit immediate
+        instr.operands[1] = Operand::Rel {
+            offset: 0,
+            size: Size::Dword,
+        };
+        assert_eq!(encode(&instr), Err(EncodeError::NoForm));
+        instr.op = Op::Sub;
+        instr.operands[1] = Operand::Imm {
+            value: 1 << 40,
+            size: Size::Dword,
+        };
+        assert_eq!(encode(&instr), Err(EncodeError::NoForm));
+
+        let mut jmp = Instr::new(Op::Jmp, Size::Qword, Prefixes::default());
+        jmp.operands[0] = Operand::Rel {
+            offset: 1 << 40,
+            size: Size::Dword,
+        };
+        assert_eq!(encode(&jmp), Err(EncodeError::OutOfRange));
+    }
+
+    /// Whatever the decoder makes of random bytes has to encode to something that decodes
+    /// back to the same instruction, and isn't longer than what it came from.
+    #[test]
+    fn fuzz_round_trip() {
+        let mut seed = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let prefixes = [0x66, 0x67, 0xf2, 0xf3, 0x2e, 0x64, 0x41, 0x48, 0x4c, 0x40];
+        let escapes: [&[u8]; 7] = [
+            &[],
+            &[0x0f],
+            &[0x0f, 0x38],
+            &[0x0f, 0x3a],
+            &[0xc5],
+            &[0xc4],
+            &[0x62],
+        ];
+
+        let mut encoded = 0;
+        for _ in 0..300_000 {
+            let mut code = vec![];
+            for _ in 0..next() % 3 {
+                code.push(prefixes[next() as usize % prefixes.len()]);
+            }
+            let escape = escapes[next() as usize % escapes.len()];
+            code.extend_from_slice(escape);
+            match escape {
+                [0xc4] => code.extend([next() as u8 & 0xe0 | (next() % 3 + 1) as u8, next() as u8]),
+                [0x62] => code.extend([
+                    next() as u8 & 0xf0 | (next() % 3 + 1) as u8,
+                    next() as u8 | 0x04,
+                    next() as u8,
+                ]),
+                _ => {}
+            }
+            code.extend((0..16).map(|_| next() as u8));
+
+            let Ok((instr, len)) = decode(&code) else {
+                continue;
+            };
+            let bytes = match encode(&instr) {
+                Ok(bytes) => bytes,
+                // dropping a useless prefix moves the end of a short branch, and with it
+                // the base of its offset, so a target right at the edge can't be reached
+                Err(EncodeError::OutOfRange)
+                    if matches!(
+                        instr.operands[0],
+                        Operand::Rel {
+                            size: Size::Byte,
+                            ..
+                        }
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => panic!("{instr} {:02x?}: {e:?}", &code[..len]),
+            };
+            let again = decode(&bytes).map(|(x, len)| (x.to_string(), len));
+            assert_eq!(
+                again,
+                Ok((instr.to_string(), bytes.len())),
+                "{:02x?} became {bytes:02x?}",
+                &code[..len]
+            );
+            assert!(
+                bytes.len() <= len,
+                "{instr} {:02x?} became {bytes:02x?}",
+                &code[..len]
+            );
+            encoded += 1;
+        }
+        assert!(encoded > 10_000, "{encoded}");
+    }
+}
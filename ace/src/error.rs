@@ -0,0 +1,68 @@
+use std::fmt::Display;
+
+/// Why the guest stopped running without the emulator being at fault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// `hlt`, we use it for testing as it can never appear in userspace code
+    Halted,
+    /// The code returned to the address it was called from, like a function would.
+    Returned,
+    /// #DE, a division by zero or a quotient too big for its register. `ip` is left at the
+    /// instruction.
+    DivideError,
+    /// #UD, like a lock prefix on an instruction that can't have one. `ip` is left at the
+    /// instruction.
+    InvalidOpcode,
+    /// #GP, like a misaligned cmpxchg16b or movaps. `ip` is left at the instruction.
+    GeneralProtection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Something the emulator can't carry on from. `bytes` is the code at `address`, at most 15 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmulatorError {
+    UnimplementedOpcode {
+        address: u64,
+        bytes: Vec<u8>,
+    },
+    /// Decoded fine, but there's no executor for it yet.
+    UnimplementedInstruction {
+        address: u64,
+        instr: String,
+    },
+    InvalidEncoding {
+        address: u64,
+        bytes: Vec<u8>,
+    },
+    MemoryFault {
+        address: u64,
+        access: Access,
+    },
+}
+
+impl Display for EmulatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmulatorError::UnimplementedOpcode { address, bytes } => {
+                write!(f, "unimplemented opcode at {address:#x}: {bytes:02x?}")
+            }
+            EmulatorError::UnimplementedInstruction { address, instr } => {
+                write!(f, "unimplemented instruction at {address:#x}: {instr}")
+            }
+            EmulatorError::InvalidEncoding { address, bytes } => {
+                write!(f, "invalid encoding at {address:#x}: {bytes:02x?}")
+            }
+            EmulatorError::MemoryFault { address, access } => {
+                write!(f, "memory fault at {address:#x} on {access:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
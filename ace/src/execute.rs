@@ -0,0 +1,217 @@
+mod atomic;
+mod bits;
+mod muldiv;
+mod shift;
+mod sse;
+mod string;
+mod vector;
+mod x87;
+
+use crate::error::{EmulatorError, StopReason};
+use crate::instr::{Base, Cond, Instr, Mem, Operand, Size};
+use crate::opcodes::Op;
+use crate::prefix::Segment;
+use crate::registers::{Reg, R64::*};
+use crate::{DisasmWriter, Emulator};
+
+/// Sign extends the low `size` bytes of `value`.
+fn sign_extend(value: u64, size: Size) -> u64 {
+    let shift = 64 - size.bytes() * 8;
+    ((value << shift) as i64 >> shift) as u64
+}
+
+impl<D: DisasmWriter> Emulator<D> {
+    /// The address before the segment base is added, which is all lea computes.
+    fn offset(&self, mem: &Mem) -> u64 {
+        let mut addr = mem.disp as u64;
+        match mem.base {
+            Some(Base::Reg(base)) => addr = addr.wrapping_add(self.regs.get(base)),
+            Some(Base::Rip) => addr = addr.wrapping_add(self.instr_ip),
+            None => {}
+        }
+        if let Some(index) = mem.index {
+            addr = addr.wrapping_add(self.regs.get(index).wrapping_mul(mem.scale as u64));
+        }
+        addr & mem.addr_size.mask()
+    }
+
+    fn address(&self, mem: &Mem) -> u64 {
+        let addr = self.offset(mem);
+        match mem.segment {
+            Some(Segment::Fs) => addr.wrapping_add(self.regs.fs_base),
+            Some(Segment::Gs) => addr.wrapping_add(self.regs.gs_base),
+            _ => addr,
+        }
+    }
+
+    /// Whether a memory operand that has to be aligned isn't, which is a #GP.
+    pub fn misaligned(&self, instr: &Instr) -> bool {
+        instr
+            .alignment()
+            .is_some_and(|(mem, align)| !self.address(&mem).is_multiple_of(align))
+    }
+
+    /// lea doesn't touch memory, the destination register cuts the offset down to its size.
+    fn lea(&self, src: Operand) -> u64 {
+        let Operand::Mem(mem) = src else {
+            unreachable!("lea of {:?}", src)
+        };
+        self.offset(&mem)
+    }
+
+    /// Reads `operand` sign extended from its own size, for movsx and movsxd.
+    fn read_signed(&self, operand: Operand) -> Result<u64, EmulatorError> {
+        let size = match operand {
+            Operand::Reg(reg) => reg.size(),
+            Operand::Mem(mem) => mem.size,
+            _ => unreachable!("can't sign extend {:?}", operand),
+        };
+        Ok(sign_extend(self.read(operand)?, size))
+    }
+
+    /// cbw, cwde and cdqe, which sign extend the lower half of the accumulator into all of it.
+    fn widen_accumulator(&mut self, size: Size) {
+        let half = match size {
+            Size::Word => Size::Byte,
+            Size::Dword => Size::Word,
+            _ => Size::Dword,
+        };
+        let value = self.regs.get(Reg::new(half, 0, false));
+        self.regs
+            .set(Reg::new(size, 0, false), sign_extend(value, half));
+    }
+
+    /// cwd, cdq and cqo, which fill dx, edx or rdx with the sign of the accumulator.
+    fn sign_into_dx(&mut self, size: Size) {
+        let value = self.regs.get(Reg::new(size, 0, false));
+        let sign = (sign_extend(value, size) as i64 >> 63) as u64;
+        self.regs.set(Reg::new(size, 2, false), sign);
+    }
+
+    fn read(&self, operand: Operand) -> Result<u64, EmulatorError> {
+        let value = match operand {
+            Operand::Reg(reg) => self.regs.get(reg),
+            Operand::Mem(mem) => self.memory.read(self.address(&mem), mem.size)?,
+            Operand::Imm { value, .. } => value as u64,
+            Operand::Mask(k) => self.regs.k[k.0 as usize],
+            Operand::None | Operand::Vec(_) | Operand::St(_) | Operand::Rel { .. } => {
+                unreachable!("can't read {:?}", operand)
+            }
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, operand: Operand, value: u64) -> Result<(), EmulatorError> {
+        match operand {
+            Operand::Reg(reg) => self.regs.set(reg, value),
+            Operand::Mem(mem) => self.memory.write(self.address(&mem), mem.size, value)?,
+            Operand::Mask(k) => self.regs.k[k.0 as usize] = value,
+            _ => unreachable!("can't write {:?}", operand),
+        }
+        Ok(())
+    }
+
+    /// Where a branch goes, relative to the instruction or an address in a register or memory.
+    fn target(&self, target: Operand) -> Result<u64, EmulatorError> {
+        match target {
+            Operand::Rel { offset, .. } => Ok(self.instr_ip.wrapping_add(offset as u64)),
+            _ => self.read(target),
+        }
+    }
+
+    fn jump(&mut self, target: Operand) -> Result<(), EmulatorError> {
+        self.ip = self.target(target)?;
+        Ok(())
+    }
+
+    /// The target is read before the return address is pushed, `call [rsp]` goes where rsp
+    /// pointed before.
+    fn call(&mut self, target: Operand) -> Result<(), EmulatorError> {
+        let target = self.target(target)?;
+        self.push(self.ip, Size::Qword)?;
+        self.ip = target;
+        Ok(())
+    }
+
+    /// Pushes the low `size` bytes of `value`, rsp only moves if the write worked.
+    fn push(&mut self, value: u64, size: Size) -> Result<(), EmulatorError> {
+        let rsp = self.regs[RSP].r64().wrapping_sub(size.bytes() as u64);
+        self.memory.write(rsp, size, value)?;
+        self.regs[RSP].set_r64(rsp);
+        Ok(())
+    }
+
+    fn pop(&mut self, size: Size) -> Result<u64, EmulatorError> {
+        let rsp = self.regs[RSP].r64();
+        let value = self.memory.read(rsp, size)?;
+        self.regs[RSP].set_r64(rsp.wrapping_add(size.bytes() as u64));
+        Ok(value)
+    }
+
+    /// `ret` and `ret imm16`, which also drops imm16 bytes of arguments.
+    fn ret(&mut self, release: Operand) -> Result<(), EmulatorError> {
+        self.ip = self.pop(Size::Qword)?;
+        if let Operand::Imm { value, .. } = release {
+            let rsp = self.regs[RSP].r64().wrapping_add(value as u16 as u64);
+            self.regs[RSP].set_r64(rsp);
+        }
+        Ok(())
+    }
+
+    /// Makes a stack frame of `bytes` bytes, copying `level - 1` frame pointers from the
+    /// enclosing frames for languages with nested functions. With a 16 bit `size` the frame
+    /// pointers are 16 bits and only bp changes.
+    fn enter(&mut self, bytes: u16, level: u8, size: Size) -> Result<(), EmulatorError> {
+        let level = level % 32;
+        let bp = Reg::new(size, RBP as u8, false);
+        self.push(self.regs.get(bp), size)?;
+        let frame = self.regs[RSP].r64();
+        if level > 0 {
+            let mut rbp = self.regs[RBP].r64();
+            for _ in 1..level {
+                rbp = rbp.wrapping_sub(size.bytes() as u64);
+                let outer = self.memory.read(rbp, size)?;
+                self.push(outer, size)?;
+            }
+            self.push(frame, size)?;
+        }
+        let rsp = self.regs[RSP].r64().wrapping_sub(bytes as u64);
+        self.regs.set(bp, frame);
+        self.regs[RSP].set_r64(rsp);
+        Ok(())
+    }
+
+    /// Pops the frame pointer from where rbp points, rsp only moves if the read worked.
+    fn leave(&mut self, size: Size) -> Result<(), EmulatorError> {
+        let frame = self.regs[RBP].r64();
+        let rbp = self.memory.read(frame, size)?;
+        self.regs[RSP].set_r64(frame.wrapping_add(size.bytes() as u64));
+        self.regs.set(Reg::new(size, RBP as u8, false), rbp);
+        Ok(())
+    }
+
+    fn condition(&self, cond: Cond) -> bool {
+        let f = &self.regs.flags;
+        match cond {
+            Cond::O => f.of,
+            Cond::No => !f.of,
+            Cond::B => f.cf,
+            Cond::Ae => !f.cf,
+            Cond::E => f.zf,
+            Cond::Ne => !f.zf,
+            Cond::Be => f.cf || f.zf,
+            Cond::A => !f.cf && !f.zf,
+            Cond::S => f.sf,
+            Cond::Ns => !f.sf,
+            Cond::P => f.pf,
+            Cond::Np => !f.pf,
+            Cond::L => f.sf != f.of,
+            Cond::Ge => f.sf == f.of,
+            Cond::Le => f.zf || f.sf != f.of,
+            Cond::G => !f.zf && f.sf == f.of,
+        }
+    }
+}
+
+// `execute`, generated from the semantics in instructions.txt
+include!(concat!(env!("OUT_DIR"), "/execute.rs"));
@@ -0,0 +1,97 @@
+//! xchg, xadd, cmpxchg, cmpxchg8b and cmpxchg16b, what locks and reference counts are built on.
+//! Each one runs within a single step, so they stay atomic as long as guest threads only take
+//! turns between instructions.
+
+use crate::error::{EmulatorError, StopReason};
+use crate::instr::{Instr, Operand, Size};
+use crate::opcodes::Op;
+use crate::registers::Reg;
+use crate::{DisasmWriter, Emulator};
+
+impl<D: DisasmWriter> Emulator<D> {
+    /// Memory is written before the register, which can be part of its address like in
+    /// `xchg [rbx], bl`.
+    pub(super) fn exchange(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let [dst, src, ..] = instr.operands;
+        let (a, b) = (self.read(dst)?, self.read(src)?);
+        self.write(dst, b)?;
+        self.write(src, a)
+    }
+
+    /// The sum goes to the destination and its old value to the source. A memory destination
+    /// is written first, like for xchg, a register one last so `xadd eax, eax` ends up with the
+    /// sum.
+    pub(super) fn exchange_add(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let [dst, src, ..] = instr.operands;
+        let (a, b) = (self.read(dst)?, self.read(src)?);
+        let result = a.wrapping_add(b);
+        self.regs.flags.add(a, b, result, instr.size);
+        if let Operand::Mem(_) = dst {
+            self.write(dst, result)?;
+            self.write(src, a)
+        } else {
+            self.write(src, a)?;
+            self.write(dst, result)
+        }
+    }
+
+    /// Compares the accumulator with the destination like cmp does. If they're equal the source
+    /// is stored, otherwise the accumulator gets the destination. Memory is written either way,
+    /// before the accumulator that can be part of its address.
+    pub(super) fn compare_exchange(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let [dst, src, ..] = instr.operands;
+        let acc = Reg::new(instr.size, 0, false);
+        let expected = self.regs.get(acc);
+        let value = self.read(dst)?;
+        let result = expected.wrapping_sub(value);
+        self.regs.flags.sub(expected, value, result, instr.size);
+        if self.regs.flags.zf {
+            self.write(dst, self.read(src)?)
+        } else {
+            if let Operand::Mem(_) = dst {
+                self.write(dst, value)?;
+            }
+            self.regs.set(acc, value);
+            Ok(())
+        }
+    }
+
+    /// cmpxchg8b and cmpxchg16b compare edx:eax or rdx:rax with memory and store ecx:ebx or
+    /// rcx:rbx if they match, ZF says whether they did. cmpxchg16b needs 16 byte alignment and
+    /// stops the guest with `GeneralProtection` without it.
+    pub(super) fn compare_exchange_pair(
+        &mut self,
+        instr: &Instr,
+    ) -> Result<Option<StopReason>, EmulatorError> {
+        let Operand::Mem(mem) = instr.operands[0] else {
+            unreachable!("{} without memory", instr.op)
+        };
+        let half = if instr.op == Op::Cmpxchg16b {
+            Size::Qword
+        } else {
+            Size::Dword
+        };
+        let low = self.address(&mem);
+        let high = low.wrapping_add(half.bytes() as u64);
+        if instr.op == Op::Cmpxchg16b && !low.is_multiple_of(16) {
+            self.ip = self.instr_ip;
+            return Ok(Some(StopReason::GeneralProtection));
+        }
+
+        let reg = |x| Reg::new(half, x, false);
+        let value = [self.memory.read(low, half)?, self.memory.read(high, half)?];
+        let expected = [self.regs.get(reg(0)), self.regs.get(reg(2))];
+        let equal = value == expected;
+        let stored = if equal {
+            [self.regs.get(reg(3)), self.regs.get(reg(1))]
+        } else {
+            self.regs.set(reg(0), value[0]);
+            self.regs.set(reg(2), value[1]);
+            value
+        };
+        self.memory.write(low, half, stored[0])?;
+        self.memory.write(high, half, stored[1])?;
+        self.regs.flags.zf = equal;
+        Ok(None)
+    }
+}
@@ -0,0 +1,108 @@
+//! Bit tests, scans and counts, crc32 and the single flag adds.
+
+use super::sign_extend;
+use crate::error::EmulatorError;
+use crate::instr::{Instr, Operand, Size};
+use crate::opcodes::Op;
+use crate::{DisasmWriter, Emulator};
+
+impl<D: DisasmWriter> Emulator<D> {
+    /// bt, bts, btr and btc. A register offset into a memory operand is signed and can reach
+    /// past the operand, into the bit string around it. An immediate offset and one into a
+    /// register wrap around the operand size. CF gets the old bit, the other flags are undefined
+    /// and left alone.
+    pub(super) fn bit_test(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let size = instr.size;
+        let bits = size.bytes() as i64 * 8;
+        let offset = self.read(instr.operands[1])?;
+        let (dst, bit) = match instr.operands {
+            [Operand::Mem(mut mem), Operand::Reg(_), ..] => {
+                let offset = sign_extend(offset, size) as i64;
+                let skip = offset.div_euclid(bits) * size.bytes() as i64;
+                mem.disp = mem.disp.wrapping_add(skip);
+                (Operand::Mem(mem), offset.rem_euclid(bits))
+            }
+            [dst, ..] => (dst, offset as i64 & (bits - 1)),
+        };
+
+        let value = self.read(dst)?;
+        let mask = 1 << bit;
+        self.regs.flags.cf = value & mask != 0;
+        let result = match instr.op {
+            Op::Bts => value | mask,
+            Op::Btr => value & !mask,
+            Op::Btc => value ^ mask,
+            _ => return Ok(()),
+        };
+        self.write(dst, result)
+    }
+
+    /// bsf and bsr. ZF says whether the source is 0, then the destination isn't written at all,
+    /// not even to zero the upper half of a 64 bit register. The other flags are undefined and
+    /// left alone.
+    pub(super) fn bit_scan(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let value = self.read(instr.operands[1])? & instr.size.mask();
+        self.regs.flags.zf = value == 0;
+        if value == 0 {
+            return Ok(());
+        }
+        let index = if instr.op == Op::Bsf {
+            value.trailing_zeros()
+        } else {
+            63 - value.leading_zeros()
+        };
+        self.write(instr.operands[0], index as u64)
+    }
+
+    /// popcnt, lzcnt and tzcnt. popcnt clears all the flags but ZF, which says whether the
+    /// source is 0. lzcnt and tzcnt set CF for a source of 0 and ZF for a result of 0, the other
+    /// flags are undefined and left alone.
+    pub(super) fn count_bits(&mut self, op: Op, value: u64, size: Size) -> u64 {
+        let bits = size.bytes() as u32 * 8;
+        let value = value & size.mask();
+        let f = &mut self.regs.flags;
+        if op == Op::Popcnt {
+            (f.cf, f.pf, f.af, f.sf, f.of) = (false, false, false, false, false);
+            f.zf = value == 0;
+            return value.count_ones() as u64;
+        }
+        let result = if op == Op::Lzcnt {
+            value.leading_zeros() - (64 - bits)
+        } else {
+            value.trailing_zeros().min(bits)
+        };
+        f.cf = value == 0;
+        f.zf = result == 0;
+        result as u64
+    }
+
+    /// adcx and adox, an add with carry through CF or OF alone. The other flags are left alone.
+    pub(super) fn add_carry(&mut self, op: Op, a: u64, b: u64, size: Size) -> u64 {
+        let f = &mut self.regs.flags;
+        let carry = if op == Op::Adcx { &mut f.cf } else { &mut f.of };
+        let mask = size.mask();
+        let wide = (a & mask) as u128 + (b & mask) as u128 + *carry as u128;
+        *carry = wide > mask as u128;
+        wide as u64 & mask
+    }
+
+    /// crc32 adds the bytes of the source to the crc in the destination, without the inversions
+    /// before and after that software does. It's crc-32c, the polynomial iscsi uses rather than
+    /// the one zlib does. The flags are left alone.
+    pub(super) fn crc32(&self, crc: u64, src: Operand) -> Result<u64, EmulatorError> {
+        let size = match src {
+            Operand::Reg(reg) => reg.size(),
+            Operand::Mem(mem) => mem.size,
+            _ => unreachable!("crc32 of {:?}", src),
+        };
+        let data = self.read(src)?;
+        let mut crc = crc as u32;
+        for byte in data.to_le_bytes().into_iter().take(size.bytes()) {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+            }
+        }
+        Ok(crc as u64)
+    }
+}
@@ -0,0 +1,107 @@
+//! Multiplies and divides. The one operand forms work on ax for bytes and on dx:ax, edx:eax
+//! or rdx:rax for everything else.
+
+use crate::error::{EmulatorError, StopReason};
+use crate::instr::{Instr, Operand, Size};
+use crate::opcodes::Op;
+use crate::registers::Reg;
+use crate::{DisasmWriter, Emulator};
+
+/// Sign extends the low `bits` of `x`.
+fn sext(x: u128, bits: u32) -> i128 {
+    let shift = 128 - bits;
+    (x << shift) as i128 >> shift
+}
+
+impl<D: DisasmWriter> Emulator<D> {
+    /// ax, or dx:ax and friends, as one number.
+    fn read_pair(&self, size: Size) -> u128 {
+        let bits = size.bytes() as u32 * 8;
+        if size == Size::Byte {
+            return self.regs.get(Reg::new(Size::Word, 0, false)) as u128;
+        }
+        let low = self.regs.get(Reg::new(size, 0, false));
+        let high = self.regs.get(Reg::new(size, 2, false));
+        (high as u128) << bits | low as u128
+    }
+
+    /// Writes `low` to al/ax/eax/rax and `high` to ah/dx/edx/rdx.
+    fn write_pair(&mut self, size: Size, low: u64, high: u64) {
+        let high_reg = if size == Size::Byte { 4 } else { 2 };
+        self.regs.set(Reg::new(size, 0, false), low);
+        self.regs.set(Reg::new(size, high_reg, false), high);
+    }
+
+    /// mul and all three forms of imul. CF and OF say whether the result needed the upper half,
+    /// the other flags are undefined and left alone.
+    pub(super) fn multiply(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let size = instr.size;
+        let bits = size.bytes() as u32 * 8;
+        let signed = instr.op == Op::Imul;
+        let extend = |x: u64| {
+            let x = (x & size.mask()) as u128;
+            if signed {
+                sext(x, bits) as u128
+            } else {
+                x
+            }
+        };
+
+        let (x, y) = match instr.operands {
+            [src, Operand::None, ..] => (self.regs.get(Reg::new(size, 0, false)), self.read(src)?),
+            [dst, src, Operand::None, _] => (self.read(dst)?, self.read(src)?),
+            [_, src, imm, _] => (self.read(src)?, self.read(imm)?),
+        };
+        // the low 128 bits of the product are the same for signed and unsigned numbers
+        let product = extend(x).wrapping_mul(extend(y));
+        let low = product as u64 & size.mask();
+        let overflow = if signed {
+            sext(product, 2 * bits) != sext(low as u128, bits)
+        } else {
+            product >> bits != 0
+        };
+
+        if matches!(instr.operands[1], Operand::None) {
+            let high = (product >> bits) as u64 & size.mask();
+            self.write_pair(size, low, high);
+        } else {
+            self.write(instr.operands[0], low)?;
+        }
+        self.regs.flags.cf = overflow;
+        self.regs.flags.of = overflow;
+        Ok(())
+    }
+
+    /// div and idiv. Dividing by zero or a quotient that doesn't fit stops the guest with
+    /// `DivideError` at the instruction, like the #DE fault would. The flags are undefined and
+    /// left alone.
+    pub(super) fn divide(&mut self, instr: &Instr) -> Result<Option<StopReason>, EmulatorError> {
+        let size = instr.size;
+        let bits = size.bytes() as u32 * 8;
+        let dividend = self.read_pair(size);
+        let divisor = self.read(instr.operands[0])? & size.mask();
+
+        let result = if instr.op == Op::Idiv {
+            let dividend = sext(dividend, 2 * bits);
+            let divisor = sext(divisor as u128, bits);
+            dividend
+                .checked_div(divisor)
+                .zip(dividend.checked_rem(divisor))
+                .filter(|&(q, _)| sext(q as u128, bits) == q)
+                .map(|(q, r)| (q as u64, r as u64))
+        } else {
+            let divisor = divisor as u128;
+            dividend
+                .checked_div(divisor)
+                .zip(dividend.checked_rem(divisor))
+                .filter(|&(q, _)| q >> bits == 0)
+                .map(|(q, r)| (q as u64, r as u64))
+        };
+        let Some((quotient, remainder)) = result else {
+            self.ip = self.instr_ip;
+            return Ok(Some(StopReason::DivideError));
+        };
+        self.write_pair(size, quotient & size.mask(), remainder & size.mask());
+        Ok(None)
+    }
+}
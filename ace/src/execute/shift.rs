@@ -0,0 +1,121 @@
+//! Shifts and rotates. A count of 0 leaves the flags alone and OF is only defined for a count
+//! of 1, where it says whether the sign changed. We set it from the same formula for any count.
+
+use crate::instr::Size;
+use crate::opcodes::Op;
+use crate::{DisasmWriter, Emulator};
+
+/// The count is taken mod 64 for 64 bit operands and mod 32 for everything else.
+fn mask_count(count: u64, size: Size) -> u32 {
+    let mask = if size == Size::Qword { 0x3f } else { 0x1f };
+    (count & mask) as u32
+}
+
+impl<D: DisasmWriter> Emulator<D> {
+    /// rol, ror, rcl, rcr, shl, shr and sar of the low `size` bytes of `value`.
+    pub(super) fn shift(&mut self, op: Op, value: u64, count: u64, size: Size) -> u64 {
+        let count = mask_count(count, size);
+        if count == 0 {
+            return value;
+        }
+        let bits = size.bytes() as u32 * 8;
+        let value = value & size.mask();
+        let top = |x: u64| x >> (bits - 1) & 1 != 0;
+        let f = &mut self.regs.flags;
+
+        match op {
+            // rotates only touch CF and OF
+            Op::Rol | Op::Ror | Op::Rcl | Op::Rcr => {
+                // rcl and rcr rotate through CF, like a bits + 1 wide rotate
+                let (x, width) = match op {
+                    Op::Rcl | Op::Rcr => (value as u128 | (f.cf as u128) << bits, bits + 1),
+                    _ => (value as u128, bits),
+                };
+                let n = count % width;
+                let n = if matches!(op, Op::Rol | Op::Rcl) {
+                    n
+                } else {
+                    width - n
+                };
+                let x = (x << n | x >> (width - n)) & ((1 << width) - 1);
+                let result = x as u64 & size.mask();
+                match op {
+                    Op::Rol => f.cf = result & 1 != 0,
+                    Op::Ror => f.cf = top(result),
+                    _ => f.cf = x >> bits & 1 != 0,
+                }
+                f.of = match op {
+                    Op::Rol | Op::Rcl => top(result) != f.cf,
+                    // the top two bits differ
+                    _ => top(result) != top(result << 1),
+                };
+                result
+            }
+            Op::Shl => {
+                let x = (value as u128) << count;
+                let result = x as u64 & size.mask();
+                f.cf = x >> bits & 1 != 0;
+                f.of = top(result) != f.cf;
+                f.af = false;
+                f.set_result(result, size);
+                result
+            }
+            Op::Shr | Op::Sar => {
+                let x = if op == Op::Sar {
+                    let shift = 64 - bits;
+                    ((value << shift) as i64 >> shift) as i128 as u128
+                } else {
+                    value as u128
+                };
+                let result = (x >> count) as u64 & size.mask();
+                f.cf = x >> (count - 1) & 1 != 0;
+                f.of = op == Op::Shr && top(value);
+                f.af = false;
+                f.set_result(result, size);
+                result
+            }
+            _ => unreachable!("{op} isn't a shift"),
+        }
+    }
+
+    /// shld and shrd, which shift `value` and fill it up with bits from `fill`. A count larger
+    /// than a 16 bit operand has an undefined result, we shift in all of `fill` and then
+    /// `value` again.
+    pub(super) fn shift_double(
+        &mut self,
+        op: Op,
+        value: u64,
+        fill: u64,
+        count: u64,
+        size: Size,
+    ) -> u64 {
+        let count = mask_count(count, size);
+        if count == 0 {
+            return value;
+        }
+        let bits = size.bytes() as u32 * 8;
+        let original = value & size.mask();
+        let (mut value, mut fill, mut count) = (original, fill & size.mask(), count);
+        if count > bits {
+            (value, fill) = (fill, value);
+            count -= bits;
+        }
+        let top = |x: u64| x >> (bits - 1) & 1 != 0;
+        let f = &mut self.regs.flags;
+
+        let result = if op == Op::Shld {
+            let x = (value as u128) << bits | fill as u128;
+            f.cf = value >> (bits - count) & 1 != 0;
+            ((x << count) >> bits) as u64
+        } else {
+            let x = (fill as u128) << bits | value as u128;
+            f.cf = value >> (count - 1) & 1 != 0;
+            (x >> count) as u64
+        };
+        let result = result & size.mask();
+        f.of = top(result) != top(original);
+        f.af = false;
+        f.set_result(result, size);
+        result
+    }
+}
@@ -0,0 +1,342 @@
+//! Legacy sse instructions, up to sse4.2. They run as their vex forms, which take the destination
+//! as a separate first source, but only write the low 128 bits of a register and leave the rest
+//! of it alone.
+
+use crate::error::EmulatorError;
+use crate::instr::{Instr, Operand};
+use crate::opcodes::Op;
+use crate::{DisasmWriter, Emulator};
+
+/// The vex instruction a legacy one runs as. The ones without a vex form here run under their
+/// own name.
+fn vex_form(op: Op) -> Option<Op> {
+    let vex = match op {
+        Op::Movups => Op::Vmovups,
+        Op::Movupd => Op::Vmovupd,
+        Op::Movaps | Op::Movntps => Op::Vmovaps,
+        Op::Movapd | Op::Movntpd => Op::Vmovapd,
+        Op::Movdqa | Op::Movntdq => Op::Vmovdqa,
+        Op::Movdqu | Op::Lddqu => Op::Vmovdqu,
+        Op::Movntdqa => Op::Vmovdqa,
+        Op::Movss => Op::Vmovss,
+        Op::Movsd => Op::Vmovsd,
+        Op::Movd => Op::Vmovd,
+        Op::Movq => Op::Vmovq,
+        Op::Pmovmskb => Op::Vpmovmskb,
+        Op::Ptest => Op::Vptest,
+
+        Op::Addps => Op::Vaddps,
+        Op::Addpd => Op::Vaddpd,
+        Op::Addss => Op::Vaddss,
+        Op::Addsd => Op::Vaddsd,
+        Op::Subps => Op::Vsubps,
+        Op::Subpd => Op::Vsubpd,
+        Op::Subss => Op::Vsubss,
+        Op::Subsd => Op::Vsubsd,
+        Op::Mulps => Op::Vmulps,
+        Op::Mulpd => Op::Vmulpd,
+        Op::Mulss => Op::Vmulss,
+        Op::Mulsd => Op::Vmulsd,
+        Op::Divps => Op::Vdivps,
+        Op::Divpd => Op::Vdivpd,
+        Op::Divss => Op::Vdivss,
+        Op::Divsd => Op::Vdivsd,
+        Op::Minps => Op::Vminps,
+        Op::Minpd => Op::Vminpd,
+        Op::Minss => Op::Vminss,
+        Op::Minsd => Op::Vminsd,
+        Op::Maxps => Op::Vmaxps,
+        Op::Maxpd => Op::Vmaxpd,
+        Op::Maxss => Op::Vmaxss,
+        Op::Maxsd => Op::Vmaxsd,
+        Op::Sqrtps => Op::Vsqrtps,
+        Op::Sqrtpd => Op::Vsqrtpd,
+        Op::Sqrtss => Op::Vsqrtss,
+        Op::Sqrtsd => Op::Vsqrtsd,
+        Op::Andps => Op::Vandps,
+        Op::Andpd => Op::Vandpd,
+        Op::Andnps => Op::Vandnps,
+        Op::Andnpd => Op::Vandnpd,
+        Op::Orps => Op::Vorps,
+        Op::Orpd => Op::Vorpd,
+        Op::Xorps => Op::Vxorps,
+        Op::Xorpd => Op::Vxorpd,
+        Op::Cvtdq2ps => Op::Vcvtdq2ps,
+        Op::Cvttps2dq => Op::Vcvttps2dq,
+        Op::Blendvps => Op::Vblendvps,
+        Op::Blendvpd => Op::Vblendvpd,
+        // blending dwords is the same as blending floats
+        Op::Blendps => Op::Vpblendd,
+        // unpacking floats only moves their bits around
+        Op::Unpcklps => Op::Vpunpckldq,
+        Op::Unpckhps => Op::Vpunpckhdq,
+        Op::Unpcklpd => Op::Vpunpcklqdq,
+        Op::Unpckhpd => Op::Vpunpckhqdq,
+
+        Op::Paddb => Op::Vpaddb,
+        Op::Paddw => Op::Vpaddw,
+        Op::Paddd => Op::Vpaddd,
+        Op::Paddq => Op::Vpaddq,
+        Op::Psubb => Op::Vpsubb,
+        Op::Psubw => Op::Vpsubw,
+        Op::Psubd => Op::Vpsubd,
+        Op::Psubq => Op::Vpsubq,
+        Op::Pmullw => Op::Vpmullw,
+        Op::Pmulld => Op::Vpmulld,
+        Op::Pand => Op::Vpand,
+        Op::Pandn => Op::Vpandn,
+        Op::Por => Op::Vpor,
+        Op::Pxor => Op::Vpxor,
+        Op::Pcmpeqb => Op::Vpcmpeqb,
+        Op::Pcmpeqw => Op::Vpcmpeqw,
+        Op::Pcmpeqd => Op::Vpcmpeqd,
+        Op::Pcmpgtb => Op::Vpcmpgtb,
+        Op::Pcmpgtw => Op::Vpcmpgtw,
+        Op::Pcmpgtd => Op::Vpcmpgtd,
+        Op::Pcmpeqq => Op::Vpcmpeqq,
+        Op::Pcmpgtq => Op::Vpcmpgtq,
+        Op::Pminub => Op::Vpminub,
+        Op::Pminuw => Op::Vpminuw,
+        Op::Pminud => Op::Vpminud,
+        Op::Pminsb => Op::Vpminsb,
+        Op::Pminsw => Op::Vpminsw,
+        Op::Pminsd => Op::Vpminsd,
+        Op::Pmaxub => Op::Vpmaxub,
+        Op::Pmaxuw => Op::Vpmaxuw,
+        Op::Pmaxud => Op::Vpmaxud,
+        Op::Pmaxsb => Op::Vpmaxsb,
+        Op::Pmaxsw => Op::Vpmaxsw,
+        Op::Pmaxsd => Op::Vpmaxsd,
+        Op::Punpcklbw => Op::Vpunpcklbw,
+        Op::Punpcklwd => Op::Vpunpcklwd,
+        Op::Punpckldq => Op::Vpunpckldq,
+        Op::Punpcklqdq => Op::Vpunpcklqdq,
+        Op::Punpckhbw => Op::Vpunpckhbw,
+        Op::Punpckhwd => Op::Vpunpckhwd,
+        Op::Punpckhdq => Op::Vpunpckhdq,
+        Op::Punpckhqdq => Op::Vpunpckhqdq,
+        Op::Pshufd => Op::Vpshufd,
+        Op::Pshufb => Op::Vpshufb,
+        Op::Pblendvb => Op::Vpblendvb,
+        Op::Psllw => Op::Vpsllw,
+        Op::Pslld => Op::Vpslld,
+        Op::Psllq => Op::Vpsllq,
+        Op::Psrlw => Op::Vpsrlw,
+        Op::Psrld => Op::Vpsrld,
+        Op::Psrlq => Op::Vpsrlq,
+        Op::Psraw => Op::Vpsraw,
+        Op::Psrad => Op::Vpsrad,
+        Op::Pslldq => Op::Vpslldq,
+        Op::Psrldq => Op::Vpsrldq,
+
+        Op::Movlps
+        | Op::Movlpd
+        | Op::Movhps
+        | Op::Movhpd
+        | Op::Movhlps
+        | Op::Movlhps
+        | Op::Movmskps
+        | Op::Movmskpd
+        | Op::Cmpps
+        | Op::Cmppd
+        | Op::Cmpss
+        | Op::Cmpsd
+        | Op::Comiss
+        | Op::Comisd
+        | Op::Ucomiss
+        | Op::Ucomisd
+        | Op::Shufps
+        | Op::Shufpd
+        | Op::Cvtsi2ss
+        | Op::Cvtsi2sd
+        | Op::Cvtss2si
+        | Op::Cvtsd2si
+        | Op::Cvttss2si
+        | Op::Cvttsd2si
+        | Op::Cvtss2sd
+        | Op::Cvtsd2ss
+        | Op::Cvtps2pd
+        | Op::Cvtpd2ps
+        | Op::Cvtps2dq
+        | Op::Cvtpd2dq
+        | Op::Cvttpd2dq
+        | Op::Cvtdq2pd
+        | Op::Paddsb
+        | Op::Paddsw
+        | Op::Paddusb
+        | Op::Paddusw
+        | Op::Psubsb
+        | Op::Psubsw
+        | Op::Psubusb
+        | Op::Psubusw
+        | Op::Pavgb
+        | Op::Pavgw
+        | Op::Pmulhw
+        | Op::Pmulhuw
+        | Op::Pmuludq
+        | Op::Pmaddwd
+        | Op::Psadbw
+        | Op::Packsswb
+        | Op::Packssdw
+        | Op::Packuswb
+        | Op::Pshuflw
+        | Op::Pshufhw
+        | Op::Pinsrw
+        | Op::Pextrw
+        | Op::Addsubps
+        | Op::Addsubpd
+        | Op::Haddps
+        | Op::Haddpd
+        | Op::Hsubps
+        | Op::Hsubpd
+        | Op::Movddup
+        | Op::Movsldup
+        | Op::Movshdup
+        | Op::Phaddw
+        | Op::Phaddd
+        | Op::Phaddsw
+        | Op::Phsubw
+        | Op::Phsubd
+        | Op::Phsubsw
+        | Op::Pmaddubsw
+        | Op::Pmulhrsw
+        | Op::Psignb
+        | Op::Psignw
+        | Op::Psignd
+        | Op::Pabsb
+        | Op::Pabsw
+        | Op::Pabsd
+        | Op::Palignr
+        | Op::Pmovsxbw
+        | Op::Pmovsxbd
+        | Op::Pmovsxbq
+        | Op::Pmovsxwd
+        | Op::Pmovsxwq
+        | Op::Pmovsxdq
+        | Op::Pmovzxbw
+        | Op::Pmovzxbd
+        | Op::Pmovzxbq
+        | Op::Pmovzxwd
+        | Op::Pmovzxwq
+        | Op::Pmovzxdq
+        | Op::Pmuldq
+        | Op::Packusdw
+        | Op::Phminposuw
+        | Op::Roundps
+        | Op::Roundpd
+        | Op::Roundss
+        | Op::Roundsd
+        | Op::Blendpd
+        | Op::Pblendw
+        | Op::Pextrb
+        | Op::Pextrd
+        | Op::Pextrq
+        | Op::Extractps
+        | Op::Pinsrb
+        | Op::Pinsrd
+        | Op::Pinsrq
+        | Op::Insertps
+        | Op::Dpps
+        | Op::Dppd
+        | Op::Mpsadbw
+        | Op::Pcmpestri
+        | Op::Pcmpestrm
+        | Op::Pcmpistri
+        | Op::Pcmpistrm => op,
+        _ => return None,
+    };
+    Some(vex)
+}
+
+/// Whether the destination register is also the first source, which the vex form takes as an
+/// operand of its own.
+fn reads_destination(instr: &Instr) -> bool {
+    let [dst, src, ..] = instr.operands;
+    if !matches!(dst, Operand::Vec(_)) {
+        return false;
+    }
+    match instr.op {
+        // the register forms merge into the destination, the loads zero the rest of it
+        Op::Movss | Op::Movsd => matches!(src, Operand::Vec(_)),
+        Op::Movups
+        | Op::Movupd
+        | Op::Movaps
+        | Op::Movapd
+        | Op::Movdqa
+        | Op::Movdqu
+        | Op::Lddqu
+        | Op::Movntdqa
+        | Op::Movd
+        | Op::Movq
+        | Op::Movddup
+        | Op::Movsldup
+        | Op::Movshdup
+        | Op::Sqrtps
+        | Op::Sqrtpd
+        | Op::Roundps
+        | Op::Roundpd
+        | Op::Comiss
+        | Op::Comisd
+        | Op::Ucomiss
+        | Op::Ucomisd
+        | Op::Ptest
+        | Op::Pcmpestri
+        | Op::Pcmpestrm
+        | Op::Pcmpistri
+        | Op::Pcmpistrm
+        | Op::Cvtdq2ps
+        | Op::Cvtps2dq
+        | Op::Cvttps2dq
+        | Op::Cvtps2pd
+        | Op::Cvtpd2ps
+        | Op::Cvtpd2dq
+        | Op::Cvttpd2dq
+        | Op::Cvtdq2pd
+        | Op::Pshufd
+        | Op::Pshuflw
+        | Op::Pshufhw
+        | Op::Pabsb
+        | Op::Pabsw
+        | Op::Pabsd
+        | Op::Pmovsxbw
+        | Op::Pmovsxbd
+        | Op::Pmovsxbq
+        | Op::Pmovsxwd
+        | Op::Pmovsxwq
+        | Op::Pmovsxdq
+        | Op::Pmovzxbw
+        | Op::Pmovzxbd
+        | Op::Pmovzxbq
+        | Op::Pmovzxwd
+        | Op::Pmovzxwq
+        | Op::Pmovzxdq
+        | Op::Phminposuw => false,
+        _ => true,
+    }
+}
+
+impl<D: DisasmWriter> Emulator<D> {
+    pub(super) fn execute_sse(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let Some(op) = vex_form(instr.op) else {
+            return Err(EmulatorError::UnimplementedInstruction {
+                address: self.instr_ip,
+                instr: instr.to_string(),
+            });
+        };
+        let mut vex = Instr { op, ..*instr };
+        if reads_destination(instr) {
+            let [dst, src, imm, _] = instr.operands;
+            vex.operands = [dst, dst, src, imm];
+        }
+
+        let upper = match instr.operands[0] {
+            Operand::Vec(reg) => Some((reg.index as usize, self.regs.zmm[reg.index as usize])),
+            _ => None,
+        };
+        self.execute_vex(&vex)?;
+        if let Some((index, old)) = upper {
+            self.regs.zmm[index][16..].copy_from_slice(&old[16..]);
+        }
+        Ok(())
+    }
+}
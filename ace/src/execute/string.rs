@@ -0,0 +1,89 @@
+//! movs, cmps, stos, lods and scas. They work on [rsi] and [rdi], or [esi] and [edi] with a 67
+//! prefix, and step them by the element size, backwards when DF is set. A rep prefix repeats
+//! them rcx times, cmps and scas also stop early once ZF is clear for repe or set for repne.
+
+use crate::error::EmulatorError;
+use crate::instr::{Instr, Size};
+use crate::opcodes::Op;
+use crate::prefix::{Rep, Segment};
+use crate::registers::Reg;
+use crate::{DisasmWriter, Emulator};
+
+impl<D: DisasmWriter> Emulator<D> {
+    pub(super) fn string(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        // movsd and cmpsd with operands are the sse2 instructions
+        if !instr.is_string() {
+            return self.execute_vector(instr);
+        }
+        let size = instr.op.string_size().expect("not a string instruction");
+        let p = &instr.prefixes;
+        let addr_size = if p.address_size {
+            Size::Dword
+        } else {
+            Size::Qword
+        };
+        let [si, di, cx] = [6, 7, 1].map(|x| Reg::new(addr_size, x, false));
+        let acc = Reg::new(size, 0, false);
+        let step = if self.regs.flags.df {
+            (size.bytes() as u64).wrapping_neg()
+        } else {
+            size.bytes() as u64
+        };
+        // only the rsi side can be overridden, rdi is always in es
+        let base = match p.segment {
+            Some(Segment::Fs) => self.regs.fs_base,
+            Some(Segment::Gs) => self.regs.gs_base,
+            _ => 0,
+        };
+
+        loop {
+            if p.rep.is_some() && self.regs.get(cx) == 0 {
+                break;
+            }
+            let src = self.regs.get(si).wrapping_add(base);
+            let dst = self.regs.get(di);
+            let (uses_si, uses_di, compares) = match instr.op {
+                Op::Movsb | Op::Movsw | Op::Movsd | Op::Movsq => {
+                    let value = self.memory.read(src, size)?;
+                    self.memory.write(dst, size, value)?;
+                    (true, true, false)
+                }
+                Op::Cmpsb | Op::Cmpsw | Op::Cmpsd | Op::Cmpsq => {
+                    let a = self.memory.read(src, size)?;
+                    let b = self.memory.read(dst, size)?;
+                    self.regs.flags.sub(a, b, a.wrapping_sub(b), size);
+                    (true, true, true)
+                }
+                Op::Stosb | Op::Stosw | Op::Stosd | Op::Stosq => {
+                    self.memory.write(dst, size, self.regs.get(acc))?;
+                    (false, true, false)
+                }
+                Op::Lodsb | Op::Lodsw | Op::Lodsd | Op::Lodsq => {
+                    let value = self.memory.read(src, size)?;
+                    self.regs.set(acc, value);
+                    (true, false, false)
+                }
+                _ => {
+                    let a = self.regs.get(acc);
+                    let b = self.memory.read(dst, size)?;
+                    self.regs.flags.sub(a, b, a.wrapping_sub(b), size);
+                    (false, true, true)
+                }
+            };
+            if uses_si {
+                self.regs.set(si, self.regs.get(si).wrapping_add(step));
+            }
+            if uses_di {
+                self.regs.set(di, self.regs.get(di).wrapping_add(step));
+            }
+
+            let Some(rep) = p.rep else { break };
+            self.regs.set(cx, self.regs.get(cx).wrapping_sub(1));
+            let zf = self.regs.flags.zf;
+            if compares && (rep == Rep::Rep && !zf || rep == Rep::Repne && zf) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,2104 @@
+//! Vector instructions. Vex and evex encoded ones zero a register past the destination size
+//! when they write it, evex ones can also go through an opmask. Legacy sse instructions run
+//! through here as their vex forms, see sse.rs. Floating point exceptions only set their flags
+//! in MXCSR as if they were masked, there's no #XM.
+
+use crate::error::{EmulatorError, StopReason};
+use crate::f80::{DENORMAL, INVALID, OVERFLOW, PRECISION, UNDERFLOW, ZERO_DIVIDE};
+use crate::instr::{Instr, Operand};
+use crate::opcodes::Op;
+use crate::prefix::Rounding;
+use crate::registers::R64;
+use crate::{DisasmWriter, Emulator};
+use std::cell::Cell;
+
+/// A vector operand, zero extended to the width of a register.
+type Vector = [u8; 64];
+
+fn lane(v: &Vector, width: usize, i: usize) -> u64 {
+    let mut x = [0; 8];
+    x[..width].copy_from_slice(&v[i * width..][..width]);
+    u64::from_le_bytes(x)
+}
+
+fn set_lane(v: &mut Vector, width: usize, i: usize, value: u64) {
+    v[i * width..][..width].copy_from_slice(&value.to_le_bytes()[..width]);
+}
+
+/// Sign extends a `width` byte lane.
+fn sext(x: u64, width: usize) -> i64 {
+    let shift = 64 - width * 8;
+    ((x << shift) as i64) >> shift
+}
+
+/// All ones for true, like the compare instructions.
+fn mask(x: bool) -> u64 {
+    if x {
+        u64::MAX
+    } else {
+        0
+    }
+}
+
+/// Applies `f` to each pair of `width` byte lanes in the first `len` bytes.
+fn lanes(a: &Vector, b: &Vector, len: usize, width: usize, f: impl Fn(u64, u64) -> u64) -> Vector {
+    let mut result = [0; 64];
+    for i in 0..len / width {
+        set_lane(
+            &mut result,
+            width,
+            i,
+            f(lane(a, width, i), lane(b, width, i)),
+        );
+    }
+    result
+}
+
+fn signed(width: usize, f: impl Fn(i64, i64) -> i64) -> impl Fn(u64, u64) -> u64 {
+    move |a, b| f(sext(a, width), sext(b, width)) as u64
+}
+
+/// The vpcmp predicates, signed lanes have to be sign extended first.
+fn compare<T: Ord>(predicate: u8, a: T, b: T) -> bool {
+    match predicate & 7 {
+        0 => a == b,
+        1 => a < b,
+        2 => a <= b,
+        3 => false,
+        4 => a != b,
+        5 => a >= b,
+        6 => a > b,
+        _ => true,
+    }
+}
+
+/// Interleaves the low or high halves of each 128 bit block of `a` and `b`.
+fn unpack(a: &Vector, b: &Vector, len: usize, width: usize, high: bool) -> Vector {
+    let mut result = [0; 64];
+    let per_block = 16 / width;
+    for block in 0..len / 16 {
+        let first = block * per_block;
+        let start = first + if high { per_block / 2 } else { 0 };
+        for i in 0..per_block / 2 {
+            set_lane(&mut result, width, first + 2 * i, lane(a, width, start + i));
+            set_lane(
+                &mut result,
+                width,
+                first + 2 * i + 1,
+                lane(b, width, start + i),
+            );
+        }
+    }
+    result
+}
+
+/// The pairs of adjacent elements of the 128 bit `a` and then of `b` combined by `f`, for the
+/// horizontal adds and subtracts.
+fn horizontal(a: &Vector, b: &Vector, width: usize, f: impl Fn(u64, u64) -> u64) -> Vector {
+    let pairs = 8 / width;
+    let mut result = [0; 64];
+    for (k, src) in [a, b].into_iter().enumerate() {
+        for i in 0..pairs {
+            let value = f(lane(src, width, 2 * i), lane(src, width, 2 * i + 1));
+            set_lane(&mut result, width, k * pairs + i, value);
+        }
+    }
+    result
+}
+
+/// pcmpestri and friends compare each element of `b` with the elements of `a`, `imm` says how,
+/// and the result has a bit for each element of `b`. Only the first `a_len` and `b_len`
+/// elements are valid, comparisons with the ones past them have fixed results.
+fn compare_strings(imm: u8, a: &Vector, a_len: usize, b: &Vector, b_len: usize) -> u64 {
+    let width = if imm & 1 == 0 { 1 } else { 2 };
+    let n = 16 / width;
+    let value = |v: &Vector, i| {
+        let x = lane(v, width, i);
+        if imm & 2 == 0 {
+            x as i64
+        } else {
+            sext(x, width)
+        }
+    };
+    // 0 is equal any, 1 ranges, 2 equal each and 3 equal ordered
+    let aggregation = imm >> 2 & 3;
+    let holds = |i: usize, j: usize| match (i < a_len, j < b_len) {
+        (true, true) => match aggregation {
+            // the even elements of a are the lower bounds of the ranges
+            1 if i.is_multiple_of(2) => value(a, i) <= value(b, j),
+            1 => value(b, j) <= value(a, i),
+            _ => value(a, i) == value(b, j),
+        },
+        (false, false) => aggregation >= 2,
+        // a substring that ended already matched
+        (false, true) => aggregation == 3,
+        (true, false) => false,
+    };
+    let mut result = 0;
+    for j in 0..n {
+        let bit = match aggregation {
+            0 => (0..n).any(|i| holds(i, j)),
+            1 => (0..n).step_by(2).any(|i| holds(i, j) && holds(i + 1, j)),
+            2 => holds(j, j),
+            _ => (0..n - j).all(|i| holds(i, j + i)),
+        };
+        result |= (bit as u64) << j;
+    }
+    // negated, or only negated for the valid elements
+    match imm >> 4 & 3 {
+        1 => result ^ ((1 << n) - 1),
+        3 => result ^ ((1 << b_len) - 1),
+        _ => result,
+    }
+}
+
+/// Size in bytes of the elements an instruction works on, which is also what an opmask bit
+/// covers.
+fn element_width(op: Op) -> usize {
+    match op {
+        Op::Vpaddb
+        | Op::Vpsubb
+        | Op::Vpminub
+        | Op::Vpmaxub
+        | Op::Vpminsb
+        | Op::Vpmaxsb
+        | Op::Vpcmpeqb
+        | Op::Vpcmpgtb
+        | Op::Vpcmpb
+        | Op::Vpcmpub
+        | Op::Vptestmb
+        | Op::Vptestnmb
+        | Op::Vpmovm2b
+        | Op::Vpmovb2m
+        | Op::Vpblendmb
+        | Op::Vpblendvb
+        | Op::Vmovdqu8
+        | Op::Vpunpcklbw
+        | Op::Vpunpckhbw
+        | Op::Vpshufb
+        | Op::Vpbroadcastb
+        | Op::Vpmovmskb
+        | Op::Paddsb
+        | Op::Paddusb
+        | Op::Psubsb
+        | Op::Psubusb
+        | Op::Pavgb
+        | Op::Pabsb
+        | Op::Psignb
+        | Op::Pextrb
+        | Op::Pinsrb => 1,
+
+        Op::Vpaddw
+        | Op::Vpsubw
+        | Op::Vpmullw
+        | Op::Vpminuw
+        | Op::Vpmaxuw
+        | Op::Vpminsw
+        | Op::Vpmaxsw
+        | Op::Vpcmpeqw
+        | Op::Vpcmpgtw
+        | Op::Vpcmpw
+        | Op::Vpcmpuw
+        | Op::Vptestmw
+        | Op::Vptestnmw
+        | Op::Vpmovm2w
+        | Op::Vpmovw2m
+        | Op::Vpblendmw
+        | Op::Vmovdqu16
+        | Op::Vpunpcklwd
+        | Op::Vpunpckhwd
+        | Op::Vpsllw
+        | Op::Vpsrlw
+        | Op::Vpsraw
+        | Op::Vpbroadcastw
+        | Op::Paddsw
+        | Op::Paddusw
+        | Op::Psubsw
+        | Op::Psubusw
+        | Op::Pavgw
+        | Op::Pmulhw
+        | Op::Pmulhuw
+        | Op::Packsswb
+        | Op::Packuswb
+        | Op::Pinsrw
+        | Op::Pextrw
+        | Op::Pshuflw
+        | Op::Pshufhw
+        | Op::Pabsw
+        | Op::Psignw
+        | Op::Phaddw
+        | Op::Phaddsw
+        | Op::Phsubw
+        | Op::Phsubsw
+        | Op::Pblendw => 2,
+
+        Op::Vpaddq
+        | Op::Vpsubq
+        | Op::Vpmullq
+        | Op::Vpandq
+        | Op::Vpandnq
+        | Op::Vporq
+        | Op::Vpxorq
+        | Op::Vpminuq
+        | Op::Vpmaxuq
+        | Op::Vpminsq
+        | Op::Vpmaxsq
+        | Op::Vpcmpeqq
+        | Op::Vpcmpgtq
+        | Op::Vpcmpq
+        | Op::Vpcmpuq
+        | Op::Vptestmq
+        | Op::Vptestnmq
+        | Op::Vpmovm2q
+        | Op::Vpmovq2m
+        | Op::Vpblendmq
+        | Op::Vblendmpd
+        | Op::Vblendvpd
+        | Op::Vmovdqa64
+        | Op::Vmovdqu64
+        | Op::Vmovapd
+        | Op::Vmovupd
+        | Op::Vmovsd
+        | Op::Vmovq
+        | Op::Vpunpcklqdq
+        | Op::Vpunpckhqdq
+        | Op::Vpsllq
+        | Op::Vpsrlq
+        | Op::Vpsraq
+        | Op::Vpsllvq
+        | Op::Vpsrlvq
+        | Op::Vpsravq
+        | Op::Vpternlogq
+        | Op::Vpbroadcastq
+        | Op::Vbroadcastsd
+        | Op::Vbroadcasti64x4
+        | Op::Vbroadcastf64x4
+        | Op::Vinserti64x4
+        | Op::Vinsertf64x4
+        | Op::Vextracti64x4
+        | Op::Vextractf64x4
+        | Op::Vshufi64x2
+        | Op::Vshuff64x2
+        | Op::Valignq
+        | Op::Vpermq
+        | Op::Vpermpd
+        | Op::Vpermt2q
+        | Op::Vpermt2pd
+        | Op::Vpermi2q
+        | Op::Vpermi2pd
+        | Op::Vaddpd
+        | Op::Vaddsd
+        | Op::Vsubpd
+        | Op::Vsubsd
+        | Op::Vmulpd
+        | Op::Vmulsd
+        | Op::Vdivpd
+        | Op::Vdivsd
+        | Op::Vminpd
+        | Op::Vminsd
+        | Op::Vmaxpd
+        | Op::Vmaxsd
+        | Op::Vsqrtpd
+        | Op::Vsqrtsd
+        | Op::Vandpd
+        | Op::Vandnpd
+        | Op::Vorpd
+        | Op::Vxorpd
+        | Op::Cmppd
+        | Op::Cmpsd
+        | Op::Comisd
+        | Op::Ucomisd
+        | Op::Shufpd
+        | Op::Movmskpd
+        | Op::Pmuludq
+        | Op::Cvtsi2sd
+        | Op::Cvtsd2si
+        | Op::Cvttsd2si
+        | Op::Addsubpd
+        | Op::Haddpd
+        | Op::Hsubpd
+        | Op::Movddup
+        | Op::Dppd
+        | Op::Roundpd
+        | Op::Roundsd
+        | Op::Blendpd
+        | Op::Pmuldq
+        | Op::Pextrq
+        | Op::Pinsrq => 8,
+
+        _ => 4,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Float {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Min,
+    Max,
+    /// of the second operand
+    Sqrt,
+}
+
+macro_rules! directed_rounding {
+    ($name:ident, $t:ty) => {
+        /// Rounds an exact result of `r + err` the way `rounding` asks, where `r` is already
+        /// rounded to nearest and only the sign of `err` matters.
+        fn $name(r: $t, err: f64, rounding: Rounding) -> $t {
+            match rounding {
+                Rounding::Up if err > 0.0 => r.next_up(),
+                Rounding::Down if err < 0.0 => r.next_down(),
+                Rounding::Zero if err > 0.0 && r < 0.0 => r.next_up(),
+                Rounding::Zero if err < 0.0 && r > 0.0 => r.next_down(),
+                _ => r,
+            }
+        }
+    };
+}
+directed_rounding!(round_f32, f32);
+directed_rounding!(round_f64, f64);
+
+/// The unbiased exponent of a finite `x`, with 0 for zero.
+fn exponent(x: f64) -> i32 {
+    let biased = (x.to_bits() >> 52 & 0x7ff) as i32;
+    match biased {
+        0 if x == 0.0 => 0,
+        0 => exponent(x * 2f64.powi(64)) - 64,
+        _ => biased - 1023,
+    }
+}
+
+/// Multiplies `x` by 2^`e`, in steps so a big `e` doesn't overflow.
+fn scale(mut x: f64, mut e: i32) -> f64 {
+    while e.abs() > 1000 {
+        let step = 1000 * e.signum();
+        x *= 2f64.powi(step);
+        e -= step;
+    }
+    x * 2f64.powi(e)
+}
+
+macro_rules! float_op {
+    ($kind:expr, $a:expr, $b:expr) => {{
+        let (a, b) = ($a, $b);
+        match $kind {
+            Float::Add => a + b,
+            Float::Sub => a - b,
+            Float::Mul => a * b,
+            Float::Div => a / b,
+            // the second operand if either is NaN or both are zero
+            Float::Min => {
+                if a < b {
+                    a
+                } else {
+                    b
+                }
+            }
+            Float::Max => {
+                if a > b {
+                    a
+                } else {
+                    b
+                }
+            }
+            Float::Sqrt => b.sqrt(),
+        }
+    }};
+}
+
+/// The sign of the rounding error of `r`, the finite result of `kind` rounded to nearest.
+/// Fma gives it exactly once the operands are scaled to around 1, so nothing underflows.
+fn rounding_error(kind: Float, a: f64, b: f64, r: f64) -> f64 {
+    match kind {
+        Float::Add | Float::Sub => {
+            let b = if matches!(kind, Float::Sub) { -b } else { b };
+            let bb = r - a;
+            (a - (r - bb)) + (b - bb)
+        }
+        // everything underflowed, the error is the exact result
+        Float::Mul | Float::Div if r == 0.0 => {
+            if a == 0.0 || b == 0.0 || b.is_infinite() {
+                0.0
+            } else {
+                a.signum() * b.signum()
+            }
+        }
+        Float::Mul => {
+            let (ea, eb) = (exponent(a), exponent(b));
+            scale(a, -ea).mul_add(scale(b, -eb), -scale(r, -ea - eb))
+        }
+        Float::Div => {
+            let (ea, eb) = (exponent(a), exponent(b));
+            (-scale(r, eb - ea)).mul_add(scale(b, -eb), scale(a, -ea)) * b.signum()
+        }
+        Float::Sqrt => {
+            let e = exponent(b) & !1;
+            let r = scale(r, -e / 2);
+            (-r).mul_add(r, scale(b, -e))
+        }
+        Float::Min | Float::Max => 0.0,
+    }
+}
+
+/// Rounds `m + err` to `bits` of precision with no limit on the exponent, where `m` is
+/// finite and not zero and only the sign of `err` matters.
+fn round_to_bits(m: f64, err: f64, bits: i32, rounding: Rounding) -> f64 {
+    let e = exponent(m);
+    // the grid points either side of the exact result are integers at this scale
+    let q = scale(m, bits - 1 - e);
+    let (lo, hi) = if q.fract() != 0.0 {
+        (q.floor(), q.ceil())
+    } else if err > 0.0 {
+        (q, q + 1.0)
+    } else if err < 0.0 {
+        (q - 1.0, q)
+    } else {
+        return m;
+    };
+    let q = match rounding {
+        Rounding::Up => hi,
+        Rounding::Down => lo,
+        Rounding::Zero if q > 0.0 => lo,
+        Rounding::Zero => hi,
+        _ => {
+            let past_half = q - (lo + hi) / 2.0;
+            let past_half = if past_half == 0.0 { err } else { past_half };
+            if past_half > 0.0 || past_half == 0.0 && lo % 2.0 != 0.0 {
+                hi
+            } else {
+                lo
+            }
+        }
+    };
+    scale(q, e - (bits - 1))
+}
+
+/// The exponent of the result of `kind` rounded to `bits` of precision as if the exponent had
+/// no limit, which is what tininess and overflow go by. The operands are split into a
+/// significand around 1 and an exponent, so the operation itself can't leave the range.
+fn unbounded_exponent(kind: Float, a: f64, b: f64, bits: i32, rounding: Rounding) -> i32 {
+    let (ea, eb) = (exponent(a), exponent(b));
+    let (a, b, e) = match kind {
+        Float::Add | Float::Sub => {
+            let e = ea.max(eb);
+            (scale(a, -e), scale(b, -e), e)
+        }
+        Float::Mul => (scale(a, -ea), scale(b, -eb), ea + eb),
+        Float::Div => (scale(a, -ea), scale(b, -eb), ea - eb),
+        Float::Sqrt => (a, scale(b, -(eb & !1)), (eb & !1) / 2),
+        Float::Min | Float::Max => (a, b, 0),
+    };
+    let m: f64 = float_op!(kind, a, b);
+    let err = rounding_error(kind, a, b, m);
+    exponent(round_to_bits(m, err, bits, rounding)) + e
+}
+
+/// What the floating point instructions need from MXCSR, and the exceptions they raise for it.
+#[derive(Clone, Copy)]
+struct Env<'a> {
+    rounding: Rounding,
+    /// denormals are zero, denormal operands are read as zeros of the same sign
+    daz: bool,
+    /// flush to zero, results that underflow are zeros of the same sign
+    ftz: bool,
+    exceptions: &'a Cell<u16>,
+}
+impl Env<'_> {
+    fn raise(&self, exceptions: u16) {
+        self.exceptions.set(self.exceptions.get() | exceptions);
+    }
+
+    /// A single or double precision operand, with DAZ applied.
+    fn operand(&self, x: u64, width: usize) -> u64 {
+        if self.daz && is_denormal(x, width) {
+            x & sign_bit(width)
+        } else {
+            x
+        }
+    }
+
+    /// Raises the exceptions of a finite or overflowed result, and flushes it with FTZ when
+    /// it's tiny. Tininess is after rounding, as if the exponent had no limit.
+    fn result(&self, r: u64, width: usize, inexact: bool, tiny: bool, overflow: bool) -> u64 {
+        if overflow {
+            self.raise(OVERFLOW | PRECISION);
+        } else if tiny && self.ftz {
+            self.raise(UNDERFLOW | PRECISION);
+            return r & sign_bit(width);
+        } else if tiny && inexact {
+            self.raise(UNDERFLOW | PRECISION);
+        } else if inexact {
+            self.raise(PRECISION);
+        }
+        r
+    }
+}
+
+fn sign_bit(width: usize) -> u64 {
+    1 << (width * 8 - 1)
+}
+
+/// The exponent and fraction fields of a single or double precision lane.
+fn fields(x: u64, width: usize) -> (u64, u64) {
+    if width == 4 {
+        (x >> 23 & 0xff, x & 0x7f_ffff)
+    } else {
+        (x >> 52 & 0x7ff, x & ((1 << 52) - 1))
+    }
+}
+
+fn is_denormal(x: u64, width: usize) -> bool {
+    let (exponent, fraction) = fields(x, width);
+    exponent == 0 && fraction != 0
+}
+
+fn quiet_bit(width: usize) -> u64 {
+    if width == 4 {
+        1 << 22
+    } else {
+        1 << 51
+    }
+}
+
+fn is_signaling(x: u64, width: usize) -> bool {
+    to_double(x, width).is_nan() && x & quiet_bit(width) == 0
+}
+
+/// One lane of a floating point operation, `width` picks single or double precision.
+fn float(kind: Float, width: usize, a: u64, b: u64, env: Env) -> u64 {
+    let rounding = env.rounding;
+    let (a, b) = (env.operand(a, width), env.operand(b, width));
+    let operands = if matches!(kind, Float::Sqrt) {
+        &[b][..]
+    } else {
+        &[a, b][..]
+    };
+    // the result with whether it's inexact, tiny and overflowed
+    macro_rules! calc {
+        ($t:ty, $a:expr, $b:expr, $round:ident) => {{
+            let (a, b): ($t, $t) = ($a, $b);
+            let r: $t = float_op!(kind, a, b);
+            // rounded to nearest, that is
+            let past = r.is_infinite()
+                && a.is_finite()
+                && b.is_finite()
+                && !matches!(kind, Float::Div if b == 0.0);
+            let bits = <$t>::MANTISSA_DIGITS as i32;
+            let unbounded = || unbounded_exponent(kind, a as f64, b as f64, bits, rounding);
+            let (r, err, overflow) = if !r.is_finite() && !past {
+                (r, 0.0, false)
+            } else if past {
+                // the exact result is somewhere past the largest finite value
+                let overflow = rounding == Rounding::Nearest || unbounded() >= <$t>::MAX_EXP;
+                let r = match rounding {
+                    Rounding::Nearest => r,
+                    _ => $round(<$t>::MAX.copysign(r), r as f64, rounding),
+                };
+                // inexact either way
+                (r, 1.0, overflow)
+            } else if r == 0.0 && matches!(kind, Float::Add | Float::Sub) {
+                // exact, but x + -x is -0 when rounding down
+                let b = if matches!(kind, Float::Sub) { -b } else { b };
+                if a.is_sign_negative() == b.is_sign_negative() || rounding != Rounding::Down {
+                    (r, 0.0, false)
+                } else {
+                    (-0.0, 0.0, false)
+                }
+            } else {
+                // single precision is exact or rounded to nearest in double precision first
+                let (a, b) = (a as f64, b as f64);
+                let wide: f64 = float_op!(kind, a, b);
+                let err = if wide != r as f64 {
+                    wide - r as f64
+                } else {
+                    rounding_error(kind, a, b, wide)
+                };
+                let r = $round(r, err, rounding);
+                (r, err, r.is_infinite())
+            };
+            let min = <$t>::MIN_POSITIVE;
+            let tiny = if r.abs() < min {
+                r != 0.0 || err != 0.0
+            } else {
+                // rounded up to the smallest normal, which it might not have been with more
+                // exponent range
+                r.abs() == min && err != 0.0 && unbounded() < <$t>::MIN_EXP - 1
+            };
+            (r.to_bits() as u64, err != 0.0, tiny, overflow)
+        }};
+    }
+    let (r, inexact, tiny, overflow) = if width == 4 {
+        calc!(
+            f32,
+            f32::from_bits(a as u32),
+            f32::from_bits(b as u32),
+            round_f32
+        )
+    } else {
+        calc!(f64, f64::from_bits(a), f64::from_bits(b), round_f64)
+    };
+
+    if operands.iter().any(|&x| to_double(x, width).is_nan()) {
+        // min and max signal for any NaN, the others only for a signaling one
+        if matches!(kind, Float::Min | Float::Max)
+            || operands.iter().any(|&x| is_signaling(x, width))
+        {
+            env.raise(INVALID);
+        }
+        return r;
+    }
+    let value = to_double(r, width);
+    // inf - inf, 0 * inf, 0 / 0, inf / inf or the square root of a negative
+    if value.is_nan() {
+        env.raise(INVALID);
+        return r;
+    }
+    if matches!(kind, Float::Div) && to_double(b, width) == 0.0 && to_double(a, width).is_finite() {
+        env.raise(ZERO_DIVIDE);
+        return r;
+    }
+    if operands.iter().any(|&x| is_denormal(x, width)) {
+        env.raise(DENORMAL);
+    }
+    // min and max return an operand as it is
+    if value.is_infinite() && !overflow || matches!(kind, Float::Min | Float::Max) {
+        return r;
+    }
+    env.result(r, width, inexact, tiny, overflow)
+}
+
+/// The floating point arithmetic instructions as (operation, scalar).
+fn float_op(op: Op) -> Option<(Float, bool)> {
+    let x = match op {
+        Op::Vaddps | Op::Vaddpd => (Float::Add, false),
+        Op::Vaddss | Op::Vaddsd => (Float::Add, true),
+        Op::Vsubps | Op::Vsubpd => (Float::Sub, false),
+        Op::Vsubss | Op::Vsubsd => (Float::Sub, true),
+        Op::Vmulps | Op::Vmulpd => (Float::Mul, false),
+        Op::Vmulss | Op::Vmulsd => (Float::Mul, true),
+        Op::Vdivps | Op::Vdivpd => (Float::Div, false),
+        Op::Vdivss | Op::Vdivsd => (Float::Div, true),
+        Op::Vminps | Op::Vminpd => (Float::Min, false),
+        Op::Vminss | Op::Vminsd => (Float::Min, true),
+        Op::Vmaxps | Op::Vmaxpd => (Float::Max, false),
+        Op::Vmaxss | Op::Vmaxsd => (Float::Max, true),
+        Op::Vsqrtps | Op::Vsqrtpd => (Float::Sqrt, false),
+        Op::Vsqrtss | Op::Vsqrtsd => (Float::Sqrt, true),
+        _ => return None,
+    };
+    Some(x)
+}
+
+/// Shifts a `width` byte lane by `count`, shifting everything out if it's at least the lane
+/// width.
+fn shift(op: Op, a: u64, width: usize, count: u64) -> u64 {
+    let bits = width as u64 * 8;
+    match op {
+        Op::Vpsraw | Op::Vpsrad | Op::Vpsraq | Op::Vpsravd | Op::Vpsravq => {
+            (sext(a, width) >> count.min(bits - 1)) as u64
+        }
+        _ if count >= bits => 0,
+        Op::Vpsllw | Op::Vpslld | Op::Vpsllq | Op::Vpsllvd | Op::Vpsllvq => a << count,
+        _ => a >> count,
+    }
+}
+
+/// Clamps `x` to a signed or unsigned `width` byte integer.
+fn saturate(x: i64, width: usize, signed: bool) -> i64 {
+    let bits = width * 8;
+    if signed {
+        x.clamp(-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        x.clamp(0, (1 << bits) - 1)
+    }
+}
+
+/// A single or double precision lane as a double, which is exact.
+fn to_double(x: u64, width: usize) -> f64 {
+    if width == 4 {
+        f32::from_bits(x as u32) as f64
+    } else {
+        f64::from_bits(x)
+    }
+}
+
+/// The cmpps predicates, the ones from 4 up are the negations of the first four so they hold
+/// for NaNs. The less and greater ones signal for a quiet NaN too.
+fn float_compare(predicate: u8, width: usize, a: u64, b: u64, env: Env) -> bool {
+    let signaling = matches!(predicate & 3, 1 | 2);
+    let (a, b) = compare_operands(width, a, b, signaling, env);
+    let unordered = a.is_nan() || b.is_nan();
+    match predicate & 7 {
+        0 => a == b,
+        1 => a < b,
+        2 => a <= b,
+        3 => unordered,
+        4 => a != b,
+        5 => a >= b || unordered,
+        6 => a > b || unordered,
+        _ => !unordered,
+    }
+}
+
+/// The operands of a compare as doubles, raising IE for a signaling NaN or, if `signaling`,
+/// any NaN, and otherwise DE for a denormal.
+fn compare_operands(width: usize, a: u64, b: u64, signaling: bool, env: Env) -> (f64, f64) {
+    let (a, b) = (env.operand(a, width), env.operand(b, width));
+    let (x, y) = (to_double(a, width), to_double(b, width));
+    if x.is_nan() || y.is_nan() {
+        if signaling || is_signaling(a, width) || is_signaling(b, width) {
+            env.raise(INVALID);
+        }
+    } else if is_denormal(a, width) || is_denormal(b, width) {
+        env.raise(DENORMAL);
+    }
+    (x, y)
+}
+
+/// The rounding modes in the order MXCSR.RC, the x87 control word and the immediate of roundps
+/// have them.
+pub(super) fn rounding_control(bits: u32) -> Rounding {
+    match bits & 3 {
+        0 => Rounding::Nearest,
+        1 => Rounding::Down,
+        2 => Rounding::Up,
+        _ => Rounding::Zero,
+    }
+}
+
+fn round_to_integer(x: f64, rounding: Rounding) -> f64 {
+    match rounding {
+        Rounding::Down => x.floor(),
+        Rounding::Up => x.ceil(),
+        Rounding::Zero => x.trunc(),
+        _ => x.round_ties_even(),
+    }
+}
+
+/// roundps and friends, which round to an integer but keep the format. NaNs come out quiet.
+/// `precision` says whether an inexact result raises PE.
+fn round_float(x: u64, width: usize, env: Env, precision: bool) -> u64 {
+    let x = env.operand(x, width);
+    let value = to_double(x, width);
+    if value.is_nan() {
+        if is_signaling(x, width) {
+            env.raise(INVALID);
+        }
+        return x | quiet_bit(width);
+    }
+    let r = round_to_integer(value, env.rounding);
+    if precision && r != value {
+        env.raise(PRECISION);
+    }
+    // an integer that fits in a float also fits after the round trip through a double
+    if width == 4 {
+        (r as f32).to_bits() as u64
+    } else {
+        r.to_bits()
+    }
+}
+
+/// Float to int conversion from a `width` byte lane into `size` bytes, NaN and out of range
+/// values give the "integer indefinite", which is the smallest negative integer.
+fn float_to_int(x: u64, width: usize, size: usize, env: Env) -> u64 {
+    let value = to_double(env.operand(x, width), width);
+    let r = round_to_integer(value, env.rounding);
+    let bits = size * 8;
+    let limit = 2f64.powi(bits as i32 - 1);
+    if r.is_nan() || !(-limit..limit).contains(&r) {
+        env.raise(INVALID);
+        1 << (bits - 1)
+    } else {
+        if r != value {
+            env.raise(PRECISION);
+        }
+        r as i64 as u64 & (u64::MAX >> (64 - bits))
+    }
+}
+
+/// Converts to single or double precision, `width` picks which.
+fn int_to_float(x: i64, width: usize, env: Env) -> u64 {
+    // the error is exact in i128
+    let (r, err) = if width == 4 {
+        let r = x as f32;
+        let err = (x as i128 - r as i128) as f64;
+        (round_f32(r, err, env.rounding).to_bits() as u64, err)
+    } else {
+        let r = x as f64;
+        let err = (x as i128 - r as i128) as f64;
+        (round_f64(r, err, env.rounding).to_bits(), err)
+    };
+    if err != 0.0 {
+        env.raise(PRECISION);
+    }
+    r
+}
+
+/// Converts single to double precision, which is exact.
+fn widen(x: u64, env: Env) -> u64 {
+    let x = env.operand(x, 4);
+    if is_signaling(x, 4) {
+        env.raise(INVALID);
+    } else if is_denormal(x, 4) {
+        env.raise(DENORMAL);
+    }
+    to_double(x, 4).to_bits()
+}
+
+/// Rounds a double to single precision.
+fn narrow(x: u64, env: Env) -> u64 {
+    let x = env.operand(x, 8);
+    let value = f64::from_bits(x);
+    let r = value as f32;
+    if !value.is_finite() {
+        if is_signaling(x, 8) {
+            env.raise(INVALID);
+        }
+        return r.to_bits() as u64;
+    }
+    if is_denormal(x, 8) {
+        env.raise(DENORMAL);
+    }
+    // an overflow is still rounded from the largest finite value, the error is exact
+    let past = r.is_infinite();
+    let base = if past { f32::MAX.copysign(r) } else { r };
+    let err = value - base as f64;
+    let r = if past && env.rounding == Rounding::Nearest {
+        r
+    } else {
+        round_f32(base, err, env.rounding)
+    };
+    // tininess and overflow go by the result rounded as if the exponent had no limit
+    let unbounded = || exponent(round_to_bits(value, 0.0, 24, env.rounding));
+    let overflow = r.is_infinite() || past && unbounded() >= f32::MAX_EXP;
+    let min = f32::MIN_POSITIVE;
+    let tiny = if r.abs() < min {
+        value != 0.0
+    } else {
+        r.abs() == min && err != 0.0 && unbounded() < f32::MIN_EXP - 1
+    };
+    env.result(r.to_bits() as u64, 4, err != 0.0, tiny, overflow)
+}
+
+/// Converts the first `count` `from` byte lanes of `v` into `to` byte lanes.
+fn convert(v: &Vector, count: usize, from: usize, to: usize, f: impl Fn(u64) -> u64) -> Vector {
+    let mut result = [0; 64];
+    for i in 0..count {
+        set_lane(&mut result, to, i, f(lane(v, from, i)));
+    }
+    result
+}
+
+/// Width in bits of the opmask instructions.
+fn opmask_bits(op: Op) -> Option<u32> {
+    let bits = match op {
+        Op::Kmovb | Op::Kandb | Op::Kandnb | Op::Korb | Op::Kxnorb | Op::Kxorb | Op::Knotb => 8,
+        Op::Kmovw | Op::Kandw | Op::Kandnw | Op::Korw | Op::Kxnorw | Op::Kxorw | Op::Knotw => 16,
+        Op::Kmovd | Op::Kandd | Op::Kandnd | Op::Kord | Op::Kxnord | Op::Kxord | Op::Knotd => 32,
+        Op::Kmovq | Op::Kandq | Op::Kandnq | Op::Korq | Op::Kxnorq | Op::Kxorq | Op::Knotq => 64,
+        _ => return None,
+    };
+    Some(bits)
+}
+
+impl<D: DisasmWriter> Emulator<D> {
+    /// Reads a vector, general purpose register, opmask or memory operand, zero extended.
+    /// Anything else reads as zero so instructions can read all their operands up front.
+    fn read_vector(&self, operand: Operand) -> Result<Vector, EmulatorError> {
+        let mut value = [0; 64];
+        match operand {
+            Operand::Vec(reg) => {
+                let len = reg.size.bytes();
+                value[..len].copy_from_slice(&self.regs.zmm[reg.index as usize][..len]);
+            }
+            Operand::Mem(mem) => {
+                let size = mem.size.bytes();
+                let bytes = self.memory.read_bytes(self.address(&mem), size)?;
+                // a broadcast repeats the one element
+                for i in 0..mem.broadcast.unwrap_or(1) as usize {
+                    value[i * size..][..size].copy_from_slice(bytes);
+                }
+            }
+            Operand::Reg(_) | Operand::Mask(_) => {
+                value[..8].copy_from_slice(&self.read(operand)?.to_le_bytes())
+            }
+            _ => {}
+        }
+        Ok(value)
+    }
+
+    /// Writes as much of `value` as fits in `operand`.
+    fn write_vector(&mut self, operand: Operand, value: &Vector) -> Result<(), EmulatorError> {
+        match operand {
+            Operand::Vec(reg) => {
+                let len = reg.size.bytes();
+                let dst = &mut self.regs.zmm[reg.index as usize];
+                *dst = [0; 64];
+                dst[..len].copy_from_slice(&value[..len]);
+            }
+            Operand::Mem(mem) => {
+                let address = self.address(&mem);
+                self.memory
+                    .write_bytes(address, &value[..mem.size.bytes()])?;
+            }
+            _ => self.write(operand, lane(value, 8, 0))?,
+        }
+        Ok(())
+    }
+
+    /// The evex opmask, all ones when there isn't one.
+    fn opmask(&self, instr: &Instr) -> u64 {
+        match instr.prefixes.evex {
+            Some(evex) if evex.mask != 0 => self.regs.k[evex.mask as usize],
+            _ => u64::MAX,
+        }
+    }
+
+    /// Writes the first `count` `width` byte elements of `value` whose opmask bit is set. The
+    /// others keep their old value, or are zeroed with `{z}`, and aren't written at all in
+    /// memory.
+    fn write_masked(
+        &mut self,
+        instr: &Instr,
+        dst: Operand,
+        value: &Vector,
+        width: usize,
+        count: usize,
+    ) -> Result<(), EmulatorError> {
+        let k = self.opmask(instr);
+        if k == u64::MAX {
+            return self.write_vector(dst, value);
+        }
+        if let Operand::Mem(mem) = dst {
+            let address = self.address(&mem);
+            for i in (0..count).filter(|i| k >> i & 1 != 0) {
+                let element = &value[i * width..][..width];
+                self.memory
+                    .write_bytes(address.wrapping_add((i * width) as u64), element)?;
+            }
+            return Ok(());
+        }
+
+        let zeroing = instr.prefixes.evex.is_some_and(|x| x.zeroing);
+        let old = self.read_vector(dst)?;
+        let mut result = *value;
+        for i in (0..count).filter(|i| k >> i & 1 == 0) {
+            let keep = if zeroing { 0 } else { lane(&old, width, i) };
+            set_lane(&mut result, width, i, keep);
+        }
+        self.write_vector(dst, &result)
+    }
+
+    fn execute_opmask(&mut self, instr: &Instr, bits: u32) -> Result<(), EmulatorError> {
+        let [dst, a, b, _] = instr.operands;
+        let x = self.read(a)?;
+        let y = match b {
+            Operand::None => 0,
+            _ => self.read(b)?,
+        };
+        let value = match instr.op {
+            Op::Kandb | Op::Kandw | Op::Kandd | Op::Kandq => x & y,
+            Op::Kandnb | Op::Kandnw | Op::Kandnd | Op::Kandnq => !x & y,
+            Op::Korb | Op::Korw | Op::Kord | Op::Korq => x | y,
+            Op::Kxnorb | Op::Kxnorw | Op::Kxnord | Op::Kxnorq => !(x ^ y),
+            Op::Kxorb | Op::Kxorw | Op::Kxord | Op::Kxorq => x ^ y,
+            Op::Knotb | Op::Knotw | Op::Knotd | Op::Knotq => !x,
+            // kmov
+            _ => x,
+        };
+        self.write(dst, value & (u64::MAX >> (64 - bits)))
+    }
+
+    /// The rounding mode in MXCSR, evex instructions can pick their own instead.
+    fn mxcsr_rounding(&self) -> Rounding {
+        rounding_control(self.regs.mxcsr >> 13)
+    }
+
+    /// ldmxcsr, setting any of the reserved upper 16 bits is a #GP.
+    pub(super) fn load_mxcsr(&mut self, value: u64) -> Option<StopReason> {
+        if value > 0xffff {
+            self.ip = self.instr_ip;
+            return Some(StopReason::GeneralProtection);
+        }
+        self.regs.mxcsr = value as u32;
+        None
+    }
+
+    pub(super) fn execute_vector(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        match instr.prefixes.vex {
+            Some(_) => self.execute_vex(instr),
+            None => self.execute_sse(instr),
+        }
+    }
+
+    /// Runs a vex or evex instruction, or a legacy sse one that execute_sse has given the
+    /// operands of its vex form.
+    pub(super) fn execute_vex(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let exceptions = Cell::new(0);
+        self.execute_lanes(instr, &exceptions)?;
+        // {sae} and embedded rounding suppress them
+        if instr.prefixes.evex.and_then(|x| x.rounding).is_none() {
+            self.regs.mxcsr |= exceptions.get() as u32;
+        }
+        Ok(())
+    }
+
+    fn execute_lanes(
+        &mut self,
+        instr: &Instr,
+        exceptions: &Cell<u16>,
+    ) -> Result<(), EmulatorError> {
+        if let Some(bits) = opmask_bits(instr.op) {
+            return self.execute_opmask(instr, bits);
+        }
+
+        let [dst, a, b, c] = instr.operands;
+        let len = match (dst, a) {
+            (Operand::Mask(_), Operand::Vec(src)) => src.size.bytes(),
+            _ => instr.size.bytes(),
+        };
+        let width = element_width(instr.op);
+        let x = self.read_vector(a)?;
+        let y = self.read_vector(b)?;
+        let imm = match instr
+            .operands
+            .iter()
+            .find(|x| matches!(x, Operand::Imm { .. }))
+        {
+            Some(Operand::Imm { value, .. }) => *value as u8,
+            _ => 0,
+        };
+        let rounding = match instr.prefixes.evex.and_then(|x| x.rounding) {
+            Some(Rounding::Sae) | None => self.mxcsr_rounding(),
+            Some(rounding) => rounding,
+        };
+        let env = Env {
+            rounding,
+            daz: self.regs.mxcsr & 0x40 != 0,
+            ftz: self.regs.mxcsr & 0x8000 != 0,
+            exceptions,
+        };
+        let size_of = |operand| match operand {
+            Operand::Reg(reg) => reg.size().bytes(),
+            Operand::Vec(reg) => reg.size.bytes(),
+            Operand::Mem(mem) => mem.size.bytes(),
+            _ => 0,
+        };
+
+        if let Some((kind, scalar)) = float_op(instr.op) {
+            let count = if scalar { 1 } else { len / width };
+            // packed sqrt has its source in the first operand, the scalar forms take the rest
+            // of the first source, and elements the opmask leaves out don't raise anything
+            let src = if matches!(kind, Float::Sqrt) && !scalar {
+                &x
+            } else {
+                &y
+            };
+            let k = self.opmask(instr);
+            let mut result = if scalar { x } else { [0; 64] };
+            for i in (0..count).filter(|i| k >> i & 1 != 0) {
+                let value = float(kind, width, lane(&x, width, i), lane(src, width, i), env);
+                set_lane(&mut result, width, i, value);
+            }
+            return self.write_masked(instr, dst, &result, width, count);
+        }
+
+        // only the low element goes through the opmask
+        let mut count = len / width;
+        let result = match instr.op {
+            Op::Vmovdqa
+            | Op::Vmovdqu
+            | Op::Vmovdqa32
+            | Op::Vmovdqa64
+            | Op::Vmovdqu8
+            | Op::Vmovdqu16
+            | Op::Vmovdqu32
+            | Op::Vmovdqu64
+            | Op::Vmovaps
+            | Op::Vmovups
+            | Op::Vmovapd
+            | Op::Vmovupd => x,
+            // the register to register forms merge into the first source
+            Op::Vmovss | Op::Vmovsd if !matches!(b, Operand::None) => {
+                count = 1;
+                let mut result = x;
+                set_lane(&mut result, width, 0, lane(&y, width, 0));
+                result
+            }
+            Op::Vmovss | Op::Vmovsd | Op::Vmovd | Op::Vmovq => {
+                count = 1;
+                let mut result = [0; 64];
+                result[..width].copy_from_slice(&x[..width]);
+                result
+            }
+            Op::Vzeroupper | Op::Vzeroall => {
+                let start = if instr.op == Op::Vzeroupper { 16 } else { 0 };
+                for reg in &mut self.regs.zmm[..16] {
+                    reg[start..].fill(0);
+                }
+                return Ok(());
+            }
+            // the sign bit of each element
+            Op::Vpmovmskb | Op::Movmskps | Op::Movmskpd => {
+                let Operand::Vec(src) = a else { unreachable!() };
+                let bits = (0..src.size.bytes() / width).fold(0, |acc, i| {
+                    acc | (lane(&x, width, i) >> (width * 8 - 1)) << i
+                });
+                return self.write(dst, bits);
+            }
+            // the loads replace one qword of the destination, the stores write one
+            Op::Movlps | Op::Movlpd | Op::Movhps | Op::Movhpd | Op::Movhlps | Op::Movlhps => {
+                let high = matches!(instr.op, Op::Movhps | Op::Movhpd) as usize;
+                if let Operand::Mem(_) = dst {
+                    let mut result = [0; 64];
+                    set_lane(&mut result, 8, 0, lane(&x, 8, high));
+                    result
+                } else {
+                    let (to, from) = match instr.op {
+                        Op::Movhlps => (0, 1),
+                        Op::Movlhps => (1, 0),
+                        _ => (high, 0),
+                    };
+                    let mut result = x;
+                    set_lane(&mut result, 8, to, lane(&y, 8, from));
+                    result
+                }
+            }
+            // duplicates the even or odd elements into the pairs they're in
+            Op::Movddup | Op::Movsldup | Op::Movshdup => {
+                let odd = (instr.op == Op::Movshdup) as usize;
+                let mut result = [0; 64];
+                for i in 0..len / width {
+                    set_lane(&mut result, width, i, lane(&x, width, i & !1 | odd));
+                }
+                result
+            }
+            Op::Pinsrb | Op::Pinsrw | Op::Pinsrd | Op::Pinsrq => {
+                let mut result = x;
+                set_lane(
+                    &mut result,
+                    width,
+                    imm as usize % (16 / width),
+                    lane(&y, width, 0),
+                );
+                result
+            }
+            Op::Pextrb | Op::Pextrw | Op::Pextrd | Op::Pextrq | Op::Extractps => {
+                return self.write(dst, lane(&x, width, imm as usize % (16 / width)))
+            }
+            // imm picks the source element, where it goes and which elements get zeroed, a
+            // memory source is the element itself
+            Op::Insertps => {
+                let from = match b {
+                    Operand::Mem(_) => 0,
+                    _ => imm as usize >> 6,
+                };
+                let mut result = x;
+                set_lane(&mut result, 4, imm as usize >> 4 & 3, lane(&y, 4, from));
+                for i in 0..4 {
+                    if imm >> i & 1 != 0 {
+                        set_lane(&mut result, 4, i, 0);
+                    }
+                }
+                result
+            }
+
+            Op::Vpaddb | Op::Vpaddw | Op::Vpaddd | Op::Vpaddq => {
+                lanes(&x, &y, len, width, u64::wrapping_add)
+            }
+            Op::Vpsubb | Op::Vpsubw | Op::Vpsubd | Op::Vpsubq => {
+                lanes(&x, &y, len, width, u64::wrapping_sub)
+            }
+            Op::Vpmullw | Op::Vpmulld | Op::Vpmullq => lanes(&x, &y, len, width, u64::wrapping_mul),
+            Op::Pmulhw => lanes(&x, &y, len, 2, signed(2, |a, b| (a * b) >> 16)),
+            Op::Pmulhuw => lanes(&x, &y, len, 2, |a, b| (a * b) >> 16),
+            Op::Pmuludq => lanes(&x, &y, len, 8, |a, b| (a & 0xffff_ffff) * (b & 0xffff_ffff)),
+            // the signed products of each pair of words, added into a dword
+            Op::Pmaddwd => {
+                let mut result = [0; 64];
+                for i in 0..len / 4 {
+                    let product = |j| sext(lane(&x, 2, j), 2) * sext(lane(&y, 2, j), 2);
+                    set_lane(
+                        &mut result,
+                        4,
+                        i,
+                        (product(2 * i) + product(2 * i + 1)) as u64,
+                    );
+                }
+                result
+            }
+            // the sum of the absolute differences of the bytes in each qword
+            Op::Psadbw => {
+                let mut result = [0; 64];
+                for i in 0..len / 8 {
+                    let sum = (8 * i..8 * i + 8).map(|j| x[j].abs_diff(y[j]) as u64).sum();
+                    set_lane(&mut result, 8, i, sum);
+                }
+                result
+            }
+            // unsigned bytes of the first source times signed bytes of the second, each pair of
+            // products is added into a saturated word
+            Op::Pmaddubsw => {
+                let mut result = [0; 64];
+                for i in 0..len / 2 {
+                    let product = |j: usize| x[j] as i64 * y[j] as i8 as i64;
+                    let sum = saturate(product(2 * i) + product(2 * i + 1), 2, true);
+                    set_lane(&mut result, 2, i, sum as u64);
+                }
+                result
+            }
+            Op::Pmulhrsw => lanes(&x, &y, len, 2, signed(2, |a, b| (((a * b) >> 14) + 1) >> 1)),
+            Op::Pmuldq => lanes(&x, &y, len, 8, |a, b| {
+                (a as i32 as i64 * b as i32 as i64) as u64
+            }),
+            // sums of absolute differences between four bytes of the second source and eight
+            // overlapping groups of four bytes of the first
+            Op::Mpsadbw => {
+                let (start_x, start_y) = ((imm as usize >> 2 & 1) * 4, (imm as usize & 3) * 4);
+                let mut result = [0; 64];
+                for i in 0..8 {
+                    let sum = (0..4)
+                        .map(|j| x[start_x + i + j].abs_diff(y[start_y + j]) as u64)
+                        .sum();
+                    set_lane(&mut result, 2, i, sum);
+                }
+                result
+            }
+            Op::Paddsb | Op::Paddsw => lanes(
+                &x,
+                &y,
+                len,
+                width,
+                signed(width, |a, b| saturate(a + b, width, true)),
+            ),
+            Op::Psubsb | Op::Psubsw => lanes(
+                &x,
+                &y,
+                len,
+                width,
+                signed(width, |a, b| saturate(a - b, width, true)),
+            ),
+            Op::Paddusb | Op::Paddusw => lanes(&x, &y, len, width, |a, b| {
+                saturate((a + b) as i64, width, false) as u64
+            }),
+            Op::Psubusb | Op::Psubusw => lanes(&x, &y, len, width, u64::saturating_sub),
+            Op::Pavgb | Op::Pavgw => lanes(&x, &y, len, width, |a, b| (a + b + 1) >> 1),
+            Op::Pabsb | Op::Pabsw | Op::Pabsd => {
+                lanes(&x, &x, len, width, signed(width, |a, _| a.abs()))
+            }
+            // negates, zeroes or keeps each element by the sign of the second source
+            Op::Psignb | Op::Psignw | Op::Psignd => {
+                lanes(&x, &y, len, width, signed(width, |a, b| a * b.signum()))
+            }
+            Op::Phaddw | Op::Phaddd | Op::Phaddsw | Op::Phsubw | Op::Phsubd | Op::Phsubsw => {
+                let op = instr.op;
+                horizontal(
+                    &x,
+                    &y,
+                    width,
+                    signed(width, |a, b| match op {
+                        Op::Phaddw | Op::Phaddd => a + b,
+                        Op::Phsubw | Op::Phsubd => a - b,
+                        Op::Phaddsw => saturate(a + b, 2, true),
+                        _ => saturate(a - b, 2, true),
+                    }),
+                )
+            }
+            Op::Vpand | Op::Vpandd | Op::Vpandq | Op::Vandps | Op::Vandpd => {
+                lanes(&x, &y, len, width, |a, b| a & b)
+            }
+            Op::Vpandn | Op::Vpandnd | Op::Vpandnq | Op::Vandnps | Op::Vandnpd => {
+                lanes(&x, &y, len, width, |a, b| !a & b)
+            }
+            Op::Vpor | Op::Vpord | Op::Vporq | Op::Vorps | Op::Vorpd => {
+                lanes(&x, &y, len, width, |a, b| a | b)
+            }
+            Op::Vpxor | Op::Vpxord | Op::Vpxorq | Op::Vxorps | Op::Vxorpd => {
+                lanes(&x, &y, len, width, |a, b| a ^ b)
+            }
+            Op::Vpternlogd | Op::Vpternlogq => {
+                let d = self.read_vector(dst)?;
+                // each bit of the immediate is the result for one combination of input bits
+                let table = |d: u64, a: u64, b: u64| {
+                    (0..8).filter(|i| imm >> i & 1 != 0).fold(0, |acc, i| {
+                        let pick = |x: u64, bit: u8| if i & bit != 0 { x } else { !x };
+                        acc | pick(d, 4) & pick(a, 2) & pick(b, 1)
+                    })
+                };
+                let mut result = [0; 64];
+                for i in 0..len / 8 {
+                    let value = table(lane(&d, 8, i), lane(&x, 8, i), lane(&y, 8, i));
+                    set_lane(&mut result, 8, i, value);
+                }
+                result
+            }
+            Op::Vpcmpeqb | Op::Vpcmpeqw | Op::Vpcmpeqd | Op::Vpcmpeqq => {
+                lanes(&x, &y, len, width, |a, b| mask(a == b))
+            }
+            Op::Vpcmpgtb | Op::Vpcmpgtw | Op::Vpcmpgtd | Op::Vpcmpgtq => {
+                lanes(&x, &y, len, width, signed(width, |a, b| -((a > b) as i64)))
+            }
+            Op::Vpcmpb | Op::Vpcmpw | Op::Vpcmpd | Op::Vpcmpq => lanes(
+                &x,
+                &y,
+                len,
+                width,
+                signed(width, |a, b| -(compare(imm, a, b) as i64)),
+            ),
+            Op::Vpcmpub | Op::Vpcmpuw | Op::Vpcmpud | Op::Vpcmpuq => {
+                lanes(&x, &y, len, width, |a, b| mask(compare(imm, a, b)))
+            }
+            Op::Vptestmb | Op::Vptestmw | Op::Vptestmd | Op::Vptestmq => {
+                lanes(&x, &y, len, width, |a, b| mask(a & b != 0))
+            }
+            Op::Vptestnmb | Op::Vptestnmw | Op::Vptestnmd | Op::Vptestnmq => {
+                lanes(&x, &y, len, width, |a, b| mask(a & b == 0))
+            }
+            // the sign bits become the mask below
+            Op::Vpmovb2m | Op::Vpmovw2m | Op::Vpmovd2m | Op::Vpmovq2m => x,
+            Op::Vpmovm2b | Op::Vpmovm2w | Op::Vpmovm2d | Op::Vpmovm2q => {
+                let bits = lane(&x, 8, 0);
+                let mut result = [0; 64];
+                for i in 0..len / width {
+                    set_lane(&mut result, width, i, mask(bits >> i & 1 != 0));
+                }
+                result
+            }
+            // the opmask picks the source instead of masking the result
+            Op::Vpblendmb
+            | Op::Vpblendmw
+            | Op::Vpblendmd
+            | Op::Vpblendmq
+            | Op::Vblendmps
+            | Op::Vblendmpd => {
+                let k = self.opmask(instr);
+                let zeroing = instr.prefixes.evex.is_some_and(|x| x.zeroing);
+                let mut result = [0; 64];
+                for i in 0..len / width {
+                    let value = if k >> i & 1 != 0 {
+                        lane(&y, width, i)
+                    } else if zeroing {
+                        0
+                    } else {
+                        lane(&x, width, i)
+                    };
+                    set_lane(&mut result, width, i, value);
+                }
+                return self.write_vector(dst, &result);
+            }
+            Op::Vpminub | Op::Vpminuw | Op::Vpminud | Op::Vpminuq => {
+                lanes(&x, &y, len, width, u64::min)
+            }
+            Op::Vpmaxub | Op::Vpmaxuw | Op::Vpmaxud | Op::Vpmaxuq => {
+                lanes(&x, &y, len, width, u64::max)
+            }
+            Op::Vpminsb | Op::Vpminsw | Op::Vpminsd | Op::Vpminsq => {
+                lanes(&x, &y, len, width, signed(width, i64::min))
+            }
+            Op::Vpmaxsb | Op::Vpmaxsw | Op::Vpmaxsd | Op::Vpmaxsq => {
+                lanes(&x, &y, len, width, signed(width, i64::max))
+            }
+            Op::Vpunpcklbw | Op::Vpunpcklwd | Op::Vpunpckldq | Op::Vpunpcklqdq => {
+                unpack(&x, &y, len, width, false)
+            }
+            Op::Vpunpckhbw | Op::Vpunpckhwd | Op::Vpunpckhdq | Op::Vpunpckhqdq => {
+                unpack(&x, &y, len, width, true)
+            }
+            // the saturated elements of the first source and then of the second, in each 128
+            // bit block
+            Op::Packsswb | Op::Packssdw | Op::Packuswb | Op::Packusdw => {
+                let half = width / 2;
+                let per_block = 16 / width;
+                let signed = !matches!(instr.op, Op::Packuswb | Op::Packusdw);
+                let mut result = [0; 64];
+                for block in 0..len / 16 {
+                    for (k, src) in [&x, &y].into_iter().enumerate() {
+                        for i in 0..per_block {
+                            let value = sext(lane(src, width, block * per_block + i), width);
+                            let j = (2 * block + k) * per_block + i;
+                            set_lane(&mut result, half, j, saturate(value, half, signed) as u64);
+                        }
+                    }
+                }
+                result
+            }
+
+            Op::Vpshufb => {
+                let mut result = [0; 64];
+                for i in 0..len {
+                    let block = i & !15;
+                    let select = y[i];
+                    if select & 0x80 == 0 {
+                        result[i] = x[block + (select & 15) as usize];
+                    }
+                }
+                result
+            }
+            Op::Vpshufd => {
+                let mut result = [0; 64];
+                for i in 0..len / 4 {
+                    let select = (imm >> (2 * (i % 4))) & 3;
+                    set_lane(&mut result, 4, i, lane(&x, 4, i & !3 | select as usize));
+                }
+                result
+            }
+            // pshufd for the words of one half, the other half is copied
+            Op::Pshuflw | Op::Pshufhw => {
+                let start = if instr.op == Op::Pshufhw { 4 } else { 0 };
+                let mut result = x;
+                for i in 0..4 {
+                    let select = (imm >> (2 * i)) & 3;
+                    set_lane(
+                        &mut result,
+                        2,
+                        start + i,
+                        lane(&x, 2, start + select as usize),
+                    );
+                }
+                result
+            }
+            // the low half of the result comes from the first source, the high half from the
+            // second
+            Op::Shufps | Op::Shufpd => {
+                let n = 16 / width;
+                let mut result = [0; 64];
+                for i in 0..n {
+                    let src = if i < n / 2 { &x } else { &y };
+                    let select = imm as usize >> (i * 8 / width) & (n - 1);
+                    set_lane(&mut result, width, i, lane(src, width, select));
+                }
+                result
+            }
+            // the bytes of first:second shifted right by imm, in each 128 bit block
+            Op::Palignr => {
+                let mut result = [0; 64];
+                for block in (0..len).step_by(16) {
+                    for i in 0..16 {
+                        let j = i + imm as usize;
+                        result[block + i] = match j {
+                            0..16 => y[block + j],
+                            16..32 => x[block + j - 16],
+                            _ => 0,
+                        };
+                    }
+                }
+                result
+            }
+            Op::Vpsllw
+            | Op::Vpsrlw
+            | Op::Vpsraw
+            | Op::Vpslld
+            | Op::Vpsrld
+            | Op::Vpsrad
+            | Op::Vpsraq
+            | Op::Vpsllq
+            | Op::Vpsrlq => {
+                // an immediate count, or the low qword of the second source
+                let count = match b {
+                    Operand::Imm { .. } => imm as u64,
+                    _ => lane(&y, 8, 0),
+                };
+                lanes(&x, &x, len, width, |a, _| shift(instr.op, a, width, count))
+            }
+            Op::Vpsllvd | Op::Vpsllvq | Op::Vpsrlvd | Op::Vpsrlvq | Op::Vpsravd | Op::Vpsravq => {
+                lanes(&x, &y, len, width, |a, count| {
+                    shift(instr.op, a, width, count)
+                })
+            }
+            Op::Vpslldq | Op::Vpsrldq => {
+                let count = (imm as usize).min(16);
+                let mut result = [0; 64];
+                for block in (0..len).step_by(16) {
+                    let (src, dst) = (&x[block..block + 16], &mut result[block..block + 16]);
+                    if instr.op == Op::Vpslldq {
+                        dst[count..].copy_from_slice(&src[..16 - count]);
+                    } else {
+                        dst[..16 - count].copy_from_slice(&src[count..]);
+                    }
+                }
+                result
+            }
+
+            Op::Vpbroadcastb
+            | Op::Vpbroadcastw
+            | Op::Vpbroadcastd
+            | Op::Vpbroadcastq
+            | Op::Vbroadcastss
+            | Op::Vbroadcastsd
+            | Op::Vbroadcasti128
+            | Op::Vbroadcastf128
+            | Op::Vbroadcasti32x4
+            | Op::Vbroadcastf32x4
+            | Op::Vbroadcasti64x4
+            | Op::Vbroadcastf64x4 => {
+                let block = match instr.op {
+                    Op::Vbroadcasti128
+                    | Op::Vbroadcastf128
+                    | Op::Vbroadcasti32x4
+                    | Op::Vbroadcastf32x4 => 16,
+                    Op::Vbroadcasti64x4 | Op::Vbroadcastf64x4 => 32,
+                    _ => width,
+                };
+                let mut result = [0; 64];
+                for chunk in result[..len].chunks_mut(block) {
+                    chunk.copy_from_slice(&x[..block]);
+                }
+                result
+            }
+            Op::Vinserti128
+            | Op::Vinsertf128
+            | Op::Vinserti32x4
+            | Op::Vinsertf32x4
+            | Op::Vinserti64x4
+            | Op::Vinsertf64x4 => {
+                let part = size_of(b);
+                let start = part * (imm as usize % (len / part));
+                let mut result = x;
+                result[start..start + part].copy_from_slice(&y[..part]);
+                result
+            }
+            Op::Vextracti128
+            | Op::Vextractf128
+            | Op::Vextracti32x4
+            | Op::Vextractf32x4
+            | Op::Vextracti64x4
+            | Op::Vextractf64x4 => {
+                let start = len * (imm as usize % (size_of(a) / len));
+                let mut result = [0; 64];
+                result[..len].copy_from_slice(&x[start..start + len]);
+                result
+            }
+            Op::Vperm2i128 | Op::Vperm2f128 => {
+                let mut result = [0; 64];
+                for half in 0..2 {
+                    let select = imm >> (4 * half);
+                    // bit 3 zeroes the half instead
+                    if select & 8 == 0 {
+                        let src = if select & 2 == 0 { &x } else { &y };
+                        let start = 16 * (select & 1) as usize;
+                        result[16 * half..16 * half + 16].copy_from_slice(&src[start..start + 16]);
+                    }
+                }
+                result
+            }
+            // the low half of the 128 bit blocks comes from the first source, the high half
+            // from the second
+            Op::Vshufi32x4 | Op::Vshuff32x4 | Op::Vshufi64x2 | Op::Vshuff64x2 => {
+                let blocks = len / 16;
+                let bits = blocks / 2;
+                let mut result = [0; 64];
+                for i in 0..blocks {
+                    let src = if i < blocks / 2 { &x } else { &y };
+                    let select = (imm as usize >> (i * bits)) & (blocks / 2 * 2 - 1);
+                    result[16 * i..16 * i + 16]
+                        .copy_from_slice(&src[16 * select..16 * select + 16]);
+                }
+                result
+            }
+            // the elements of second:first shifted right by imm
+            Op::Valignd | Op::Valignq => {
+                let n = len / width;
+                let start = imm as usize & (n - 1);
+                let mut result = [0; 64];
+                for i in 0..n {
+                    let j = start + i;
+                    let value = if j < n {
+                        lane(&y, width, j)
+                    } else {
+                        lane(&x, width, j - n)
+                    };
+                    set_lane(&mut result, width, i, value);
+                }
+                result
+            }
+            // vpermq with an immediate shuffles within each 256 bit block
+            Op::Vpermq | Op::Vpermpd if matches!(b, Operand::Imm { .. }) => {
+                let mut result = [0; 64];
+                for i in 0..len / 8 {
+                    let select = (imm >> (2 * (i % 4))) & 3;
+                    set_lane(&mut result, 8, i, lane(&x, 8, i & !3 | select as usize));
+                }
+                result
+            }
+            // the first source has the indices
+            Op::Vpermd | Op::Vpermps | Op::Vpermq | Op::Vpermpd => {
+                let n = len / width;
+                let mut result = [0; 64];
+                for i in 0..n {
+                    let select = lane(&x, width, i) as usize & (n - 1);
+                    set_lane(&mut result, width, i, lane(&y, width, select));
+                }
+                result
+            }
+            // two tables with the indices in the first source for t2, in the destination for i2
+            Op::Vpermt2d
+            | Op::Vpermt2q
+            | Op::Vpermt2ps
+            | Op::Vpermt2pd
+            | Op::Vpermi2d
+            | Op::Vpermi2q
+            | Op::Vpermi2ps
+            | Op::Vpermi2pd => {
+                let d = self.read_vector(dst)?;
+                let t2 = matches!(
+                    instr.op,
+                    Op::Vpermt2d | Op::Vpermt2q | Op::Vpermt2ps | Op::Vpermt2pd
+                );
+                let (indices, low) = if t2 { (&x, &d) } else { (&d, &x) };
+                let n = len / width;
+                let mut result = [0; 64];
+                for i in 0..n {
+                    let select = lane(indices, width, i) as usize & (2 * n - 1);
+                    let value = if select < n {
+                        lane(low, width, select)
+                    } else {
+                        lane(&y, width, select - n)
+                    };
+                    set_lane(&mut result, width, i, value);
+                }
+                result
+            }
+            // imm picks the elements of the second source, pblendw repeats it for each 128 bit
+            // block
+            Op::Vpblendd | Op::Blendpd | Op::Pblendw => {
+                let mut result = x;
+                for i in 0..len / width {
+                    if imm >> (i % 8) & 1 != 0 {
+                        set_lane(&mut result, width, i, lane(&y, width, i));
+                    }
+                }
+                result
+            }
+            Op::Vpblendvb | Op::Vblendvps | Op::Vblendvpd => {
+                let selector = self.read_vector(c)?;
+                let mut result = x;
+                for i in 0..len / width {
+                    if sext(lane(&selector, width, i), width) < 0 {
+                        set_lane(&mut result, width, i, lane(&y, width, i));
+                    }
+                }
+                result
+            }
+
+            Op::Vcvtdq2ps => convert(&x, len / 4, 4, 4, |a| int_to_float(a as i32 as i64, 4, env)),
+            Op::Cvtps2dq | Op::Vcvttps2dq => {
+                let env = if instr.op == Op::Cvtps2dq {
+                    env
+                } else {
+                    Env {
+                        rounding: Rounding::Zero,
+                        ..env
+                    }
+                };
+                convert(&x, len / 4, 4, 4, |a| float_to_int(a, 4, 4, env))
+            }
+            Op::Cvtpd2dq | Op::Cvttpd2dq => {
+                let env = if instr.op == Op::Cvtpd2dq {
+                    env
+                } else {
+                    Env {
+                        rounding: Rounding::Zero,
+                        ..env
+                    }
+                };
+                convert(&x, len / 8, 8, 4, |a| float_to_int(a, 8, 4, env))
+            }
+            Op::Cvtdq2pd => convert(&x, len / 8, 4, 8, |a| (a as i32 as f64).to_bits()),
+            Op::Cvtps2pd => convert(&x, len / 8, 4, 8, |a| widen(a, env)),
+            Op::Cvtpd2ps => convert(&x, len / 8, 8, 4, |a| narrow(a, env)),
+            Op::Pmovsxbw
+            | Op::Pmovsxbd
+            | Op::Pmovsxbq
+            | Op::Pmovsxwd
+            | Op::Pmovsxwq
+            | Op::Pmovsxdq
+            | Op::Pmovzxbw
+            | Op::Pmovzxbd
+            | Op::Pmovzxbq
+            | Op::Pmovzxwd
+            | Op::Pmovzxwq
+            | Op::Pmovzxdq => {
+                let (from, to) = match instr.op {
+                    Op::Pmovsxbw | Op::Pmovzxbw => (1, 2),
+                    Op::Pmovsxbd | Op::Pmovzxbd => (1, 4),
+                    Op::Pmovsxbq | Op::Pmovzxbq => (1, 8),
+                    Op::Pmovsxwd | Op::Pmovzxwd => (2, 4),
+                    Op::Pmovsxwq | Op::Pmovzxwq => (2, 8),
+                    _ => (4, 8),
+                };
+                let signed = matches!(
+                    instr.op,
+                    Op::Pmovsxbw
+                        | Op::Pmovsxbd
+                        | Op::Pmovsxbq
+                        | Op::Pmovsxwd
+                        | Op::Pmovsxwq
+                        | Op::Pmovsxdq
+                );
+                convert(&x, len / to, from, to, |a| {
+                    if signed {
+                        sext(a, from) as u64
+                    } else {
+                        a
+                    }
+                })
+            }
+            // imm picks the rounding mode, unless bit 2 says to use MXCSR's, and bit 3 keeps an
+            // inexact result from raising PE
+            Op::Roundps | Op::Roundpd => {
+                let env = if imm & 4 != 0 {
+                    env
+                } else {
+                    Env {
+                        rounding: rounding_control(imm as u32),
+                        ..env
+                    }
+                };
+                lanes(&x, &x, len, width, |a, _| {
+                    round_float(a, width, env, imm & 8 == 0)
+                })
+            }
+            Op::Roundss | Op::Roundsd => {
+                let env = if imm & 4 != 0 {
+                    env
+                } else {
+                    Env {
+                        rounding: rounding_control(imm as u32),
+                        ..env
+                    }
+                };
+                count = 1;
+                let mut result = x;
+                set_lane(
+                    &mut result,
+                    width,
+                    0,
+                    round_float(lane(&y, width, 0), width, env, imm & 8 == 0),
+                );
+                result
+            }
+            // the scalar conversions into a vector merge into the first source
+            Op::Cvtsi2ss | Op::Cvtsi2sd | Op::Cvtss2sd | Op::Cvtsd2ss => {
+                let (value, to) = match instr.op {
+                    Op::Cvtss2sd => (widen(lane(&y, 4, 0), env), 8),
+                    Op::Cvtsd2ss => (narrow(lane(&y, 8, 0), env), 4),
+                    _ => {
+                        let size = size_of(b);
+                        let value = sext(lane(&y, size, 0), size);
+                        (int_to_float(value, width, env), width)
+                    }
+                };
+                count = 1;
+                let mut result = x;
+                set_lane(&mut result, to, 0, value);
+                result
+            }
+            Op::Cvtss2si | Op::Cvtsd2si | Op::Cvttss2si | Op::Cvttsd2si => {
+                let env = if matches!(instr.op, Op::Cvtss2si | Op::Cvtsd2si) {
+                    env
+                } else {
+                    Env {
+                        rounding: Rounding::Zero,
+                        ..env
+                    }
+                };
+                let value = float_to_int(lane(&x, width, 0), width, instr.size.bytes(), env);
+                return self.write(dst, value);
+            }
+
+            Op::Cmpps | Op::Cmppd => lanes(&x, &y, len, width, |a, b| {
+                mask(float_compare(imm, width, a, b, env))
+            }),
+            // even elements subtract, odd ones add
+            Op::Addsubps | Op::Addsubpd => {
+                let mut result = [0; 64];
+                for i in 0..len / width {
+                    let kind = if i % 2 == 0 { Float::Sub } else { Float::Add };
+                    let value = float(kind, width, lane(&x, width, i), lane(&y, width, i), env);
+                    set_lane(&mut result, width, i, value);
+                }
+                result
+            }
+            Op::Haddps | Op::Haddpd | Op::Hsubps | Op::Hsubpd => {
+                let kind = if matches!(instr.op, Op::Haddps | Op::Haddpd) {
+                    Float::Add
+                } else {
+                    Float::Sub
+                };
+                horizontal(&x, &y, width, |a, b| float(kind, width, a, b, env))
+            }
+            // the high bits of imm pick the products that get added up, each rounded on its
+            // own, and the low bits pick the elements that get the sum
+            Op::Dpps | Op::Dppd => {
+                let n = 16 / width;
+                let product = |i: usize| {
+                    if imm >> (4 + i) & 1 != 0 {
+                        float(
+                            Float::Mul,
+                            width,
+                            lane(&x, width, i),
+                            lane(&y, width, i),
+                            env,
+                        )
+                    } else {
+                        0
+                    }
+                };
+                let add = |a, b| float(Float::Add, width, a, b, env);
+                // every lane sums the products in its own order, which only shows in the NaN it
+                // returns: dpps adds neighbours and then the other half, dppd adds its own first
+                let sum = |i: usize| {
+                    if n == 4 {
+                        let pair = |i: usize| add(product(i ^ 1), product(i));
+                        add(pair(i), pair(i ^ 2))
+                    } else {
+                        add(product(i), product(i ^ 1))
+                    }
+                };
+                let mut result = [0; 64];
+                for i in 0..n {
+                    // the sum raises its exceptions even when no element gets it
+                    let value = sum(i);
+                    if imm >> i & 1 != 0 {
+                        set_lane(&mut result, width, i, value);
+                    }
+                }
+                result
+            }
+            Op::Cmpss | Op::Cmpsd => {
+                count = 1;
+                let holds = float_compare(imm, width, lane(&x, width, 0), lane(&y, width, 0), env);
+                let mut result = x;
+                set_lane(&mut result, width, 0, mask(holds));
+                result
+            }
+            // the smallest unsigned word and the index of the first one that has it
+            Op::Phminposuw => {
+                let (index, min) = (0..8)
+                    .map(|i| (i, lane(&x, 2, i)))
+                    .min_by_key(|&(_, value)| value)
+                    .unwrap();
+                let mut result = [0; 64];
+                set_lane(&mut result, 2, 0, min);
+                set_lane(&mut result, 2, 1, index as u64);
+                result
+            }
+
+            // The rest only set flags or write somewhere else, and read their first operand
+            // instead.
+
+            // ZF says whether the sources have no bits in common, CF whether the second one has
+            // no bits the first doesn't have, the other flags are cleared
+            Op::Vptest => {
+                let d = self.read_vector(dst)?;
+                let f = &mut self.regs.flags;
+                f.zf = (0..len).all(|i| d[i] & x[i] == 0);
+                f.cf = (0..len).all(|i| !d[i] & x[i] == 0);
+                (f.of, f.sf, f.af, f.pf) = (false, false, false, false);
+                return Ok(());
+            }
+            // unordered sets ZF, PF and CF, less than CF and equal ZF, the other flags are
+            // cleared
+            Op::Comiss | Op::Comisd | Op::Ucomiss | Op::Ucomisd => {
+                let a = lane(&self.read_vector(dst)?, width, 0);
+                let b = lane(&x, width, 0);
+                // comis signals for a quiet NaN too
+                let signaling = matches!(instr.op, Op::Comiss | Op::Comisd);
+                let (a, b) = compare_operands(width, a, b, signaling, env);
+                let unordered = a.is_nan() || b.is_nan();
+                let f = &mut self.regs.flags;
+                f.zf = a == b || unordered;
+                f.pf = unordered;
+                f.cf = a < b || unordered;
+                (f.of, f.sf, f.af) = (false, false, false);
+                return Ok(());
+            }
+            // The e forms have the string lengths in rax and rdx, the i forms end them at a
+            // zero element. The index goes into ecx, the mask into xmm0. CF says whether
+            // anything matched, ZF and SF whether the second and first string end early and OF
+            // is the first bit of the result.
+            Op::Pcmpestri | Op::Pcmpestrm | Op::Pcmpistri | Op::Pcmpistrm => {
+                let first = self.read_vector(dst)?;
+                let width = if imm & 1 == 0 { 1 } else { 2 };
+                let n = 16 / width;
+                let explicit = matches!(instr.op, Op::Pcmpestri | Op::Pcmpestrm);
+                let length = |v: &Vector, reg: R64| {
+                    if explicit {
+                        let value = self.regs[reg].r64();
+                        let value = if instr.prefixes.rex().w() {
+                            value as i64
+                        } else {
+                            value as i32 as i64
+                        };
+                        (value.unsigned_abs() as usize).min(n)
+                    } else {
+                        (0..n).find(|&i| lane(v, width, i) == 0).unwrap_or(n)
+                    }
+                };
+                let (a_len, b_len) = (length(&first, R64::RAX), length(&x, R64::RDX));
+                let result = compare_strings(imm, &first, a_len, &x, b_len);
+
+                if matches!(instr.op, Op::Pcmpestri | Op::Pcmpistri) {
+                    // imm bit 6 picks the last match instead of the first
+                    let index = if result == 0 {
+                        n as u32
+                    } else if imm & 0x40 == 0 {
+                        result.trailing_zeros()
+                    } else {
+                        63 - result.leading_zeros()
+                    };
+                    self.regs[R64::RCX].set_r64(index as u64);
+                } else {
+                    // imm bit 6 expands the bits into elements
+                    let mut value = [0; 64];
+                    if imm & 0x40 == 0 {
+                        set_lane(&mut value, 2, 0, result);
+                    } else {
+                        for i in 0..n {
+                            set_lane(&mut value, width, i, mask(result >> i & 1 != 0));
+                        }
+                    }
+                    self.regs.zmm[0][..16].copy_from_slice(&value[..16]);
+                }
+                let f = &mut self.regs.flags;
+                f.cf = result != 0;
+                f.zf = b_len < n;
+                f.sf = a_len < n;
+                f.of = result & 1 != 0;
+                (f.af, f.pf) = (false, false);
+                return Ok(());
+            }
+
+            _ => {
+                return Err(EmulatorError::UnimplementedInstruction {
+                    address: self.instr_ip,
+                    instr: instr.to_string(),
+                })
+            }
+        };
+
+        // compares and vpmov*2m set one opmask bit per element from its sign
+        if let Operand::Mask(_) = dst {
+            let bits = (0..len / width).fold(0, |acc, i| {
+                acc | (lane(&result, width, i) >> (width * 8 - 1)) << i
+            });
+            return self.write(dst, bits & self.opmask(instr));
+        }
+        self.write_masked(instr, dst, &result, width, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Rounding::*;
+
+    const UE_PE: u16 = UNDERFLOW | PRECISION;
+    const OE_PE: u16 = OVERFLOW | PRECISION;
+
+    /// Runs `f` with MXCSR's rounding and FTZ, giving the result and the exceptions it raised.
+    fn run(rounding: Rounding, ftz: bool, f: impl Fn(Env) -> u64) -> (u64, u16) {
+        let exceptions = Cell::new(0);
+        let env = Env {
+            rounding,
+            daz: false,
+            ftz,
+            exceptions: &exceptions,
+        };
+        (f(env), exceptions.get())
+    }
+
+    #[test]
+    fn tininess_after_rounding() {
+        // (1 - 2^-24) * 2^-126 rounds to the smallest normal, but it's one step below it with
+        // an unbounded exponent, so it's tiny in every mode
+        let mul = |r, ftz| {
+            run(r, ftz, |env| {
+                float(Float::Mul, 4, 0x3f7f_ffff, 0x0080_0000, env)
+            })
+        };
+        assert_eq!(mul(Nearest, false), (0x0080_0000, UE_PE));
+        assert_eq!(mul(Down, false), (0x007f_ffff, UE_PE));
+        assert_eq!(mul(Up, false), (0x0080_0000, UE_PE));
+        assert_eq!(mul(Zero, false), (0x007f_ffff, UE_PE));
+        assert_eq!(mul(Nearest, true), (0, UE_PE));
+        let neg = |r, ftz| {
+            run(r, ftz, |env| {
+                float(Float::Mul, 4, 0xbf7f_ffff, 0x0080_0000, env)
+            })
+        };
+        assert_eq!(neg(Down, true), (0x8000_0000, UE_PE));
+
+        // just above the boundary it's only inexact, and FTZ leaves it alone
+        let mul = |r, ftz| {
+            run(r, ftz, |env| {
+                float(Float::Mul, 4, 0x3f7f_ffff, 0x0080_0001, env)
+            })
+        };
+        assert_eq!(mul(Nearest, false), (0x0080_0000, PRECISION));
+        assert_eq!(mul(Up, false), (0x0080_0001, PRECISION));
+        assert_eq!(mul(Zero, false), (0x0080_0000, PRECISION));
+        assert_eq!(mul(Nearest, true), (0x0080_0000, PRECISION));
+
+        // (1 - 2^-30) * 2^-126 is within half a step of the smallest normal, so it depends on
+        // the rounding
+        let narrow = |r, ftz| run(r, ftz, |env| narrow(0x380f_ffff_ffc0_0000, env));
+        assert_eq!(narrow(Nearest, false), (0x0080_0000, PRECISION));
+        assert_eq!(narrow(Up, false), (0x0080_0000, PRECISION));
+        assert_eq!(narrow(Down, false), (0x007f_ffff, UE_PE));
+        assert_eq!(narrow(Zero, true), (0, UE_PE));
+        assert_eq!(narrow(Nearest, true), (0x0080_0000, PRECISION));
+
+        // the same in double precision, (1 - 2^-53) * 2^-1022 and a tie that rounds up to it
+        let mul = |r, ftz| {
+            run(r, ftz, |env| {
+                float(
+                    Float::Mul,
+                    8,
+                    0x3fef_ffff_ffff_ffff,
+                    0x0010_0000_0000_0000,
+                    env,
+                )
+            })
+        };
+        assert_eq!(mul(Nearest, false), (0x0010_0000_0000_0000, UE_PE));
+        assert_eq!(mul(Zero, false), (0x000f_ffff_ffff_ffff, UE_PE));
+        assert_eq!(mul(Nearest, true), (0, UE_PE));
+        let div = |r, ftz| {
+            run(r, ftz, |env| {
+                float(
+                    Float::Div,
+                    8,
+                    0x001f_ffff_ffff_ffff,
+                    0x4000_0000_0000_0000,
+                    env,
+                )
+            })
+        };
+        assert_eq!(div(Nearest, false), (0x0010_0000_0000_0000, UE_PE));
+        assert_eq!(div(Up, false), (0x0010_0000_0000_0000, UE_PE));
+    }
+
+    #[test]
+    fn overflow_after_rounding() {
+        // the largest finite value plus 3/4 of its last place only overflows when it rounds up
+        let add = |r, ftz| {
+            run(r, ftz, |env| {
+                float(Float::Add, 4, 0x7f7f_ffff, 0x7340_0000, env)
+            })
+        };
+        assert_eq!(add(Nearest, false), (0x7f80_0000, OE_PE));
+        assert_eq!(add(Up, false), (0x7f80_0000, OE_PE));
+        assert_eq!(add(Down, false), (0x7f7f_ffff, PRECISION));
+        assert_eq!(add(Zero, false), (0x7f7f_ffff, PRECISION));
+        // a whole last place overflows even rounding toward zero
+        let add = |r, ftz| {
+            run(r, ftz, |env| {
+                float(Float::Add, 4, 0x7f7f_ffff, 0x7380_0000, env)
+            })
+        };
+        assert_eq!(add(Zero, false), (0x7f7f_ffff, OE_PE));
+    }
+}
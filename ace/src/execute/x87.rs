@@ -0,0 +1,505 @@
+//! The x87 register stack around the arithmetic in f80.rs. Empty registers are a stack fault,
+//! pushing onto a full stack too. Exceptions only set their flags in the status word, there's
+//! no #MF, but an unmasked invalid operation, denormal or division by zero leaves the
+//! destination and the stack alone like it would before the fault.
+
+use super::sign_extend;
+use super::vector::rounding_control;
+use crate::error::EmulatorError;
+use crate::f80::{self, Class, Constant, Env, Trig, F80};
+use crate::instr::{Cond, Instr, Operand, Size};
+use crate::opcodes::Op;
+use crate::prefix::Rounding;
+use crate::{DisasmWriter, Emulator, Fpu};
+use std::cmp::Ordering;
+
+const C0: u16 = 1 << 8;
+const C1: u16 = 1 << 9;
+const C2: u16 = 1 << 10;
+const C3: u16 = 1 << 14;
+const CONDITION_CODES: u16 = C0 | C1 | C2 | C3;
+/// with IE, a register that should have been empty wasn't or the other way around
+const STACK_FAULT: u16 = 0x40;
+const ERROR_SUMMARY: u16 = 0x80;
+const BUSY: u16 = 0x8000;
+const EXCEPTIONS: u16 = 0x3f;
+const EMPTY: u16 = 3;
+
+impl Fpu {
+    /// The rounding and precision control.
+    fn env(&self) -> Env {
+        let precision = match self.control >> 8 & 3 {
+            0 => 24,
+            2 => 53,
+            _ => 64,
+        };
+        Env::new(rounding_control(self.control as u32 >> 10), precision)
+    }
+
+    fn physical(&self, i: u8) -> usize {
+        (self.top.wrapping_add(i) & 7) as usize
+    }
+    fn tag(&self, i: u8) -> u16 {
+        self.tags >> (2 * self.physical(i)) & 3
+    }
+    fn set_tag(&mut self, i: u8, tag: u16) {
+        let shift = 2 * self.physical(i);
+        self.tags = self.tags & !(3 << shift) | tag << shift;
+    }
+
+    /// st(i), `None` if it's empty.
+    fn get(&self, i: u8) -> Option<F80> {
+        (self.tag(i) != EMPTY).then(|| self.st[self.physical(i)])
+    }
+    fn set(&mut self, i: u8, value: F80) {
+        self.st[self.physical(i)] = value;
+        self.set_tag(i, value.tag());
+    }
+    fn pop(&mut self) {
+        self.set_tag(0, EMPTY);
+        self.top = (self.top + 1) & 7;
+    }
+    /// Pushes `value`, or the indefinite when it's `None` because it came from an empty register.
+    fn push(&mut self, value: Option<F80>) {
+        // the register below st0 has to be empty
+        let full = self.tag(7) != EMPTY;
+        if (full || value.is_none()) && !self.stack_fault(full) {
+            return;
+        }
+        self.top = (self.top + 7) & 7;
+        self.set(0, value.filter(|_| !full).unwrap_or(F80::INDEFINITE));
+    }
+    /// Makes st0 `first` and pushes `second` on top of it, for the instructions that return two
+    /// results. The caller checks the stack has room.
+    fn push_pair(&mut self, first: F80, second: F80) {
+        self.set(0, first);
+        self.top = (self.top + 7) & 7;
+        self.set(0, second);
+    }
+
+    fn set_codes(&mut self, codes: u16) {
+        self.status = self.status & !CONDITION_CODES | codes;
+    }
+    /// Sets ES and B when an exception that's raised isn't masked.
+    fn summarize(&mut self) {
+        if self.status & !self.control & EXCEPTIONS != 0 {
+            self.status |= ERROR_SUMMARY | BUSY;
+        } else {
+            self.status &= !(ERROR_SUMMARY | BUSY);
+        }
+    }
+    /// Adds the exceptions of an operation to the status word, C1 says whether it rounded up.
+    /// Returns whether the result should be stored.
+    fn report(&mut self, env: &Env) -> bool {
+        let c1 = if env.rounded_up { C1 } else { 0 };
+        self.status = self.status & !C1 | c1 | env.exceptions;
+        self.summarize();
+        let faults = f80::INVALID | f80::DENORMAL | f80::ZERO_DIVIDE;
+        env.exceptions & !self.control & faults == 0
+    }
+    /// A stack overflow or underflow, which C1 tells apart. Returns whether it's masked, and the
+    /// indefinite should go where the result would have.
+    fn stack_fault(&mut self, overflow: bool) -> bool {
+        let c1 = if overflow { C1 } else { 0 };
+        self.status = self.status & !C1 | c1 | f80::INVALID | STACK_FAULT;
+        self.summarize();
+        self.control & f80::INVALID != 0
+    }
+
+    /// fldcw, which can unmask exceptions that are already raised. Bit 6 always reads as 1,
+    /// bit 7 and bits 13 to 15 as 0.
+    pub(super) fn load_control(&mut self, value: u64) {
+        self.control = value as u16 & 0x1f3f | 0x40;
+        self.summarize();
+    }
+    /// fnclex, clearing the exceptions with the stack fault, ES and B.
+    pub(super) fn clear_exceptions(&mut self) {
+        self.status &= !(EXCEPTIONS | STACK_FAULT | ERROR_SUMMARY | BUSY);
+    }
+    pub(super) fn free(&mut self, st: Operand) {
+        let Operand::St(st) = st else {
+            unreachable!("ffree of {:?}", st)
+        };
+        self.set_tag(st.0, EMPTY);
+    }
+    /// fincstp and fdecstp, which only rotate the stack and don't touch the tags.
+    pub(super) fn rotate(&mut self, by: u8) {
+        self.top = self.top.wrapping_add(by) & 7;
+        self.status &= !C1;
+    }
+}
+
+fn codes(c3: bool, c2: bool, c1: bool, c0: bool) -> u16 {
+    let bits = [(c0, C0), (c1, C1), (c2, C2), (c3, C3)];
+    bits.iter()
+        .fold(0, |x, &(set, bit)| if set { x | bit } else { x })
+}
+
+/// Whether an instruction's memory operand is an integer.
+fn is_integer(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Fiadd
+            | Op::Fisub
+            | Op::Fisubr
+            | Op::Fimul
+            | Op::Fidiv
+            | Op::Fidivr
+            | Op::Ficom
+            | Op::Ficomp
+            | Op::Fild
+    )
+}
+
+impl<D: DisasmWriter> Emulator<D> {
+    /// The value of a stack register or a memory operand, `None` if the register is empty.
+    fn fpu_source(
+        &self,
+        op: Op,
+        operand: Operand,
+        env: &mut Env,
+    ) -> Result<Option<F80>, EmulatorError> {
+        let mem = match operand {
+            Operand::St(st) => return Ok(self.regs.fpu.get(st.0)),
+            Operand::Mem(mem) => mem,
+            _ => unreachable!("not an x87 operand: {:?}", operand),
+        };
+        let address = self.address(&mem);
+        if mem.size == Size::Tword {
+            let bytes = self.memory.read_bytes(address, 10)?;
+            return Ok(Some(F80::from_bytes(bytes.try_into().unwrap())));
+        }
+        let value = self.memory.read(address, mem.size)?;
+        let value = match mem.size {
+            size if is_integer(op) => F80::from_i64(sign_extend(value, size) as i64),
+            Size::Dword => F80::from_f32(value as u32, env),
+            _ => F80::from_f64(value, env),
+        };
+        Ok(Some(value))
+    }
+
+    /// fadd, fsub, fsubr, fmul, fdiv and fdivr with their popping and integer forms. The memory
+    /// forms work on st0.
+    pub(super) fn fpu_arithmetic(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let op = instr.op;
+        let mut env = self.regs.fpu.env();
+        let (dst, src) = match instr.operands {
+            [Operand::St(dst), src, ..] => (dst.0, src),
+            [src, ..] => (0, src),
+        };
+        let pop = matches!(
+            op,
+            Op::Faddp | Op::Fsubp | Op::Fsubrp | Op::Fmulp | Op::Fdivp | Op::Fdivrp
+        );
+        let a = self.regs.fpu.get(dst);
+        let b = self.fpu_source(op, src, &mut env)?;
+        let fpu = &mut self.regs.fpu;
+        let (Some(a), Some(b)) = (a, b) else {
+            if fpu.stack_fault(false) {
+                fpu.set(dst, F80::INDEFINITE);
+                if pop {
+                    fpu.pop();
+                }
+            }
+            return Ok(());
+        };
+        let result = match op {
+            Op::Fadd | Op::Faddp | Op::Fiadd => a.add(b, false, &mut env),
+            Op::Fsub | Op::Fsubp | Op::Fisub => a.add(b, true, &mut env),
+            Op::Fsubr | Op::Fsubrp | Op::Fisubr => b.add(a, true, &mut env),
+            Op::Fmul | Op::Fmulp | Op::Fimul => a.mul(b, &mut env),
+            Op::Fdiv | Op::Fdivp | Op::Fidiv => a.div(b, &mut env),
+            _ => b.div(a, &mut env),
+        };
+        if fpu.report(&env) {
+            fpu.set(dst, result);
+            if pop {
+                fpu.pop();
+            }
+        }
+        Ok(())
+    }
+
+    /// The fcom and fucom families and ftst set C3, C2 and C0, fcomi and fucomi set ZF, PF and CF
+    /// the same way. Unordered is all three.
+    pub(super) fn fpu_compare(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let op = instr.op;
+        let mut env = self.regs.fpu.env();
+        let fpu = &self.regs.fpu;
+        let (a, b) = match instr.operands {
+            _ if op == Op::Ftst => (fpu.get(0), Some(F80::default())),
+            [Operand::St(a), Operand::St(b), ..] => (fpu.get(a.0), fpu.get(b.0)),
+            [Operand::None, ..] => (fpu.get(0), fpu.get(1)),
+            [src, ..] => (fpu.get(0), self.fpu_source(op, src, &mut env)?),
+        };
+        let quiet = matches!(
+            op,
+            Op::Fucom | Op::Fucomp | Op::Fucompp | Op::Fucomi | Op::Fucomip
+        );
+        let fpu = &mut self.regs.fpu;
+        let ordering = match (a, b) {
+            (Some(a), Some(b)) => {
+                let ordering = a.compare(b, quiet, &mut env);
+                if !fpu.report(&env) {
+                    return Ok(());
+                }
+                ordering
+            }
+            _ if fpu.stack_fault(false) => None,
+            _ => return Ok(()),
+        };
+        let (c3, c2, c0) = match ordering {
+            Some(Ordering::Greater) => (false, false, false),
+            Some(Ordering::Less) => (false, false, true),
+            Some(Ordering::Equal) => (true, false, false),
+            None => (true, true, true),
+        };
+        if matches!(op, Op::Fcomi | Op::Fcomip | Op::Fucomi | Op::Fucomip) {
+            fpu.status &= !C1;
+            let f = &mut self.regs.flags;
+            (f.zf, f.pf, f.cf) = (c3, c2, c0);
+            (f.of, f.sf, f.af) = (false, false, false);
+        } else {
+            fpu.set_codes(codes(c3, c2, false, c0));
+        }
+        let pops = match op {
+            Op::Fcomp | Op::Fucomp | Op::Ficomp | Op::Fcomip | Op::Fucomip => 1,
+            Op::Fcompp | Op::Fucompp => 2,
+            _ => 0,
+        };
+        for _ in 0..pops {
+            self.regs.fpu.pop();
+        }
+        Ok(())
+    }
+
+    /// fld, fild and the constants.
+    pub(super) fn fpu_load(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let mut env = self.regs.fpu.env();
+        let constant = match instr.op {
+            Op::Fld1 => Some(Constant::One),
+            Op::Fldl2t => Some(Constant::Log2Of10),
+            Op::Fldl2e => Some(Constant::Log2OfE),
+            Op::Fldpi => Some(Constant::Pi),
+            Op::Fldlg2 => Some(Constant::Log10Of2),
+            Op::Fldln2 => Some(Constant::Ln2),
+            Op::Fldz => Some(Constant::Zero),
+            _ => None,
+        };
+        let value = match constant {
+            Some(constant) => Some(F80::constant(constant, env.rounding)),
+            None => self.fpu_source(instr.op, instr.operands[0], &mut env)?,
+        };
+        if self.regs.fpu.report(&env) {
+            self.regs.fpu.push(value);
+        }
+        Ok(())
+    }
+
+    /// fst, fstp, fist, fistp and fisttp, which always truncates.
+    pub(super) fn fpu_store(&mut self, instr: &Instr) -> Result<(), EmulatorError> {
+        let op = instr.op;
+        let mut env = self.regs.fpu.env();
+        let pop = matches!(op, Op::Fstp | Op::Fistp | Op::Fisttp);
+        let value = match self.regs.fpu.get(0) {
+            Some(value) => value,
+            None if self.regs.fpu.stack_fault(false) => F80::INDEFINITE,
+            None => return Ok(()),
+        };
+        match instr.operands[0] {
+            Operand::St(st) => self.regs.fpu.set(st.0, value),
+            Operand::Mem(mem) => {
+                let address = self.address(&mem);
+                let bits = mem.size.bytes() as u32 * 8;
+                let stored = match (op, mem.size) {
+                    (Op::Fisttp, _) => Some(value.to_int(bits, Rounding::Zero, &mut env)),
+                    (Op::Fist | Op::Fistp, _) => Some(value.to_int(bits, env.rounding, &mut env)),
+                    (_, Size::Tword) => None,
+                    (_, Size::Dword) => Some(value.to_f32(&mut env) as u64),
+                    _ => Some(value.to_f64(&mut env)),
+                };
+                if !self.regs.fpu.report(&env) {
+                    return Ok(());
+                }
+                match stored {
+                    Some(stored) => self.memory.write(address, mem.size, stored)?,
+                    None => self.memory.write_bytes(address, &value.to_bytes())?,
+                }
+            }
+            other => unreachable!("can't store to {:?}", other),
+        }
+        if pop {
+            self.regs.fpu.pop();
+        }
+        Ok(())
+    }
+
+    /// The instructions that replace st0 with a function of it. fsin and fcos set C2 and leave
+    /// st0 alone when it's too big to reduce.
+    pub(super) fn fpu_unary(&mut self, instr: &Instr) {
+        let mut env = self.regs.fpu.env();
+        let fpu = &mut self.regs.fpu;
+        let Some(x) = fpu.get(0) else {
+            if fpu.stack_fault(false) {
+                fpu.set(0, F80::INDEFINITE);
+            }
+            return;
+        };
+        let result = match instr.op {
+            Op::Fchs => Some(x.neg()),
+            Op::Fabs => Some(x.abs()),
+            Op::Fsqrt => Some(x.sqrt(&mut env)),
+            Op::Frndint => Some(x.round_to_int(&mut env)),
+            Op::F2xm1 => Some(x.exp2_m1(&mut env)),
+            Op::Fsin => x.trig(Trig::Sin, &mut env),
+            _ => x.trig(Trig::Cos, &mut env),
+        };
+        if !fpu.report(&env) {
+            return;
+        }
+        if matches!(instr.op, Op::Fsin | Op::Fcos) {
+            let c2 = if result.is_none() { C2 } else { 0 };
+            fpu.status = fpu.status & !C2 | c2;
+        }
+        if let Some(result) = result {
+            fpu.set(0, result);
+        }
+    }
+
+    /// The instructions that take st0 and st1. fyl2x, fyl2xp1 and fpatan pop st0 and leave their
+    /// result in the new st0, fscale, fprem and fprem1 replace st0.
+    pub(super) fn fpu_binary(&mut self, instr: &Instr) {
+        let op = instr.op;
+        let mut env = self.regs.fpu.env();
+        let fpu = &mut self.regs.fpu;
+        let replaces = matches!(op, Op::Fscale | Op::Fprem | Op::Fprem1);
+        let (Some(x), Some(y)) = (fpu.get(0), fpu.get(1)) else {
+            if fpu.stack_fault(false) {
+                if replaces {
+                    fpu.set(0, F80::INDEFINITE);
+                } else {
+                    fpu.set(1, F80::INDEFINITE);
+                    fpu.pop();
+                }
+            }
+            return;
+        };
+        let mut condition = None;
+        let result = match op {
+            Op::Fyl2x => x.y_log2_x(y, &mut env),
+            Op::Fyl2xp1 => x.y_log2_x_p1(y, &mut env),
+            Op::Fpatan => x.atan2(y, &mut env),
+            Op::Fscale => x.scale(y, &mut env),
+            _ => {
+                let (result, quotient, partial) = x.remainder(y, op == Op::Fprem1, &mut env);
+                let bit = |n: u8| quotient >> n & 1 != 0;
+                condition = Some(if result.class() == Class::Nan {
+                    // there's no quotient, only C2 is cleared
+                    fpu.status & (C0 | C3)
+                } else if partial {
+                    C2
+                } else {
+                    codes(bit(1), false, bit(0), bit(2))
+                });
+                result
+            }
+        };
+        if !fpu.report(&env) {
+            return;
+        }
+        if let Some(condition) = condition {
+            fpu.set_codes(condition);
+        }
+        if replaces {
+            fpu.set(0, result);
+        } else {
+            fpu.set(1, result);
+            fpu.pop();
+        }
+    }
+
+    /// fxtract, fptan and fsincos, which leave two results on the stack. fptan and fsincos set
+    /// C2 and leave st0 alone when it's too big to reduce.
+    pub(super) fn fpu_push_pair(&mut self, instr: &Instr) {
+        let mut env = self.regs.fpu.env();
+        let fpu = &mut self.regs.fpu;
+        let x = fpu.get(0);
+        let full = fpu.tag(7) != EMPTY;
+        let Some(x) = x.filter(|_| !full) else {
+            if fpu.stack_fault(full) {
+                fpu.push_pair(F80::INDEFINITE, F80::INDEFINITE);
+            }
+            return;
+        };
+        let results = match instr.op {
+            Op::Fxtract => Some(x.extract(&mut env)),
+            // a NaN goes in both
+            Op::Fptan => x.trig(Trig::Tan, &mut env).map(|tan| match tan.class() {
+                Class::Nan => (tan, tan),
+                _ => (tan, F80::ONE),
+            }),
+            _ => {
+                let sin = x.trig(Trig::Sin, &mut env);
+                sin.zip(x.trig(Trig::Cos, &mut env))
+            }
+        };
+        if !fpu.report(&env) {
+            return;
+        }
+        if instr.op != Op::Fxtract {
+            let c2 = if results.is_none() { C2 } else { 0 };
+            fpu.status = fpu.status & !C2 | c2;
+        }
+        if let Some((first, second)) = results {
+            fpu.push_pair(first, second);
+        }
+    }
+
+    /// fxam, C3 C2 C0 say what st0 is and C1 has its sign, even when it's empty.
+    pub(super) fn fpu_examine(&mut self) {
+        let fpu = &mut self.regs.fpu;
+        let class = fpu.get(0).map_or(5, |x| x.class() as u8);
+        let sign = fpu.st[fpu.physical(0)].sign();
+        let bit = |n: u8| class >> n & 1 != 0;
+        fpu.set_codes(codes(bit(2), bit(1), sign, bit(0)));
+    }
+
+    pub(super) fn fpu_exchange(&mut self, instr: &Instr) {
+        let Operand::St(st) = instr.operands[0] else {
+            unreachable!("fxch of {:?}", instr.operands[0])
+        };
+        let fpu = &mut self.regs.fpu;
+        let (a, b) = (fpu.get(0), fpu.get(st.0));
+        if (a.is_none() || b.is_none()) && !fpu.stack_fault(false) {
+            return;
+        }
+        fpu.set(0, b.unwrap_or(F80::INDEFINITE));
+        fpu.set(st.0, a.unwrap_or(F80::INDEFINITE));
+        fpu.status &= !C1;
+    }
+
+    /// fcmovcc, the conditions are the unsigned ones fcomi sets up.
+    pub(super) fn fpu_move(&mut self, instr: &Instr) {
+        let cond = match instr.op {
+            Op::Fcmovb => Cond::B,
+            Op::Fcmove => Cond::E,
+            Op::Fcmovbe => Cond::Be,
+            Op::Fcmovu => Cond::P,
+            Op::Fcmovnb => Cond::Ae,
+            Op::Fcmovne => Cond::Ne,
+            Op::Fcmovnbe => Cond::A,
+            _ => Cond::Np,
+        };
+        let Operand::St(src) = instr.operands[1] else {
+            unreachable!("fcmov from {:?}", instr.operands[1])
+        };
+        let taken = self.condition(cond);
+        let fpu = &mut self.regs.fpu;
+        match (fpu.get(0), fpu.get(src.0)) {
+            (Some(_), Some(value)) if taken => fpu.set(0, value),
+            (Some(_), Some(_)) => {}
+            _ if fpu.stack_fault(false) => fpu.set(0, F80::INDEFINITE),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,1252 @@
+//! 80 bit extended precision floating point for the x87, in software so results are rounded the
+//! way the fpu rounds them instead of going through the host's f64. Everything is computed on
+//! exact or wide enough intermediates and rounded once, at the precision the control word asks
+//! for, with the masked response to any exception it raises.
+
+use crate::prefix::Rounding;
+use std::cmp::Ordering;
+
+/// Exception flags, in the order of the status word.
+pub const INVALID: u16 = 0x01;
+pub const DENORMAL: u16 = 0x02;
+pub const ZERO_DIVIDE: u16 = 0x04;
+pub const OVERFLOW: u16 = 0x08;
+pub const UNDERFLOW: u16 = 0x10;
+pub const PRECISION: u16 = 0x20;
+
+const BIAS: i32 = 16383;
+const MAX_EXPONENT: u16 = 0x7fff;
+const INTEGER_BIT: u64 = 1 << 63;
+const QUIET_BIT: u64 = 1 << 62;
+
+/// An x87 register or m80fp, with the integer bit of the significand spelled out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct F80 {
+    pub sign_exponent: u16,
+    pub mantissa: u64,
+}
+
+/// What an operation needs from the control word, and what it reports back for the status word.
+#[derive(Debug, Clone, Copy)]
+pub struct Env {
+    pub rounding: Rounding,
+    /// significand bits the precision control rounds to, 24, 53 or 64
+    pub precision: u32,
+    pub exceptions: u16,
+    /// the result was rounded away from zero, which is what C1 reports
+    pub rounded_up: bool,
+}
+impl Env {
+    pub fn new(rounding: Rounding, precision: u32) -> Env {
+        Env {
+            rounding,
+            precision,
+            exceptions: 0,
+            rounded_up: false,
+        }
+    }
+}
+
+/// What fxam reports, in the order of its C3 C2 C0 codes. Empty registers are up to the caller.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Class {
+    /// unnormals, pseudo-NaNs and pseudo-infinities, which the 387 stopped supporting
+    Unsupported = 0,
+    Nan = 1,
+    Normal = 2,
+    Infinity = 3,
+    Zero = 4,
+    Denormal = 6,
+}
+
+/// A memory format's significand and exponent range.
+#[derive(Debug, Copy, Clone)]
+struct Format {
+    precision: u32,
+    bias: i32,
+    /// the biased exponent of the largest finite numbers
+    max: i32,
+}
+impl Format {
+    fn exponent_bits(self) -> u32 {
+        (self.max as u32 + 2).ilog2()
+    }
+}
+const SINGLE: Format = Format {
+    precision: 24,
+    bias: 127,
+    max: 254,
+};
+const DOUBLE: Format = Format {
+    precision: 53,
+    bias: 1023,
+    max: 2046,
+};
+/// The register format, the precision control can still cut the significand down.
+fn extended(precision: u32) -> Format {
+    Format {
+        precision,
+        bias: BIAS,
+        max: MAX_EXPONENT as i32 - 1,
+    }
+}
+
+/// Rounds away the low `shift` bits of `sig` like `rounding` says, returning what's kept, whether
+/// anything was lost and whether it was rounded away from zero.
+fn round_bits(sig: u128, shift: u32, sign: bool, rounding: Rounding) -> (u128, bool, bool) {
+    if shift == 0 {
+        return (sig, false, false);
+    }
+    let (kept, rest) = if shift >= 128 {
+        (0, sig)
+    } else {
+        (sig >> shift, sig & ((1 << shift) - 1))
+    };
+    let half = match shift {
+        129.. => Ordering::Less,
+        _ => rest.cmp(&(1 << (shift - 1))),
+    };
+    let up = rest != 0
+        && match rounding {
+            Rounding::Nearest | Rounding::Sae => {
+                half == Ordering::Greater || (half == Ordering::Equal && kept & 1 != 0)
+            }
+            Rounding::Down => sign,
+            Rounding::Up => !sign,
+            Rounding::Zero => false,
+        };
+    (kept + up as u128, rest != 0, up)
+}
+
+/// A finite value as `sig * 2^(exp - 127)` with the top bit of `sig` set unless it's zero. The
+/// operations keep whatever falls off the bottom as a sticky 1 in bit 0, so rounding a `Wide`
+/// gives the same result as rounding the exact value.
+#[derive(Debug, Copy, Clone)]
+struct Wide {
+    sign: bool,
+    exp: i32,
+    sig: u128,
+}
+impl Wide {
+    fn new(sign: bool, exp: i32, sig: u128) -> Wide {
+        let shift = sig.leading_zeros();
+        if shift == 128 {
+            return Wide { sign, exp: 0, sig };
+        }
+        Wide {
+            sign,
+            exp: exp - shift as i32,
+            sig: sig << shift,
+        }
+    }
+    fn from_int(sign: bool, x: u128) -> Wide {
+        Wide::new(sign, 127, x)
+    }
+    /// A constant from the top 128 bits of its significand, the rest isn't 0 for any of them.
+    const fn constant(exp: i32, sig: u128) -> Wide {
+        Wide {
+            sign: false,
+            exp,
+            sig: sig | 1,
+        }
+    }
+    fn is_zero(self) -> bool {
+        self.sig == 0
+    }
+    /// Marks a result as rounded, for the logarithms, which the fpu never knows to be exact.
+    fn inexact(self) -> Wide {
+        Wide {
+            sig: self.sig | 1,
+            ..self
+        }
+    }
+    fn neg(self) -> Wide {
+        Wide {
+            sign: !self.sign,
+            ..self
+        }
+    }
+    fn abs(self) -> Wide {
+        Wide {
+            sign: false,
+            ..self
+        }
+    }
+    fn scale(self, n: i32) -> Wide {
+        Wide {
+            exp: self.exp + n,
+            ..self
+        }
+    }
+
+    /// Shifts right, keeping anything shifted out as a sticky bit.
+    fn shift_sticky(sig: u128, shift: u32) -> u128 {
+        match shift {
+            0 => sig,
+            128.. => (sig != 0) as u128,
+            _ => sig >> shift | (sig << (128 - shift) != 0) as u128,
+        }
+    }
+
+    fn add(self, other: Wide) -> Wide {
+        if other.is_zero() {
+            return self;
+        }
+        if self.is_zero() {
+            return other;
+        }
+        let (big, small) = if (self.exp, self.sig) >= (other.exp, other.sig) {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let small_sig = Wide::shift_sticky(small.sig, (big.exp - small.exp) as u32);
+        // bit 0 is sticky, adding two of them mustn't carry it away
+        let sticky = (big.sig | small_sig) & 1;
+        if big.sign == small.sign {
+            let (sum, carry) = (big.sig & !1).overflowing_add(small_sig & !1);
+            if carry {
+                let sig = sum >> 1 | 1 << 127 | sticky;
+                return Wide::new(big.sign, big.exp + 1, sig);
+            }
+            Wide::new(big.sign, big.exp, sum | sticky)
+        } else {
+            Wide::new(big.sign, big.exp, (big.sig - small_sig) | sticky)
+        }
+    }
+    fn sub(self, other: Wide) -> Wide {
+        self.add(other.neg())
+    }
+
+    fn mul(self, other: Wide) -> Wide {
+        if self.is_zero() || other.is_zero() {
+            return Wide::new(self.sign != other.sign, 0, 0);
+        }
+        // the 256 bit product from 64 bit halves
+        let (a1, a0) = (self.sig >> 64, self.sig as u64 as u128);
+        let (b1, b0) = (other.sig >> 64, other.sig as u64 as u128);
+        let low = a0 * b0;
+        let (middle, carry) = (a1 * b0).overflowing_add(a0 * b1);
+        let (low, carry_low) = low.overflowing_add(middle << 64);
+        let high = a1 * b1 + (middle >> 64) + ((carry as u128) << 64) + carry_low as u128;
+        let mut exp = self.exp + other.exp + 1;
+        let (mut high, mut low) = (high, low);
+        if high >> 127 == 0 {
+            high = high << 1 | low >> 127;
+            low <<= 1;
+            exp -= 1;
+        }
+        Wide {
+            sign: self.sign != other.sign,
+            exp,
+            sig: high | (low != 0) as u128,
+        }
+    }
+
+    fn div(self, other: Wide) -> Wide {
+        let sign = self.sign != other.sign;
+        if self.is_zero() {
+            return Wide::new(sign, 0, 0);
+        }
+        let (mut rem, divisor) = (self.sig, other.sig);
+        let mut exp = self.exp - other.exp;
+        let (mut quotient, bits) = if rem >= divisor {
+            rem -= divisor;
+            (1, 127)
+        } else {
+            exp -= 1;
+            (0, 128)
+        };
+        for _ in 0..bits {
+            let carry = rem >> 127 != 0;
+            rem <<= 1;
+            quotient <<= 1;
+            if carry || rem >= divisor {
+                rem = rem.wrapping_sub(divisor);
+                quotient |= 1;
+            }
+        }
+        Wide {
+            sign,
+            exp,
+            sig: quotient | (rem != 0) as u128,
+        }
+    }
+
+    fn from_f80(x: F80) -> Wide {
+        let exp = x.exponent().max(1) as i32 - BIAS;
+        Wide::new(x.sign(), exp + 64, x.mantissa as u128)
+    }
+
+    /// Rounds to `format`, the biased exponent and the significand right aligned. Overflows are
+    /// `format.max + 1` with a zero significand for infinity, or the largest finite number when
+    /// the rounding doesn't go that way.
+    fn round(self, format: Format, env: &mut Env) -> (i32, u64) {
+        env.rounded_up = false;
+        if self.is_zero() {
+            return (0, 0);
+        }
+        let p = format.precision;
+        let biased = self.exp + format.bias;
+        let denormal_shift = (1 - biased).max(0) as u32;
+        let (mut sig, inexact, up) =
+            round_bits(self.sig, 128 - p + denormal_shift, self.sign, env.rounding);
+        // tininess is detected after rounding, like it would be with an unbounded exponent
+        let tiny = biased < 0
+            || biased == 0 && round_bits(self.sig, 128 - p, self.sign, env.rounding).0 >> p == 0;
+        let mut biased = biased.max(1);
+        if sig >> p != 0 {
+            sig >>= 1;
+            biased += 1;
+        }
+        if sig >> (p - 1) == 0 {
+            biased = 0;
+        }
+        if biased > format.max {
+            env.exceptions |= OVERFLOW | PRECISION;
+            let infinity = match env.rounding {
+                Rounding::Nearest | Rounding::Sae => true,
+                Rounding::Down => self.sign,
+                Rounding::Up => !self.sign,
+                Rounding::Zero => false,
+            };
+            env.rounded_up = infinity;
+            if infinity {
+                return (format.max + 1, 0);
+            }
+            return (format.max, (1 << p) - 1);
+        }
+        if inexact {
+            env.exceptions |= PRECISION;
+            if tiny {
+                env.exceptions |= UNDERFLOW;
+            }
+        }
+        env.rounded_up = up;
+        (biased, sig as u64)
+    }
+
+    fn to_f80(self, precision: u32, env: &mut Env) -> F80 {
+        let (biased, sig) = self.round(extended(precision), env);
+        if biased > MAX_EXPONENT as i32 - 1 {
+            return F80::infinity(self.sign);
+        }
+        F80::new(self.sign, biased as u16, sig << (64 - precision))
+    }
+
+    /// Compares magnitudes.
+    fn cmp_abs(self, other: Wide) -> Ordering {
+        match (self.is_zero(), other.is_zero()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => (self.exp, self.sig).cmp(&(other.exp, other.sig)),
+        }
+    }
+}
+
+const PI: Wide = Wide::constant(1, 0xc90fdaa22168c234c4c6628b80dc1cd1);
+const LN_2: Wide = Wide::constant(-1, 0xb17217f7d1cf79abc9e3b39803f2f6af);
+const LOG2_E: Wide = Wide::constant(0, 0xb8aa3b295c17f0bbbe87fed0691d3e88);
+const LOG2_10: Wide = Wide::constant(1, 0xd49a784bcd1b8afe492bf6ff4dafdb4c);
+const LOG10_2: Wide = Wide::constant(-2, 0x9a209a84fbcff7988f8959ac0b7c9178);
+/// tan(pi/8), where atan switches to the pi/4 reduction
+const TAN_PI_8: Wide = Wide::constant(-2, 0xd413cccfe779921165f626cdd52afa7c);
+/// sqrt(2), where the logarithms move the significand down to [sqrt(2)/2, sqrt(2))
+const SQRT_2: Wide = Wide::constant(0, 0xb504f333f9de6484597d89b3754abe9f);
+
+/// The pi that fsin and friends reduce their argument with, pi rounded to 66 bits in units of
+/// 2^-64.
+const REDUCTION_PI: u128 = 0x3_243f_6a88_85a3_08d3;
+
+/// The constants fldpi and friends load.
+#[derive(Debug, Copy, Clone)]
+pub enum Constant {
+    One,
+    Log2Of10,
+    Log2OfE,
+    Pi,
+    Log10Of2,
+    Ln2,
+    Zero,
+}
+
+impl F80 {
+    /// The QNaN an invalid operation produces when it's masked.
+    pub const INDEFINITE: F80 = F80 {
+        sign_exponent: 0xffff,
+        mantissa: 0xc000_0000_0000_0000,
+    };
+    pub const ONE: F80 = F80 {
+        sign_exponent: BIAS as u16,
+        mantissa: INTEGER_BIT,
+    };
+
+    fn new(sign: bool, exponent: u16, mantissa: u64) -> F80 {
+        F80 {
+            sign_exponent: (sign as u16) << 15 | exponent,
+            mantissa,
+        }
+    }
+    fn zero(sign: bool) -> F80 {
+        F80::new(sign, 0, 0)
+    }
+    fn infinity(sign: bool) -> F80 {
+        F80::new(sign, MAX_EXPONENT, INTEGER_BIT)
+    }
+
+    pub fn from_bytes(bytes: [u8; 10]) -> F80 {
+        F80 {
+            mantissa: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            sign_exponent: u16::from_le_bytes([bytes[8], bytes[9]]),
+        }
+    }
+    pub fn to_bytes(self) -> [u8; 10] {
+        let mut bytes = [0; 10];
+        bytes[..8].copy_from_slice(&self.mantissa.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.sign_exponent.to_le_bytes());
+        bytes
+    }
+
+    pub fn sign(self) -> bool {
+        self.sign_exponent >> 15 != 0
+    }
+    fn exponent(self) -> u16 {
+        self.sign_exponent & MAX_EXPONENT
+    }
+    pub fn neg(self) -> F80 {
+        F80 {
+            sign_exponent: self.sign_exponent ^ 0x8000,
+            ..self
+        }
+    }
+    pub fn abs(self) -> F80 {
+        F80 {
+            sign_exponent: self.sign_exponent & MAX_EXPONENT,
+            ..self
+        }
+    }
+
+    pub fn class(self) -> Class {
+        let integer = self.mantissa & INTEGER_BIT != 0;
+        match self.exponent() {
+            0 if self.mantissa == 0 => Class::Zero,
+            0 => Class::Denormal,
+            _ if !integer => Class::Unsupported,
+            MAX_EXPONENT if self.mantissa == INTEGER_BIT => Class::Infinity,
+            MAX_EXPONENT => Class::Nan,
+            _ => Class::Normal,
+        }
+    }
+    fn is_nan(self) -> bool {
+        self.class() == Class::Nan
+    }
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.mantissa & QUIET_BIT == 0
+    }
+    fn quiet(self) -> F80 {
+        F80 {
+            mantissa: self.mantissa | QUIET_BIT,
+            ..self
+        }
+    }
+    /// The tag word's classification: 0 valid, 1 zero, 2 special. Empty is up to the caller.
+    pub fn tag(self) -> u16 {
+        match self.class() {
+            Class::Normal => 0,
+            Class::Zero => 1,
+            _ => 2,
+        }
+    }
+
+    /// The result of an operation on a NaN or an unsupported operand, if there's one.
+    fn nan1(self, env: &mut Env) -> Option<F80> {
+        match self.class() {
+            Class::Unsupported => {
+                env.exceptions |= INVALID;
+                Some(F80::INDEFINITE)
+            }
+            Class::Nan => {
+                if self.is_signaling() {
+                    env.exceptions |= INVALID;
+                }
+                Some(self.quiet())
+            }
+            _ => None,
+        }
+    }
+    /// Like `nan1` for two operands: a QNaN beats an SNaN, otherwise the larger significand
+    /// wins and the positive one when they're the same.
+    fn nan2(self, other: F80, env: &mut Env) -> Option<F80> {
+        if self.class() == Class::Unsupported || other.class() == Class::Unsupported {
+            env.exceptions |= INVALID;
+            return Some(F80::INDEFINITE);
+        }
+        match (self.is_nan(), other.is_nan()) {
+            (false, false) => return None,
+            (true, false) => return self.nan1(env),
+            (false, true) => return other.nan1(env),
+            (true, true) => {}
+        }
+        if self.is_signaling() || other.is_signaling() {
+            env.exceptions |= INVALID;
+        }
+        let larger = match (self.is_signaling(), other.is_signaling()) {
+            (true, false) => other,
+            (false, true) => self,
+            _ => match (self.mantissa << 1).cmp(&(other.mantissa << 1)) {
+                Ordering::Greater => self,
+                Ordering::Less => other,
+                Ordering::Equal if self.sign_exponent < other.sign_exponent => self,
+                Ordering::Equal => other,
+            },
+        };
+        Some(larger.quiet())
+    }
+    /// Raises the denormal operand exception if any of `operands` is one.
+    fn check_denormal(operands: &[F80], env: &mut Env) {
+        if operands.iter().any(|x| x.class() == Class::Denormal) {
+            env.exceptions |= DENORMAL;
+        }
+    }
+
+    pub fn constant(constant: Constant, rounding: Rounding) -> F80 {
+        let value = match constant {
+            Constant::One => return F80::ONE,
+            Constant::Zero => return F80::zero(false),
+            Constant::Log2Of10 => LOG2_10,
+            Constant::Log2OfE => LOG2_E,
+            Constant::Pi => PI,
+            Constant::Log10Of2 => LOG10_2,
+            Constant::Ln2 => LN_2,
+        };
+        // loading a constant doesn't raise the precision exception
+        value.to_f80(64, &mut Env::new(rounding, 64))
+    }
+
+    pub fn from_i64(value: i64) -> F80 {
+        let wide = Wide::from_int(value < 0, value.unsigned_abs() as u128);
+        wide.to_f80(64, &mut Env::new(Rounding::Nearest, 64))
+    }
+
+    fn from_format(bits: u64, format: Format, env: &mut Env) -> F80 {
+        let fraction_bits = format.precision - 1;
+        let sign = bits >> (fraction_bits + format.exponent_bits()) & 1 != 0;
+        let biased = (bits >> fraction_bits) as i32 & (format.max + 1);
+        let fraction = bits & ((1 << fraction_bits) - 1);
+        let mantissa = INTEGER_BIT | fraction << (64 - format.precision);
+        match biased {
+            0 if fraction == 0 => F80::zero(sign),
+            0 => {
+                env.exceptions |= DENORMAL;
+                let exp = 1 - format.bias - fraction_bits as i32;
+                Wide::from_int(sign, fraction as u128)
+                    .scale(exp)
+                    .to_f80(64, env)
+            }
+            _ if biased == format.max + 1 => {
+                let special = F80::new(sign, MAX_EXPONENT, mantissa);
+                if special.is_signaling() {
+                    env.exceptions |= INVALID;
+                    return special.quiet();
+                }
+                special
+            }
+            _ => F80::new(sign, (biased - format.bias + BIAS) as u16, mantissa),
+        }
+    }
+    pub fn from_f32(bits: u32, env: &mut Env) -> F80 {
+        F80::from_format(bits as u64, SINGLE, env)
+    }
+    pub fn from_f64(bits: u64, env: &mut Env) -> F80 {
+        F80::from_format(bits, DOUBLE, env)
+    }
+
+    fn to_format(self, format: Format, env: &mut Env) -> u64 {
+        let fraction_bits = format.precision - 1;
+        let sign_bit = 1 << (fraction_bits + format.exponent_bits());
+        let sign = if self.sign() { sign_bit } else { 0 };
+        let special = (format.max as u64 + 1) << fraction_bits;
+        match self.class() {
+            Class::Unsupported => {
+                env.exceptions |= INVALID;
+                sign_bit | special | 1 << (fraction_bits - 1)
+            }
+            Class::Nan => {
+                if self.is_signaling() {
+                    env.exceptions |= INVALID;
+                }
+                let fraction = (self.quiet().mantissa << 1) >> (64 - fraction_bits);
+                sign | special | fraction
+            }
+            Class::Infinity => sign | special,
+            Class::Zero => sign,
+            Class::Normal | Class::Denormal => {
+                let (biased, sig) = Wide::from_f80(self).round(format, env);
+                let fraction = sig & ((1 << fraction_bits) - 1);
+                sign | (biased as u64) << fraction_bits | fraction
+            }
+        }
+    }
+    pub fn to_f32(self, env: &mut Env) -> u32 {
+        self.to_format(SINGLE, env) as u32
+    }
+    pub fn to_f64(self, env: &mut Env) -> u64 {
+        self.to_format(DOUBLE, env)
+    }
+
+    /// fist and fisttp into a `bits` wide integer, too big and NaNs are the integer indefinite.
+    pub fn to_int(self, bits: u32, rounding: Rounding, env: &mut Env) -> u64 {
+        let indefinite = 1 << (bits - 1);
+        env.rounded_up = false;
+        match self.class() {
+            Class::Zero => return 0,
+            Class::Normal | Class::Denormal => {}
+            _ => {
+                env.exceptions |= INVALID;
+                return indefinite;
+            }
+        }
+        let x = Wide::from_f80(self);
+        if x.exp >= 64 {
+            env.exceptions |= INVALID;
+            return indefinite;
+        }
+        let shift = (127 - x.exp) as u32;
+        let (value, inexact, up) = round_bits(x.sig, shift, x.sign, rounding);
+        let limit = 1u128 << (bits - 1);
+        if value > limit || (value == limit && !x.sign) {
+            env.exceptions |= INVALID;
+            return indefinite;
+        }
+        if inexact {
+            env.exceptions |= PRECISION;
+        }
+        env.rounded_up = up;
+        let value = value as u64;
+        if x.sign {
+            value.wrapping_neg()
+        } else {
+            value
+        }
+    }
+
+    /// fadd, and fsub with `negate` set, which flips `other` after the NaNs are out of the way.
+    pub fn add(self, other: F80, negate: bool, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan2(other, env) {
+            return nan;
+        }
+        let other = if negate { other.neg() } else { other };
+        F80::check_denormal(&[self, other], env);
+        match (self.class(), other.class()) {
+            (Class::Infinity, Class::Infinity) if self.sign() != other.sign() => {
+                env.exceptions |= INVALID;
+                return F80::INDEFINITE;
+            }
+            (Class::Infinity, _) => return self,
+            (_, Class::Infinity) => return other,
+            (Class::Zero, Class::Zero) => {
+                let sign = if self.sign() == other.sign() {
+                    self.sign()
+                } else {
+                    env.rounding == Rounding::Down
+                };
+                return F80::zero(sign);
+            }
+            _ => {}
+        }
+        let sum = Wide::from_f80(self).add(Wide::from_f80(other));
+        if sum.is_zero() {
+            return F80::zero(env.rounding == Rounding::Down);
+        }
+        sum.to_f80(env.precision, env)
+    }
+
+    pub fn mul(self, other: F80, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan2(other, env) {
+            return nan;
+        }
+        F80::check_denormal(&[self, other], env);
+        let sign = self.sign() != other.sign();
+        match (self.class(), other.class()) {
+            (Class::Infinity, Class::Zero) | (Class::Zero, Class::Infinity) => {
+                env.exceptions |= INVALID;
+                return F80::INDEFINITE;
+            }
+            (Class::Infinity, _) | (_, Class::Infinity) => return F80::infinity(sign),
+            _ => {}
+        }
+        if self.class() == Class::Zero || other.class() == Class::Zero {
+            return F80::zero(sign);
+        }
+        Wide::from_f80(self)
+            .mul(Wide::from_f80(other))
+            .to_f80(env.precision, env)
+    }
+
+    pub fn div(self, other: F80, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan2(other, env) {
+            return nan;
+        }
+        let sign = self.sign() != other.sign();
+        match (self.class(), other.class()) {
+            (Class::Infinity, Class::Infinity) | (Class::Zero, Class::Zero) => {
+                env.exceptions |= INVALID;
+                return F80::INDEFINITE;
+            }
+            (Class::Infinity, Class::Zero) => return F80::infinity(sign),
+            // a zero divisor beats a denormal dividend
+            (_, Class::Zero) => {
+                env.exceptions |= ZERO_DIVIDE;
+                return F80::infinity(sign);
+            }
+            _ => {}
+        }
+        F80::check_denormal(&[self, other], env);
+        match (self.class(), other.class()) {
+            (Class::Infinity, _) => return F80::infinity(sign),
+            (_, Class::Infinity) => return F80::zero(sign),
+            _ => {}
+        }
+        if self.class() == Class::Zero {
+            return F80::zero(sign);
+        }
+        Wide::from_f80(self)
+            .div(Wide::from_f80(other))
+            .to_f80(env.precision, env)
+    }
+
+    pub fn sqrt(self, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan1(env) {
+            return nan;
+        }
+        match self.class() {
+            Class::Zero => return self,
+            _ if self.sign() => {
+                env.exceptions |= INVALID;
+                return F80::INDEFINITE;
+            }
+            Class::Infinity => return self,
+            _ => {}
+        }
+        F80::check_denormal(&[self], env);
+        let x = Wide::from_f80(self);
+        // sqrt(sig * 2^(exp - 127)) with an even power of two, the significand only has 64 bits
+        // so shifting it right by one doesn't lose anything
+        let (radicand, exp) = if (x.exp - 127) % 2 == 0 {
+            (x.sig, x.exp - 127)
+        } else {
+            (x.sig >> 1, x.exp - 126)
+        };
+        // 66 bits of root a bit at a time, enough to round a 64 bit significand
+        let (mut root, mut rem) = (0u128, 0u128);
+        for i in 0..66 {
+            let pair = if i < 64 {
+                radicand >> (126 - 2 * i) & 3
+            } else {
+                0
+            };
+            rem = rem << 2 | pair;
+            let trial = root << 2 | 1;
+            root <<= 1;
+            if rem >= trial {
+                rem -= trial;
+                root |= 1;
+            }
+        }
+        // the root is sqrt(radicand * 4) with its top bit at 65
+        let sig = root << 62 | (rem != 0) as u128;
+        Wide {
+            sign: false,
+            exp: exp / 2 + 63,
+            sig,
+        }
+        .to_f80(env.precision, env)
+    }
+
+    /// Compares for fcom and friends, `None` when they're unordered. Only quiet compares take
+    /// QNaNs without an invalid operation.
+    pub fn compare(self, other: F80, quiet: bool, env: &mut Env) -> Option<Ordering> {
+        let unsupported = |x: F80| x.class() == Class::Unsupported;
+        if self.is_nan() || other.is_nan() || unsupported(self) || unsupported(other) {
+            if !quiet || self.is_signaling() || other.is_signaling() {
+                env.exceptions |= INVALID;
+            }
+            if unsupported(self) || unsupported(other) {
+                env.exceptions |= INVALID;
+            }
+            return None;
+        }
+        F80::check_denormal(&[self, other], env);
+        let magnitude = |x: F80| match x.class() {
+            Class::Infinity => Wide::new(false, i32::MAX, 1),
+            _ => Wide::from_f80(x),
+        };
+        let (a, b) = (magnitude(self), magnitude(other));
+        let ordering = match (a.is_zero(), b.is_zero(), self.sign(), other.sign()) {
+            (true, true, _, _) => Ordering::Equal,
+            (_, _, false, true) => Ordering::Greater,
+            (_, _, true, false) => Ordering::Less,
+            (_, _, false, false) => a.cmp_abs(b),
+            (_, _, true, true) => b.cmp_abs(a),
+        };
+        Some(ordering)
+    }
+
+    /// frndint, rounding to an integer the way the control word says.
+    pub fn round_to_int(self, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan1(env) {
+            return nan;
+        }
+        match self.class() {
+            Class::Zero | Class::Infinity => return self,
+            _ => {}
+        }
+        F80::check_denormal(&[self], env);
+        let x = Wide::from_f80(self);
+        if x.exp >= 63 {
+            return self;
+        }
+        let (value, inexact, up) = round_bits(x.sig, (127 - x.exp) as u32, x.sign, env.rounding);
+        if inexact {
+            env.exceptions |= PRECISION;
+        }
+        let result = if value == 0 {
+            F80::zero(x.sign)
+        } else {
+            Wide::from_int(x.sign, value).to_f80(64, env)
+        };
+        env.rounded_up = up;
+        result
+    }
+
+    /// fscale, `self * 2^trunc(other)`.
+    pub fn scale(self, other: F80, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan2(other, env) {
+            return nan;
+        }
+        F80::check_denormal(&[self, other], env);
+        match (self.class(), other.class()) {
+            (Class::Zero, Class::Infinity) if !other.sign() => {
+                env.exceptions |= INVALID;
+                return F80::INDEFINITE;
+            }
+            (Class::Infinity, Class::Infinity) if other.sign() => {
+                env.exceptions |= INVALID;
+                return F80::INDEFINITE;
+            }
+            (Class::Zero | Class::Infinity, _) => return self,
+            (_, Class::Infinity) if other.sign() => return F80::zero(self.sign()),
+            (_, Class::Infinity) => return F80::infinity(self.sign()),
+            _ => {}
+        }
+        let n = Wide::from_f80(other);
+        // anything past this over or underflows whatever it's scaling
+        let n = if n.exp >= 20 {
+            1 << 20
+        } else if n.exp < 0 {
+            0
+        } else {
+            (n.sig >> (127 - n.exp)) as i32
+        };
+        let n = if other.sign() { -n } else { n };
+        Wide::from_f80(self).scale(n).to_f80(64, env)
+    }
+
+    /// fxtract, the exponent and the significand with the exponent of 1.0.
+    pub fn extract(self, env: &mut Env) -> (F80, F80) {
+        if let Some(nan) = self.nan1(env) {
+            return (nan, nan);
+        }
+        match self.class() {
+            Class::Zero => {
+                env.exceptions |= ZERO_DIVIDE;
+                return (F80::infinity(true), self);
+            }
+            Class::Infinity => return (self.abs(), self),
+            _ => {}
+        }
+        F80::check_denormal(&[self], env);
+        let x = Wide::from_f80(self);
+        let exponent = F80::from_i64(x.exp as i64);
+        (
+            exponent,
+            F80::new(x.sign, BIAS as u16, (x.sig >> 64) as u64),
+        )
+    }
+
+    /// fprem and fprem1 with `nearest`, the remainder, the low three bits of the quotient and
+    /// whether it's only a partial remainder. When the exponents are 64 or more apart the
+    /// fpu only takes off 32 to 63 of the quotient's top bits and leaves the rest for the next
+    /// round.
+    pub fn remainder(self, other: F80, nearest: bool, env: &mut Env) -> (F80, u8, bool) {
+        if let Some(nan) = self.nan2(other, env) {
+            return (nan, 0, false);
+        }
+        match (self.class(), other.class()) {
+            (Class::Infinity, _) | (_, Class::Zero) => {
+                env.exceptions |= INVALID;
+                return (F80::INDEFINITE, 0, false);
+            }
+            (Class::Zero, _) | (_, Class::Infinity) => {
+                F80::check_denormal(&[self, other], env);
+                return (self, 0, false);
+            }
+            _ => {}
+        }
+        F80::check_denormal(&[self, other], env);
+        let (a, b) = (Wide::from_f80(self), Wide::from_f80(other));
+        let (a_sig, b_sig) = ((a.sig >> 64) as u64, (b.sig >> 64) as u64);
+        let diff = a.exp - b.exp;
+        let partial = diff >= 64;
+        // the quotient is worked out in units of b shifted left this much
+        let shift = if partial { diff - 32 - diff % 32 } else { 0 };
+        let bits = diff - shift;
+        if bits < -1 {
+            // |a| < |b| / 2, even for the nearest quotient
+            return (self, 0, false);
+        }
+        // a / (b * 2^shift) bit by bit, a's significand is `bits` places to the left of b's
+        let (mut rem, divisor, mut quotient) = if bits < 0 {
+            (a_sig as u128, (b_sig as u128) << 1, 0u64)
+        } else {
+            (0u128, b_sig as u128, 0u64)
+        };
+        if bits >= 0 {
+            for i in (0..64 + bits).rev() {
+                let bit = if i >= bits {
+                    a_sig >> (i - bits) & 1
+                } else {
+                    0
+                };
+                rem = rem << 1 | bit as u128;
+                quotient <<= 1;
+                if rem >= divisor {
+                    rem -= divisor;
+                    quotient |= 1;
+                }
+            }
+        }
+        // the remainder is in units of the lower of the two lowest significand bits
+        let unit = a.exp.min(b.exp + shift) - 63;
+        let mut sign = a.sign;
+        if nearest && !partial {
+            let twice = rem << 1;
+            if twice > divisor || (twice == divisor && quotient & 1 != 0) {
+                rem = divisor - rem;
+                quotient = quotient.wrapping_add(1);
+                sign = !sign;
+            }
+        }
+        let result = if rem == 0 {
+            F80::zero(a.sign)
+        } else {
+            Wide::new(sign, unit + 127, rem).to_f80(64, env)
+        };
+        (result, (quotient & 7) as u8, partial)
+    }
+}
+
+/// Sums a power series `x^k * coefficient(n)` for n = 0, 1, ... until the terms stop mattering,
+/// where `x^k` is `start` and each term multiplies in `step`.
+fn series(start: Wide, step: Wide, coefficient: impl Fn(u32) -> Wide) -> Wide {
+    let mut power = start;
+    let mut sum = Wide::new(false, 0, 0);
+    for n in 0.. {
+        let term = power.mul(coefficient(n));
+        // the last term only matters as a sticky bit, but it says which way the tail goes
+        let last = term.is_zero() || (!sum.is_zero() && term.exp < sum.exp - 130);
+        sum = sum.add(term);
+        if last {
+            break;
+        }
+        power = power.mul(step);
+    }
+    sum
+}
+
+fn int(n: u32) -> Wide {
+    Wide::from_int(false, n as u128)
+}
+
+/// e^x - 1 for |x| up to about 1.
+fn exp_m1(x: Wide) -> Wide {
+    // x + x^2/2! + ..., the factorials get too big for a u128 so each term divides its own
+    let mut term = x;
+    let mut sum = x;
+    for n in 2.. {
+        term = term.mul(x).div(int(n));
+        sum = sum.add(term);
+        if term.is_zero() || term.exp < sum.exp - 130 {
+            break;
+        }
+    }
+    sum
+}
+
+/// 2 atanh(t) = ln((1 + t) / (1 - t)) for small t.
+fn atanh2(t: Wide) -> Wide {
+    let odd = series(t, t.mul(t), |n| int(1).div(int(2 * n + 1)));
+    odd.scale(1)
+}
+
+/// ln(1 + x) when x is small, where forming 1 + x would lose bits.
+fn ln_1p(x: Wide) -> Wide {
+    atanh2(x.div(int(2).add(x)))
+}
+
+/// log2(x) for x > 0.
+fn log2(x: Wide) -> Wide {
+    // x = m * 2^e with m in [sqrt(2)/2, sqrt(2))
+    let mut m = Wide { exp: 0, ..x };
+    let mut e = x.exp;
+    if m.cmp_abs(SQRT_2) != Ordering::Less {
+        m = m.scale(-1);
+        e += 1;
+    }
+    let one = int(1);
+    let ln_m = atanh2(m.sub(one).div(m.add(one)));
+    Wide::from_int(e < 0, e.unsigned_abs() as u128).add(ln_m.mul(LOG2_E))
+}
+
+/// atan(x) for 0 <= x <= 1.
+fn atan(x: Wide) -> Wide {
+    let one = int(1);
+    if x.cmp_abs(TAN_PI_8) == Ordering::Greater {
+        // atan(x) = pi/4 + atan((x - 1) / (x + 1))
+        return PI.scale(-2).add(atan_series(x.sub(one).div(x.add(one))));
+    }
+    atan_series(x)
+}
+fn atan_series(x: Wide) -> Wide {
+    series(x, x.mul(x).neg(), |n| int(1).div(int(2 * n + 1)))
+}
+
+/// sin(x) and cos(x) for |x| <= pi/4.
+fn sin_cos(x: Wide) -> (Wide, Wide) {
+    let x2 = x.mul(x).neg();
+    // 1/n! for the odd and even n
+    let factorial = |n: u32| (1..=n).fold(int(1), |acc, i| acc.div(int(i)));
+    let sin = series(x, x2, |n| factorial(2 * n + 1));
+    let cos = series(int(1), x2, |n| factorial(2 * n));
+    (sin, cos)
+}
+
+/// What fsin, fcos, fsincos and fptan compute.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trig {
+    Sin,
+    Cos,
+    Tan,
+}
+
+impl F80 {
+    /// f2xm1, 2^x - 1 for x in -1..1.
+    pub fn exp2_m1(self, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan1(env) {
+            return nan;
+        }
+        F80::check_denormal(&[self], env);
+        match self.class() {
+            Class::Zero => return self,
+            Class::Infinity if self.sign() => return F80::ONE.neg(),
+            Class::Infinity => return self,
+            _ => {}
+        }
+        let x = Wide::from_f80(self);
+        if x.cmp_abs(int(1)) == Ordering::Greater {
+            // the result is undefined out here, the fpu hands back x
+            env.exceptions |= PRECISION;
+            return self;
+        }
+        // inexact even for 1 and -1, like the fpu
+        exp_m1(x.mul(LN_2)).inexact().to_f80(64, env)
+    }
+
+    /// fyl2x, `other * log2(self)`.
+    pub fn y_log2_x(self, other: F80, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan2(other, env) {
+            return nan;
+        }
+        let (x, y) = (self, other);
+        let one = F80::ONE;
+        let invalid = |env: &mut Env| {
+            env.exceptions |= INVALID;
+            F80::INDEFINITE
+        };
+        if x.sign() && x.class() != Class::Zero {
+            return invalid(env);
+        }
+        match (x.class(), y.class()) {
+            (Class::Zero, Class::Zero) => return invalid(env),
+            (Class::Zero, Class::Infinity) => return F80::infinity(!y.sign()),
+            (Class::Zero, _) => {
+                env.exceptions |= ZERO_DIVIDE;
+                return F80::infinity(!y.sign());
+            }
+            _ => {}
+        }
+        F80::check_denormal(&[self, other], env);
+        match (x.class(), y.class()) {
+            (Class::Infinity, Class::Zero) => return invalid(env),
+            (Class::Infinity, _) => return F80::infinity(y.sign()),
+            _ => {}
+        }
+        let log = log2(Wide::from_f80(x));
+        if y.class() == Class::Infinity {
+            if log.is_zero() {
+                return invalid(env);
+            }
+            return F80::infinity(y.sign() != log.sign);
+        }
+        if y.class() == Class::Zero || x == one {
+            return F80::zero(y.sign() != (log.sign && !log.is_zero()));
+        }
+        log.inexact().mul(Wide::from_f80(y)).to_f80(64, env)
+    }
+
+    /// fyl2xp1, `other * log2(self + 1)`, for `self` close to 0.
+    pub fn y_log2_x_p1(self, other: F80, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan2(other, env) {
+            return nan;
+        }
+        let (x, y) = (self, other);
+        let invalid = |env: &mut Env| {
+            env.exceptions |= INVALID;
+            F80::INDEFINITE
+        };
+        match (x.class(), y.class()) {
+            (Class::Zero, Class::Infinity) | (Class::Infinity, Class::Zero) => return invalid(env),
+            (Class::Infinity, _) if x.sign() => return invalid(env),
+            _ => {}
+        }
+        F80::check_denormal(&[self, other], env);
+        match (x.class(), y.class()) {
+            (Class::Zero, _) | (_, Class::Zero) => return F80::zero(x.sign() != y.sign()),
+            (Class::Infinity, _) => return F80::infinity(y.sign()),
+            _ => {}
+        }
+        let x = Wide::from_f80(x);
+        let one = int(1);
+        let log = if x.exp < -1 {
+            ln_1p(x).mul(LOG2_E)
+        } else {
+            let sum = x.add(one);
+            if sum.sign || sum.is_zero() {
+                // it's undefined this far out, the fpu gives back x as if the logarithm was a
+                // negative number that doesn't change anything
+                if y.class() == Class::Infinity {
+                    return F80::infinity(!y.sign());
+                }
+                env.exceptions |= PRECISION;
+                return self;
+            }
+            log2(sum)
+        };
+        if y.class() == Class::Infinity {
+            return F80::infinity(y.sign() != log.sign);
+        }
+        log.inexact().mul(Wide::from_f80(y)).to_f80(64, env)
+    }
+
+    /// fpatan, the angle of (`self`, `other`) like atan2(other, self).
+    pub fn atan2(self, other: F80, env: &mut Env) -> F80 {
+        if let Some(nan) = self.nan2(other, env) {
+            return nan;
+        }
+        F80::check_denormal(&[self, other], env);
+        let (x, y) = (self, other);
+        let sign = y.sign();
+        let angle = |value: Wide, env: &mut Env| {
+            let value = if sign { value.neg() } else { value };
+            value.to_f80(64, env)
+        };
+        let half_pi = PI.scale(-1);
+        match (x.class(), y.class()) {
+            (_, Class::Zero) if x.sign() => return angle(PI, env),
+            (_, Class::Zero) => return y,
+            (Class::Infinity, Class::Infinity) if x.sign() => {
+                return angle(PI.scale(-2).mul(int(3)), env)
+            }
+            (Class::Infinity, Class::Infinity) => return angle(PI.scale(-2), env),
+            (Class::Zero, _) | (_, Class::Infinity) => return angle(half_pi, env),
+            (Class::Infinity, _) if x.sign() => return angle(PI, env),
+            (Class::Infinity, _) => return F80::zero(sign),
+            _ => {}
+        }
+        let (a, b) = (Wide::from_f80(y).abs(), Wide::from_f80(x).abs());
+        let mut value = if a.cmp_abs(b) == Ordering::Greater {
+            half_pi.sub(atan(b.div(a)))
+        } else {
+            atan(a.div(b))
+        };
+        if x.sign() {
+            value = PI.sub(value);
+        }
+        angle(value, env)
+    }
+
+    /// fsin, fcos and fptan, `None` when `self` is too big to reduce, which leaves it alone.
+    pub fn trig(self, trig: Trig, env: &mut Env) -> Option<F80> {
+        if let Some(nan) = self.nan1(env) {
+            return Some(nan);
+        }
+        F80::check_denormal(&[self], env);
+        match self.class() {
+            Class::Infinity => {
+                env.exceptions |= INVALID;
+                return Some(F80::INDEFINITE);
+            }
+            Class::Zero if trig == Trig::Cos => return Some(F80::ONE),
+            Class::Zero => return Some(self),
+            _ => {}
+        }
+        let x = Wide::from_f80(self);
+        if x.exp >= 63 {
+            return None;
+        }
+        let (r, quadrant) = reduce(x.abs());
+        let (sin, cos) = sin_cos(r);
+        let (sin, cos) = match quadrant {
+            0 => (sin, cos),
+            1 => (cos, sin.neg()),
+            2 => (sin.neg(), cos.neg()),
+            _ => (cos.neg(), sin),
+        };
+        let sin = if x.sign { sin.neg() } else { sin };
+        let value = match trig {
+            Trig::Sin => sin,
+            Trig::Cos => cos,
+            Trig::Tan => sin.div(cos),
+        };
+        Some(value.to_f80(64, env))
+    }
+}
+
+/// `x - k * pi/2` for the nearest k, with the fpu's 66 bit pi, and k mod 4. The result is exact.
+fn reduce(x: Wide) -> (Wide, u8) {
+    // below 1/4 there's nothing to take off
+    if x.exp < -2 {
+        return (x, 0);
+    }
+    // x and pi/2 in units of 2^-65
+    let sig = (x.sig >> 64) as u64;
+    let bits = (x.exp + 2) as u32;
+    let (mut rem, mut quotient) = (0u128, 0u8);
+    for i in (0..64 + bits).rev() {
+        let bit = if i >= bits { sig >> (i - bits) & 1 } else { 0 };
+        rem = rem << 1 | bit as u128;
+        quotient = quotient.wrapping_shl(1);
+        if rem >= REDUCTION_PI {
+            rem -= REDUCTION_PI;
+            quotient |= 1;
+        }
+    }
+    let mut sign = false;
+    if rem << 1 > REDUCTION_PI || (rem << 1 == REDUCTION_PI && quotient & 1 != 0) {
+        rem = REDUCTION_PI - rem;
+        quotient = quotient.wrapping_add(1);
+        sign = true;
+    }
+    (Wide::new(sign, 127 - 65, rem), quotient & 3)
+}
@@ -56,7 +56,7 @@ fn is_valid_ident(x: u8) -> bool {
     x.is_ascii_alphabetic() || x == b'-'
 }
 
-fn lex(input_str: &str) -> Vec<Tok> {
+fn lex(input_str: &str) -> Vec<Tok<'_>> {
     let mut tokens = Vec::new();
 
     let input = input_str.as_bytes();
@@ -149,7 +149,7 @@ impl<'x> Parser<'x> {
     fn len(&self) -> usize {
         self.input.len()
     }
-    fn next(&mut self) -> Tok {
+    fn next(&mut self) -> Tok<'_> {
         let r = self.input[self.offset];
         self.offset += 1;
         r
@@ -275,7 +275,7 @@ fn parse_impl<'x>(parser: &mut Parser<'x>) -> Value<'x> {
         _ => unreachable!("{:?}", value),
     }
 }
-pub fn parse(input: &str) -> Value {
+pub fn parse(input: &str) -> Value<'_> {
     let tokens = lex(input);
     let mut parser = Parser {
         input: tokens,
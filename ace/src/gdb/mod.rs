@@ -5,7 +5,7 @@ use std::{
     fmt::{Arguments, Display, Write as FmtWrite},
     fs::File,
     io::{BufRead, BufReader, BufWriter, Write},
-    process::{ChildStdin, ChildStdout, Command, Stdio},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
     sync::mpsc::{channel, Receiver, Sender},
 };
 
@@ -42,6 +42,7 @@ struct MessageInfo {
 }
 
 pub struct Gdb {
+    child: Child,
     writer: BufWriter<ChildStdin>,
     log: BufWriter<File>,
     receiver: Receiver<String>,
@@ -50,19 +51,20 @@ pub struct Gdb {
 
 impl Gdb {
     pub fn new(program: &str) -> Gdb {
-        let child = Command::new("gdb")
+        let mut child = Command::new("gdb")
             .args([program, "--interpreter", "mi"])
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
             .unwrap();
-        let writer = child.stdin.unwrap();
-        let reader = child.stdout.unwrap();
+        let writer = child.stdin.take().unwrap();
+        let reader = child.stdout.take().unwrap();
 
         let (sender, receiver) = channel();
         std::thread::spawn(|| reader_thread(reader, sender));
 
         Gdb {
+            child,
             writer: BufWriter::new(writer),
             log: BufWriter::new(File::create("gdb_log.txt").unwrap()),
             receiver,
@@ -70,10 +72,6 @@ impl Gdb {
         }
     }
 
-    pub fn breakpoint(&mut self, file: &str, line: u32) {
-        w!(self, "b {}:{}", file, line);
-    }
-
     pub fn breakpoint_fn(&mut self, fun: &str) {
         w!(self, "b {}", fun);
     }
@@ -166,10 +164,7 @@ impl Gdb {
         if let Some(value) = value.get("register-names") {
             let value = value.as_list();
 
-            let registers = value
-                .iter()
-                .map(|x| x.as_string().to_string())
-                .collect();
+            let registers = value.iter().map(|x| x.as_string().to_string()).collect();
 
             return Message::RegisterNames(registers);
         }
@@ -284,6 +279,14 @@ impl Gdb {
     }
 }
 
+impl Drop for Gdb {
+    fn drop(&mut self) {
+        // gdb would otherwise outlive the test that spawned it
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
 fn split_comma(line: &str) -> (&str, &str) {
     match line.find(',') {
         Some(comma) => (&line[..comma], &line[comma + 1..]),
@@ -305,11 +308,7 @@ pub struct RegisterNames {
     pub inner: Vec<String>,
 }
 
-pub trait Debuggable {
-    fn init(&mut self, gdb: &mut Gdb);
-}
-
-fn unescape_c(input: &str) -> UnescapeC {
+fn unescape_c(input: &str) -> UnescapeC<'_> {
     UnescapeC { s: input }
 }
 
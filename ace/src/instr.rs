@@ -0,0 +1,420 @@
+use crate::opcodes::Op;
+use crate::prefix::{Prefixes, Rep, Segment};
+use crate::registers::{MaskReg, Reg, StReg, VecReg};
+use std::fmt::Display;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+    /// x87 extended precision
+    Tword,
+    Oword,
+    Yword,
+    Zword,
+}
+impl Size {
+    pub fn bytes(self) -> usize {
+        match self {
+            Size::Byte => 1,
+            Size::Word => 2,
+            Size::Dword => 4,
+            Size::Qword => 8,
+            Size::Tword => 10,
+            Size::Oword => 16,
+            Size::Yword => 32,
+            Size::Zword => 64,
+        }
+    }
+    pub fn mask(self) -> u64 {
+        match self {
+            Size::Qword | Size::Tword | Size::Oword | Size::Yword | Size::Zword => u64::MAX,
+            _ => (1 << (self.bytes() * 8)) - 1,
+        }
+    }
+    fn keyword(self) -> &'static str {
+        match self {
+            Size::Byte => "byte",
+            Size::Word => "word",
+            Size::Dword => "dword",
+            Size::Qword => "qword",
+            Size::Tword => "tword",
+            Size::Oword => "oword",
+            Size::Yword => "yword",
+            Size::Zword => "zword",
+        }
+    }
+}
+
+/// Condition codes in the order of the low 4 opcode bits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cond {
+    O,
+    No,
+    B,
+    Ae,
+    E,
+    Ne,
+    Be,
+    A,
+    S,
+    Ns,
+    P,
+    Np,
+    L,
+    Ge,
+    Le,
+    G,
+}
+impl Cond {
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Cond::O => "o",
+            Cond::No => "no",
+            Cond::B => "b",
+            Cond::Ae => "ae",
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::Be => "be",
+            Cond::A => "a",
+            Cond::S => "s",
+            Cond::Ns => "ns",
+            Cond::P => "p",
+            Cond::Np => "np",
+            Cond::L => "l",
+            Cond::Ge => "ge",
+            Cond::Le => "le",
+            Cond::G => "g",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Base {
+    Reg(Reg),
+    /// `disp` is relative to the start of the instruction, like `Operand::Rel`.
+    Rip,
+}
+
+/// A memory operand, `base + index * scale + disp` truncated to `addr_size`.
+#[derive(Debug, Copy, Clone)]
+pub struct Mem {
+    pub size: Size,
+    pub base: Option<Base>,
+    pub index: Option<Reg>,
+    pub scale: u8,
+    pub disp: i64,
+    /// Width of the encoded displacement, only needed to reproduce the exact encoding.
+    pub disp_size: Option<Size>,
+    /// What an 8 bit displacement is multiplied by, evex uses the operand size (disp8*N).
+    pub disp_scale: u8,
+    pub addr_size: Size,
+    pub segment: Option<Segment>,
+    /// Number of elements when evex broadcasts the `size` byte operand, like `{1to16}`.
+    pub broadcast: Option<u8>,
+}
+impl Mem {
+    /// The displacement width nasm picks on its own for this address.
+    fn default_disp_size(&self) -> Option<Size> {
+        let Some(Base::Reg(base)) = self.base else {
+            return Some(Size::Dword);
+        };
+        let needs_disp = matches!(base.as_usize(), 5 | 13);
+        let scale = self.disp_scale as i64;
+        if self.disp == 0 && !needs_disp {
+            None
+        } else if self.disp % scale == 0 && i8::try_from(self.disp / scale).is_ok() {
+            Some(Size::Byte)
+        } else {
+            Some(Size::Dword)
+        }
+    }
+}
+impl Display for Mem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        if self.disp_size != self.default_disp_size() {
+            if let Some(size) = self.disp_size {
+                write!(f, "{} ", size.keyword())?;
+            }
+        }
+
+        if let Some(segment) = self.segment {
+            write!(f, "{segment}:")?;
+        }
+
+        let mut empty = true;
+        match self.base {
+            Some(Base::Rip) => {
+                f.write_str("rel $")?;
+                empty = false;
+            }
+            Some(Base::Reg(base)) => {
+                write!(f, "{base}")?;
+                empty = false;
+            }
+            None if self.index.is_some() => f.write_str("nosplit ")?,
+            None => {}
+        }
+        if let Some(index) = self.index {
+            if !empty {
+                f.write_str("+")?;
+            }
+            write!(f, "{index}*{}", self.scale)?;
+            empty = false;
+        }
+
+        if empty {
+            write!(f, "{:#x}", self.disp as u64 & self.addr_size.mask())?;
+        } else if self.disp_size.is_some() || self.base == Some(Base::Rip) {
+            write!(f, "{}", Signed(self.disp))?;
+        }
+        f.write_str("]")?;
+        if let Some(count) = self.broadcast {
+            write!(f, "{{1to{count}}}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats as hex with an explicit sign, for displacements.
+struct Signed(i64);
+impl Display for Signed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        write!(f, "{sign}{:#x}", self.0.unsigned_abs())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub enum Operand {
+    #[default]
+    None,
+    Reg(Reg),
+    Vec(VecReg),
+    Mask(MaskReg),
+    St(StReg),
+    Mem(Mem),
+    /// Sign extended, `size` is the width of the encoded immediate.
+    Imm {
+        value: i64,
+        size: Size,
+    },
+    /// Branch target relative to the start of the instruction, `size` is the width of the encoded displacement.
+    Rel {
+        offset: i64,
+        size: Size,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Instr {
+    pub op: Op,
+    pub size: Size,
+    pub operands: [Operand; 4],
+    pub prefixes: Prefixes,
+}
+impl Instr {
+    pub fn new(op: Op, size: Size, prefixes: Prefixes) -> Instr {
+        Instr {
+            op,
+            size,
+            operands: Default::default(),
+            prefixes,
+        }
+    }
+
+    /// Where the branch goes when the instruction is at `address`.
+    pub fn branch_target(&self, address: u64) -> Option<u64> {
+        self.operands.iter().find_map(|x| match x {
+            Operand::Rel { offset, .. } => Some(address.wrapping_add(*offset as u64)),
+            _ => None,
+        })
+    }
+
+    /// Whether a lock prefix is allowed, only read-modify-write instructions with a memory
+    /// destination can have one.
+    pub fn lockable(&self) -> bool {
+        let op = matches!(
+            self.op,
+            Op::Add
+                | Op::Adc
+                | Op::And
+                | Op::Btc
+                | Op::Btr
+                | Op::Bts
+                | Op::Cmpxchg
+                | Op::Cmpxchg8b
+                | Op::Cmpxchg16b
+                | Op::Dec
+                | Op::Inc
+                | Op::Neg
+                | Op::Not
+                | Op::Or
+                | Op::Sbb
+                | Op::Sub
+                | Op::Xadd
+                | Op::Xchg
+                | Op::Xor
+        );
+        op && matches!(self.operands[0], Operand::Mem(_))
+    }
+
+    /// Whether it's one of movs, cmps, stos, lods or scas, and not the sse2 movsd or cmpsd.
+    pub fn is_string(&self) -> bool {
+        self.op.string_size().is_some() && matches!(self.operands[0], Operand::None)
+    }
+
+    /// The memory operand and the alignment it needs, when it has to be aligned: the aligned
+    /// vector moves, and any 16 byte memory operand of a legacy sse instruction but the
+    /// unaligned moves, lddqu and the string compares.
+    pub fn alignment(&self) -> Option<(Mem, u64)> {
+        let mem = self.operands.iter().find_map(|x| match x {
+            Operand::Mem(mem) => Some(*mem),
+            _ => None,
+        })?;
+        let aligned_move = matches!(
+            self.op,
+            Op::Movaps
+                | Op::Movapd
+                | Op::Movdqa
+                | Op::Movntps
+                | Op::Movntpd
+                | Op::Movntdq
+                | Op::Vmovaps
+                | Op::Vmovapd
+                | Op::Vmovdqa
+                | Op::Vmovdqa32
+                | Op::Vmovdqa64
+        );
+        let sse = self.prefixes.vex.is_none()
+            && mem.size == Size::Oword
+            && self.operands.iter().any(|x| matches!(x, Operand::Vec(_)))
+            && !matches!(
+                self.op,
+                Op::Movups
+                    | Op::Movupd
+                    | Op::Movdqu
+                    | Op::Lddqu
+                    | Op::Pcmpestri
+                    | Op::Pcmpestrm
+                    | Op::Pcmpistri
+                    | Op::Pcmpistrm
+            );
+        (aligned_move || sse).then_some((mem, mem.size.bytes() as u64))
+    }
+
+    fn mem_needs_size(&self, mem: &Mem) -> bool {
+        // {1to16} already says how big a broadcast element is
+        if mem.broadcast.is_some() {
+            return false;
+        }
+        // nasm can't tell crc32 r64, r/m8 from r/m64 by the register, or movzx r16, r/m8 from
+        // r/m16
+        matches!(self.op, Op::Crc32 | Op::Movzx | Op::Movsx)
+            || !self.operands.iter().any(|x| match x {
+                Operand::Reg(r) => r.size() == mem.size,
+                Operand::Vec(r) => r.size == mem.size,
+                _ => false,
+            })
+    }
+}
+
+impl Display for Instr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let p = &self.prefixes;
+        if let Some(segment) = p.segment {
+            // otherwise it's printed as part of the memory operand
+            if !self.operands.iter().any(|x| matches!(x, Operand::Mem(_))) {
+                write!(f, "{segment} ")?;
+            }
+        }
+        if p.lock {
+            f.write_str("lock ")?;
+        }
+        if p.vex.is_some_and(|x| x.vex3) {
+            f.write_str("{vex3} ")?;
+        }
+        if p.evex.is_some_and(|x| x.vex_encodable) {
+            f.write_str("{evex} ")?;
+        }
+        // string instructions don't have a memory operand to show the address size
+        if p.address_size && self.is_string() {
+            f.write_str("a32 ")?;
+        }
+        let compares = matches!(
+            self.op,
+            Op::Cmpsb
+                | Op::Cmpsw
+                | Op::Cmpsd
+                | Op::Cmpsq
+                | Op::Scasb
+                | Op::Scasw
+                | Op::Scasd
+                | Op::Scasq
+        );
+        match p.rep {
+            Some(Rep::Rep) if compares => f.write_str("repe ")?,
+            Some(Rep::Rep) => f.write_str("rep ")?,
+            Some(Rep::Repne) => f.write_str("repne ")?,
+            None => {}
+        }
+        // push imm, enter and leave have no register to say they're 16 bits
+        let imm = matches!(self.operands[0], Operand::Imm { .. });
+        let frame = matches!(self.op, Op::Enter | Op::Leave);
+        if (self.op == Op::Push && imm || frame) && self.size == Size::Word {
+            f.write_str("o16 ")?;
+        }
+        write!(f, "{}", self.op)?;
+
+        for (i, operand) in self.operands.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            match operand {
+                Operand::None => break,
+                Operand::Reg(reg) => write!(f, "{sep}{reg}")?,
+                Operand::Vec(reg) => write!(f, "{sep}{reg}")?,
+                Operand::Mask(reg) => write!(f, "{sep}{reg}")?,
+                Operand::St(reg) => write!(f, "{sep}{reg}")?,
+                Operand::Mem(mem) => {
+                    f.write_str(sep)?;
+                    if self.mem_needs_size(mem) {
+                        write!(f, "{} ", mem.size.keyword())?;
+                    }
+                    write!(f, "{mem}")?;
+                }
+                Operand::Imm { value, size } => {
+                    f.write_str(sep)?;
+                    // the sign extended imm8 forms, nasm picks the full size ones on its own
+                    if *size == Size::Byte && self.size != Size::Byte && self.op.has_imm8_forms() {
+                        f.write_str("byte ")?;
+                    }
+                    if *value < 0 {
+                        write!(f, "-{:#x}", value.unsigned_abs())?;
+                    } else {
+                        write!(f, "{value:#x}")?;
+                    }
+                }
+                Operand::Rel { offset, size } => {
+                    let kind = if *size == Size::Byte { "short" } else { "near" };
+                    write!(f, "{sep}{kind} ${offset:+}")?;
+                }
+            }
+            if let Some(evex) = p.evex.filter(|_| i == 0) {
+                // zeroing only means something with a mask
+                if evex.mask != 0 {
+                    write!(f, "{{k{}}}", evex.mask)?;
+                    if evex.zeroing {
+                        f.write_str("{z}")?;
+                    }
+                }
+            }
+        }
+        if let Some(rounding) = p.evex.and_then(|x| x.rounding) {
+            write!(f, ", {rounding}")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,545 @@
+//! Instruction lengths without decoding the instruction, for when only the boundaries matter.
+//! This knows the layout of every opcode map, not just the instructions in the opcode tables.
+
+use crate::decode::{DecodeError, MAX_INSTRUCTION_LEN};
+
+/// The processor mode, which sets the default operand and address sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// 16 bit
+    Real,
+    /// 32 bit, also compatibility mode
+    Protected,
+    /// 64 bit
+    Long,
+}
+
+/// The immediate, offset or pointer after the modrm byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Imm {
+    None,
+    Byte,
+    Word,
+    Dword,
+    /// 16 or 32 bits by operand size
+    Z,
+    /// mov r, imm which also has 64 bits with rex.w
+    V,
+    /// relative offset, always 32 bits in long mode
+    Rel,
+    /// enter, imm16 then imm8
+    Enter,
+    /// mov al, moffs takes an address sized offset
+    Moffs,
+    /// call far and jmp far, an offset and a 16 bit selector
+    Far,
+}
+
+/// How an opcode continues, `None` for opcodes that don't exist in the mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Shape {
+    modrm: bool,
+    imm: Imm,
+}
+const fn shape(modrm: bool, imm: Imm) -> Option<Shape> {
+    Some(Shape { modrm, imm })
+}
+
+fn one_byte(opcode: u8, mode: Mode) -> Option<Shape> {
+    let long = mode == Mode::Long;
+    match opcode {
+        // push/pop es/cs/ss/ds and the bcd instructions
+        0x06 | 0x07 | 0x0e | 0x16 | 0x17 | 0x1e | 0x1f | 0x27 | 0x2f | 0x37 | 0x3f if long => None,
+        // the alu block, op r/m, r and op r, r/m then op al/eax, imm
+        0x00..=0x3f => match opcode & 7 {
+            0..=3 => shape(true, Imm::None),
+            4 => shape(false, Imm::Byte),
+            5 => shape(false, Imm::Z),
+            _ => shape(false, Imm::None),
+        },
+        // inc/dec, rex is handled with the prefixes
+        0x40..=0x5f => shape(false, Imm::None),
+        // pusha, popa, bound
+        0x60..=0x62 if long => None,
+        0x60 | 0x61 => shape(false, Imm::None),
+        0x62 | 0x63 => shape(true, Imm::None),
+        0x68 => shape(false, Imm::Z),
+        0x69 => shape(true, Imm::Z),
+        0x6a => shape(false, Imm::Byte),
+        0x6b => shape(true, Imm::Byte),
+        0x6c..=0x6f => shape(false, Imm::None),
+        0x70..=0x7f => shape(false, Imm::Byte),
+        0x82 if long => None,
+        0x80 | 0x82 | 0x83 => shape(true, Imm::Byte),
+        0x81 => shape(true, Imm::Z),
+        0x84..=0x8f => shape(true, Imm::None),
+        0x9a if long => None,
+        0x9a => shape(false, Imm::Far),
+        0x90..=0x9f => shape(false, Imm::None),
+        0xa0..=0xa3 => shape(false, Imm::Moffs),
+        0xa8 => shape(false, Imm::Byte),
+        0xa9 => shape(false, Imm::Z),
+        0xa4..=0xaf => shape(false, Imm::None),
+        0xb0..=0xb7 => shape(false, Imm::Byte),
+        0xb8..=0xbf => shape(false, Imm::V),
+        0xc0 | 0xc1 => shape(true, Imm::Byte),
+        0xc2 | 0xca => shape(false, Imm::Word),
+        0xc3 | 0xc9 | 0xcb | 0xcc | 0xcf => shape(false, Imm::None),
+        // les and lds, vex takes these over in long mode
+        0xc4 | 0xc5 if long => None,
+        0xc4 | 0xc5 => shape(true, Imm::None),
+        0xc6 => shape(true, Imm::Byte),
+        0xc7 => shape(true, Imm::Z),
+        0xc8 => shape(false, Imm::Enter),
+        0xcd => shape(false, Imm::Byte),
+        // into, aam, aad, salc
+        0xce | 0xd4..=0xd6 if long => None,
+        0xce | 0xd6 => shape(false, Imm::None),
+        0xd4 | 0xd5 => shape(false, Imm::Byte),
+        // shifts and x87
+        0xd0..=0xd3 | 0xd8..=0xdf => shape(true, Imm::None),
+        0xd7 => shape(false, Imm::None),
+        // loop, jcxz, in and out
+        0xe0..=0xe7 | 0xeb => shape(false, Imm::Byte),
+        0xe8 | 0xe9 => shape(false, Imm::Rel),
+        0xea if long => None,
+        0xea => shape(false, Imm::Far),
+        0xec..=0xef | 0xf1 | 0xf4 | 0xf5 | 0xf8..=0xfd => shape(false, Imm::None),
+        // test is the only one in group 3 with an immediate, see `instruction_length`
+        0xf6 | 0xf7 | 0xfe | 0xff => shape(true, Imm::None),
+        // prefixes and escapes never get here
+        _ => None,
+    }
+}
+
+fn map_0f(opcode: u8) -> Option<Shape> {
+    match opcode {
+        0x04
+        | 0x0a
+        | 0x0c
+        | 0x24..=0x27
+        | 0x36
+        | 0x39
+        | 0x3b..=0x3f
+        | 0x7a
+        | 0x7b
+        | 0xa6
+        | 0xa7 => None,
+        // syscall, clts, sysret, invd, wbinvd, ud2, femms
+        0x05..=0x09 | 0x0b | 0x0e => shape(false, Imm::None),
+        // 3dnow has its opcode in the immediate
+        0x0f => shape(true, Imm::Byte),
+        // wrmsr, rdtsc, rdmsr, rdpmc, sysenter, sysexit, getsec
+        0x30..=0x37 => shape(false, Imm::None),
+        0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => shape(true, Imm::Byte),
+        // emms
+        0x77 => shape(false, Imm::None),
+        0x80..=0x8f => shape(false, Imm::Rel),
+        // push/pop fs/gs, cpuid, rsm
+        0xa0..=0xa2 | 0xa8..=0xaa => shape(false, Imm::None),
+        // bswap
+        0xc8..=0xcf => shape(false, Imm::None),
+        _ => shape(true, Imm::None),
+    }
+}
+
+/// The vex and evex maps, 4 and everything after it are reserved.
+fn vex_map(map: u8, opcode: u8) -> Option<Shape> {
+    match map {
+        // vzeroupper and vzeroall
+        1 if opcode == 0x77 => shape(false, Imm::None),
+        1 => match opcode {
+            0x70..=0x73 | 0xc2 | 0xc4..=0xc6 => shape(true, Imm::Byte),
+            _ => shape(true, Imm::None),
+        },
+        2 => shape(true, Imm::None),
+        3 => shape(true, Imm::Byte),
+        _ => None,
+    }
+}
+
+struct Reader<'x> {
+    code: &'x [u8],
+    pos: usize,
+}
+impl Reader<'_> {
+    /// Fails like the decoder, past the maximum length before running out of bytes.
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        if self.pos == MAX_INSTRUCTION_LEN {
+            return Err(DecodeError::Invalid);
+        }
+        let x = *self.code.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(x)
+    }
+    fn skip(&mut self, n: usize) -> Result<(), DecodeError> {
+        for _ in 0..n {
+            self.u8()?;
+        }
+        Ok(())
+    }
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.code
+            .get(self.pos)
+            .copied()
+            .ok_or(DecodeError::Truncated)
+    }
+}
+
+/// Skips a modrm byte with its sib and displacement, returning modrm.
+fn skip_modrm(r: &mut Reader, address_16: bool) -> Result<u8, DecodeError> {
+    let modrm = r.u8()?;
+    let (mod_, rm) = (modrm >> 6, modrm & 7);
+    let disp = if address_16 {
+        match mod_ {
+            0b00 if rm == 0b110 => 2,
+            0b01 => 1,
+            0b10 => 2,
+            _ => 0,
+        }
+    } else {
+        let base = if mod_ != 0b11 && rm == 0b100 {
+            r.u8()? & 7
+        } else {
+            rm
+        };
+        match mod_ {
+            0b00 if base == 0b101 => 4,
+            0b01 => 1,
+            0b10 => 4,
+            _ => 0,
+        }
+    };
+    r.skip(disp)?;
+    Ok(modrm)
+}
+
+/// Length of the instruction at the start of `code`, with the same errors as `decode` for
+/// running out of bytes or going past 15 of them.
+pub fn instruction_length(code: &[u8], mode: Mode) -> Result<usize, DecodeError> {
+    let mut r = Reader { code, pos: 0 };
+    let (mut operand_size, mut address_size, mut lock, mut simd_prefix) =
+        (false, false, false, false);
+    let mut rex = None;
+    let mut opcode = r.u8()?;
+    loop {
+        match opcode {
+            0x66 => operand_size = true,
+            0x67 => address_size = true,
+            0xf0 => lock = true,
+            0xf2 | 0xf3 => simd_prefix = true,
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => {}
+            0x40..=0x4f if mode == Mode::Long => {
+                rex = Some(opcode);
+                opcode = r.u8()?;
+                continue;
+            }
+            _ => break,
+        }
+        // a rex prefix only counts right before the opcode
+        rex = None;
+        opcode = r.u8()?;
+    }
+    let rex_w = rex.is_some_and(|x| x & 0b1000 != 0);
+    let operand_16 = (mode == Mode::Real) != operand_size && !rex_w;
+    let address_16 = match mode {
+        Mode::Real => !address_size,
+        Mode::Protected => address_size,
+        Mode::Long => false,
+    };
+
+    // outside long mode c4, c5 and 62 are les, lds and bound unless modrm would be a register
+    let vex = matches!(opcode, 0xc4 | 0xc5 | 0x62)
+        && match mode {
+            Mode::Long => true,
+            Mode::Protected => r.peek()? >> 6 == 0b11,
+            Mode::Real => false,
+        };
+    let shape = match opcode {
+        _ if vex => {
+            // vex and evex already encode these
+            if operand_size || simd_prefix || lock || rex.is_some() {
+                return Err(DecodeError::Invalid);
+            }
+            let map = match opcode {
+                0xc5 => {
+                    r.skip(1)?;
+                    1
+                }
+                0xc4 => {
+                    let map = r.u8()? & 0x1f;
+                    r.skip(1)?;
+                    map
+                }
+                _ => {
+                    let map = match r.u8()? & 0b111 {
+                        // avx512-fp16, laid out like 0F 38
+                        5 | 6 => 2,
+                        map => map,
+                    };
+                    r.skip(2)?;
+                    map
+                }
+            };
+            let opcode = r.u8()?;
+            vex_map(map, opcode)
+        }
+        // xop, 8f /0 is pop
+        0x8f if mode != Mode::Real && r.peek()? & 0x1f >= 8 => {
+            let map = r.u8()? & 0x1f;
+            r.skip(2)?;
+            match map {
+                8 => shape(true, Imm::Byte),
+                9 => shape(true, Imm::None),
+                10 => shape(true, Imm::Dword),
+                _ => None,
+            }
+        }
+        0x0f => {
+            let opcode = r.u8()?;
+            match opcode {
+                0x38 => {
+                    r.skip(1)?;
+                    shape(true, Imm::None)
+                }
+                0x3a => {
+                    r.skip(1)?;
+                    shape(true, Imm::Byte)
+                }
+                // extrq and insertq have two immediates
+                0x78 if operand_size || simd_prefix => shape(true, Imm::Word),
+                _ => map_0f(opcode),
+            }
+        }
+        _ => one_byte(opcode, mode),
+    };
+    let shape = shape.ok_or(DecodeError::Invalid)?;
+
+    let mut imm = shape.imm;
+    if shape.modrm {
+        let modrm = skip_modrm(&mut r, address_16)?;
+        if matches!(opcode, 0xf6 | 0xf7) && (modrm >> 3) & 7 < 2 {
+            imm = if opcode == 0xf6 { Imm::Byte } else { Imm::Z };
+        }
+    }
+
+    let z = if operand_16 { 2 } else { 4 };
+    let imm_len = match imm {
+        Imm::None => 0,
+        Imm::Byte => 1,
+        Imm::Word => 2,
+        Imm::Dword => 4,
+        Imm::Z => z,
+        Imm::V if rex_w => 8,
+        Imm::V => z,
+        Imm::Rel if mode == Mode::Long => 4,
+        Imm::Rel => z,
+        Imm::Enter => 3,
+        Imm::Moffs => match mode {
+            Mode::Long if address_size => 4,
+            Mode::Long => 8,
+            _ if address_16 => 2,
+            _ => 4,
+        },
+        Imm::Far => z + 2,
+    };
+    r.skip(imm_len)?;
+    Ok(r.pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instruction_length, Mode};
+    use crate::decode::{decode, DecodeError};
+
+    fn len(code: &[u8], mode: Mode) -> usize {
+        instruction_length(code, mode).unwrap()
+    }
+
+    #[test]
+    fn long_mode() {
+        // moffs is 64 bits, or 32 with 67
+        assert_eq!(len(&[0x48, 0xa1, 1, 2, 3, 4, 5, 6, 7, 8], Mode::Long), 10);
+        assert_eq!(len(&[0x67, 0xa1, 1, 2, 3, 4], Mode::Long), 6);
+        // mov rax, imm64
+        assert_eq!(len(&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], Mode::Long), 10);
+        assert_eq!(len(&[0x66, 0xb8, 0x34, 0x12], Mode::Long), 4);
+        // rex.w wins over 66, but not when it comes first
+        assert_eq!(
+            len(&[0x66, 0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], Mode::Long),
+            11
+        );
+        assert_eq!(len(&[0x48, 0x66, 0xb8, 0x34, 0x12], Mode::Long), 5);
+        // enter 0x10, 1
+        assert_eq!(len(&[0xc8, 0x10, 0x00, 0x01], Mode::Long), 4);
+        // test has an immediate, not doesn't
+        assert_eq!(len(&[0xf6, 0x00, 0x01], Mode::Long), 3);
+        assert_eq!(
+            len(
+                &[0xf7, 0x84, 0x58, 0, 1, 0, 0, 0x45, 0x23, 1, 0],
+                Mode::Long
+            ),
+            11
+        );
+        assert_eq!(len(&[0xf7, 0x10], Mode::Long), 2);
+        // call rel32 even with 66
+        assert_eq!(len(&[0x66, 0xe8, 0, 0, 0, 0], Mode::Long), 6);
+        // pfadd mm0, mm1 and extrq xmm1, 4, 5
+        assert_eq!(len(&[0x0f, 0x0f, 0xc1, 0x9e], Mode::Long), 4);
+        assert_eq!(len(&[0x66, 0x0f, 0x78, 0xc1, 0x04, 0x05], Mode::Long), 6);
+        // vpermq ymm0, ymm1, 1 and vzeroupper
+        assert_eq!(len(&[0xc4, 0xe3, 0xfd, 0x00, 0xc1, 0x01], Mode::Long), 6);
+        assert_eq!(len(&[0xc5, 0xf8, 0x77], Mode::Long), 3);
+        // vpaddd zmm0{k1}, zmm1, [rax+0x40] and vaddph zmm1, zmm2, zmm3
+        assert_eq!(
+            len(&[0x62, 0xf1, 0x75, 0x49, 0xfe, 0x40, 0x01], Mode::Long),
+            7
+        );
+        assert_eq!(len(&[0x62, 0xf5, 0x6c, 0x48, 0x58, 0xcb], Mode::Long), 6);
+        // xop vpcomb, vfrczps and bextr with its 32 bit immediate
+        assert_eq!(len(&[0x8f, 0xe8, 0x68, 0xcc, 0xcb, 0x04], Mode::Long), 6);
+        assert_eq!(len(&[0x8f, 0xe9, 0x78, 0x80, 0xca], Mode::Long), 5);
+        assert_eq!(
+            len(
+                &[0x8f, 0xea, 0x78, 0x10, 0xc3, 0x45, 0x23, 1, 0],
+                Mode::Long
+            ),
+            9
+        );
+        // pop [rax] isn't xop
+        assert_eq!(len(&[0x8f, 0x00], Mode::Long), 2);
+    }
+
+    #[test]
+    fn legacy_modes() {
+        // mov eax, [0x12345678] and mov ax, [0x1234]
+        assert_eq!(len(&[0xa1, 0x78, 0x56, 0x34, 0x12], Mode::Protected), 5);
+        assert_eq!(len(&[0x66, 0xa1, 0x34, 0x12, 0, 0], Mode::Protected), 6);
+        // inc eax, not a rex prefix
+        assert_eq!(len(&[0x40, 0x90], Mode::Protected), 1);
+        // les eax, [ebx] and vpaddd ymm0, ymm1, ymm2
+        assert_eq!(len(&[0xc4, 0x03], Mode::Protected), 2);
+        assert_eq!(len(&[0xc5, 0xf5, 0xfe, 0xc2], Mode::Protected), 4);
+        // jmp 0x10:0x12345678
+        assert_eq!(
+            len(&[0xea, 0x78, 0x56, 0x34, 0x12, 0x10, 0], Mode::Protected),
+            7
+        );
+        // mov eax, [bx+si+0x12] and mov eax, [bx]
+        assert_eq!(len(&[0x67, 0x8b, 0x40, 0x12], Mode::Protected), 4);
+        assert_eq!(len(&[0x67, 0x8b, 0x07], Mode::Protected), 3);
+        // vpbroadcastd ymm0, xmm1 through the three byte vex
+        assert_eq!(len(&[0xc4, 0xe2, 0x7d, 0x58, 0xc1], Mode::Protected), 5);
+
+        // mov ax, 0x1234 and mov eax, 0x12345678
+        assert_eq!(len(&[0xb8, 0x34, 0x12], Mode::Real), 3);
+        assert_eq!(len(&[0x66, 0xb8, 0x78, 0x56, 0x34, 0x12], Mode::Real), 6);
+        assert_eq!(len(&[0xa1, 0x34, 0x12], Mode::Real), 3);
+        // mov al, [bp+0x12] and mov ax, [bx+di+0x1234]
+        assert_eq!(len(&[0x8a, 0x46, 0x12], Mode::Real), 3);
+        assert_eq!(len(&[0x8b, 0x81, 0x34, 0x12], Mode::Real), 4);
+        // mov ax, [0x1234] through modrm
+        assert_eq!(len(&[0x8b, 0x06, 0x34, 0x12], Mode::Real), 4);
+        // mov eax, [ebx+ecx*4+0x12]
+        assert_eq!(len(&[0x67, 0x66, 0x8b, 0x44, 0x8b, 0x12], Mode::Real), 6);
+        assert_eq!(len(&[0xe8, 0, 0], Mode::Real), 3);
+        assert_eq!(len(&[0xea, 0x34, 0x12, 0x10, 0], Mode::Real), 5);
+        // lds si, [bx]
+        assert_eq!(len(&[0xc5, 0x37], Mode::Real), 2);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            instruction_length(&[0x0f, 0x38], Mode::Long),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            instruction_length(&[0x05, 0x01, 0x02], Mode::Long),
+            Err(DecodeError::Truncated)
+        );
+        // no bcd or far jumps in long mode
+        assert_eq!(
+            instruction_length(&[0x27], Mode::Long),
+            Err(DecodeError::Invalid)
+        );
+        assert_eq!(
+            instruction_length(&[0xea, 0, 0, 0, 0, 0, 0], Mode::Long),
+            Err(DecodeError::Invalid)
+        );
+        // too long
+        let mut code = vec![0x66; 15];
+        code.push(0x90);
+        assert_eq!(
+            instruction_length(&code, Mode::Long),
+            Err(DecodeError::Invalid)
+        );
+    }
+
+    /// Random instructions, biased towards ones the decoder knows, must have the length the
+    /// decoder gives them. The length also can't depend on what comes after the instruction.
+    #[test]
+    fn fuzz_against_decoder() {
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let prefixes = [0x66, 0x67, 0xf2, 0xf3, 0xf0, 0x2e, 0x64, 0x41, 0x48, 0x4c];
+        let escapes: [&[u8]; 8] = [
+            &[],
+            &[0x0f],
+            &[0x0f, 0x38],
+            &[0x0f, 0x3a],
+            &[0xc5],
+            &[0xc4],
+            &[0x62],
+            &[0x8f],
+        ];
+
+        let mut decoded = 0;
+        for _ in 0..300_000 {
+            let mut code = vec![];
+            for _ in 0..next() % 3 {
+                code.push(prefixes[next() as usize % prefixes.len()]);
+            }
+            let escape = escapes[next() as usize % escapes.len()];
+            code.extend_from_slice(escape);
+            match escape {
+                // a valid map and mostly 1111 for vvvv
+                [0xc4] => code.extend([0xe0 | (next() % 3 + 1) as u8, 0x78 | next() as u8 & 0x87]),
+                [0x62] => code.extend([
+                    0xf0 | (next() % 3 + 1) as u8,
+                    0x7c | next() as u8 & 0x83,
+                    next() as u8 & 0xf7 | 0x08,
+                ]),
+                _ => {}
+            }
+            code.extend((0..16).map(|_| next() as u8));
+
+            let Ok(length) = instruction_length(&code, Mode::Long) else {
+                assert!(decode(&code).is_err(), "{:02x?}", code);
+                continue;
+            };
+            // the same with different bytes after it, and truncated without its last one
+            let mut other = code[..length].to_vec();
+            other.extend([0xff; 4]);
+            assert_eq!(instruction_length(&other, Mode::Long), Ok(length));
+            assert_eq!(
+                instruction_length(&code[..length - 1], Mode::Long),
+                Err(DecodeError::Truncated),
+                "{:02x?}",
+                &code[..length]
+            );
+
+            if let Ok((instr, decoder_length)) = decode(&code) {
+                assert_eq!(length, decoder_length, "{instr} {:02x?}", &code[..length]);
+                decoded += 1;
+            }
+        }
+        // enough to cover every opcode table
+        assert!(decoded > 10_000, "{decoded}");
+    }
+}
@@ -1,20 +1,31 @@
+mod decode;
 #[cfg(test)]
 mod disasm_tests;
+mod encode;
+mod error;
+mod execute;
+mod f80;
 mod gdb;
+mod instr;
+mod length;
+mod memory;
 mod new_tester;
+mod opcodes;
+mod prefix;
 mod registers;
 
 use anyhow::Result;
-use std::fmt::Debug;
-use std::fmt::Display;
-// use registers::R16::*;
-// use registers::R32::*;
+use decode::{DecodeError, MAX_INSTRUCTION_LEN};
+use error::{Access, EmulatorError, StopReason};
+use f80::F80;
+use instr::Size;
+use length::Mode;
+use memory::Memory;
+use registers::Reg;
 use registers::Register;
-use registers::R16;
-use registers::R32;
-use registers::R64;
 use registers::R64::*;
-use registers::R8;
+use std::fmt::Debug;
+use std::fmt::Display;
 
 #[repr(align(8))]
 #[derive(Clone, Copy, PartialEq, Default)]
@@ -34,9 +45,15 @@ impl RegData {
     fn r64(self) -> u64 {
         u64::from_ne_bytes(self.x)
     }
+    fn r8h(self) -> u8 {
+        self.x[1]
+    }
     fn set_r8(&mut self, new: u8) {
         self.x[0] = new;
     }
+    fn set_r8h(&mut self, new: u8) {
+        self.x[1] = new;
+    }
     fn set_r16(&mut self, new: u16) {
         self.x[0..2].copy_from_slice(&new.to_le_bytes());
     }
@@ -53,63 +70,6 @@ impl Debug for RegData {
     }
 }
 
-fn push_reg(regs: &Registers, stack: &mut [u8], register: R64) {
-    let rsp = regs[RSP].r64() as usize;
-    let source = regs[register];
-    stack[rsp - 8..rsp].copy_from_slice(&source.x);
-}
-
-#[derive(Clone, Copy, Default)]
-struct Rex(u8);
-#[allow(dead_code)]
-impl Rex {
-    fn b(self) -> bool {
-        self.0 & 0b1 != 0
-    }
-    fn x(self) -> bool {
-        self.0 & 0b10 != 0
-    }
-    fn r(self) -> bool {
-        self.0 & 0b100 != 0
-    }
-    fn w(self) -> bool {
-        self.0 & 0b1000 != 0
-    }
-}
-impl Debug for Rex {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Rex")
-            .field("b", &self.b())
-            .field("x", &self.x())
-            .field("r", &self.r())
-            .field("w", &self.w())
-            .finish()
-    }
-}
-
-#[derive(Clone, Copy)]
-struct ModRm(u8);
-impl ModRm {
-    fn mod_(self) -> u8 {
-        (self.0 >> 6) & 0b11
-    }
-    fn reg(self) -> u8 {
-        (self.0 >> 3) & 0b111
-    }
-    fn rm(self) -> u8 {
-        self.0 & 0b111
-    }
-}
-impl Debug for ModRm {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("ModRm")
-            .field("mod", &self.mod_())
-            .field("reg", &self.reg())
-            .field("rm", &self.rm())
-            .finish()
-    }
-}
-
 trait DisasmWriter: Display {
     fn write(&mut self, args: std::fmt::Arguments<'_>);
 }
@@ -130,53 +90,205 @@ macro_rules! w {
     };
 }
 
-fn xor<R: Register, D: DisasmWriter>(modrm: ModRm, rex: Rex, registers: &mut Registers, d: &mut D) {
-    // dbg!(modrm);
-    // dbg!(rex);
-    let r1 = R::from_index(modrm.rm() + 8 * rex.b() as u8);
-    let r2 = R::from_index(modrm.reg() + 8 * rex.r() as u8);
-
-    w!(d, "xor {}, {}", r1, r2);
-
-    let v1 = R::from_reg(registers[r1]);
-    let v2 = R::from_reg(registers[r2]);
-
-    let result = v1 ^ v2;
-
-    registers[r1].set_r64(result.into());
-}
-
-// #[inline(always)]
-// fn MOV<T: Sized + Copy>(src: &T, dst: &mut T) {
-//     *dst = *src;
-// }
-
-#[derive(Default)]
+/// The RFLAGS bits a user mode program can see.
 struct Flags {
-    // cf: bool,
-    // pf: bool,
+    cf: bool,
+    pf: bool,
+    af: bool,
     zf: bool,
-    // sf: bool,
-    // of: bool,
+    sf: bool,
+    tf: bool,
+    /// always set, user mode can't turn interrupts off
+    if_: bool,
+    df: bool,
+    of: bool,
 }
-impl Flags {}
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags::from_bits(0x202)
+    }
+}
+impl Flags {
+    fn from_bits(value: u64) -> Flags {
+        let bit = |n: u32| value >> n & 1 != 0;
+        Flags {
+            cf: bit(0),
+            pf: bit(2),
+            af: bit(4),
+            zf: bit(6),
+            sf: bit(7),
+            tf: bit(8),
+            if_: bit(9),
+            df: bit(10),
+            of: bit(11),
+        }
+    }
+    /// RFLAGS as pushfq would push it, bit 1 is always set.
+    fn get(&self) -> u64 {
+        let bits = [
+            (self.cf, 0),
+            (true, 1),
+            (self.pf, 2),
+            (self.af, 4),
+            (self.zf, 6),
+            (self.sf, 7),
+            (self.tf, 8),
+            (self.if_, 9),
+            (self.df, 10),
+            (self.of, 11),
+        ];
+        bits.iter().fold(0, |x, &(set, n)| x | (set as u64) << n)
+    }
+    /// Like popfq, which leaves IF alone in user mode.
+    fn set(&mut self, value: u64) {
+        *self = Flags {
+            if_: self.if_,
+            ..Flags::from_bits(value)
+        };
+    }
 
-macro_rules! calc_flags {
-    ($r:expr, $e1:expr, $e2:expr) => {
-        let e1 = $e1 as i64;
-        let e2 = $e2 as i64;
+    /// zf, sf and pf, which only depend on the result.
+    fn set_result(&mut self, result: u64, size: Size) {
+        self.zf = result & size.mask() == 0;
+        self.sf = result >> (size.bytes() * 8 - 1) & 1 != 0;
+        self.pf = (result as u8).count_ones().is_multiple_of(2);
+    }
+    /// and, or, xor and test
+    fn logic(&mut self, result: u64, size: Size) {
+        self.cf = false;
+        self.of = false;
+        self.af = false;
+        self.set_result(result, size);
+    }
+    /// add and adc, `result` is `a + b` plus the carry for adc
+    fn add(&mut self, a: u64, b: u64, result: u64, size: Size) {
+        let top = size.bytes() * 8 - 1;
+        // the carry into each bit, whatever came into the lowest one
+        let carries = (a & b) | ((a | b) & !result);
+        self.cf = carries >> top & 1 != 0;
+        self.of = ((a ^ result) & (b ^ result)) >> top & 1 != 0;
+        self.af = (a ^ b ^ result) & 0x10 != 0;
+        self.set_result(result, size);
+    }
+    /// sub, sbb and cmp, `result` is `a - b` minus the borrow for sbb
+    fn sub(&mut self, a: u64, b: u64, result: u64, size: Size) {
+        let top = size.bytes() * 8 - 1;
+        let borrows = (!a & b) | ((!a | b) & result);
+        self.cf = borrows >> top & 1 != 0;
+        self.of = ((a ^ b) & (a ^ result)) >> top & 1 != 0;
+        self.af = (a ^ b ^ result) & 0x10 != 0;
+        self.set_result(result, size);
+    }
+    /// inc leaves CF alone
+    fn inc(&mut self, a: u64, result: u64, size: Size) {
+        let cf = self.cf;
+        self.add(a, 1, result, size);
+        self.cf = cf;
+    }
+    /// dec leaves CF alone
+    fn dec(&mut self, a: u64, result: u64, size: Size) {
+        let cf = self.cf;
+        self.sub(a, 1, result, size);
+        self.cf = cf;
+    }
+    /// neg is `0 - a`, so CF is set unless `a` is 0
+    fn neg(&mut self, a: u64, result: u64, size: Size) {
+        self.sub(0, a, result, size);
+    }
+}
 
-        $r.flags = Flags {
-            zf: e1 == e2,
-            // sf: e1 > e2,
+/// The x87 unit. `st` is indexed by physical register, `st(i)` is `st[(top + i) % 8]`.
+struct Fpu {
+    st: [F80; 8],
+    /// masks in bits 0 to 5, precision control in bits 8 and 9, rounding in 10 and 11
+    control: u16,
+    /// the status word without TOP, which is kept apart
+    status: u16,
+    top: u8,
+    /// two bits per physical register: valid, zero, special or empty
+    tags: u16,
+}
+impl Default for Fpu {
+    fn default() -> Fpu {
+        let mut fpu = Fpu {
+            st: Default::default(),
+            control: 0,
+            status: 0,
+            top: 0,
+            tags: 0,
         };
-    };
+        fpu.init();
+        fpu
+    }
+}
+impl Fpu {
+    /// fninit, which empties the stack but leaves what was in the registers.
+    fn init(&mut self) {
+        // 64 bit precision, round to nearest and everything masked
+        self.control = 0x37f;
+        self.status = 0;
+        self.top = 0;
+        self.tags = 0xffff;
+    }
+    /// The status word as fnstsw stores it, with TOP in bits 11 to 13.
+    fn status_word(&self) -> u16 {
+        self.status & !0x3800 | (self.top as u16) << 11
+    }
 }
 
-#[derive(Default)]
 struct Registers {
     general: [RegData; 16],
+    /// zmm0..zmm31, the xmm and ymm registers are their low parts
+    zmm: [[u8; 64]; 32],
+    /// opmask registers k0..k7
+    k: [u64; 8],
+    /// sse control and status, the rounding mode is in bits 13 and 14
+    mxcsr: u32,
     flags: Flags,
+    fpu: Fpu,
+    // the only segments with a base in long mode
+    fs_base: u64,
+    gs_base: u64,
+}
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers {
+            general: Default::default(),
+            zmm: [[0; 64]; 32],
+            k: Default::default(),
+            // round to nearest with all exceptions masked
+            mxcsr: 0x1f80,
+            flags: Default::default(),
+            fpu: Default::default(),
+            fs_base: 0,
+            gs_base: 0,
+        }
+    }
+}
+
+impl Registers {
+    /// Reads `reg` zero extended to 64 bits.
+    fn get(&self, reg: Reg) -> u64 {
+        let data = self.general[reg.as_usize()];
+        match reg {
+            Reg::R8(x) if x.is_high() => data.r8h() as u64,
+            Reg::R8(_) => data.r8() as u64,
+            Reg::R16(_) => data.r16() as u64,
+            Reg::R32(_) => data.r32() as u64,
+            Reg::R64(_) => data.r64(),
+        }
+    }
+    /// Writes the low bits of `value` into `reg`, 32 bit writes zero the upper half.
+    fn set(&mut self, reg: Reg, value: u64) {
+        let data = &mut self.general[reg.as_usize()];
+        match reg {
+            Reg::R8(x) if x.is_high() => data.set_r8h(value as u8),
+            Reg::R8(_) => data.set_r8(value as u8),
+            Reg::R16(_) => data.set_r16(value as u16),
+            Reg::R32(_) => data.set_r32(value as u32),
+            Reg::R64(_) => data.set_r64(value),
+        }
+    }
 }
 
 impl<T: Register> std::ops::Index<T> for Registers {
@@ -192,487 +304,152 @@ impl<T: Register> std::ops::IndexMut<T> for Registers {
     }
 }
 
-struct Emulator<'x, D: DisasmWriter> {
+struct Emulator<D: DisasmWriter> {
     regs: Registers,
-    stack: [u8; 1024 * 1024],
-    ip: usize,
-    code: &'x [u8],
-    rex_prefix: Option<Rex>,
-    is_16_bit: bool,
-    running: bool,
+    /// The code is mapped at address 0 and the stack grows down from the end. The stack starts
+    /// with a return address just past the end, where the code stops with `Returned`.
+    memory: Memory,
+    ip: u64,
+    /// Address of the instruction being executed, `ip` already points past it.
+    instr_ip: u64,
     d: D,
 }
-impl<'x, D: DisasmWriter> Emulator<'x, D> {
-    fn new(code: &[u8], d: D) -> Emulator<'_, D> {
-        Emulator {
+impl<D: DisasmWriter> Emulator<D> {
+    fn new(code: &[u8], d: D) -> Emulator<D> {
+        let mut emulator = Emulator {
             regs: Registers::default(),
-            stack: [0; 1024 * 1024],
+            memory: Memory::new(1024 * 1024),
             ip: 0,
-            code,
-            rex_prefix: None,
-            is_16_bit: false,
-            running: true,
+            instr_ip: 0,
             d,
-        }
-    }
-
-    fn run(&mut self) {
-        crate::run(self)
-    }
-    #[cfg(test)]
-    fn run_to_end(&mut self) -> Registers {
-        while self.running {
-            self.run();
-        }
-        std::mem::replace(&mut self.regs, Registers::default())
+        };
+        emulator
+            .memory
+            .write_bytes(0, code)
+            .expect("code doesn't fit in memory");
+        let end = emulator.memory.len() as u64;
+        emulator
+            .memory
+            .write(end - 8, Size::Qword, end)
+            .expect("no room for the return address");
+        emulator.regs[RBP].set_r64(end);
+        emulator.regs[RSP].set_r64(end - 8);
+        emulator
     }
-}
-
-fn run<D: DisasmWriter>(emulator: &mut Emulator<D>) {
-    let ip = &mut emulator.ip;
-    let registers = &mut emulator.regs;
-    let stack = &mut emulator.stack;
-    let d = &mut emulator.d;
-
-    registers[RBP].set_r64(stack.len() as u64);
-    registers[RSP].set_r64(stack.len() as u64);
-
-    let rex_prefix = &mut emulator.rex_prefix;
-    let is_16_bit = &mut emulator.is_16_bit;
-
-    let code = emulator.code;
-    let opcode = code[*ip];
-    match opcode {
-        0x0f => {
-            // je/jz rel32
-            // 0F 84 cd 	JE rel32 	D 	Valid 	Valid 	Jump near if equal (ZF=1).
-
-            let rel32 =
-                i32::from_le_bytes([code[*ip + 2], code[*ip + 3], code[*ip + 4], code[*ip + 5]]);
-
-            let (result, s) = match code[*ip + 1] {
-                0x84 => (registers.flags.zf, "je"),
-                0x85 => (!registers.flags.zf, "jne"),
-                _ => todo!(),
-            };
-
-            if result {
-                *ip += rel32 as usize + 1 + 1 + 4;
-            } else {
-                *ip += 1 + 1 + 4;
-            }
-
-            w!(d, "{s} near {}", rel32 + 4);
-        }
-        0x31 => {
-            // xor
-
-            let rex = rex_prefix.unwrap_or_default();
-            let modrm = ModRm(code[*ip + 1]);
-
-            if *is_16_bit {
-                xor::<R16, _>(modrm, rex, registers, d);
-            } else if rex.w() {
-                xor::<R64, _>(modrm, rex, registers, d);
-            } else {
-                xor::<R32, _>(modrm, rex, registers, d);
-            }
-
-            *ip += 2;
-            *rex_prefix = None;
-        }
-        0x55 => {
-            // push rbp
-            w!(d, "push rbp");
-            push_reg(registers, stack, RBP);
-            *ip += 1;
-        }
-        0x58..=0x5f => {
-            // pop r64
-            // 58+ rd 	POP r64 	O 	Valid 	N.E. 	Pop top of stack into r64; increment stack pointer.
-
-            let reg = R64::from_index(opcode - 0x58);
-
-            w!(d, "pop {}", reg);
-
-            *ip += 1;
-        }
-        0x66 => {
-            *is_16_bit = true;
-            *ip += 1;
-
-            run(emulator)
-        }
-        0x74 => {
-            // je/jz rel8
-            //  74 cb 	JE rel8 	D 	Valid 	Valid 	Jump short if equal (ZF=1).
-
-            let rel8 = code[1] as i8;
-
-            w!(d, "je {}", rel8);
-
-            *ip += 2;
-        }
-        0x80 => {
-            // cmp r/m8, imm8
-
-            let modrm = ModRm(code[*ip + 1]);
-
-            let dst = R64::from_index(modrm.rm());
-            // let src = R8::from_index(modrm.reg());
-
-            let mod_ = modrm.mod_();
-            match mod_ {
-                0b01 => {
-                    let disp = code[*ip + 2] as i8;
-                    let imm = code[*ip + 3];
-
-                    calc_flags!(registers, 5, 6);
 
-                    w!(d, "cmp byte [{}{:+}], {}", dst, disp, imm);
-                    *ip += 4;
-                }
-                _ => todo!(),
-            }
-
-            *rex_prefix = None;
+    /// Runs a single instruction, returning why the guest stopped if it did.
+    fn step(&mut self) -> Result<Option<StopReason>, EmulatorError> {
+        if self.ip == self.memory.len() as u64 {
+            return Ok(Some(StopReason::Returned));
         }
-        0x81 => {
-            // sub r/m16/32/64, imm16/32
-            // 81 /5 iw 	SUB r/m16, imm16 	MI 	Valid 	Valid 	Subtract imm16 from r/m16.
-
-            let rex = rex_prefix.unwrap_or_default();
-            let modrm = ModRm(code[*ip + 1]);
-
-            let is_cmp = modrm.reg() == 7;
-
-            // let src = R8::from_index(modrm.reg());
-
-            let mod_ = modrm.mod_();
-            match mod_ {
-                0b01 => {
-                    todo!()
-                    // let disp = code[*ip + 2] as i8;
-                    // let imm = code[*ip + 3];
-
-                    // w!(d, "sub {}, {}", dst, disp);
-
-                    // *ip += 4;
-                }
-                0b11 => {
-                    if rex.w() {
-                        let dst = R64::from_index(modrm.rm());
-
-                        let imm = i32::from_le_bytes([
-                            code[*ip + 2],
-                            code[*ip + 3],
-                            code[*ip + 4],
-                            code[*ip + 5],
-                        ]);
-
-                        let value = registers[dst].r64() as i64 - imm as i64;
-
-                        if is_cmp {
-                            w!(d, "cmp {}, {}", dst, imm);
-                        } else {
-                            registers[dst].set_r64(value as u64);
-
-                            w!(d, "sub {}, {}", dst, imm);
-                        }
-
-                        *ip += 1 + 1 + 4;
-                    } else {
-                        let dst = R32::from_index(modrm.rm());
-
-                        let imm = i32::from_le_bytes([
-                            code[*ip + 2],
-                            code[*ip + 3],
-                            code[*ip + 4],
-                            code[*ip + 5],
-                        ]);
-
-                        let value = registers[dst].r32() as i32 - imm;
-
-                        if is_cmp {
-                            w!(d, "cmp {}, {}", dst, imm);
-                        } else {
-                            registers[dst].set_r32(value as u32);
-
-                            w!(d, "sub {}, {}", dst, imm);
-                        }
-
-                        *ip += 1 + 1 + 4;
-                    }
-                }
-                _ => todo!("{:?}", modrm),
+        self.instr_ip = self.ip;
+        let code = self.memory.fetch(self.ip)?;
+        let (instr, len) = decode::decode(code).map_err(|e| {
+            let address = self.ip;
+            // just the instruction when we can tell where it ends
+            let len = length::instruction_length(code, Mode::Long)
+                .unwrap_or(MAX_INSTRUCTION_LEN)
+                .min(code.len());
+            let bytes = code[..len].to_vec();
+            match e {
+                DecodeError::Unimplemented => EmulatorError::UnimplementedOpcode { address, bytes },
+                DecodeError::Invalid => EmulatorError::InvalidEncoding { address, bytes },
+                DecodeError::Truncated => EmulatorError::MemoryFault {
+                    address: address + code.len() as u64,
+                    access: Access::Execute,
+                },
             }
+        })?;
+        self.ip += len as u64;
 
-            *rex_prefix = None;
+        match instr.branch_target(self.instr_ip) {
+            // a comment, so the output still assembles
+            Some(target) => w!(self.d, "{instr} ; {target:#x}"),
+            None => w!(self.d, "{}", instr),
         }
-        0x88 => {
-            // mov r/m8, r8
-
-            // let rex = rex_prefix.unwrap_or_default();
-            let modrm = ModRm(code[*ip + 1]);
-
-            let dst = R64::from_index(modrm.rm());
-            let src = R8::from_index(modrm.reg());
 
-            let mod_ = modrm.mod_();
-            match mod_ {
-                0x01 => {
-                    let disp = code[*ip + 2] as i8;
-                    let addr = registers[dst].r64() as i64 + disp as i64;
-
-                    stack[addr as usize] = registers[src].r8();
-
-                    w!(d, "mov [{}{:+}], {}", dst, disp, src);
-
-                    *ip += 3;
-                }
-                _ => todo!(),
-            }
-
-            *rex_prefix = None;
+        if instr.prefixes.lock && !instr.lockable() {
+            self.ip = self.instr_ip;
+            return Ok(Some(StopReason::InvalidOpcode));
         }
-        0x89 => {
-            // mov r,r
-
-            let rex = rex_prefix.unwrap_or_default();
-            let modrm = ModRm(code[*ip + 1]);
-
-            if *is_16_bit {
-                todo!()
-            } else if rex.w() {
-                assert_eq!(modrm.mod_(), 0b11);
-
-                let dst = R64::from_index(modrm.rm());
-                let src = R64::from_index(modrm.reg());
-
-                w!(d, "mov {}, {}", dst, src);
-
-                let value = registers[src].r64();
-                registers[dst].set_r64(value);
-            } else {
-                assert_eq!(modrm.mod_(), 0b11);
-
-                let dst = R32::from_index(modrm.rm());
-                let src = R32::from_index(modrm.reg());
-
-                w!(d, "mov {}, {}", dst, src);
-
-                let value = registers[src].r32();
-                registers[dst].set_r32(value);
-            }
-
-            *ip += 2;
-
-            *is_16_bit = false;
-            *rex_prefix = None;
+        if self.misaligned(&instr) {
+            self.ip = self.instr_ip;
+            return Ok(Some(StopReason::GeneralProtection));
         }
-        0x8b => {
-            // mov
-
-            let rex = rex_prefix.unwrap_or_default();
-            let modrm = ModRm(code[*ip + 1]);
-
-            let mod_ = modrm.mod_();
-            match mod_ {
-                0b01 => {
-                    if rex.w() {
-                        todo!()
-                    } else {
-                        let disp = code[*ip + 2] as i8;
-
-                        let src = R64::from_index(modrm.rm());
-                        let dst = R32::from_index(modrm.reg());
-                        let mem_src = registers[src].r64() as i64 + disp as i64;
-                        let mem_src = mem_src as usize;
-
-                        let mut data = [0; 4];
-                        data.copy_from_slice(&stack[mem_src..mem_src + 4]);
-                        let data = i32::from_le_bytes(data);
-                        registers[dst].set_r32(data as u32);
-
-                        w!(d, "mov {}, [{}{:+}]", dst, src, disp);
-
-                        *ip += 1 + 1 + 1;
-                    }
-                }
-                _ => todo!("{:?}", modrm),
-            }
-
-            *rex_prefix = None;
-        }
-        0xb0..=0xb7 => {
-            // let rex = rex_prefix.unwrap_or_default();
-
-            let reg = R8::from_index(opcode - 0xb0);
-            let data = code[*ip + 1];
-
-            registers[reg].set_r8(data);
-
-            w!(d, "mov {}, {}", reg, data);
-
-            *ip += 2;
-            *rex_prefix = None;
-        }
-        0xb8..=0xbf => {
-            // mov r, imm16/32/64
-
-            let rex = rex_prefix.unwrap_or_default();
-
-            if *is_16_bit {
-                assert!(code.len() >= *ip + 2);
-
-                let reg = R16::from_index(opcode - 0xb8 + 8 * rex.b() as u8);
-
-                let data = i16::from_le_bytes([code[*ip + 1], code[*ip + 2]]);
-
-                registers[reg].set_r16(data as u16);
-
-                w!(d, "mov {}, {:#x}", reg, data);
-
-                *ip += 1 + 2;
-            } else if rex.w() {
-                assert!(code.len() >= *ip + 8);
-
-                let reg = R64::from_index(opcode - 0xb8 + 8 * rex.b() as u8);
-
-                let data = i64::from_le_bytes([
-                    code[*ip + 1],
-                    code[*ip + 2],
-                    code[*ip + 3],
-                    code[*ip + 4],
-                    code[*ip + 5],
-                    code[*ip + 6],
-                    code[*ip + 7],
-                    code[*ip + 8],
-                ]);
-
-                registers[reg].set_r64(data as u64);
-
-                w!(d, "mov {}, {:#x}", reg, data);
-
-                *ip += 1 + 8;
-            } else {
-                assert!(code.len() >= *ip + 5);
-
-                let reg = R32::from_index(opcode - 0xb8 + 8 * rex.b() as u8);
-
-                let data = i32::from_le_bytes([
-                    code[*ip + 1],
-                    code[*ip + 2],
-                    code[*ip + 3],
-                    code[*ip + 4],
-                ]);
-
-                registers[reg].set_r32(data as u32);
-
-                w!(d, "mov {}, {:#x}", reg, data);
-
-                *ip += 1 + 4;
-            }
-
-            *is_16_bit = false;
-            *rex_prefix = None;
-        }
-        0xc3 => {
-            w!(d, "ret");
-            // break;
-        }
-        0xc7 => {
-            // mov
-
-            let rex = rex_prefix.unwrap_or_default();
-            let modrm = ModRm(code[*ip + 1]);
-
-            let mod_ = modrm.mod_();
-            match mod_ {
-                0b01 => {
-                    if rex.w() {
-                        todo!()
-                    } else {
-                        let disp = code[*ip + 2] as i8;
-                        let data = i32::from_le_bytes([
-                            code[*ip + 3],
-                            code[*ip + 4],
-                            code[*ip + 5],
-                            code[*ip + 6],
-                        ]);
-
-                        let dst = R64::from_index(modrm.rm());
-                        let mem_dst = registers[dst].r64() as i64 + disp as i64;
-                        let mem_dst = mem_dst as usize;
-
-                        stack[mem_dst..mem_dst + 4].copy_from_slice(&data.to_le_bytes());
-
-                        w!(d, "mov dword [{}{:+}], {}", dst, disp, data);
-
-                        *ip += 1 + 1 + 1 + 4;
-                    }
-                }
-                0b11 => {
-                    if rex.w() {
-                        assert!(code.len() >= *ip + 6);
-                        let data = [code[*ip + 2], code[*ip + 3], code[*ip + 4], code[*ip + 5]];
-                        let data = i32::from_le_bytes(data) as i64;
-
-                        let dst = R64::from_index(modrm.reg());
-                        registers[dst].set_r64(data as u64);
-
-                        *ip += 6;
+        self.execute(&instr)
+    }
 
-                        w!(d, "mov {}, {:#x}", dst, data);
-                    } else {
-                        todo!()
-                    }
-                }
-                _ => todo!("{:?}", modrm),
+    /// Runs until the guest stops or something goes wrong.
+    #[allow(dead_code)]
+    fn run(&mut self) -> Result<StopReason, EmulatorError> {
+        loop {
+            if let Some(reason) = self.step()? {
+                return Ok(reason);
             }
-
-            *rex_prefix = None;
         }
-        0xe9 => {
-            // jmp rel32
-            // E9 cd 	JMP rel32 	D 	Valid 	Valid 	Jump near, relative, Rip = Rip + 32-bit displacement sign extended to 64-bits.
-
-            let rel32 =
-                i32::from_le_bytes([code[*ip + 1], code[*ip + 2], code[*ip + 3], code[*ip + 4]]);
+    }
+    #[cfg(test)]
+    fn run_to_end(&mut self) -> Registers {
+        let reason = self.run().unwrap_or_else(|e| panic!("{e}\n{}", self.d));
+        // code that ends in a ret doesn't get to the hlt after it
+        assert!(matches!(reason, StopReason::Halted | StopReason::Returned));
+        std::mem::take(&mut self.regs)
+    }
+}
 
-            w!(d, "jmp near {}", rel32 + 4);
+/// Parses bytes written as hex, with or without spaces between them.
+fn parse_hex(args: &[String]) -> Result<Vec<u8>> {
+    let hex: String = args.concat().split_whitespace().collect();
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
 
-            *ip += rel32 as i64 as usize;
-        }
-        0xf3 => {
-            if code[*ip + 1..].starts_with(&[0x0f, 0x1e, 0xfa]) {
-                // endbr64
-                w!(d, "endbr64");
-                *ip += 4;
-            } else {
-                todo!();
-            }
-        }
-        0xf4 => {
-            // hlt, we use it for testing as it can never appear in userspace code
-            emulator.running = false;
-        }
-        _ => {
-            if opcode & 0b0100 << 4 != 0 {
-                *rex_prefix = Some(Rex(opcode));
-                // dbg!(rex_prefix);
-                *ip += 1;
+/// `length <16|32|64> <hex>`, prints the length of each instruction in the bytes.
+fn print_lengths(args: &[String]) -> Result<()> {
+    let mode = match args.first().map(String::as_str) {
+        Some("16") => Mode::Real,
+        Some("32") => Mode::Protected,
+        Some("64") => Mode::Long,
+        _ => anyhow::bail!("usage: length <16|32|64> <hex>"),
+    };
+    let code = parse_hex(&args[1..])?;
+    let mut pos = 0;
+    while pos < code.len() {
+        let len = length::instruction_length(&code[pos..], mode)
+            .map_err(|e| anyhow::anyhow!("at {pos}: {e:?}"))?;
+        println!("{pos}: {len}");
+        pos += len;
+    }
+    Ok(())
+}
 
-                run(emulator)
-            } else {
-                todo!("opcode={:#x}\n+++++++++++++++++++++++++++++++++++\n{}+++++++++++++++++++++++++++++++++++", opcode, d);
-            }
-        }
+/// `encode <hex>`, decodes the bytes and prints each instruction with its shortest encoding.
+fn reencode(args: &[String]) -> Result<()> {
+    let code = parse_hex(args)?;
+    let mut pos = 0;
+    while pos < code.len() {
+        let (instr, len) =
+            decode::decode(&code[pos..]).map_err(|e| anyhow::anyhow!("at {pos}: {e:?}"))?;
+        let bytes = encode::encode(&instr).map_err(|e| anyhow::anyhow!("{instr}: {e:?}"))?;
+        let hex: Vec<String> = bytes.iter().map(|x| format!("{x:02x}")).collect();
+        println!("{:<40} {}", instr.to_string(), hex.join(" "));
+        pos += len;
     }
+    Ok(())
 }
 
 fn main() -> Result<()> {
-    new_tester::run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("length") => print_lengths(&args[1..])?,
+        Some("encode") => reencode(&args[1..])?,
+        _ => new_tester::run(),
+    }
 
     Ok(())
 }
@@ -0,0 +1,62 @@
+use crate::error::{Access, EmulatorError};
+use crate::instr::Size;
+
+/// Flat guest memory starting at address 0, anything past the end faults.
+pub struct Memory {
+    data: Vec<u8>,
+}
+impl Memory {
+    pub fn new(size: usize) -> Memory {
+        Memory {
+            data: vec![0; size],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn range(
+        &self,
+        address: u64,
+        len: usize,
+        access: Access,
+    ) -> Result<std::ops::Range<usize>, EmulatorError> {
+        let fault = EmulatorError::MemoryFault { address, access };
+        let start = usize::try_from(address).map_err(|_| fault.clone())?;
+        let end = start.checked_add(len).ok_or(fault.clone())?;
+        if end > self.data.len() {
+            return Err(fault);
+        }
+        Ok(start..end)
+    }
+
+    pub fn read_bytes(&self, address: u64, len: usize) -> Result<&[u8], EmulatorError> {
+        let range = self.range(address, len, Access::Read)?;
+        Ok(&self.data[range])
+    }
+
+    pub fn write_bytes(&mut self, address: u64, bytes: &[u8]) -> Result<(), EmulatorError> {
+        let range = self.range(address, bytes.len(), Access::Write)?;
+        self.data[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Reads `size` bytes zero extended to 64 bits.
+    pub fn read(&self, address: u64, size: Size) -> Result<u64, EmulatorError> {
+        let mut data = [0; 8];
+        data[..size.bytes()].copy_from_slice(self.read_bytes(address, size.bytes())?);
+        Ok(u64::from_le_bytes(data))
+    }
+
+    /// Writes the low `size` bytes of `value`.
+    pub fn write(&mut self, address: u64, size: Size, value: u64) -> Result<(), EmulatorError> {
+        self.write_bytes(address, &value.to_le_bytes()[..size.bytes()])
+    }
+
+    /// Everything from `address` to the end of memory, for the decoder.
+    pub fn fetch(&self, address: u64) -> Result<&[u8], EmulatorError> {
+        let range = self.range(address, 1, Access::Execute)?;
+        Ok(&self.data[range.start..])
+    }
+}
@@ -1,5 +1,5 @@
 use crate::gdb::RegisterNames;
-use crate::gdb::{Debuggable, Message, Gdb};
+use crate::gdb::{Gdb, Message};
 use crate::registers::{Register, R64};
 use crate::{Emulator, Nothing};
 use anyhow::anyhow;
@@ -24,12 +24,6 @@ impl ProcSpawnOk for Command {
     }
 }
 
-#[derive(Default, Copy, Clone)]
-#[repr(C)]
-struct HwRegs {
-    regs: [u64; 16],
-}
-
 fn process_register_names(names: RegisterNames) -> [R64; 16] {
     let mut regs = [R64::R15; 16];
 
@@ -134,7 +128,9 @@ fn run_one(s: &str, tmp: &mut Vec<u8>) -> Result<()> {
             continue;
         }
 
-        emulator.run();
+        if emulator.step()?.is_some() {
+            break;
+        }
 
         for (i, &hw_value) in registers.iter().enumerate() {
             if i == 4 || i == 5 {
@@ -155,18 +151,12 @@ fn run_one(s: &str, tmp: &mut Vec<u8>) -> Result<()> {
     Ok(())
 }
 
-struct NewTester {}
-impl Debuggable for NewTester {
-    fn init(&mut self, gdb: &mut Gdb) {
-        gdb.breakpoint("main.cpp", 5);
-    }
-}
-
 pub fn run_impl() -> Result<()> {
     fs::create_dir_all("tmp")?;
 
     let mut buffer = String::new();
     let mut tmp = Vec::new();
+    let mut failures = Vec::new();
     for i in fs::read_dir("../tests")? {
         let path = i?.path();
         let path = path.to_str().unwrap();
@@ -177,7 +167,17 @@ pub fn run_impl() -> Result<()> {
         tmp.clear();
         buffer.clear();
         file.read_to_string(&mut buffer)?;
-        run_one(&buffer, &mut tmp)?;
+        if let Err(e) = run_one(&buffer, &mut tmp) {
+            eprintln!("error: {path}: {e}");
+            failures.push((path.to_string(), e));
+        }
+    }
+
+    if !failures.is_empty() {
+        for (path, e) in &failures {
+            eprintln!("failed: {path}: {e}");
+        }
+        return Err(anyhow!("{} test(s) failed", failures.len()));
     }
 
     Ok(())
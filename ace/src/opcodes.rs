@@ -0,0 +1,189 @@
+//! Opcode tables, generated by build.rs from instructions.txt.
+
+use crate::decode::ModRm;
+use crate::instr::{Cond, Size};
+use crate::prefix::{Prefixes, Rep};
+use std::fmt::Display;
+
+include!(concat!(env!("OUT_DIR"), "/opcodes.rs"));
+
+impl Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Jcc(cond) | Op::Setcc(cond) | Op::Cmovcc(cond) => {
+                write!(f, "{}{}", self.name(), cond.suffix())
+            }
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+impl Op {
+    /// The element size of movs, cmps, stos, lods and scas, `None` for everything else.
+    pub fn string_size(self) -> Option<Size> {
+        match self {
+            Op::Movsb | Op::Cmpsb | Op::Stosb | Op::Lodsb | Op::Scasb => Some(Size::Byte),
+            Op::Movsw | Op::Cmpsw | Op::Stosw | Op::Lodsw | Op::Scasw => Some(Size::Word),
+            Op::Movsd | Op::Cmpsd | Op::Stosd | Op::Lodsd | Op::Scasd => Some(Size::Dword),
+            Op::Movsq | Op::Cmpsq | Op::Stosq | Op::Lodsq | Op::Scasq => Some(Size::Qword),
+            _ => None,
+        }
+    }
+}
+
+/// Where an operand is encoded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Src {
+    /// modrm.reg
+    Reg,
+    /// modrm.rm, register or memory
+    Rm,
+    /// modrm.rm, memory only
+    Mem,
+    /// modrm.rm, register only
+    RmReg,
+    /// vex.vvvv
+    Vvvv,
+    /// the top 4 bits of an 8 bit immediate
+    Is4,
+    /// low 3 bits of the opcode
+    Opcode,
+    Imm,
+    Rel,
+    /// a register that isn't encoded, like xmm0 for pblendvb
+    Fixed(u8),
+    /// the count of `shl r/m32, 1` and friends, an immediate 1 that isn't encoded
+    One,
+}
+
+/// Which register file a register operand is in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegKind {
+    /// general purpose registers of the operand's width
+    Gpr,
+    /// `r32/m8` and friends, registers are always 32 bits
+    Gpr32,
+    Xmm,
+    Ymm,
+    Zmm,
+    /// opmask registers k0..k7
+    Mask,
+    /// x87 stack registers st0..st7
+    St,
+}
+impl RegKind {
+    /// Whether evex can encode registers 16..31 of this kind.
+    pub fn is_vector(self) -> bool {
+        matches!(self, RegKind::Xmm | RegKind::Ymm | RegKind::Zmm)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Width {
+    Fixed(Size),
+    /// 16/32/64, from the operand size
+    V,
+    /// 16/32, 64 bit operand size still uses 32 bits
+    Z,
+    /// 16/64, stack operations are 64 bits unless there's a 66 prefix
+    D,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Spec {
+    pub src: Src,
+    pub reg: RegKind,
+    /// for `Rm` this is the width of the memory operand
+    pub width: Width,
+}
+
+/// A 66/F2/F3 (or vex.pp) that selects the instruction instead of acting as a prefix.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mandatory {
+    Any,
+    /// none of 66, F2 or F3
+    Np,
+    P66,
+    F2,
+    F3,
+}
+
+/// The opmask decorations an evex instruction takes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Masking {
+    None,
+    /// `{k1}`
+    Merge,
+    /// `{k1}{z}`, merging or zeroing
+    Zero,
+}
+
+/// One line of instructions.txt.
+#[derive(Debug)]
+pub struct Def {
+    pub op: Op,
+    pub prefix: Mandatory,
+    /// REX.W or VEX.W, `None` if it's ignored
+    pub w: Option<bool>,
+    /// o16, o32 or o64, the only operand size the form is for
+    pub size: Option<Size>,
+    /// VEX.L or EVEX.L'L, 0 for 128 bits up to 2 for 512 bits
+    pub l: Option<u8>,
+    /// `/digit`, the value modrm.reg must have
+    pub ext: Option<u8>,
+    pub modrm: bool,
+    pub fixed_modrm: Option<u8>,
+    pub mask: Masking,
+    /// element size of the `/m32bcst` style memory operand that evex.b broadcasts
+    pub broadcast: Option<Size>,
+    /// `{er}`, evex.b on a register form picks the rounding mode
+    pub er: bool,
+    /// `{sae}`, evex.b on a register form suppresses exceptions
+    pub sae: bool,
+    pub operands: &'static [Spec],
+}
+impl Def {
+    pub fn matches(&self, prefixes: &Prefixes, modrm: Option<ModRm>) -> bool {
+        let prefix = match self.prefix {
+            Mandatory::Any => true,
+            Mandatory::Np => !prefixes.operand_size && prefixes.rep.is_none(),
+            // f2 and f3 win over 66 when there are both
+            Mandatory::P66 => prefixes.operand_size && prefixes.rep.is_none(),
+            Mandatory::F2 => prefixes.rep == Some(Rep::Repne),
+            Mandatory::F3 => prefixes.rep == Some(Rep::Rep),
+        };
+        let modrm_matches = match (self.modrm, modrm) {
+            (true, Some(modrm)) => {
+                self.ext.is_none_or(|x| x == modrm.reg())
+                    && self.fixed_modrm.is_none_or(|x| x == modrm.0)
+            }
+            (false, None) => true,
+            _ => false,
+        };
+        let l = prefixes.vex.map(|x| x.l);
+        prefix
+            && modrm_matches
+            && self.w.is_none_or(|w| w == prefixes.rex().w())
+            && self.size.is_none_or(|x| x == prefixes.op_size())
+            && self.l.is_none_or(|x| Some(x) == l)
+    }
+
+    /// Whether a register or memory modrm fits operands that only take one of them.
+    pub fn fits(&self, modrm: Option<ModRm>) -> bool {
+        let is_reg = modrm.is_some_and(|x| x.mod_() == 0b11);
+        self.operands.iter().all(|x| match x.src {
+            Src::Mem => !is_reg,
+            Src::RmReg => is_reg,
+            _ => true,
+        })
+    }
+
+    /// Removes the mandatory prefix, so it isn't taken as an operand size or rep prefix.
+    pub fn strip_prefix(&self, prefixes: &mut Prefixes) {
+        match self.prefix {
+            Mandatory::Any | Mandatory::Np => {}
+            Mandatory::P66 => prefixes.operand_size = false,
+            Mandatory::F2 | Mandatory::F3 => prefixes.rep = None,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use crate::instr::Size;
+use std::fmt::{Debug, Display};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rex(pub u8);
+#[allow(dead_code)]
+impl Rex {
+    pub fn b(self) -> bool {
+        self.0 & 0b1 != 0
+    }
+    pub fn x(self) -> bool {
+        self.0 & 0b10 != 0
+    }
+    pub fn r(self) -> bool {
+        self.0 & 0b100 != 0
+    }
+    pub fn w(self) -> bool {
+        self.0 & 0b1000 != 0
+    }
+}
+impl Debug for Rex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rex")
+            .field("b", &self.b())
+            .field("x", &self.x())
+            .field("r", &self.r())
+            .field("w", &self.w())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+}
+impl Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Segment::Es => "es",
+            Segment::Cs => "cs",
+            Segment::Ss => "ss",
+            Segment::Ds => "ds",
+            Segment::Fs => "fs",
+            Segment::Gs => "gs",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rep {
+    /// F3, also `repe`/`repz` for cmps and scas
+    Rep,
+    /// F2
+    Repne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+    // group 1
+    Lock,
+    Rep(Rep),
+    // group 2
+    Segment(Segment),
+    // group 3
+    OperandSize,
+    // group 4
+    AddressSize,
+    Rex(Rex),
+}
+impl Prefix {
+    pub fn from_byte(x: u8) -> Option<Prefix> {
+        let prefix = match x {
+            0xf0 => Prefix::Lock,
+            0xf2 => Prefix::Rep(Rep::Repne),
+            0xf3 => Prefix::Rep(Rep::Rep),
+            0x26 => Prefix::Segment(Segment::Es),
+            0x2e => Prefix::Segment(Segment::Cs),
+            0x36 => Prefix::Segment(Segment::Ss),
+            0x3e => Prefix::Segment(Segment::Ds),
+            0x64 => Prefix::Segment(Segment::Fs),
+            0x65 => Prefix::Segment(Segment::Gs),
+            0x66 => Prefix::OperandSize,
+            0x67 => Prefix::AddressSize,
+            0x40..=0x4f => Prefix::Rex(Rex(x)),
+            _ => return None,
+        };
+        Some(prefix)
+    }
+}
+
+/// The parts of a C4/C5 prefix that don't map to legacy prefixes, pp and RXBW end up in
+/// `operand_size`, `rep` and `rex`. Evex instructions have one of these too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vex {
+    /// the extra register operand, already inverted back, evex.V' makes it 5 bits
+    pub vvvv: u8,
+    /// vector length, 0 for 128 bits up to 2 for 512 bits
+    pub l: u8,
+    /// C4 where C5 would have done, only needed to reproduce the exact encoding
+    pub vex3: bool,
+}
+
+/// Static rounding for an evex instruction, `Sae` only suppresses exceptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Nearest,
+    Down,
+    Up,
+    Zero,
+    Sae,
+}
+impl Display for Rounding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Rounding::Nearest => "{rn-sae}",
+            Rounding::Down => "{rd-sae}",
+            Rounding::Up => "{ru-sae}",
+            Rounding::Zero => "{rz-sae}",
+            Rounding::Sae => "{sae}",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The parts of a 62 prefix that vex doesn't have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evex {
+    /// opmask register, k0 means no masking
+    pub mask: u8,
+    /// zero the masked out elements instead of keeping them
+    pub zeroing: bool,
+    /// evex.b, a broadcast for memory operands and rounding or sae for registers
+    pub b: bool,
+    /// evex.R', bit 4 of modrm.reg
+    pub high_r: bool,
+    pub rounding: Option<Rounding>,
+    /// element size when a memory operand is broadcast
+    pub broadcast: Option<Size>,
+    /// a vex prefix would have done, only needed to reproduce the exact encoding
+    pub vex_encodable: bool,
+}
+
+/// Prefixes seen before an opcode. Only one prefix per group has an effect, the last one wins.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Prefixes {
+    pub lock: bool,
+    pub rep: Option<Rep>,
+    pub segment: Option<Segment>,
+    pub operand_size: bool,
+    pub address_size: bool,
+    pub rex: Option<Rex>,
+    pub vex: Option<Vex>,
+    pub evex: Option<Evex>,
+}
+impl Prefixes {
+    pub fn add(&mut self, prefix: Prefix) {
+        // a rex prefix only counts if it's right before the opcode, anything after it cancels it
+        self.rex = None;
+
+        match prefix {
+            Prefix::Lock => self.lock = true,
+            Prefix::Rep(rep) => self.rep = Some(rep),
+            Prefix::Segment(segment) => self.segment = Some(segment),
+            Prefix::OperandSize => self.operand_size = true,
+            Prefix::AddressSize => self.address_size = true,
+            Prefix::Rex(rex) => self.rex = Some(rex),
+        }
+    }
+
+    pub fn rex(&self) -> Rex {
+        self.rex.unwrap_or_default()
+    }
+
+    /// Operand size of an instruction that has 16/32/64 bit forms.
+    pub fn op_size(&self) -> Size {
+        if self.rex().w() {
+            Size::Qword
+        } else if self.operand_size {
+            Size::Word
+        } else {
+            Size::Dword
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Prefix, Prefixes, Rep, Rex, Segment};
+
+    fn accumulate(bytes: &[u8]) -> Prefixes {
+        let mut prefixes = Prefixes::default();
+        for &x in bytes {
+            prefixes.add(Prefix::from_byte(x).unwrap());
+        }
+        prefixes
+    }
+
+    #[test]
+    fn last_rex_wins() {
+        assert_eq!(accumulate(&[0x41, 0x48]).rex, Some(Rex(0x48)));
+    }
+
+    #[test]
+    fn rex_before_legacy_is_ignored() {
+        let prefixes = accumulate(&[0x48, 0x66]);
+        assert_eq!(prefixes.rex, None);
+        assert!(prefixes.operand_size);
+    }
+
+    #[test]
+    fn groups() {
+        let prefixes = accumulate(&[0xf2, 0x64, 0xf0, 0xf3, 0x65, 0x67]);
+        assert!(prefixes.lock);
+        assert!(prefixes.address_size);
+        assert_eq!(prefixes.rep, Some(Rep::Rep));
+        assert_eq!(prefixes.segment, Some(Segment::Gs));
+    }
+}
@@ -1,22 +1,19 @@
 #![allow(clippy::upper_case_acronyms)]
 
-use std::{fmt::Display, ops::BitXor};
+use std::fmt::Display;
 
-use crate::RegData;
+use crate::instr::Size;
 
 pub trait Register: Copy + Display {
-    type BaseType: BitXor<Output = Self::BaseType> + Into<u64>;
-
     fn from_index(x: u8) -> Self;
     fn as_usize(self) -> usize;
-    fn from_reg(x: RegData) -> Self::BaseType;
 }
 
 use R16::*;
 use R32::*;
 use R64::*;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum R64 {
     RAX,
     RCX,
@@ -61,8 +58,6 @@ impl R64 {
 }
 
 impl Register for R64 {
-    type BaseType = u64;
-
     fn from_index(x: u8) -> R64 {
         match x {
             0 => RAX,
@@ -90,10 +85,6 @@ impl Register for R64 {
     fn as_usize(self) -> usize {
         self as usize
     }
-
-    fn from_reg(x: RegData) -> Self::BaseType {
-        x.r64()
-    }
 }
 impl Display for R64 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -119,7 +110,7 @@ impl Display for R64 {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum R32 {
     EAX,
     ECX,
@@ -140,8 +131,6 @@ pub enum R32 {
 }
 
 impl Register for R32 {
-    type BaseType = u32;
-
     fn from_index(x: u8) -> R32 {
         match x {
             0 => EAX,
@@ -169,10 +158,6 @@ impl Register for R32 {
     fn as_usize(self) -> usize {
         self as usize
     }
-
-    fn from_reg(x: RegData) -> Self::BaseType {
-        x.r32()
-    }
 }
 
 impl Display for R32 {
@@ -199,7 +184,7 @@ impl Display for R32 {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum R16 {
     AX,
     CX,
@@ -220,8 +205,6 @@ pub enum R16 {
 }
 
 impl Register for R16 {
-    type BaseType = u16;
-
     fn from_index(x: u8) -> R16 {
         match x {
             0 => AX,
@@ -249,10 +232,6 @@ impl Register for R16 {
     fn as_usize(self) -> usize {
         self as usize
     }
-
-    fn from_reg(x: RegData) -> Self::BaseType {
-        x.r16()
-    }
 }
 
 impl Display for R16 {
@@ -279,20 +258,16 @@ impl Display for R16 {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum R8 {
     AL,
     CL,
     DL,
     BL,
-    AH,
-    CH,
-    DH,
-    BH,
-    BPL,
     SPL,
-    DIL,
+    BPL,
     SIL,
+    DIL,
     R8B,
     R9B,
     R10B,
@@ -301,43 +276,61 @@ pub enum R8 {
     R13B,
     R14B,
     R15B,
+    AH,
+    CH,
+    DH,
+    BH,
 }
-impl Register for R8 {
-    type BaseType = u8;
 
+impl R8 {
+    /// Byte register encoding without a REX prefix, where 4..=7 are ah..bh.
+    pub fn from_legacy_index(x: u8) -> R8 {
+        match x {
+            4..=7 => R8::from_index(x + 12),
+            _ => R8::from_index(x),
+        }
+    }
+
+    pub fn is_high(self) -> bool {
+        matches!(self, R8::AH | R8::CH | R8::DH | R8::BH)
+    }
+}
+
+impl Register for R8 {
     fn from_index(x: u8) -> Self {
         match x {
             0 => R8::AL,
             1 => R8::CL,
             2 => R8::DL,
             3 => R8::BL,
-            4 => R8::AH,
-            5 => R8::CH,
-            6 => R8::DH,
-            7 => R8::BH,
-            8 => R8::BPL,
-            9 => R8::SPL,
-            10 => R8::DIL,
-            11 => R8::SIL,
-            12 => R8::R8B,
-            13 => R8::R9B,
-            14 => R8::R10B,
-            15 => R8::R11B,
-            16 => R8::R12B,
-            17 => R8::R13B,
-            18 => R8::R14B,
-            19 => R8::R15B,
+            4 => R8::SPL,
+            5 => R8::BPL,
+            6 => R8::SIL,
+            7 => R8::DIL,
+            8 => R8::R8B,
+            9 => R8::R9B,
+            10 => R8::R10B,
+            11 => R8::R11B,
+            12 => R8::R12B,
+            13 => R8::R13B,
+            14 => R8::R14B,
+            15 => R8::R15B,
+            16 => R8::AH,
+            17 => R8::CH,
+            18 => R8::DH,
+            19 => R8::BH,
             //
             _ => unreachable!("invalid register number"),
         }
     }
 
+    /// Index of the general purpose register that holds this byte, ah..bh live in rax..rbx.
     fn as_usize(self) -> usize {
-        self as usize
-    }
-
-    fn from_reg(x: RegData) -> Self::BaseType {
-        x.r8()
+        if self.is_high() {
+            self as usize - 16
+        } else {
+            self as usize
+        }
     }
 }
 
@@ -348,14 +341,10 @@ impl Display for R8 {
             R8::CL => "cl",
             R8::DL => "dl",
             R8::BL => "bl",
-            R8::AH => "ah",
-            R8::CH => "ch",
-            R8::DH => "dh",
-            R8::BH => "bh",
-            R8::BPL => "bpl",
             R8::SPL => "spl",
-            R8::DIL => "dil",
+            R8::BPL => "bpl",
             R8::SIL => "sil",
+            R8::DIL => "dil",
             R8::R8B => "r8b",
             R8::R9B => "r9b",
             R8::R10B => "r10b",
@@ -364,7 +353,103 @@ impl Display for R8 {
             R8::R13B => "r13b",
             R8::R14B => "r14b",
             R8::R15B => "r15b",
+            R8::AH => "ah",
+            R8::CH => "ch",
+            R8::DH => "dh",
+            R8::BH => "bh",
         };
         f.write_str(s)
     }
 }
+
+/// A general purpose register of any width, as it appears in a decoded instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reg {
+    R8(R8),
+    R16(R16),
+    R32(R32),
+    R64(R64),
+}
+
+impl Reg {
+    /// `rex` selects between ah..bh and spl..dil for byte registers 4..=7.
+    pub fn new(size: Size, index: u8, rex: bool) -> Reg {
+        match size {
+            Size::Byte if rex => Reg::R8(R8::from_index(index)),
+            Size::Byte => Reg::R8(R8::from_legacy_index(index)),
+            Size::Word => Reg::R16(R16::from_index(index)),
+            Size::Dword => Reg::R32(R32::from_index(index)),
+            Size::Qword => Reg::R64(R64::from_index(index)),
+            Size::Tword | Size::Oword | Size::Yword | Size::Zword => {
+                unreachable!("no {size:?} general purpose registers")
+            }
+        }
+    }
+
+    pub fn size(self) -> Size {
+        match self {
+            Reg::R8(_) => Size::Byte,
+            Reg::R16(_) => Size::Word,
+            Reg::R32(_) => Size::Dword,
+            Reg::R64(_) => Size::Qword,
+        }
+    }
+
+    pub fn as_usize(self) -> usize {
+        match self {
+            Reg::R8(x) => x.as_usize(),
+            Reg::R16(x) => x.as_usize(),
+            Reg::R32(x) => x.as_usize(),
+            Reg::R64(x) => x.as_usize(),
+        }
+    }
+}
+
+impl Display for Reg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reg::R8(x) => x.fmt(f),
+            Reg::R16(x) => x.fmt(f),
+            Reg::R32(x) => x.fmt(f),
+            Reg::R64(x) => x.fmt(f),
+        }
+    }
+}
+
+/// A vector register, `size` says which part of it the instruction uses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VecReg {
+    pub size: Size,
+    pub index: u8,
+}
+
+impl Display for VecReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.size {
+            Size::Oword => write!(f, "xmm{}", self.index),
+            Size::Yword => write!(f, "ymm{}", self.index),
+            Size::Zword => write!(f, "zmm{}", self.index),
+            _ => unreachable!("no {:?} vector registers", self.size),
+        }
+    }
+}
+
+/// An opmask register, k0..k7.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MaskReg(pub u8);
+
+impl Display for MaskReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "k{}", self.0)
+    }
+}
+
+/// An x87 stack register, st0 is the top of the stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StReg(pub u8);
+
+impl Display for StReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "st{}", self.0)
+    }
+}